@@ -93,6 +93,43 @@ pub enum LocalRequest {
     /// This is an expensive operation! Throw away our state and rebuild from scratch.
     /// Re-index the locally downloaded/installed packages AND the onchain data.
     RebuildIndex,
+    /// Get every capability the processes in a downloaded package request in
+    /// its manifest, along with the operator's decision on each. The decisions
+    /// are also written to `/<package_id>/pkg/permissions.json` on install,
+    /// where a process can read which of its requested capabilities were denied.
+    ///
+    /// If requested, will return a PermissionsResponse.
+    /// No blob is expected.
+    GetPermissions(PackageId),
+    /// Grant the given capabilities requested by a downloaded package, and
+    /// deny every other capability it requests. Takes effect on the next
+    /// Install of the package.
+    ///
+    /// If requested, will return a PermissionsResponse.
+    /// No blob is expected.
+    DecidePermissions {
+        package: PackageId,
+        granted: Vec<RequestedCapability>,
+    },
+}
+
+/// A capability that a process in a package requests in the package manifest.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RequestedCapability {
+    /// the process in the package that requests the capability
+    pub process: ProcessId,
+    /// the process that issues the capability
+    pub issuer: ProcessId,
+    /// JSON-string
+    pub params: String,
+}
+
+/// The operator's decision on a [`RequestedCapability`]: `None` until they
+/// have made one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PermissionDecision {
+    pub capability: RequestedCapability,
+    pub granted: Option<bool>,
 }
 
 /// Local responses take this form.
@@ -107,6 +144,7 @@ pub enum LocalResponse {
     MirrorResponse(MirrorResponse),
     AutoUpdateResponse(AutoUpdateResponse),
     RebuiltIndex,
+    PermissionsResponse(PermissionsResponse),
 }
 
 // TODO for all: expand these to elucidate why something failed
@@ -147,3 +185,9 @@ pub enum AutoUpdateResponse {
     Success,
     Failure,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PermissionsResponse {
+    Permissions(Vec<PermissionDecision>),
+    Failure,
+}
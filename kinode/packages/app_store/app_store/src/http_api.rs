@@ -1,4 +1,6 @@
-use crate::{DownloadResponse, PackageListing, PackageState, RequestedPackage, State};
+use crate::{
+    DownloadResponse, PackageListing, PackageState, RequestedCapability, RequestedPackage, State,
+};
 use kinode_process_lib::{
     eth,
     http::{send_response, IncomingHttpRequest, Method, StatusCode},
@@ -20,6 +22,8 @@ use std::collections::HashMap;
 /// - uninstall/delete a downloaded app: DELETE /apps/:id
 /// - update a downloaded app: PUT /apps/:id
 /// - approve capabilities for a downloaded app: POST /apps/:id/caps
+/// - get the operator's decision on each capability a downloaded app requests: GET /apps/:id/permissions
+/// - grant some requested capabilities and deny the rest: POST /apps/:id/permissions
 /// - start mirroring a downloaded app: PUT /apps/:id/mirror
 /// - stop mirroring a downloaded app: DELETE /apps/:id/mirror
 /// - start auto-updating a downloaded app: PUT /apps/:id/auto-update
@@ -89,6 +93,7 @@ fn gen_package_info(
                 "mirrored_from": state.mirrored_from,
                 "our_version": state.our_version,
                 "caps_approved": state.caps_approved,
+                "granted_caps": state.granted_caps,
                 "mirroring": state.mirroring,
                 "auto_update": state.auto_update,
                 "verified": state.verified,
@@ -349,6 +354,7 @@ fn serve_paths(
                 Method::POST => Ok(
                     match state.update_downloaded_package(&package_id, |pkg| {
                         pkg.caps_approved = true;
+                        pkg.granted_caps = None;
                     }) {
                         true => (StatusCode::OK, None, vec![]),
                         false => (
@@ -365,6 +371,60 @@ fn serve_paths(
                 )),
             }
         }
+        // GET the operator's decision on each capability a downloaded app requests
+        // grant some requested capabilities and deny the rest: POST
+        "/apps/:id/permissions" => {
+            let Ok(package_id) = get_package_id(url_params) else {
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    None,
+                    format!("Missing id").into_bytes(),
+                ));
+            };
+
+            match method {
+                Method::GET => Ok(match crate::get_permissions(state, &package_id) {
+                    Ok(permissions) => (StatusCode::OK, None, serde_json::to_vec(&permissions)?),
+                    Err(_) => (
+                        StatusCode::NOT_FOUND,
+                        None,
+                        format!("App not found: {package_id}").into_bytes(),
+                    ),
+                }),
+                // POST body is the list of capabilities to grant:
+                // any other requested capability is denied
+                Method::POST => {
+                    let body = crate::get_blob()
+                        .ok_or(anyhow::anyhow!("missing blob"))?
+                        .bytes;
+                    let Ok(granted) = serde_json::from_slice::<Vec<RequestedCapability>>(&body)
+                    else {
+                        return Ok((
+                            StatusCode::BAD_REQUEST,
+                            None,
+                            format!("Invalid capability list").into_bytes(),
+                        ));
+                    };
+                    if !state.update_downloaded_package(&package_id, |pkg| {
+                        pkg.caps_approved = true;
+                        pkg.granted_caps = Some(granted);
+                    }) {
+                        return Ok((
+                            StatusCode::NOT_FOUND,
+                            None,
+                            format!("App not found: {package_id}").into_bytes(),
+                        ));
+                    }
+                    let permissions = crate::get_permissions(state, &package_id)?;
+                    Ok((StatusCode::OK, None, serde_json::to_vec(&permissions)?))
+                }
+                _ => Ok((
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    None,
+                    format!("Invalid method {method} for {bound_path}").into_bytes(),
+                )),
+            }
+        }
         // start mirroring a downloaded app: PUT
         // stop mirroring a downloaded app: DELETE
         "/apps/:id/mirror" => {
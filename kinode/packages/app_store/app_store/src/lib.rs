@@ -39,6 +39,10 @@ use ft_worker_lib::{
 
 const ICON: &str = include_str!("icon");
 
+/// where, in its package drive, a package's processes find the operator's
+/// decision on each capability they requested
+const PERMISSIONS_FILE: &str = "permissions.json";
+
 #[cfg(not(feature = "simulation-mode"))]
 const CHAIN_ID: u64 = 10; // optimism
 #[cfg(feature = "simulation-mode")]
@@ -183,6 +187,7 @@ fn init(our: Address) {
         "/apps/:id",
         "/apps/listed/:id",
         "/apps/:id/caps",
+        "/apps/:id/permissions",
         "/apps/:id/mirror",
         "/apps/:id/auto-update",
         "/apps/rebuild-index",
@@ -218,8 +223,7 @@ fn init(our: Address) {
         .send()
         .unwrap();
 
-    let mut state: State = match get_typed_state(|bytes| Ok(bincode::deserialize::<State>(bytes)?))
-    {
+    let mut state: State = match get_typed_state(|bytes| State::load(bytes)) {
         Some(state) => {
             println!("loaded saved state");
             state
@@ -266,6 +270,17 @@ fn init(our: Address) {
     loop {
         match await_message() {
             Err(send_error) => {
+                if let Some(context) = send_error
+                    .context
+                    .as_ref()
+                    .and_then(|context| serde_json::from_slice::<UpgradeContext>(context).ok())
+                {
+                    println!(
+                        "app-store: gave up waiting for {} to upgrade",
+                        context.process_id
+                    );
+                    continue;
+                }
                 // TODO handle these based on what they are triggered by
                 println!("got network error: {send_error}");
             }
@@ -383,11 +398,15 @@ fn handle_message(
             }
         },
         Message::Response { body, context, .. } => {
-            // the only kind of response we care to handle here!
+            // the only kinds of response we care to handle here!
             let Some(context) = context else {
                 return Err(anyhow::anyhow!("missing context"));
             };
-            handle_ft_worker_result(body, context)?;
+            if let Ok(context) = serde_json::from_slice::<UpgradeContext>(context) {
+                handle_upgrade_response(our, state, context, body)?;
+            } else {
+                handle_ft_worker_result(body, context)?;
+            }
         }
     }
     Ok(())
@@ -479,6 +498,7 @@ fn handle_local_request(
                 mirroring: *mirror,
                 auto_update: false, // can't auto-update a local package
                 metadata: None,     // TODO
+                granted_caps: None,
             };
             let Ok(()) = state.add_downloaded_package(package, package_state, Some(blob.bytes))
             else {
@@ -526,9 +546,87 @@ fn handle_local_request(
             false => LocalResponse::AutoUpdateResponse(AutoUpdateResponse::Failure),
         },
         LocalRequest::RebuildIndex => rebuild_index(our, state, eth_provider),
+        LocalRequest::GetPermissions(package) => {
+            LocalResponse::PermissionsResponse(match get_permissions(state, package) {
+                Ok(permissions) => PermissionsResponse::Permissions(permissions),
+                Err(_) => PermissionsResponse::Failure,
+            })
+        }
+        LocalRequest::DecidePermissions { package, granted } => {
+            state.update_downloaded_package(package, |package_state| {
+                package_state.caps_approved = true;
+                package_state.granted_caps = Some(granted.clone());
+            });
+            LocalResponse::PermissionsResponse(match get_permissions(state, package) {
+                Ok(permissions) => PermissionsResponse::Permissions(permissions),
+                Err(_) => PermissionsResponse::Failure,
+            })
+        }
     }
 }
 
+/// every capability the processes in a package request in its manifest
+pub fn requested_capabilities(
+    package_id: &PackageId,
+    manifest: &[kt::PackageManifestEntry],
+) -> Vec<RequestedCapability> {
+    let mut requested = vec![];
+    for entry in manifest {
+        let process_id = format!("{}:{}", entry.process_name, package_id);
+        let Ok(process) = process_id.parse::<ProcessId>() else {
+            continue;
+        };
+        for value in &entry.request_capabilities {
+            let (issuer, params) = match value {
+                serde_json::Value::String(process_name) => (
+                    process_name.parse::<ProcessId>().ok(),
+                    Some("\"messaging\"".to_string()),
+                ),
+                serde_json::Value::Object(map) => (
+                    map.get("process")
+                        .and_then(|process_name| process_name.as_str())
+                        .and_then(|process_name| process_name.parse::<ProcessId>().ok()),
+                    map.get("params").map(|params| params.to_string()),
+                ),
+                _ => continue,
+            };
+            let (Some(issuer), Some(params)) = (issuer, params) else {
+                println!(
+                    "app-store: invalid cap: {} for {} to request!",
+                    value.to_string(),
+                    package_id
+                );
+                continue;
+            };
+            requested.push(RequestedCapability {
+                process: process.clone(),
+                issuer,
+                params,
+            });
+        }
+        if entry.request_networking {
+            requested.push(RequestedCapability {
+                process,
+                issuer: ProcessId::new(Some("kernel"), "distro", "sys"),
+                params: "\"network\"".into(),
+            });
+        }
+    }
+    requested
+}
+
+/// the operator's decision on each capability a downloaded package requests
+pub fn get_permissions(
+    state: &State,
+    package_id: &PackageId,
+) -> anyhow::Result<Vec<PermissionDecision>> {
+    let Some(package_state) = state.downloaded_packages.get(package_id) else {
+        return Err(anyhow::anyhow!("no such package"));
+    };
+    let manifest = fetch_package_manifest(package_id)?;
+    Ok(package_state.permissions(requested_capabilities(package_id, &manifest)))
+}
+
 pub fn rebuild_index(
     our: &Address,
     state: &mut State,
@@ -678,6 +776,19 @@ fn handle_receive_download(
             .unwrap_or("OLD".to_string()),
         _ => "OLD".to_string(),
     };
+    let old_granted_caps = state
+        .downloaded_packages
+        .get(&package_id)
+        .and_then(|package_state| package_state.granted_caps.clone());
+    let was_installed = state
+        .downloaded_packages
+        .get(&package_id)
+        .is_some_and(|package_state| package_state.installed);
+    let old_version = state
+        .downloaded_packages
+        .get(&package_id)
+        .map(|package_state| package_state.our_version.clone())
+        .unwrap_or_default();
 
     state.add_downloaded_package(
         &package_id,
@@ -691,6 +802,7 @@ fn handle_receive_download(
             mirroring: requested_package.mirror,
             auto_update: requested_package.auto_update,
             metadata: None, // TODO
+            granted_caps: None,
         },
         Some(blob.bytes),
     )?;
@@ -703,10 +815,133 @@ fn handle_receive_download(
         _ => "NEW".to_string(),
     };
 
+    // if the manifest has NOT changed, the operator's decisions on the
+    // capabilities it requests still stand
+    if old_manifest_hash == new_manifest_hash && old_granted_caps.is_some() {
+        state.update_downloaded_package(&package_id, |package_state| {
+            package_state.caps_approved = true;
+            package_state.granted_caps = old_granted_caps;
+        });
+    }
+
     // lastly, if auto_update is true, AND the caps_hash has NOT changed,
-    // trigger install!
+    // trigger install! if the package is already running, upgrade its
+    // processes in place rather than killing them.
     if requested_package.auto_update && old_manifest_hash == new_manifest_hash {
-        handle_install(our, state, &package_id)?;
+        if was_installed {
+            handle_upgrade(our, state, &package_id, &old_version)?;
+        } else {
+            handle_install(our, state, &package_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// swap in the new code of an installed package whose manifest hasn't changed,
+/// keeping each process's state and in-flight messages. a process whose new
+/// code fails to start is rolled back to its old code by the kernel, which
+/// keeps running the old code's versioned copy until the upgrade is recorded.
+///
+/// the kernel responds to each upgrade once the new code is running, which can
+/// take a while if a process has a backlog of messages, so the responses are
+/// handled as they arrive in `handle_upgrade_response`.
+pub fn handle_upgrade(
+    our: &Address,
+    state: &mut State,
+    package_id: &PackageId,
+    previous_version: &str,
+) -> anyhow::Result<()> {
+    let manifest = fetch_package_manifest(package_id)?;
+    // a package installed before its code was versioned runs straight off the
+    // package drive, which now holds the new code: the old code can't be
+    // rolled back to, so reinstall it instead
+    for entry in &manifest {
+        if !vfs_ok(
+            &versioned_wasm_path(package_id, previous_version, entry),
+            vfs::VfsAction::Metadata,
+        ) {
+            println!("app-store: can't upgrade {package_id} in place, reinstalling");
+            return handle_install(our, state, package_id);
+        }
+    }
+    let Some(version) = state
+        .downloaded_packages
+        .get(package_id)
+        .map(|package_state| package_state.our_version.clone())
+    else {
+        return Err(anyhow::anyhow!("no such package"));
+    };
+    for entry in &manifest {
+        let process_id = format!("{}:{}", entry.process_name, package_id);
+        let Ok(parsed_process_id) = process_id.parse::<ProcessId>() else {
+            return Err(anyhow::anyhow!("invalid process id!"));
+        };
+        let wasm_path = keep_version(package_id, &version, entry)?;
+        Request::to(("our", "kernel", "distro", "sys"))
+            .body(serde_json::to_vec(&serde_json::json!({
+                "UpgradeProcess": {
+                    "id": parsed_process_id,
+                    "wasm_bytes_handle": wasm_path,
+                }
+            }))?)
+            .expects_response(60)
+            .context(serde_json::to_vec(&UpgradeContext {
+                package_id: package_id.clone(),
+                process_id: parsed_process_id,
+            })?)
+            .send()?;
+    }
+    state.update_downloaded_package(package_id, |package_state| {
+        package_state.installed = true;
+    });
+    Ok(())
+}
+
+/// The context of an `UpgradeProcess` request, to handle its response with.
+#[derive(Debug, Serialize, Deserialize)]
+struct UpgradeContext {
+    package_id: PackageId,
+    process_id: ProcessId,
+}
+
+/// The kernel's response to `UpgradeProcess`.
+/// Mirrors the relevant variants of `KernelResponse` in the kernel.
+#[derive(Debug, Deserialize)]
+enum UpgradeResponse {
+    UpgradedProcess(ProcessId),
+    UpgradeProcessError,
+    UpgradeProcessNotRunning(ProcessId),
+}
+
+fn handle_upgrade_response(
+    our: &Address,
+    state: &mut State,
+    context: UpgradeContext,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    match serde_json::from_slice::<UpgradeResponse>(body)? {
+        UpgradeResponse::UpgradedProcess(id) => println!("app-store: upgraded {id}"),
+        UpgradeResponse::UpgradeProcessError => println!(
+            "app-store: failed to upgrade {}, still running old code",
+            context.process_id
+        ),
+        UpgradeResponse::UpgradeProcessNotRunning(_) => {
+            // there is no old code to swap out: start it afresh on the new code
+            let manifest = fetch_package_manifest(&context.package_id)?;
+            let Some(entry) = manifest
+                .iter()
+                .find(|entry| entry.process_name == context.process_id.process())
+            else {
+                return Err(anyhow::anyhow!(
+                    "{} is not in its package manifest",
+                    context.process_id
+                ));
+            };
+            let installer = Installer::new(our, state, &context.package_id, &manifest)?;
+            installer.initialize(entry)?;
+            installer.start(entry)?;
+            println!("app-store: started {} on its new code", context.process_id);
+        }
     }
     Ok(())
 }
@@ -759,53 +994,166 @@ fn fetch_package_manifest(package: &PackageId) -> anyhow::Result<Vec<kt::Package
 }
 
 /// the steps to take an existing package on disk and install/start it
-/// make sure you have reviewed and approved caps in manifest before calling this
+/// make sure you have reviewed and approved caps in manifest before calling this:
+/// any requested caps the operator denied are left out.
 pub fn handle_install(
     our: &Address,
     state: &mut State,
     package_id: &PackageId,
 ) -> anyhow::Result<()> {
-    let drive_path = format!("/{package_id}/pkg");
     let manifest = fetch_package_manifest(package_id)?;
-    // always grant read/write to their drive, which we created for them
-    let Some(read_cap) = get_capability(
-        &Address::new(&our.node, ("vfs", "distro", "sys")),
-        &serde_json::to_string(&serde_json::json!({
-            "kind": "read",
-            "drive": drive_path,
-        }))?,
-    ) else {
-        return Err(anyhow::anyhow!("no read cap"));
-    };
-    let Some(write_cap) = get_capability(
-        &Address::new(&our.node, ("vfs", "distro", "sys")),
-        &serde_json::to_string(&serde_json::json!({
-            "kind": "write",
-            "drive": drive_path,
-        }))?,
-    ) else {
-        return Err(anyhow::anyhow!("no write cap"));
-    };
-    let Some(networking_cap) = get_capability(
-        &Address::new(&our.node, ("kernel", "distro", "sys")),
-        &"\"network\"".to_string(),
-    ) else {
-        return Err(anyhow::anyhow!("no net cap"));
-    };
+    let installer = Installer::new(our, state, package_id, &manifest)?;
     // first, for each process in manifest, initialize it
     // then, once all have been initialized, grant them requested caps
     // and finally start them.
     for entry in &manifest {
-        let wasm_path = if entry.process_wasm_path.starts_with("/") {
-            entry.process_wasm_path.clone()
-        } else {
-            format!("/{}", entry.process_wasm_path)
+        installer.initialize(entry)?;
+    }
+    installer.record_permissions()?;
+    // THEN, *after* all processes have been initialized, grant caps in manifest
+    for entry in &manifest {
+        installer.start(entry)?;
+    }
+    // every process now runs this version: older ones are no longer needed
+    installer.remove_other_versions();
+    // finally set the package as installed
+    state.update_downloaded_package(package_id, |package_state| {
+        package_state.installed = true;
+    });
+    Ok(())
+}
+
+/// path of a process's wasm within its package drive
+fn wasm_path(entry: &kt::PackageManifestEntry) -> String {
+    if entry.process_wasm_path.starts_with("/") {
+        entry.process_wasm_path.clone()
+    } else {
+        format!("/{}", entry.process_wasm_path)
+    }
+}
+
+/// where a process's wasm is kept for one version of its package
+fn versioned_wasm_path(
+    package_id: &PackageId,
+    version: &str,
+    entry: &kt::PackageManifestEntry,
+) -> String {
+    format!("/{package_id}/pkg/versions/{version}{}", wasm_path(entry))
+}
+
+/// copy a process's wasm out of the package drive to where this version of it
+/// is kept. processes are started from the copy, so extracting a newer
+/// version over the package drive leaves the code they run on disk, to
+/// reboot into or roll back to, until they are upgraded away from it.
+fn keep_version(
+    package_id: &PackageId,
+    version: &str,
+    entry: &kt::PackageManifestEntry,
+) -> anyhow::Result<String> {
+    let versioned = versioned_wasm_path(package_id, version, entry);
+    if let Some((dir, _)) = versioned.rsplit_once('/') {
+        if !vfs_ok(dir, vfs::VfsAction::CreateDirAll) {
+            return Err(anyhow::anyhow!("failed to create {dir}"));
+        }
+    }
+    if !vfs_ok(
+        &format!("/{package_id}/pkg{}", wasm_path(entry)),
+        vfs::VfsAction::CopyFile {
+            new_path: versioned.clone(),
+        },
+    ) {
+        return Err(anyhow::anyhow!("failed to copy process file"));
+    }
+    Ok(versioned)
+}
+
+/// whether vfs carried out an action
+fn vfs_ok(path: &str, action: vfs::VfsAction) -> bool {
+    let Ok(body) = serde_json::to_vec(&vfs::VfsRequest {
+        path: path.to_string(),
+        action,
+    }) else {
+        return false;
+    };
+    match Request::to(("our", "vfs", "distro", "sys"))
+        .body(body)
+        .send_and_await_response(5)
+    {
+        Ok(Ok(response)) => !matches!(
+            serde_json::from_slice::<vfs::VfsResponse>(response.body()),
+            Ok(vfs::VfsResponse::Err(_)) | Err(_)
+        ),
+        _ => false,
+    }
+}
+
+/// Installs the processes of a downloaded package, with the capabilities the
+/// operator granted them.
+struct Installer {
+    our: Address,
+    package_id: PackageId,
+    version: String,
+    requested: Vec<RequestedCapability>,
+    granted_caps: Option<Vec<RequestedCapability>>,
+    permissions: Vec<PermissionDecision>,
+    read_cap: Capability,
+    write_cap: Capability,
+}
+
+impl Installer {
+    fn new(
+        our: &Address,
+        state: &State,
+        package_id: &PackageId,
+        manifest: &[kt::PackageManifestEntry],
+    ) -> anyhow::Result<Self> {
+        let Some(package_state) = state.downloaded_packages.get(package_id) else {
+            return Err(anyhow::anyhow!("no such package"));
         };
-        let wasm_path = format!("{}{}", drive_path, wasm_path);
-        let process_id = format!("{}:{}", entry.process_name, package_id);
-        let Ok(parsed_new_process_id) = process_id.parse::<ProcessId>() else {
-            return Err(anyhow::anyhow!("invalid process id!"));
+        let drive_path = format!("/{package_id}/pkg");
+        // always grant read/write to their drive, which we created for them
+        let Some(read_cap) = get_capability(
+            &Address::new(&our.node, ("vfs", "distro", "sys")),
+            &serde_json::to_string(&serde_json::json!({
+                "kind": "read",
+                "drive": drive_path,
+            }))?,
+        ) else {
+            return Err(anyhow::anyhow!("no read cap"));
         };
+        let Some(write_cap) = get_capability(
+            &Address::new(&our.node, ("vfs", "distro", "sys")),
+            &serde_json::to_string(&serde_json::json!({
+                "kind": "write",
+                "drive": drive_path,
+            }))?,
+        ) else {
+            return Err(anyhow::anyhow!("no write cap"));
+        };
+        let requested = requested_capabilities(package_id, manifest);
+        Ok(Self {
+            our: our.clone(),
+            package_id: package_id.clone(),
+            version: package_state.our_version.clone(),
+            permissions: package_state.permissions(requested.clone()),
+            requested,
+            granted_caps: package_state.granted_caps.clone(),
+            read_cap,
+            write_cap,
+        })
+    }
+
+    fn process_id(&self, entry: &kt::PackageManifestEntry) -> anyhow::Result<ProcessId> {
+        let process_id = format!("{}:{}", entry.process_name, self.package_id);
+        process_id
+            .parse::<ProcessId>()
+            .map_err(|_| anyhow::anyhow!("invalid process id!"))
+    }
+
+    /// (re)create a process from this version of its code, with the
+    /// capabilities it requested that the operator granted
+    fn initialize(&self, entry: &kt::PackageManifestEntry) -> anyhow::Result<()> {
+        let parsed_new_process_id = self.process_id(entry)?;
         // kill process if it already exists
         Request::to(("our", "kernel", "distro", "sys"))
             .body(serde_json::to_vec(&kt::KernelCommand::KillProcess(
@@ -816,7 +1164,7 @@ pub fn handle_install(
         if let Ok(vfs::VfsResponse::Err(_)) = serde_json::from_slice(
             Request::to(("our", "vfs", "distro", "sys"))
                 .body(serde_json::to_vec(&vfs::VfsRequest {
-                    path: wasm_path.clone(),
+                    path: format!("/{}/pkg{}", self.package_id, wasm_path(entry)),
                     action: vfs::VfsAction::Read,
                 })?)
                 .send_and_await_response(5)??
@@ -824,6 +1172,7 @@ pub fn handle_install(
         ) {
             return Err(anyhow::anyhow!("failed to read process file"));
         };
+        let wasm_path = keep_version(&self.package_id, &self.version, entry)?;
 
         let Ok(kt::KernelResponse::InitializedProcess) = serde_json::from_slice(
             Request::new()
@@ -842,78 +1191,58 @@ pub fn handle_install(
         ) else {
             return Err(anyhow::anyhow!("failed to initialize process"));
         };
-        // build initial caps
+        // build initial caps, leaving out any the operator denied
         let mut requested_capabilities: Vec<kt::Capability> = vec![];
-        for value in &entry.request_capabilities {
-            match value {
-                serde_json::Value::String(process_name) => {
-                    if let Ok(parsed_process_id) = process_name.parse::<ProcessId>() {
-                        requested_capabilities.push(kt::Capability {
-                            issuer: Address {
-                                node: our.node.clone(),
-                                process: parsed_process_id.clone(),
-                            },
-                            params: "\"messaging\"".into(),
-                        });
-                    } else {
-                        println!(
-                            "app-store: invalid cap: {} for {} to request!",
-                            value.to_string(),
-                            package_id
-                        );
-                    }
-                }
-                serde_json::Value::Object(map) => {
-                    if let Some(process_name) = map.get("process") {
-                        if let Ok(parsed_process_id) = process_name
-                            .as_str()
-                            .unwrap_or_default()
-                            .parse::<ProcessId>()
-                        {
-                            if let Some(params) = map.get("params") {
-                                requested_capabilities.push(kt::Capability {
-                                    issuer: Address {
-                                        node: our.node.clone(),
-                                        process: parsed_process_id.clone(),
-                                    },
-                                    params: params.to_string(),
-                                });
-                            } else {
-                                println!(
-                                    "app-store: invalid cap: {} for {} to request!",
-                                    value.to_string(),
-                                    package_id
-                                );
-                            }
-                        }
-                    }
-                }
-                _ => {
+        for cap in self
+            .requested
+            .iter()
+            .filter(|cap| cap.process == parsed_new_process_id)
+        {
+            if let Some(granted_caps) = &self.granted_caps {
+                if !granted_caps.contains(cap) {
+                    println!(
+                        "app-store: not granting {} to {}: denied by operator",
+                        cap.issuer, cap.process
+                    );
                     continue;
                 }
             }
+            requested_capabilities.push(kt::Capability {
+                issuer: Address {
+                    node: self.our.node.clone(),
+                    process: cap.issuer.clone(),
+                },
+                params: cap.params.clone(),
+            });
         }
-        if entry.request_networking {
-            requested_capabilities.push(kt::de_wit_capability(networking_cap.clone()));
-        }
-        requested_capabilities.push(kt::de_wit_capability(read_cap.clone()));
-        requested_capabilities.push(kt::de_wit_capability(write_cap.clone()));
+        requested_capabilities.push(kt::de_wit_capability(self.read_cap.clone()));
+        requested_capabilities.push(kt::de_wit_capability(self.write_cap.clone()));
         Request::new()
             .target(("our", "kernel", "distro", "sys"))
             .body(serde_json::to_vec(&kt::KernelCommand::GrantCapabilities {
-                target: parsed_new_process_id.clone(),
+                target: parsed_new_process_id,
                 capabilities: requested_capabilities,
             })?)
             .send()?;
+        Ok(())
     }
-    // THEN, *after* all processes have been initialized, grant caps in manifest
+
+    /// write the operator's decision on each capability the package requested
+    /// into its drive, so that its processes can tell which were denied
+    fn record_permissions(&self) -> anyhow::Result<()> {
+        let permissions_file = vfs::File {
+            path: format!("/{}/pkg/{PERMISSIONS_FILE}", self.package_id),
+            timeout: 5,
+        };
+        permissions_file.write(&serde_json::to_vec(&self.permissions)?)?;
+        Ok(())
+    }
+
+    /// grant the caps a process gives others in the manifest, then run it
     // TODO for both grants and requests: make the vector of caps
     // and then do one GrantCapabilities message at the end. much faster.
-    for entry in &manifest {
-        let process_id = format!("{}:{}", entry.process_name, package_id);
-        let Ok(parsed_new_process_id) = process_id.parse::<ProcessId>() else {
-            return Err(anyhow::anyhow!("invalid process id!"));
-        };
+    fn start(&self, entry: &kt::PackageManifestEntry) -> anyhow::Result<()> {
+        let parsed_new_process_id = self.process_id(entry)?;
         for value in &entry.grant_capabilities {
             match value {
                 serde_json::Value::String(process_name) => {
@@ -924,7 +1253,7 @@ pub fn handle_install(
                                     target: parsed_process_id,
                                     capabilities: vec![kt::Capability {
                                         issuer: Address {
-                                            node: our.node.clone(),
+                                            node: self.our.node.clone(),
                                             process: parsed_new_process_id.clone(),
                                         },
                                         params: "\"messaging\"".into(),
@@ -949,7 +1278,7 @@ pub fn handle_install(
                                             target: parsed_process_id,
                                             capabilities: vec![kt::Capability {
                                                 issuer: Address {
-                                                    node: our.node.clone(),
+                                                    node: self.our.node.clone(),
                                                     process: parsed_new_process_id.clone(),
                                                 },
                                                 params: params.to_string(),
@@ -976,10 +1305,30 @@ pub fn handle_install(
         ) else {
             return Err(anyhow::anyhow!("failed to start process"));
         };
+        Ok(())
+    }
+
+    /// delete the kept code of every version but this one
+    fn remove_other_versions(&self) {
+        let Ok(body) = serde_json::to_vec(&vfs::VfsRequest {
+            path: format!("/{}/pkg/versions", self.package_id),
+            action: vfs::VfsAction::ReadDir,
+        }) else {
+            return;
+        };
+        let Ok(Ok(response)) = Request::to(("our", "vfs", "distro", "sys"))
+            .body(body)
+            .send_and_await_response(5)
+        else {
+            return;
+        };
+        let Ok(vfs::VfsResponse::ReadDir(entries)) = serde_json::from_slice(response.body()) else {
+            return;
+        };
+        for entry in entries {
+            if entry.path.rsplit('/').next() != Some(self.version.as_str()) {
+                vfs_ok(&format!("/{}", entry.path), vfs::VfsAction::RemoveDirAll);
+            }
+        }
     }
-    // finally set the package as installed
-    state.update_downloaded_package(package_id, |package_state| {
-        package_state.installed = true;
-    });
-    Ok(())
 }
@@ -1,4 +1,4 @@
-use crate::LocalRequest;
+use crate::{LocalRequest, PermissionDecision, RequestedCapability};
 use alloy_sol_types::{sol, SolEvent};
 use kinode_process_lib::eth::Log;
 use kinode_process_lib::kernel_types as kt;
@@ -33,7 +33,7 @@ pub enum IndexerRequests {
     /// returns an Option<String>
     NamehashToName { hash: String, block: u64 },
     /// return the most recent on-chain routing information for a node name.
-    /// returns an Option<KnsUpdate>
+    /// returns an Option<NodeRecord> (a KnsUpdate and the chain it is on)
     NodeInfo { name: String, block: u64 },
 }
 
@@ -82,6 +82,29 @@ pub struct PackageState {
     /// if we get a listing data update, will we try to download it?
     pub auto_update: bool,
     pub metadata: Option<kt::Erc721Metadata>,
+    /// the requested capabilities the operator granted, the rest having been
+    /// denied. if none, every requested capability is granted once `caps_approved`.
+    pub granted_caps: Option<Vec<RequestedCapability>>,
+}
+
+impl PackageState {
+    /// the operator's decision on each of the capabilities a package requests
+    pub fn permissions(&self, requested: Vec<RequestedCapability>) -> Vec<PermissionDecision> {
+        requested
+            .into_iter()
+            .map(|capability| {
+                let granted = match &self.granted_caps {
+                    Some(granted_caps) => Some(granted_caps.contains(&capability)),
+                    None if self.caps_approved => Some(true),
+                    None => None,
+                };
+                PermissionDecision {
+                    capability,
+                    granted,
+                }
+            })
+            .collect()
+    }
 }
 
 /// this process's saved state
@@ -107,7 +130,81 @@ pub struct State {
     pub downloaded_packages: HashMap<PackageId, PackageState>,
 }
 
+/// bumped whenever the layout of [`State`] changes, so that state saved by
+/// an older version of this process can be migrated rather than thrown away
+const STATE_VERSION: u64 = 1;
+
+/// [`State`] as saved before it was versioned, when [`PackageState`] had
+/// no `granted_caps`
+#[derive(Deserialize)]
+struct StateV0 {
+    contract_address: String,
+    last_saved_block: u64,
+    package_hashes: HashMap<PackageId, PackageHash>,
+    listed_packages: HashMap<PackageHash, PackageListing>,
+    downloaded_packages: HashMap<PackageId, PackageStateV0>,
+}
+
+#[derive(Deserialize)]
+struct PackageStateV0 {
+    mirrored_from: Option<NodeId>,
+    our_version: String,
+    installed: bool,
+    verified: bool,
+    caps_approved: bool,
+    manifest_hash: Option<String>,
+    mirroring: bool,
+    auto_update: bool,
+    metadata: Option<kt::Erc721Metadata>,
+}
+
+impl From<StateV0> for State {
+    fn from(old: StateV0) -> Self {
+        State {
+            contract_address: old.contract_address,
+            last_saved_block: old.last_saved_block,
+            package_hashes: old.package_hashes,
+            listed_packages: old.listed_packages,
+            downloaded_packages: old
+                .downloaded_packages
+                .into_iter()
+                .map(|(package_id, old)| {
+                    let package_state = PackageState {
+                        mirrored_from: old.mirrored_from,
+                        our_version: old.our_version,
+                        installed: old.installed,
+                        verified: old.verified,
+                        caps_approved: old.caps_approved,
+                        manifest_hash: old.manifest_hash,
+                        mirroring: old.mirroring,
+                        auto_update: old.auto_update,
+                        metadata: old.metadata,
+                        // packages approved back then were granted everything they requested
+                        granted_caps: None,
+                    };
+                    (package_id, package_state)
+                })
+                .collect(),
+        }
+    }
+}
+
 impl State {
+    /// Load saved state, migrating it from an older layout if need be.
+    /// Unversioned state begins with the length of the contract address
+    /// rather than a version, so the two can't be mistaken for each other.
+    pub fn load(bytes: &[u8]) -> anyhow::Result<Self> {
+        match bincode::deserialize::<(u64, State)>(bytes) {
+            Ok((STATE_VERSION, state)) => Ok(state),
+            _ => Ok(bincode::deserialize::<StateV0>(bytes)?.into()),
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::set_state(&bincode::serialize(&(STATE_VERSION, self))?);
+        Ok(())
+    }
+
     /// To create a new state, we populate the downloaded_packages map
     /// with all packages parseable from our filesystem.
     pub fn new(contract_address: String) -> anyhow::Result<Self> {
@@ -214,7 +311,7 @@ impl State {
         }
         self.downloaded_packages
             .insert(package_id.to_owned(), package_state);
-        crate::set_state(&bincode::serialize(self)?);
+        self.save()?;
         Ok(())
     }
 
@@ -232,7 +329,7 @@ impl State {
                 true
             })
             .unwrap_or(false);
-        crate::set_state(&bincode::serialize(self).unwrap());
+        self.save().unwrap();
         res
     }
 
@@ -311,6 +408,7 @@ impl State {
                         mirroring: false,
                         auto_update: false,
                         metadata: None,
+                        granted_caps: None,
                     },
                     None,
                 )?
@@ -357,7 +455,7 @@ impl State {
 
         // finally, remove from downloaded packages
         self.downloaded_packages.remove(package_id);
-        crate::set_state(&bincode::serialize(self)?);
+        self.save()?;
 
         println!("uninstalled {package_id}");
         Ok(())
@@ -522,7 +620,7 @@ impl State {
             _ => {}
         }
         self.last_saved_block = block_number;
-        crate::set_state(&bincode::serialize(self)?);
+        self.save()?;
         Ok(())
     }
 }
@@ -70,6 +70,7 @@ pub fn spawn_transfer(
     ) else {
         return Err(anyhow::anyhow!("failed to spawn ft_worker!"));
     };
+    set_bulk_priority(&worker_process_id)?;
     // tell the worker what to do
     let blob_or_inherit = match file_bytes {
         Some(bytes) => Some(LazyLoadBlob { mime: None, bytes }),
@@ -126,6 +127,7 @@ pub fn spawn_receive_transfer(our: &Address, body: &[u8]) -> anyhow::Result<()>
     ) else {
         return Err(anyhow::anyhow!("failed to spawn ft_worker!"));
     };
+    set_bulk_priority(&worker_process_id)?;
     // forward receive command to worker
     Request::new()
         .target((our.node.as_ref(), worker_process_id))
@@ -133,3 +135,16 @@ pub fn spawn_receive_transfer(our: &Address, body: &[u8]) -> anyhow::Result<()>
         .body(body)
         .send()
 }
+
+/// Have the kernel deliver a worker's messages after everyone else's,
+/// so that a large transfer doesn't hold up interactive traffic.
+fn set_bulk_priority(worker_process_id: &ProcessId) -> anyhow::Result<()> {
+    Request::to(("our", "kernel", "distro", "sys"))
+        .body(serde_json::to_vec(&serde_json::json!({
+            "SetPriority": {
+                "target": worker_process_id,
+                "priority": "Bulk",
+            }
+        }))?)
+        .send()
+}
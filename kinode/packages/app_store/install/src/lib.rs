@@ -17,11 +17,13 @@ fn init(our: Address) {
         return;
     };
 
-    let arg = String::from_utf8(body).unwrap_or_default();
+    let args = String::from_utf8(body).unwrap_or_default();
+    let mut args = args.split_whitespace();
 
-    if arg.is_empty() {
+    let Some(arg) = args.next() else {
         println!("install: 1 argument required, the package id of the app");
         println!("example: install app:publisher.os");
+        println!("decide on the capabilities it requests with: install app:publisher.os --grant 1,2 --deny 3");
         return;
     };
 
@@ -31,6 +33,114 @@ fn init(our: Address) {
         return;
     };
 
+    // capabilities are picked by their number in the listing below, or all at once
+    let mut grant: Option<Selection> = None;
+    let mut deny: Option<Selection> = None;
+    while let Some(flag) = args.next() {
+        let picked = match flag {
+            "--grant" => &mut grant,
+            "--deny" => &mut deny,
+            _ => {
+                println!("install: unexpected argument {flag}");
+                return;
+            }
+        };
+        let Some(selection) = args.next() else {
+            println!("install: {flag} needs a comma-separated list of capabilities, or all");
+            return;
+        };
+        let Some(selection) = Selection::parse(selection) else {
+            println!("install: invalid list of capabilities {selection}");
+            return;
+        };
+        *picked = Some(selection);
+    }
+
+    // show the operator every capability the package requests, and
+    // record their decision on each before installing
+    let Some(mut permissions) =
+        app_store_permissions(&our, &LocalRequest::GetPermissions(package_id.clone()))
+    else {
+        println!("install: failed to get permissions for {package_id} from app_store..!");
+        println!("make sure that the package has been downloaded!");
+        return;
+    };
+    for (index, decision) in permissions.iter_mut().enumerate() {
+        let number = index + 1;
+        let granted = grant.as_ref().is_some_and(|s| s.contains(number));
+        let denied = deny.as_ref().is_some_and(|s| s.contains(number));
+        match (granted, denied) {
+            (true, true) => {
+                println!("install: capability {number} is both granted and denied");
+                return;
+            }
+            (true, false) => decision.granted = Some(true),
+            (false, true) => decision.granted = Some(false),
+            (false, false) => {}
+        }
+    }
+    if let Some(number) = [&grant, &deny]
+        .into_iter()
+        .flatten()
+        .find_map(|s| s.out_of_range(permissions.len()))
+    {
+        println!("install: {package_id} requests no capability {number}");
+        return;
+    }
+    for (
+        index,
+        PermissionDecision {
+            capability,
+            granted,
+        },
+    ) in permissions.iter().enumerate()
+    {
+        println!(
+            "{}. {} {} requests {} from {}",
+            index + 1,
+            match granted {
+                Some(true) => "granted:  ",
+                Some(false) => "denied:   ",
+                None => "undecided:",
+            },
+            capability.process,
+            capability.params,
+            capability.issuer,
+        );
+    }
+    let undecided: Vec<String> = permissions
+        .iter()
+        .enumerate()
+        .filter(|(_, decision)| decision.granted.is_none())
+        .map(|(index, _)| (index + 1).to_string())
+        .collect();
+    if !undecided.is_empty() {
+        println!(
+            "install: grant or deny each capability before installing, e.g.: install {package_id} --grant {}",
+            undecided.join(",")
+        );
+        return;
+    }
+    if grant.is_some() || deny.is_some() {
+        let granted = permissions
+            .into_iter()
+            .filter(|decision| decision.granted == Some(true))
+            .map(|decision| decision.capability)
+            .collect();
+        if app_store_permissions(
+            &our,
+            &LocalRequest::DecidePermissions {
+                package: package_id.clone(),
+                granted,
+            },
+        )
+        .is_none()
+        {
+            println!("install: failed to record permissions for {package_id}..!");
+            return;
+        }
+    }
+
     let Ok(Ok(Message::Response { body, .. })) =
         Request::to((our.node(), ("main", "app_store", "sys")))
             .body(serde_json::to_vec(&LocalRequest::Install(package_id.clone())).unwrap())
@@ -59,3 +169,55 @@ fn init(our: Address) {
         }
     }
 }
+
+fn app_store_permissions(our: &Address, request: &LocalRequest) -> Option<Vec<PermissionDecision>> {
+    let Ok(Ok(Message::Response { body, .. })) =
+        Request::to((our.node(), ("main", "app_store", "sys")))
+            .body(serde_json::to_vec(request).unwrap())
+            .send_and_await_response(5)
+    else {
+        return None;
+    };
+    match serde_json::from_slice::<LocalResponse>(&body) {
+        Ok(LocalResponse::PermissionsResponse(PermissionsResponse::Permissions(permissions))) => {
+            Some(permissions)
+        }
+        _ => None,
+    }
+}
+
+/// capabilities picked on the command line by their number in the listing
+enum Selection {
+    All,
+    Numbers(Vec<usize>),
+}
+
+impl Selection {
+    fn parse(arg: &str) -> Option<Self> {
+        if arg == "all" {
+            return Some(Selection::All);
+        }
+        arg.split(',')
+            .map(|number| number.parse::<usize>().ok())
+            .collect::<Option<Vec<usize>>>()
+            .map(Selection::Numbers)
+    }
+
+    fn contains(&self, number: usize) -> bool {
+        match self {
+            Selection::All => true,
+            Selection::Numbers(numbers) => numbers.contains(&number),
+        }
+    }
+
+    /// a picked number that isn't in a listing of `len` capabilities
+    fn out_of_range(&self, len: usize) -> Option<usize> {
+        match self {
+            Selection::All => None,
+            Selection::Numbers(numbers) => numbers
+                .iter()
+                .copied()
+                .find(|number| *number == 0 || *number > len),
+        }
+    }
+}
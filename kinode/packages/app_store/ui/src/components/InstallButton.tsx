@@ -1,9 +1,10 @@
 import React, { FormEvent, useCallback, useEffect, useMemo, useState } from "react";
-import { AppInfo } from "../types/Apps";
+import { AppInfo, PermissionDecision } from "../types/Apps";
 import useAppsStore from "../store/apps-store";
 import Modal from "./Modal";
 import { getAppName } from "../utils/app";
 import Loader from "./Loader";
+import Checkbox from "./Checkbox";
 import classNames from "classnames";
 import { FaI } from "react-icons/fa6";
 
@@ -13,24 +14,34 @@ interface InstallButtonProps extends React.HTMLAttributes<HTMLButtonElement> {
 }
 
 export default function InstallButton({ app, isIcon = false, ...props }: InstallButtonProps) {
-  const { installApp, getCaps, getMyApp, getMyApps } =
+  const { installApp, getPermissions, getMyApp, getMyApps } =
     useAppsStore();
   const [showModal, setShowModal] = useState(false);
-  const [caps, setCaps] = useState<string[]>([]);
+  const [permissions, setPermissions] = useState<PermissionDecision[]>([]);
   const [loading, setLoading] = useState("");
 
   const onClick = useCallback(async (e: React.MouseEvent<HTMLButtonElement>) => {
     e.preventDefault();
-    getCaps(app).then((manifest) => {
-      setCaps(manifest.request_capabilities);
+    getPermissions(app).then((permissions) => {
+      // grant everything the operator hasn't already denied by default
+      setPermissions(permissions.map((p) => ({ ...p, granted: p.granted !== false })));
     });
     setShowModal(true);
-  }, [app, setShowModal, getCaps]);
+  }, [app, setShowModal, getPermissions]);
+
+  const setGranted = useCallback((index: number, granted: boolean) => {
+    setPermissions((permissions) =>
+      permissions.map((p, i) => (i === index ? { ...p, granted } : p))
+    );
+  }, [setPermissions]);
 
   const install = useCallback(async () => {
     try {
       setLoading(`Installing ${getAppName(app)}...`);
-      await installApp(app);
+      await installApp(
+        app,
+        permissions.filter((p) => p.granted).map((p) => p.capability)
+      );
 
       const interval = setInterval(() => {
         getMyApp(app)
@@ -48,7 +59,7 @@ export default function InstallButton({ app, isIcon = false, ...props }: Install
       window.alert(`Failed to install, please try again.`);
       setLoading("");
     }
-  }, [app, installApp, getMyApp]);
+  }, [app, permissions, installApp, getMyApp]);
 
   return (
     <>
@@ -69,15 +80,23 @@ export default function InstallButton({ app, isIcon = false, ...props }: Install
           <>
             <h4>Approve App Permissions</h4>
             <h5 className="m-0">
-              {getAppName(app)} needs the following permissions:
+              {getAppName(app)} requests the following permissions:
             </h5>
             <ul className="flex flex-col items-start">
-              {caps.map((cap) => (
-                <li key={cap}>{cap}</li>
+              {permissions.map(({ capability, granted }, i) => (
+                <li key={i} className="flex items-center gap-2">
+                  <Checkbox
+                    checked={!!granted}
+                    setChecked={(checked) => setGranted(i, checked)}
+                  />
+                  <span>
+                    {capability.process} requests {capability.params} from {capability.issuer}
+                  </span>
+                </li>
               ))}
             </ul>
             <button type="button" onClick={install}>
-              Approve & Install
+              Approve Selected & Install
             </button>
           </>
         )}
@@ -1,6 +1,6 @@
 import { create } from 'zustand'
 import { persist, createJSONStorage } from 'zustand/middleware'
-import { MyApps, AppInfo, PackageManifest } from '../types/Apps'
+import { MyApps, AppInfo, PackageManifest, PermissionDecision, RequestedCapability } from '../types/Apps'
 import { HTTP_STATUS } from '../constants/http';
 import { appId, getAppType } from '../utils/app';
 
@@ -17,13 +17,15 @@ export interface AppsStore {
   getMyApps: () => Promise<MyApps>
   getListedApps: () => Promise<AppInfo[]>
   getMyApp: (app: AppInfo) => Promise<AppInfo>
-  installApp: (app: AppInfo) => Promise<void>
+  installApp: (app: AppInfo, granted?: RequestedCapability[]) => Promise<void>
   updateApp: (app: AppInfo) => Promise<void>
   uninstallApp: (app: AppInfo) => Promise<void>
   getListedApp: (packageName: string) => Promise<AppInfo>
   downloadApp: (app: AppInfo, download_from: string) => Promise<void>
   getCaps: (app: AppInfo) => Promise<PackageManifest>
   approveCaps: (app: AppInfo) => Promise<void>
+  getPermissions: (app: AppInfo) => Promise<PermissionDecision[]>
+  decidePermissions: (app: AppInfo, granted: RequestedCapability[]) => Promise<PermissionDecision[]>
   setMirroring: (info: AppInfo, mirroring: boolean) => Promise<void>
   setAutoUpdate: (app: AppInfo, autoUpdate: boolean) => Promise<void>
   rebuildIndex: () => Promise<void>
@@ -85,12 +87,11 @@ const useAppsStore = create<AppsStore>()(
         set({ myApps, listedApps })
         return app
       },
-      installApp: async (info: AppInfo) => {
-        const approveRes = await fetch(`${BASE_URL}/apps/${appId(info)}/caps`, {
-          method: 'POST'
-        })
-        if (approveRes.status !== HTTP_STATUS.OK) {
-          throw new Error(`Failed to approve caps for app: ${appId(info)}`)
+      installApp: async (info: AppInfo, granted?: RequestedCapability[]) => {
+        if (granted) {
+          await get().decidePermissions(info, granted)
+        } else {
+          await get().approveCaps(info)
         }
 
         const installRes = await fetch(`${BASE_URL}/apps/${appId(info)}`, {
@@ -160,6 +161,23 @@ const useAppsStore = create<AppsStore>()(
           throw new Error(`Failed to get app: ${appId(info)}`)
         }
       },
+      getPermissions: async (info: AppInfo) => {
+        const res = await fetch(`${BASE_URL}/apps/${appId(info)}/permissions`)
+        if (res.status !== HTTP_STATUS.OK) {
+          throw new Error(`Failed to get permissions for app: ${appId(info)}`)
+        }
+        return await res.json() as PermissionDecision[]
+      },
+      decidePermissions: async (info: AppInfo, granted: RequestedCapability[]) => {
+        const res = await fetch(`${BASE_URL}/apps/${appId(info)}/permissions`, {
+          method: 'POST',
+          body: JSON.stringify(granted),
+        })
+        if (res.status !== HTTP_STATUS.OK) {
+          throw new Error(`Failed to approve caps for app: ${appId(info)}`)
+        }
+        return await res.json() as PermissionDecision[]
+      },
       rebuildIndex: async () => {
         const res = await fetch(`${BASE_URL}/apps/rebuild-index`, {
           method: 'POST'
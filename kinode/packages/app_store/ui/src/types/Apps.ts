@@ -51,6 +51,17 @@ export interface AppInfo extends AppListing {
     permissions?: string[]
 }
 
+export interface RequestedCapability {
+    process: string
+    issuer: string
+    params: string
+}
+
+export interface PermissionDecision {
+    capability: RequestedCapability
+    granted: boolean | null
+}
+
 export interface PackageManifest {
     process_name: string
     process_wasm_path: string
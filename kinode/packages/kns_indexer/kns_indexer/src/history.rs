@@ -0,0 +1,194 @@
+use crate::KnsUpdate;
+use kinode_process_lib::{sqlite, Address};
+use serde::{Deserialize, Serialize};
+
+/// where history was kept before it moved in with the index, so that a log's
+/// changes to both are written in one transaction
+const LEGACY_DB: &str = "kns_history";
+
+/// What a log changed about a node. Ownership is held by the registrars
+/// rather than the contract we index, so it does not appear here.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ChangeKind {
+    Registered,
+    Key,
+    Ip,
+    Port,
+    Routers,
+}
+
+/// One change to a node's on-chain record.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeChange {
+    pub chain_id: u64,
+    pub block: u64,
+    pub log_index: u64,
+    pub kind: ChangeKind,
+    /// the node's full record after the change
+    pub record: KnsUpdate,
+}
+
+/// Every change ever made to each node name, kept in the index's sqlite
+/// database so that it survives restarts. Changes are keyed by where their
+/// log appeared, so re-indexing from the first block does not record them twice.
+pub struct History {
+    db: sqlite::Sqlite,
+}
+
+impl History {
+    pub fn open(our: &Address) -> anyhow::Result<Self> {
+        let db = sqlite::open(our.package_id(), crate::index::DB, None)?;
+        db.write(
+            "CREATE TABLE IF NOT EXISTS node_history (
+                name TEXT NOT NULL,
+                chain_id INTEGER NOT NULL,
+                block INTEGER NOT NULL,
+                log_index INTEGER NOT NULL,
+                change TEXT NOT NULL,
+                UNIQUE (chain_id, block, log_index, name)
+            )"
+            .to_string(),
+            vec![],
+            None,
+        )?;
+        db.write(
+            "CREATE INDEX IF NOT EXISTS node_history_name ON node_history (name, chain_id, block)"
+                .to_string(),
+            vec![],
+            None,
+        )?;
+        let history = Self { db };
+        history.migrate(our)?;
+        Ok(history)
+    }
+
+    /// move over any history kept in its own database by earlier versions
+    fn migrate(&self, our: &Address) -> anyhow::Result<()> {
+        let kept = self
+            .db
+            .read("SELECT 1 FROM node_history LIMIT 1".to_string(), vec![])?;
+        if !kept.is_empty() {
+            return Ok(());
+        }
+        let legacy = sqlite::open(our.package_id(), LEGACY_DB, None)?;
+        // a database that was only just created has no table to read
+        if let Ok(rows) = legacy.read(
+            "SELECT name, chain_id, block, log_index, change FROM node_history".to_string(),
+            vec![],
+        ) {
+            let tx_id = self.db.begin_tx()?;
+            for row in rows.iter() {
+                self.db.write(
+                    "INSERT OR IGNORE INTO node_history (name, chain_id, block, log_index, change)
+                     VALUES (?, ?, ?, ?, ?)"
+                        .to_string(),
+                    ["name", "chain_id", "block", "log_index", "change"]
+                        .iter()
+                        .map(|column| row.get(*column).cloned().unwrap_or_default())
+                        .collect(),
+                    Some(tx_id),
+                )?;
+            }
+            self.db.commit_tx(tx_id)?;
+        }
+        sqlite::remove_db(our.package_id(), LEGACY_DB, None)
+    }
+
+    pub fn record(&self, change: &NodeChange, tx_id: Option<u64>) -> anyhow::Result<()> {
+        self.db.write(
+            "INSERT OR IGNORE INTO node_history (name, chain_id, block, log_index, change)
+             VALUES (?, ?, ?, ?, ?)"
+                .to_string(),
+            vec![
+                change.record.name.clone().into(),
+                change.chain_id.into(),
+                change.block.into(),
+                change.log_index.into(),
+                serde_json::to_string(change)?.into(),
+            ],
+            tx_id,
+        )
+    }
+
+    /// every change to a name, oldest first
+    pub fn history(&self, name: &str) -> anyhow::Result<Vec<NodeChange>> {
+        let rows = self.db.read(
+            "SELECT change FROM node_history WHERE name = ?
+             ORDER BY chain_id, block, log_index"
+                .to_string(),
+            vec![name.into()],
+        )?;
+        rows.iter().map(parse_row).collect()
+    }
+
+    /// the most recent change to a name on a chain at or before the given block
+    pub fn at(&self, name: &str, chain_id: u64, block: u64) -> anyhow::Result<Option<NodeChange>> {
+        let rows = self.db.read(
+            "SELECT change FROM node_history WHERE name = ? AND chain_id = ? AND block <= ?
+             ORDER BY block DESC, log_index DESC LIMIT 1"
+                .to_string(),
+            vec![name.into(), chain_id.into(), block.into()],
+        )?;
+        rows.first().map(parse_row).transpose()
+    }
+
+    /// the most recent change to a name on a chain, other than the one made
+    /// by the given log
+    pub fn latest_except(
+        &self,
+        name: &str,
+        chain_id: u64,
+        block: u64,
+        log_index: u64,
+    ) -> anyhow::Result<Option<NodeChange>> {
+        let rows = self.db.read(
+            "SELECT change FROM node_history WHERE name = ? AND chain_id = ?
+             AND NOT (block = ? AND log_index = ?)
+             ORDER BY block DESC, log_index DESC LIMIT 1"
+                .to_string(),
+            vec![name.into(), chain_id.into(), block.into(), log_index.into()],
+        )?;
+        rows.first().map(parse_row).transpose()
+    }
+
+    /// Forget the change a log made to a name, after a reorg removed the log.
+    /// Returns false if we had no such change.
+    pub fn remove(
+        &self,
+        chain_id: u64,
+        block: u64,
+        log_index: u64,
+        name: &str,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<bool> {
+        let params: Vec<serde_json::Value> =
+            vec![chain_id.into(), block.into(), log_index.into(), name.into()];
+        let rows = self.db.read(
+            "SELECT change FROM node_history
+             WHERE chain_id = ? AND block = ? AND log_index = ? AND name = ?"
+                .to_string(),
+            params.clone(),
+        )?;
+        if rows.is_empty() {
+            return Ok(false);
+        }
+        self.db.write(
+            "DELETE FROM node_history
+             WHERE chain_id = ? AND block = ? AND log_index = ? AND name = ?"
+                .to_string(),
+            params,
+            tx_id,
+        )?;
+        Ok(true)
+    }
+}
+
+fn parse_row(
+    row: &std::collections::HashMap<String, serde_json::Value>,
+) -> anyhow::Result<NodeChange> {
+    let change = row
+        .get("change")
+        .and_then(|c| c.as_str())
+        .ok_or(anyhow::anyhow!("malformed history row"))?;
+    Ok(serde_json::from_str(change)?)
+}
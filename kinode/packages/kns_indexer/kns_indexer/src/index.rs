@@ -0,0 +1,306 @@
+use crate::KnsUpdate;
+use kinode_process_lib::{sqlite, Address};
+use std::collections::HashMap;
+
+pub const DB: &str = "kns_index";
+
+/// The names and nodes we have indexed on each chain, along with how far
+/// we've indexed it, kept in sqlite and written one log at a time.
+pub struct Index {
+    db: sqlite::Sqlite,
+}
+
+type Row = HashMap<String, serde_json::Value>;
+
+impl Index {
+    pub fn open(our: &Address) -> anyhow::Result<Self> {
+        let db = sqlite::open(our.package_id(), DB, None)?;
+        for statement in [
+            "CREATE TABLE IF NOT EXISTS chains (
+                chain_id INTEGER PRIMARY KEY,
+                contract_address TEXT NOT NULL,
+                block INTEGER NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS names (
+                chain_id INTEGER NOT NULL,
+                namehash TEXT NOT NULL,
+                name TEXT NOT NULL,
+                PRIMARY KEY (chain_id, namehash)
+            )",
+            "CREATE INDEX IF NOT EXISTS names_name ON names (name)",
+            "CREATE TABLE IF NOT EXISTS nodes (
+                chain_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                namehash TEXT NOT NULL,
+                owner TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                ip TEXT NOT NULL,
+                port INTEGER NOT NULL,
+                routers TEXT NOT NULL,
+                PRIMARY KEY (chain_id, name)
+            )",
+            "CREATE INDEX IF NOT EXISTS nodes_name ON nodes (name)",
+            "CREATE INDEX IF NOT EXISTS nodes_namehash ON nodes (namehash)",
+            // the KNS contract emits no ownership events, so `owner` is never
+            // filled in; drop the index earlier versions kept on it
+            "DROP INDEX IF EXISTS nodes_owner",
+            "CREATE TABLE IF NOT EXISTS node_routers (
+                chain_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                router TEXT NOT NULL
+            )",
+            "CREATE INDEX IF NOT EXISTS node_routers_router ON node_routers (router)",
+            "CREATE INDEX IF NOT EXISTS node_routers_name ON node_routers (chain_id, name)",
+            "CREATE TABLE IF NOT EXISTS confirmations (
+                chain_id INTEGER PRIMARY KEY,
+                confirmations INTEGER NOT NULL
+            )",
+        ] {
+            db.write(statement.to_string(), vec![], None)?;
+        }
+        Ok(Self { db })
+    }
+
+    pub fn begin_tx(&self) -> anyhow::Result<u64> {
+        self.db.begin_tx()
+    }
+
+    pub fn commit_tx(&self, tx_id: u64) -> anyhow::Result<()> {
+        self.db.commit_tx(tx_id)
+    }
+
+    /// The block we have indexed a chain up to. If we have nothing saved for
+    /// this contract on the chain, any rows from another contract are dropped.
+    pub fn cursor(&self, chain_id: u64, contract_address: &str) -> anyhow::Result<Option<u64>> {
+        let rows = self.db.read(
+            "SELECT contract_address, block FROM chains WHERE chain_id = ?".to_string(),
+            vec![chain_id.into()],
+        )?;
+        if let Some(row) = rows.first() {
+            if text(row, "contract_address")? == contract_address {
+                return Ok(Some(integer(row, "block")?));
+            }
+        }
+        for table in ["chains", "names", "nodes", "node_routers"] {
+            self.db.write(
+                format!("DELETE FROM {table} WHERE chain_id = ?"),
+                vec![chain_id.into()],
+                None,
+            )?;
+        }
+        Ok(None)
+    }
+
+    pub fn set_cursor(
+        &self,
+        chain_id: u64,
+        contract_address: &str,
+        block: u64,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        self.db.write(
+            "INSERT OR REPLACE INTO chains (chain_id, contract_address, block) VALUES (?, ?, ?)"
+                .to_string(),
+            vec![chain_id.into(), contract_address.into(), block.into()],
+            tx_id,
+        )
+    }
+
+    /// the confirmation depth set for a chain, if it has been changed from
+    /// the default
+    pub fn confirmations(&self, chain_id: u64) -> anyhow::Result<Option<u64>> {
+        let rows = self.db.read(
+            "SELECT confirmations FROM confirmations WHERE chain_id = ?".to_string(),
+            vec![chain_id.into()],
+        )?;
+        rows.first()
+            .map(|row| integer(row, "confirmations"))
+            .transpose()
+    }
+
+    pub fn set_confirmations(&self, chain_id: u64, confirmations: u64) -> anyhow::Result<()> {
+        self.db.write(
+            "INSERT OR REPLACE INTO confirmations (chain_id, confirmations) VALUES (?, ?)"
+                .to_string(),
+            vec![chain_id.into(), confirmations.into()],
+            None,
+        )
+    }
+
+    pub fn name(&self, chain_id: u64, namehash: &str) -> anyhow::Result<Option<String>> {
+        let rows = self.db.read(
+            "SELECT name FROM names WHERE chain_id = ? AND namehash = ?".to_string(),
+            vec![chain_id.into(), namehash.into()],
+        )?;
+        rows.first().map(|row| text(row, "name")).transpose()
+    }
+
+    pub fn set_name(
+        &self,
+        chain_id: u64,
+        namehash: &str,
+        name: &str,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        self.db.write(
+            "INSERT OR REPLACE INTO names (chain_id, namehash, name) VALUES (?, ?, ?)".to_string(),
+            vec![chain_id.into(), namehash.into(), name.into()],
+            tx_id,
+        )
+    }
+
+    /// every namehash and name on a chain
+    pub fn names(&self, chain_id: u64) -> anyhow::Result<HashMap<String, String>> {
+        let rows = self.db.read(
+            "SELECT namehash, name FROM names WHERE chain_id = ?".to_string(),
+            vec![chain_id.into()],
+        )?;
+        rows.iter()
+            .map(|row| Ok((text(row, "namehash")?, text(row, "name")?)))
+            .collect()
+    }
+
+    pub fn node(&self, chain_id: u64, name: &str) -> anyhow::Result<Option<KnsUpdate>> {
+        let rows = self.db.read(
+            "SELECT * FROM nodes WHERE chain_id = ? AND name = ?".to_string(),
+            vec![chain_id.into(), name.into()],
+        )?;
+        rows.first().map(parse_node).transpose()
+    }
+
+    pub fn set_node(
+        &self,
+        chain_id: u64,
+        node: &KnsUpdate,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        self.db.write(
+            "INSERT OR REPLACE INTO nodes
+             (chain_id, name, namehash, owner, public_key, ip, port, routers)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+                .to_string(),
+            vec![
+                chain_id.into(),
+                node.name.clone().into(),
+                node.node.clone().into(),
+                node.owner.clone().into(),
+                node.public_key.clone().into(),
+                node.ip.clone().into(),
+                node.port.into(),
+                serde_json::to_string(&node.routers)?.into(),
+            ],
+            tx_id,
+        )?;
+        self.db.write(
+            "DELETE FROM node_routers WHERE chain_id = ? AND name = ?".to_string(),
+            vec![chain_id.into(), node.name.clone().into()],
+            tx_id,
+        )?;
+        for router in node.routers.iter() {
+            self.db.write(
+                "INSERT INTO node_routers (chain_id, name, router) VALUES (?, ?, ?)".to_string(),
+                vec![
+                    chain_id.into(),
+                    node.name.clone().into(),
+                    router.clone().into(),
+                ],
+                tx_id,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// drop a name and its node from a chain, as if it had never been registered
+    pub fn remove_node(
+        &self,
+        chain_id: u64,
+        name: &str,
+        namehash: &str,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        for table in ["nodes", "node_routers"] {
+            self.db.write(
+                format!("DELETE FROM {table} WHERE chain_id = ? AND name = ?"),
+                vec![chain_id.into(), name.into()],
+                tx_id,
+            )?;
+        }
+        self.db.write(
+            "DELETE FROM names WHERE chain_id = ? AND namehash = ?".to_string(),
+            vec![chain_id.into(), namehash.into()],
+            tx_id,
+        )
+    }
+
+    /// every node on a chain
+    pub fn nodes(&self, chain_id: u64) -> anyhow::Result<Vec<KnsUpdate>> {
+        let rows = self.db.read(
+            "SELECT * FROM nodes WHERE chain_id = ?".to_string(),
+            vec![chain_id.into()],
+        )?;
+        rows.iter().map(parse_node).collect()
+    }
+
+    /// every node on a chain that routes through the given router namehash
+    pub fn nodes_with_router(&self, chain_id: u64, router: &str) -> anyhow::Result<Vec<KnsUpdate>> {
+        let rows = self.db.read(
+            "SELECT nodes.* FROM node_routers JOIN nodes
+             ON nodes.chain_id = node_routers.chain_id AND nodes.name = node_routers.name
+             WHERE node_routers.chain_id = ? AND node_routers.router = ?"
+                .to_string(),
+            vec![chain_id.into(), router.into()],
+        )?;
+        rows.iter().map(parse_node).collect()
+    }
+
+    /// Names on any chain that start with `prefix`, or merely contain it if
+    /// `anywhere` is set, in alphabetical order.
+    pub fn search_names(
+        &self,
+        prefix: &str,
+        anywhere: bool,
+        limit: u64,
+    ) -> anyhow::Result<Vec<String>> {
+        let escaped = prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let pattern = if anywhere {
+            format!("%{escaped}%")
+        } else {
+            format!("{escaped}%")
+        };
+        let rows = self.db.read(
+            "SELECT DISTINCT name FROM names WHERE name LIKE ? ESCAPE '\\'
+             ORDER BY name LIMIT ?"
+                .to_string(),
+            vec![pattern.into(), limit.into()],
+        )?;
+        rows.iter().map(|row| text(row, "name")).collect()
+    }
+}
+
+fn text(row: &Row, column: &str) -> anyhow::Result<String> {
+    row.get(column)
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .ok_or(anyhow::anyhow!("malformed index row: missing {column}"))
+}
+
+fn integer(row: &Row, column: &str) -> anyhow::Result<u64> {
+    row.get(column)
+        .and_then(|v| v.as_u64())
+        .ok_or(anyhow::anyhow!("malformed index row: missing {column}"))
+}
+
+fn parse_node(row: &Row) -> anyhow::Result<KnsUpdate> {
+    Ok(KnsUpdate {
+        name: text(row, "name")?,
+        owner: text(row, "owner")?,
+        node: text(row, "namehash")?,
+        public_key: text(row, "public_key")?,
+        ip: text(row, "ip")?,
+        port: integer(row, "port")? as u16,
+        routers: serde_json::from_str(&text(row, "routers")?)?,
+    })
+}
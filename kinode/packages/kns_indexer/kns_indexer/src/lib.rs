@@ -1,45 +1,198 @@
 use alloy_sol_types::{sol, SolEvent};
+use history::{ChangeKind, History, NodeChange};
+use index::Index;
 use kinode_process_lib::{
-    await_message, call_init, eth, net, println, Address, Message, Request, Response,
+    await_message, call_init, eth, net, println, timer, Address, Message, Request, Response,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::{
-    hash_map::{Entry, HashMap},
-    BTreeMap,
-};
+use std::collections::{BTreeMap, HashMap};
+
+mod history;
+mod index;
 
 wit_bindgen::generate!({
     path: "wit",
     world: "process",
 });
 
-#[cfg(not(feature = "simulation-mode"))]
-const KNS_ADDRESS: &'static str = "0xca5b5811c0c40aab3295f932b1b5112eb7bb4bd6"; // optimism
-#[cfg(feature = "simulation-mode")]
-const KNS_ADDRESS: &'static str = "0x5FbDB2315678afecb367f032d93F642f64180aa3"; // local
+/// A KNS contract deployment to index.
+struct KnsChain {
+    chain_id: u64,
+    contract_address: &'static str,
+    first_block: u64,
+    /// how many blocks must be built on top of a log before we apply it
+    confirmations: u64,
+}
 
+/// Every KNS deployment we index, in order of precedence: if a name is
+/// registered on more than one chain, we use its record from the chain that
+/// comes first here, and ignore it on the others.
 #[cfg(not(feature = "simulation-mode"))]
-const CHAIN_ID: u64 = 10; // optimism
+const KNS_CHAINS: &[KnsChain] = &[KnsChain {
+    chain_id: 10, // optimism
+    contract_address: "0xca5b5811c0c40aab3295f932b1b5112eb7bb4bd6",
+    first_block: 114_923_786,
+    confirmations: 12,
+}];
 #[cfg(feature = "simulation-mode")]
-const CHAIN_ID: u64 = 31337; // local
+const KNS_CHAINS: &[KnsChain] = &[KnsChain {
+    chain_id: 31337, // local
+    contract_address: "0x5FbDB2315678afecb367f032d93F642f64180aa3",
+    first_block: 1,
+    confirmations: 0,
+}];
 
-#[cfg(not(feature = "simulation-mode"))]
-const KNS_FIRST_BLOCK: u64 = 114_923_786; // optimism
-#[cfg(feature = "simulation-mode")]
-const KNS_FIRST_BLOCK: u64 = 1; // local
+/// how often to check each chain for new blocks, confirming buffered logs
+const HEAD_POLL_INTERVAL_MS: u64 = 12_000;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug)]
 struct State {
+    // one per entry in KNS_CHAINS, in the same order
+    chains: Vec<ChainState>,
+}
+
+#[derive(Clone, Debug)]
+struct ChainState {
     chain_id: u64,
     // what contract this state pertains to
     contract_address: String,
-    // namehash to human readable name
-    names: HashMap<String, String>,
-    // human readable name to most recent on-chain routing information as json
-    // NOTE: not every namehash will have a node registered
-    nodes: HashMap<String, KnsUpdate>,
-    // last block we have an update from
+    // last block we have applied an update from
     block: u64,
+    // blocks a log must be buried under before we apply it
+    confirmations: u64,
+    // newest block we know of
+    head: u64,
+    // logs too recent to apply yet, by block and log index
+    unconfirmed: BTreeMap<(u64, u64), eth::Log>,
+}
+
+impl ChainState {
+    /// the newest block at or before which every log has been applied
+    fn confirmed_block(&self) -> u64 {
+        self.block.max(self.head.saturating_sub(self.confirmations))
+    }
+}
+
+impl State {
+    /// pick up each chain from where the index left off
+    fn load(index: &Index) -> anyhow::Result<Self> {
+        let mut chains = vec![];
+        for chain in KNS_CHAINS {
+            let block = index
+                .cursor(chain.chain_id, chain.contract_address)?
+                .unwrap_or(chain.first_block);
+            chains.push(ChainState {
+                chain_id: chain.chain_id,
+                contract_address: chain.contract_address.to_string(),
+                block,
+                confirmations: index
+                    .confirmations(chain.chain_id)?
+                    .unwrap_or(chain.confirmations),
+                head: block,
+                unconfirmed: BTreeMap::new(),
+            });
+        }
+        Ok(Self { chains })
+    }
+
+    /// the last confirmed block on the first chain, which request `block`s
+    /// are measured against
+    fn block(&self) -> u64 {
+        self.chains[0].confirmed_block()
+    }
+
+    fn name(&self, index: &Index, hash: &str) -> anyhow::Result<Option<NameRecord>> {
+        for chain in self.chains.iter() {
+            if let Some(name) = index.name(chain.chain_id, hash)? {
+                return Ok(Some(NameRecord {
+                    name,
+                    chain_id: chain.chain_id,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// a node's record from the chain with the highest precedence that has one
+    fn node_info(&self, index: &Index, name: &str) -> anyhow::Result<Option<NodeRecord>> {
+        for chain in self.chains.iter() {
+            if let Some(update) = index.node(chain.chain_id, name)? {
+                return Ok(Some(NodeRecord {
+                    update,
+                    chain_id: chain.chain_id,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// every node, each with its record from the chain with the highest precedence
+    fn all_nodes(&self, index: &Index) -> anyhow::Result<Vec<KnsUpdate>> {
+        let mut nodes: HashMap<String, KnsUpdate> = HashMap::new();
+        for chain in self.chains.iter().rev() {
+            for update in index.nodes(chain.chain_id)? {
+                nodes.insert(update.name.clone(), update);
+            }
+        }
+        Ok(nodes.into_values().collect())
+    }
+
+    /// every node routing through a router, from the chain with the highest
+    /// precedence for each
+    fn nodes_with_router(&self, index: &Index, router: &str) -> anyhow::Result<Vec<NodeRecord>> {
+        let mut nodes: HashMap<String, NodeRecord> = HashMap::new();
+        for chain in self.chains.iter() {
+            for update in index.nodes_with_router(chain.chain_id, router)? {
+                // a higher-precedence chain may hold this name without the router
+                if self.node_info(index, &update.name)?.map(|n| n.chain_id) != Some(chain.chain_id)
+                {
+                    continue;
+                }
+                nodes.entry(update.name.clone()).or_insert(NodeRecord {
+                    update,
+                    chain_id: chain.chain_id,
+                });
+            }
+        }
+        Ok(nodes.into_values().collect())
+    }
+
+    /// the entire index, as returned by GetState
+    fn dump(&self, index: &Index) -> anyhow::Result<serde_json::Value> {
+        let mut chains = vec![];
+        for chain in self.chains.iter() {
+            let nodes: HashMap<String, KnsUpdate> = index
+                .nodes(chain.chain_id)?
+                .into_iter()
+                .map(|node| (node.name.clone(), node))
+                .collect();
+            chains.push(serde_json::json!({
+                "chain_id": chain.chain_id,
+                "contract_address": chain.contract_address,
+                "names": index.names(chain.chain_id)?,
+                "nodes": nodes,
+                "block": chain.block,
+            }));
+        }
+        Ok(serde_json::json!({ "chains": chains }))
+    }
+}
+
+/// Response to a [`IndexerRequests::NamehashToNameRecord`] request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NameRecord {
+    pub name: String,
+    /// the chain the name was found on
+    pub chain_id: u64,
+}
+
+/// Response to a [`IndexerRequests::NodeInfo`] request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeRecord {
+    #[serde(flatten)]
+    pub update: KnsUpdate,
+    /// the chain the record was found on
+    pub chain_id: u64,
 }
 
 /// IndexerRequests are used to query discrete information from the indexer
@@ -48,25 +201,54 @@ struct State {
 /// If you want to know the most recent on-chain routing information for a
 /// human readable name, you would send a NodeInfo request.
 /// The block parameter specifies the recency of the data: the indexer will
-/// not respond until it has processed events up to the specified block.
+/// not respond until it has processed events up to the specified block on
+/// the first of the chains it indexes.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum IndexerRequests {
     /// return the human readable name for a namehash
     /// returns an Option<String>
     NamehashToName { hash: String, block: u64 },
     /// return the most recent on-chain routing information for a node name.
-    /// returns an Option<KnsUpdate>
+    /// returns an Option<NodeRecord>
     /// set block to 0 if you just want to get the current state of the indexer
     NodeInfo { name: String, block: u64 },
     /// return the entire state of the indexer at the given block
     /// set block to 0 if you just want to get the current state of the indexer
     GetState { block: u64 },
+    /// return every change ever made to a node name's on-chain record
+    /// returns a Vec<NodeChange>, oldest first
+    NodeHistory { name: String },
+    /// return the on-chain routing information a node name had as of the given
+    /// block, compared against the block numbers of each chain in turn.
+    /// returns an Option<NodeRecord>
+    NodeInfoAt { name: String, block: u64 },
+    /// return up to `limit` names, on any chain, starting with `prefix`,
+    /// in alphabetical order
+    /// returns a Vec<String>
+    NamesWithPrefix { prefix: String, limit: u64 },
+    /// return up to `limit` names, on any chain, containing `query`,
+    /// in alphabetical order
+    /// returns a Vec<String>
+    SearchNames { query: String, limit: u64 },
+    /// return every node that routes through the router with the given namehash
+    /// returns a Vec<NodeRecord>
+    NodesByRouter { router: String, block: u64 },
+    /// set how many blocks must be built on top of a log on the given chain
+    /// before it is applied. the setting is saved with the index.
+    /// **only accepted from our own node**
+    /// returns an Option<u64>: the previous depth, or None for an unknown chain
+    SetConfirmations { chain_id: u64, confirmations: u64 },
+    /// return the human readable name for a namehash, along with the chain
+    /// it was found on
+    /// returns an Option<NameRecord>
+    NamehashToNameRecord { hash: String, block: u64 },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum NetAction {
     KnsUpdate(KnsUpdate),
     KnsBatchUpdate(Vec<KnsUpdate>),
+    KnsRemove(String),
 }
 
 impl TryInto<Vec<u8>> for NetAction {
@@ -109,9 +291,14 @@ sol! {
     event RoutingUpdate(bytes32 indexed node, bytes32[] routers);
 }
 
-fn subscribe_to_logs(eth_provider: &eth::Provider, from_block: u64, filter: eth::Filter) {
+fn subscribe_to_logs(
+    eth_provider: &eth::Provider,
+    sub_id: u64,
+    from_block: u64,
+    filter: eth::Filter,
+) {
     loop {
-        match eth_provider.subscribe(1, filter.clone().from_block(from_block)) {
+        match eth_provider.subscribe(sub_id, filter.clone().from_block(from_block)) {
             Ok(()) => break,
             Err(_) => {
                 println!("failed to subscribe to chain! trying again in 5s...");
@@ -123,49 +310,21 @@ fn subscribe_to_logs(eth_provider: &eth::Provider, from_block: u64, filter: eth:
     println!("subscribed to logs successfully");
 }
 
+/// subscriptions are identified by the index of their chain in KNS_CHAINS
+fn sub_id(chain_index: usize) -> u64 {
+    chain_index as u64 + 1
+}
+
 call_init!(init);
 fn init(our: Address) {
-    println!("indexing on contract address {}", KNS_ADDRESS);
-
-    // we **can** persist PKI state between boots but with current size, it's
-    // more robust just to reload the whole thing. the new contracts will allow
-    // us to quickly verify we have the updated mapping with root hash, but right
-    // now it's tricky to recover from missed events.
-    //
-    // let state: State = match get_typed_state(|bytes| Ok(bincode::deserialize::<State>(bytes)?)) {
-    //     Some(s) => {
-    //         // if chain id or contract address changed from a previous run, reset state
-    //         if s.chain_id != CHAIN_ID || s.contract_address != KNS_ADDRESS {
-    //             println!("resetting state because runtime contract address or chain ID changed");
-    //             State {
-    //                 chain_id: CHAIN_ID,
-    //                 contract_address: KNS_ADDRESS.to_string(),
-    //                 names: HashMap::new(),
-    //                 nodes: HashMap::new(),
-    //                 block: KNS_FIRST_BLOCK,
-    //             }
-    //         } else {
-    //             println!("loading in {} persisted PKI entries", s.nodes.len());
-    //             s
-    //         }
-    //     }
-    //     None => State {
-    //         chain_id: CHAIN_ID,
-    //         contract_address: KNS_ADDRESS.to_string(),
-    //         names: HashMap::new(),
-    //         nodes: HashMap::new(),
-    //         block: KNS_FIRST_BLOCK,
-    //     },
-    // };
-    let state = State {
-        chain_id: CHAIN_ID,
-        contract_address: KNS_ADDRESS.to_string(),
-        names: HashMap::new(),
-        nodes: HashMap::new(),
-        block: KNS_FIRST_BLOCK,
-    };
+    for chain in KNS_CHAINS {
+        println!(
+            "indexing on contract address {} on chain {}",
+            chain.contract_address, chain.chain_id
+        );
+    }
 
-    match main(our, state) {
+    match main(our) {
         Ok(_) => {}
         Err(e) => {
             println!("error: {:?}", e);
@@ -173,52 +332,80 @@ fn init(our: Address) {
     }
 }
 
-fn main(our: Address, mut state: State) -> anyhow::Result<()> {
-    let filter = eth::Filter::new()
-        .address(state.contract_address.parse::<eth::Address>().unwrap())
-        .from_block(state.block - 1)
-        .to_block(eth::BlockNumberOrTag::Latest)
-        .events(vec![
-            "NodeRegistered(bytes32,bytes)",
-            "KeyUpdate(bytes32,bytes32)",
-            "IpUpdate(bytes32,uint128)",
-            "WsUpdate(bytes32,uint16)",
-            "RoutingUpdate(bytes32,bytes32[])",
-        ]);
+fn main(our: Address) -> anyhow::Result<()> {
+    // the index persists between boots: each chain picks up from the last block
+    // we indexed on it. logs from that block are fetched again, which is fine,
+    // since applying a log twice leaves the index as it was.
+    let index = Index::open(&our)?;
+    let history = History::open(&our)?;
+    let mut state = State::load(&index)?;
+
+    let filters: Vec<eth::Filter> = state
+        .chains
+        .iter()
+        .map(|chain| {
+            eth::Filter::new()
+                .address(chain.contract_address.parse::<eth::Address>().unwrap())
+                .from_block(chain.block.saturating_sub(1))
+                .to_block(eth::BlockNumberOrTag::Latest)
+                .events(vec![
+                    "NodeRegistered(bytes32,bytes)",
+                    "KeyUpdate(bytes32,bytes32)",
+                    "IpUpdate(bytes32,uint128)",
+                    "WsUpdate(bytes32,uint16)",
+                    "RoutingUpdate(bytes32,bytes32[])",
+                ])
+        })
+        .collect();
 
     // 60s timeout -- these calls can take a long time
     // if they do time out, we try them again
-    let eth_provider = eth::Provider::new(state.chain_id, 60);
-
-    println!(
-        "subscribing, state.block: {}, chain_id: {}",
-        state.block - 1,
-        state.chain_id
-    );
-
-    subscribe_to_logs(&eth_provider, state.block - 1, filter.clone());
+    let eth_providers: Vec<eth::Provider> = state
+        .chains
+        .iter()
+        .map(|chain| eth::Provider::new(chain.chain_id, 60))
+        .collect();
+
+    for (chain_index, chain) in state.chains.iter().enumerate() {
+        println!(
+            "subscribing, state.block: {}, chain_id: {}",
+            chain.block.saturating_sub(1),
+            chain.chain_id
+        );
+        subscribe_to_logs(
+            &eth_providers[chain_index],
+            sub_id(chain_index),
+            chain.block.saturating_sub(1),
+            filters[chain_index].clone(),
+        );
+    }
 
     // if block in state is < current_block, get logs from that part.
-    loop {
-        match eth_provider.get_logs(&filter) {
-            Ok(logs) => {
-                for log in logs {
-                    match handle_log(&our, &mut state, &log) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("log-handling error! {e:?}");
+    // logs newer than the chain's confirmation depth are held back until
+    // enough blocks have been built on top of them.
+    for chain_index in 0..state.chains.len() {
+        poll_head(&mut state, &eth_providers, chain_index);
+        loop {
+            match eth_providers[chain_index].get_logs(&filters[chain_index]) {
+                Ok(logs) => {
+                    for log in logs {
+                        match handle_log(&our, &mut state, &index, &history, chain_index, &log) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                println!("log-handling error! {e:?}");
+                            }
                         }
                     }
+                    break;
+                }
+                Err(e) => {
+                    println!(
+                        "got eth error while fetching logs: {:?}, trying again in 5s...",
+                        e
+                    );
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                    continue;
                 }
-                break;
-            }
-            Err(e) => {
-                println!(
-                    "got eth error while fetching logs: {:?}, trying again in 5s...",
-                    e
-                );
-                std::thread::sleep(std::time::Duration::from_secs(5));
-                continue;
             }
         }
     }
@@ -226,97 +413,168 @@ fn main(our: Address, mut state: State) -> anyhow::Result<()> {
     // shove initial state into net::net
     Request::new()
         .target((&our.node, "net", "distro", "sys"))
-        .try_body(NetAction::KnsBatchUpdate(
-            state.nodes.values().cloned().collect::<Vec<_>>(),
-        ))?
+        .try_body(NetAction::KnsBatchUpdate(state.all_nodes(&index)?))?
         .send()?;
 
-    // set_state(&bincode::serialize(&state)?);
-
     let mut pending_requests: BTreeMap<u64, Vec<IndexerRequests>> = BTreeMap::new();
 
+    timer::set_timer(HEAD_POLL_INTERVAL_MS, None);
+
     loop {
         let Ok(message) = await_message() else {
             println!("got network error");
             continue;
         };
-        let Message::Request { source, body, .. } = message else {
-            // TODO we could store the subscription ID for eth
-            // in case we want to cancel/reset it
-            continue;
+        let (source, body) = match message {
+            Message::Request { source, body, .. } => (source, body),
+            Message::Response { source, .. } => {
+                if source.process == "timer:distro:sys" {
+                    for chain_index in 0..state.chains.len() {
+                        poll_head(&mut state, &eth_providers, chain_index);
+                        apply_confirmed(&our, &mut state, &index, &history, chain_index)?;
+                    }
+                    respond_to_pending(&state, &index, &history, &mut pending_requests)?;
+                    timer::set_timer(HEAD_POLL_INTERVAL_MS, None);
+                }
+                // TODO we could store the subscription ID for eth
+                // in case we want to cancel/reset it
+                continue;
+            }
         };
 
         if source.process == "eth:distro:sys" {
             handle_eth_message(
                 &our,
                 &mut state,
-                &eth_provider,
-                &mut pending_requests,
+                &index,
+                &history,
+                &eth_providers,
                 &body,
-                &filter,
+                &filters,
             )?;
+            respond_to_pending(&state, &index, &history, &mut pending_requests)?;
         } else {
             let Ok(request) = serde_json::from_slice::<IndexerRequests>(&body) else {
                 println!("got invalid message");
                 continue;
             };
-
-            match request {
-                IndexerRequests::NamehashToName { ref hash, block } => {
-                    if block <= state.block {
-                        Response::new()
-                            .body(serde_json::to_vec(&state.names.get(hash))?)
-                            .send()?;
-                    } else {
-                        pending_requests
-                            .entry(block)
-                            .or_insert(vec![])
-                            .push(request);
-                    }
-                }
-                IndexerRequests::NodeInfo { ref name, block } => {
-                    if block <= state.block {
-                        Response::new()
-                            .body(serde_json::to_vec(&state.nodes.get(name))?)
-                            .send()?;
-                    } else {
-                        pending_requests
-                            .entry(block)
-                            .or_insert(vec![])
-                            .push(request);
-                    }
+            if let IndexerRequests::SetConfirmations {
+                chain_id,
+                confirmations,
+            } = request
+            {
+                if source.node != our.node {
+                    println!("got SetConfirmations from remote node");
+                    continue;
                 }
-                IndexerRequests::GetState { block } => {
-                    if block <= state.block {
-                        Response::new().body(serde_json::to_vec(&state)?).send()?;
-                    } else {
-                        pending_requests
-                            .entry(block)
-                            .or_insert(vec![])
-                            .push(request);
+                let mut previous = None;
+                for chain_index in 0..state.chains.len() {
+                    if state.chains[chain_index].chain_id == chain_id {
+                        previous = Some(state.chains[chain_index].confirmations);
+                        state.chains[chain_index].confirmations = confirmations;
+                        index.set_confirmations(chain_id, confirmations)?;
+                        apply_confirmed(&our, &mut state, &index, &history, chain_index)?;
                     }
                 }
+                Response::new()
+                    .body(serde_json::to_vec(&previous)?)
+                    .send()?;
+                continue;
+            }
+            let block = match request {
+                IndexerRequests::NamehashToName { block, .. }
+                | IndexerRequests::NodeInfo { block, .. }
+                | IndexerRequests::GetState { block }
+                | IndexerRequests::NodeInfoAt { block, .. }
+                | IndexerRequests::NodesByRouter { block, .. }
+                | IndexerRequests::NamehashToNameRecord { block, .. } => block,
+                IndexerRequests::NodeHistory { .. }
+                | IndexerRequests::NamesWithPrefix { .. }
+                | IndexerRequests::SearchNames { .. }
+                | IndexerRequests::SetConfirmations { .. } => 0,
+            };
+            if block <= state.block() {
+                respond(&state, &index, &history, &request)?;
+            } else {
+                pending_requests
+                    .entry(block)
+                    .or_insert(vec![])
+                    .push(request);
             }
         }
     }
 }
 
+fn respond(
+    state: &State,
+    index: &Index,
+    history: &History,
+    request: &IndexerRequests,
+) -> anyhow::Result<()> {
+    let body = match request {
+        IndexerRequests::NamehashToName { hash, .. } => {
+            serde_json::to_vec(&state.name(index, hash)?.map(|record| record.name))?
+        }
+        IndexerRequests::NamehashToNameRecord { hash, .. } => {
+            serde_json::to_vec(&state.name(index, hash)?)?
+        }
+        IndexerRequests::NodeInfo { name, .. } => {
+            serde_json::to_vec(&state.node_info(index, name)?)?
+        }
+        IndexerRequests::GetState { .. } => serde_json::to_vec(&state.dump(index)?)?,
+        IndexerRequests::NodeHistory { name } => serde_json::to_vec(&history.history(name)?)?,
+        IndexerRequests::NodeInfoAt { name, block } => {
+            let mut found = None;
+            for chain in state.chains.iter() {
+                if let Some(change) = history.at(name, chain.chain_id, *block)? {
+                    found = Some(NodeRecord {
+                        update: change.record,
+                        chain_id: change.chain_id,
+                    });
+                    break;
+                }
+            }
+            serde_json::to_vec(&found)?
+        }
+        IndexerRequests::NamesWithPrefix { prefix, limit } => {
+            serde_json::to_vec(&index.search_names(prefix, false, *limit)?)?
+        }
+        IndexerRequests::SearchNames { query, limit } => {
+            serde_json::to_vec(&index.search_names(query, true, *limit)?)?
+        }
+        IndexerRequests::NodesByRouter { router, .. } => {
+            serde_json::to_vec(&state.nodes_with_router(index, router)?)?
+        }
+        IndexerRequests::SetConfirmations { .. } => {
+            // handled in main loop
+            return Ok(());
+        }
+    };
+    Response::new().body(body).send()?;
+    Ok(())
+}
+
 fn handle_eth_message(
     our: &Address,
     state: &mut State,
-    eth_provider: &eth::Provider,
-    pending_requests: &mut BTreeMap<u64, Vec<IndexerRequests>>,
+    index: &Index,
+    history: &History,
+    eth_providers: &[eth::Provider],
     body: &[u8],
-    filter: &eth::Filter,
+    filters: &[eth::Filter],
 ) -> anyhow::Result<()> {
     let Ok(eth_result) = serde_json::from_slice::<eth::EthSubResult>(body) else {
         return Err(anyhow::anyhow!("got invalid message"));
     };
 
     match eth_result {
-        Ok(eth::EthSub { result, .. }) => {
+        Ok(eth::EthSub { id, result }) => {
+            let chain_index = (id as usize).wrapping_sub(1);
+            if chain_index >= state.chains.len() {
+                return Err(anyhow::anyhow!("got log for unknown subscription {id}"));
+            }
             if let eth::SubscriptionResult::Log(log) = result {
-                match handle_log(our, state, &log) {
+                match handle_log(our, state, index, history, chain_index, &log) {
                     Ok(_) => {}
                     Err(e) => {
                         println!("log-handling error! {e:?}");
@@ -324,38 +582,49 @@ fn handle_eth_message(
                 }
             }
         }
-        Err(_e) => {
+        Err(eth::EthSubError { id, .. }) => {
             println!("got eth subscription error");
-            subscribe_to_logs(&eth_provider, state.block - 1, filter.clone());
+            let chain_index = (id as usize).wrapping_sub(1);
+            if chain_index < state.chains.len() {
+                subscribe_to_logs(
+                    &eth_providers[chain_index],
+                    id,
+                    state.chains[chain_index].block - 1,
+                    filters[chain_index].clone(),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// get the newest block on a chain, so that buffered logs can be confirmed
+fn poll_head(state: &mut State, eth_providers: &[eth::Provider], chain_index: usize) {
+    match eth_providers[chain_index].get_block_number() {
+        Ok(head) => {
+            let chain = &mut state.chains[chain_index];
+            chain.head = chain.head.max(head);
+        }
+        Err(e) => {
+            println!("got eth error while fetching block number: {e:?}");
         }
     }
+}
 
+fn respond_to_pending(
+    state: &State,
+    index: &Index,
+    history: &History,
+    pending_requests: &mut BTreeMap<u64, Vec<IndexerRequests>>,
+) -> anyhow::Result<()> {
     // check the pending_requests btreemap to see if there are any requests that
     // can be handled now that the state block has been updated
     let mut blocks_to_remove = vec![];
     for (block, requests) in pending_requests.iter() {
-        if *block <= state.block {
+        if *block <= state.block() {
             for request in requests.iter() {
-                match request {
-                    IndexerRequests::NamehashToName { hash, .. } => {
-                        Response::new()
-                            .body(serde_json::to_vec(&state.names.get(hash))?)
-                            .send()
-                            .unwrap();
-                    }
-                    IndexerRequests::NodeInfo { name, .. } => {
-                        Response::new()
-                            .body(serde_json::to_vec(&state.nodes.get(name))?)
-                            .send()
-                            .unwrap();
-                    }
-                    IndexerRequests::GetState { .. } => {
-                        Response::new()
-                            .body(serde_json::to_vec(&state)?)
-                            .send()
-                            .unwrap();
-                    }
-                }
+                respond(state, index, history, request)?;
             }
             blocks_to_remove.push(*block);
         } else {
@@ -366,31 +635,165 @@ fn handle_eth_message(
         pending_requests.remove(block);
     }
 
-    // set_state(&bincode::serialize(state)?);
     Ok(())
 }
 
-fn handle_log(our: &Address, state: &mut State, log: &eth::Log) -> anyhow::Result<()> {
-    let node_id = log.topics()[1];
+/// Hold a new log until it is buried under the chain's confirmation depth,
+/// or undo it if a reorg has removed it.
+fn handle_log(
+    our: &Address,
+    state: &mut State,
+    index: &Index,
+    history: &History,
+    chain_index: usize,
+    log: &eth::Log,
+) -> anyhow::Result<()> {
+    let (Some(block), Some(log_index)) = (log.block_number, log.log_index) else {
+        // pending logs can't be confirmed: we'll see them again once mined
+        return Ok(());
+    };
+    let chain = &mut state.chains[chain_index];
+    if log.removed {
+        // a log we never held may have been applied already: if so, its
+        // change is in the history
+        if chain.unconfirmed.remove(&(block, log_index)).is_none() {
+            rollback_log(our, state, index, history, chain_index, log)?;
+        }
+        return Ok(());
+    }
+    chain.head = chain.head.max(block);
+    chain.unconfirmed.insert((block, log_index), log.clone());
+    apply_confirmed(our, state, index, history, chain_index)
+}
+
+/// apply, in order, every buffered log on a chain that is now deep enough
+fn apply_confirmed(
+    our: &Address,
+    state: &mut State,
+    index: &Index,
+    history: &History,
+    chain_index: usize,
+) -> anyhow::Result<()> {
+    loop {
+        let chain = &mut state.chains[chain_index];
+        let Some(entry) = chain.unconfirmed.first_entry() else {
+            break;
+        };
+        if entry.key().0 + chain.confirmations > chain.head {
+            break;
+        }
+        let log = entry.remove();
+        match apply_log(our, state, index, history, chain_index, &log) {
+            Ok(_) => {}
+            Err(e) => {
+                println!("log-handling error! {e:?}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Undo a log we applied that a reorg has since removed: restore the node's
+/// record from before it, rewind the chain to before its block so that logs
+/// replacing it are fetched again after a restart, and give net whatever
+/// record now takes precedence.
+fn rollback_log(
+    our: &Address,
+    state: &mut State,
+    index: &Index,
+    history: &History,
+    chain_index: usize,
+    log: &eth::Log,
+) -> anyhow::Result<()> {
+    let (Some(block), Some(log_index)) = (log.block_number, log.log_index) else {
+        return Ok(());
+    };
+    let chain = &mut state.chains[chain_index];
+    let node_id = log.topics()[1].to_string();
+    let Some(name) = index.name(chain.chain_id, &node_id)? else {
+        return Ok(());
+    };
+    let tx_id = index.begin_tx()?;
+    if !history.remove(chain.chain_id, block, log_index, &name, Some(tx_id))? {
+        return index.commit_tx(tx_id);
+    }
+    println!(
+        "rolling back removed log for {name} at block {block} on chain {}",
+        chain.chain_id
+    );
+
+    match history.latest_except(&name, chain.chain_id, block, log_index)? {
+        Some(previous) => index.set_node(chain.chain_id, &previous.record, Some(tx_id))?,
+        None => index.remove_node(chain.chain_id, &name, &node_id, Some(tx_id))?,
+    }
+    chain.block = chain.block.min(block.saturating_sub(1));
+    index.set_cursor(
+        chain.chain_id,
+        &chain.contract_address,
+        chain.block,
+        Some(tx_id),
+    )?;
+    index.commit_tx(tx_id)?;
+
+    let action = match state.node_info(index, &name)? {
+        Some(record) if is_routable(&record.update) => NetAction::KnsUpdate(record.update),
+        _ => NetAction::KnsRemove(name),
+    };
+    Request::new()
+        .target((&our.node, "net", "distro", "sys"))
+        .try_body(action)?
+        .send()?;
+    Ok(())
+}
+
+/// whether a node has enough information on-chain for us to reach it
+fn is_routable(node: &KnsUpdate) -> bool {
+    node.public_key != "" && ((node.ip != "" && node.port != 0) || node.routers.len() > 0)
+}
 
-    let name = match state.names.entry(node_id.to_string()) {
-        Entry::Occupied(o) => o.into_mut(),
-        Entry::Vacant(v) => v.insert(get_name(&log)?),
+fn apply_log(
+    our: &Address,
+    state: &mut State,
+    index: &Index,
+    history: &History,
+    chain_index: usize,
+    log: &eth::Log,
+) -> anyhow::Result<()> {
+    let (preceding, rest) = state.chains.split_at_mut(chain_index);
+    let chain = &mut rest[0];
+
+    if let Some(block) = log.block_number {
+        chain.block = chain.block.max(block);
+    }
+
+    let node_id = log.topics()[1].to_string();
+
+    let (name, new_name) = match index.name(chain.chain_id, &node_id)? {
+        Some(name) => (name, false),
+        None => (get_name(&log)?, true),
     };
 
-    let node = state
-        .nodes
-        .entry(name.to_string())
-        .or_insert_with(|| KnsUpdate::new(name, &node_id.to_string()));
+    // a chain with higher precedence has this name: its record is the one we use
+    let mut shadowed = false;
+    for c in preceding.iter() {
+        if index.node(c.chain_id, &name)?.is_some() {
+            shadowed = true;
+            break;
+        }
+    }
 
-    let mut send = true;
+    let mut node = index
+        .node(chain.chain_id, &name)?
+        .unwrap_or_else(|| KnsUpdate::new(&name, &node_id));
 
-    match log.topics()[0] {
+    let kind = match log.topics()[0] {
+        NodeRegistered::SIGNATURE_HASH => ChangeKind::Registered,
         KeyUpdate::SIGNATURE_HASH => {
             node.public_key = KeyUpdate::decode_log_data(log.data(), true)
                 .unwrap()
                 .key
                 .to_string();
+            ChangeKind::Key
         }
         IpUpdate::SIGNATURE_HASH => {
             let ip = IpUpdate::decode_log_data(log.data(), true).unwrap().ip;
@@ -404,12 +807,14 @@ fn handle_log(our: &Address, state: &mut State, log: &eth::Log) -> anyhow::Resul
             // when we get ip data, we should delete any router data,
             // since the assignment of ip indicates an direct node
             node.routers = vec![];
+            ChangeKind::Ip
         }
         WsUpdate::SIGNATURE_HASH => {
             node.port = WsUpdate::decode_log_data(log.data(), true).unwrap().port;
             // when we get port data, we should delete any router data,
             // since the assignment of port indicates an direct node
             node.routers = vec![];
+            ChangeKind::Port
         }
         RoutingUpdate::SIGNATURE_HASH => {
             node.routers = RoutingUpdate::decode_log_data(log.data(), true)
@@ -422,36 +827,41 @@ fn handle_log(our: &Address, state: &mut State, log: &eth::Log) -> anyhow::Resul
             // since the assignment of routers indicates an indirect node
             node.ip = "".to_string();
             node.port = 0;
+            ChangeKind::Routers
         }
-        _ => {
-            send = false;
-        }
+        _ => return Ok(()),
+    };
+
+    let tx_id = index.begin_tx()?;
+    if new_name {
+        index.set_name(chain.chain_id, &node_id, &name, Some(tx_id))?;
+    }
+    index.set_node(chain.chain_id, &node, Some(tx_id))?;
+    index.set_cursor(
+        chain.chain_id,
+        &chain.contract_address,
+        chain.block,
+        Some(tx_id),
+    )?;
+    if let (Some(block), Some(log_index)) = (log.block_number, log.log_index) {
+        let change = NodeChange {
+            chain_id: chain.chain_id,
+            block,
+            log_index,
+            kind: kind.clone(),
+            record: node.clone(),
+        };
+        history.record(&change, Some(tx_id))?;
     }
+    index.commit_tx(tx_id)?;
 
-    if node.public_key != ""
-        && ((node.ip != "" && node.port != 0) || node.routers.len() > 0)
-        && send
-    {
+    if is_routable(&node) && kind != ChangeKind::Registered && !shadowed {
         Request::new()
             .target((&our.node, "net", "distro", "sys"))
             .try_body(NetAction::KnsUpdate(node.clone()))?
             .send()?;
     }
 
-    // if new block is > 100 from last block, save state
-    // let block = log.block_number.expect("expect");
-    // if block > state.block + 100 {
-    //     kinode_process_lib::print_to_terminal(
-    //         1,
-    //         &format!(
-    //             "persisting {} PKI entries at block {}",
-    //             state.nodes.len(),
-    //             block
-    //         ),
-    //     );
-    //     state.block = block;
-    //     set_state(&bincode::serialize(state)?);
-    // }
     Ok(())
 }
 
@@ -10,6 +10,12 @@ wit_bindgen::generate!({
 /// From main kns_indexer process
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct State {
+    // in order of precedence
+    chains: Vec<ChainState>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ChainState {
     chain_id: u64,
     // what contract this state pertains to
     contract_address: String,
@@ -44,14 +50,16 @@ fn init(_our: Address) {
     };
     let state = serde_json::from_slice::<State>(&body).expect("failed to deserialize state");
     // can change later, but for now, just print every known node name
-    let mut names = state.names.values().map(AsRef::as_ref).collect::<Vec<_>>();
-    names.sort();
-    println!(
-        "\nrunning on chain id {}\nCA: {}\n{} known nodes as of block {}\n     {}",
-        state.chain_id,
-        state.contract_address,
-        names.len(),
-        state.block,
-        names.join("\n     ")
-    );
+    for chain in state.chains {
+        let mut names = chain.names.values().map(AsRef::as_ref).collect::<Vec<_>>();
+        names.sort();
+        println!(
+            "\nrunning on chain id {}\nCA: {}\n{} known nodes as of block {}\n     {}",
+            chain.chain_id,
+            chain.contract_address,
+            names.len(),
+            chain.block,
+            names.join("\n     ")
+        );
+    }
 }
@@ -29,6 +29,10 @@ function populate(data) {
     populate_net_diagnostics(data.diagnostics);
     populate_eth_rpc_providers(data.eth_rpc_providers);
     populate_eth_rpc_settings(data.eth_rpc_access_settings);
+    // don't replace the results of a search with the latest entries
+    if (!cap_audit_searched) {
+        populate_cap_audit(data.cap_audit);
+    }
     // populate_kernel()
 }
 
@@ -96,6 +100,26 @@ function populate_eth_rpc_settings(settings) {
     }
 }
 
+let cap_audit_searched = false;
+
+function populate_cap_audit(entries) {
+    const ul = document.getElementById('cap-audit-entries');
+    ul.innerHTML = '';
+    if (entries.length === 0) {
+        const li = document.createElement('li');
+        li.innerText = '(none)';
+        ul.appendChild(li);
+        return;
+    }
+    entries.forEach(entry => {
+        const li = document.createElement('li');
+        const time = new Date(entry.timestamp).toLocaleString();
+        const caps = entry.caps.map(cap => `${cap.issuer}(${cap.params})`).join(', ');
+        li.innerText = `${time} ${entry.process}: ${JSON.stringify(entry.event)} ${caps}`;
+        ul.appendChild(li);
+    });
+}
+
 // Call init to start the application
 init();
 
@@ -214,6 +238,36 @@ document.getElementById('remove-eth-provider').addEventListener('submit', (e) =>
         });
 })
 
+document.getElementById('query-cap-audit').addEventListener('submit', (e) => {
+    e.preventDefault();
+    const data = new FormData(e.target);
+    const time = (name) => data.get(name) ? new Date(data.get(name)).getTime() : null;
+    const body = {
+        "CapAudit": {
+            process: data.get('process') || null,
+            issuer: data.get('issuer') || null,
+            since: time('since'),
+            until: time('until'),
+            limit: 200,
+        }
+    };
+    fetch(APP_PATH, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+        },
+        body: JSON.stringify(body),
+    }).then(response => response.json())
+        .then(data => {
+            if (data && data.CapAudit) {
+                cap_audit_searched = true;
+                populate_cap_audit(data.CapAudit);
+            } else {
+                alert(data);
+            }
+        });
+})
+
 // Setup WebSocket connection
 const wsProtocol = location.protocol === 'https:' ? 'wss://' : 'ws://';
 const ws = new WebSocket(wsProtocol + location.host + "/settings:settings:sys/");
@@ -16,6 +16,9 @@ enum SettingsRequest {
     EthConfig(eth::EthConfigAction),
     Shutdown,
     KillProcess(ProcessId),
+    CapAudit(CapAuditQuery),
+    SuspendProcess(ProcessId),
+    ResumeProcess(ProcessId),
 }
 
 type SettingsResponse = Result<Option<SettingsData>, SettingsError>;
@@ -23,8 +26,30 @@ type SettingsResponse = Result<Option<SettingsData>, SettingsError>;
 #[derive(Debug, Serialize, Deserialize)]
 enum SettingsData {
     PeerId(net::Identity),
+    CapAudit(Vec<serde_json::Value>),
 }
 
+/// Which entries to read from the kernel's capability audit log.
+/// Mirrors `CapAuditQuery` in the kernel.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CapAuditQuery {
+    process: Option<String>,
+    issuer: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// The kernel's response to `GetCapAudit`, with entries left as JSON
+/// since we only pass them on to the frontend.
+#[derive(Debug, Deserialize)]
+enum CapAuditResponse {
+    CapAudit(Vec<serde_json::Value>),
+}
+
+/// how many of the latest audit log entries the settings page shows
+const CAP_AUDIT_PAGE: usize = 50;
+
 #[derive(Debug, Serialize, Deserialize)]
 enum SettingsError {
     HiTimeout,
@@ -43,6 +68,7 @@ struct SettingsState {
     pub diagnostics: Option<String>,
     pub eth_rpc_providers: Option<eth::SavedConfigs>,
     pub eth_rpc_access_settings: Option<eth::AccessSettings>,
+    pub cap_audit: Option<Vec<serde_json::Value>>,
 }
 
 impl SettingsState {
@@ -54,6 +80,7 @@ impl SettingsState {
             diagnostics: None,
             eth_rpc_providers: None,
             eth_rpc_access_settings: None,
+            cap_audit: None,
         }
     }
 
@@ -74,6 +101,7 @@ impl SettingsState {
     /// - get Identity struct from net:distro:sys
     /// - get ETH RPC providers from eth:distro:sys
     /// - get ETH RPC access settings from eth:distro:sys
+    /// - get the latest capability audit log entries from kernel:distro:sys
     /// - get running processes from kernel:distro:sys
     fn fetch(&mut self) -> anyhow::Result<()> {
         // identity
@@ -127,11 +155,32 @@ impl SettingsState {
         };
         self.eth_rpc_access_settings = Some(access_settings);
 
+        // capability audit log
+        self.cap_audit = Some(get_cap_audit(CapAuditQuery {
+            limit: Some(CAP_AUDIT_PAGE),
+            ..Default::default()
+        })?);
+
         // TODO: running processes
         Ok(())
     }
 }
 
+fn get_cap_audit(query: CapAuditQuery) -> anyhow::Result<Vec<serde_json::Value>> {
+    let Ok(Ok(Message::Response { body, .. })) = Request::to(("our", "kernel", "distro", "sys"))
+        .body(serde_json::to_vec(&serde_json::json!({ "GetCapAudit": query })).unwrap())
+        .send_and_await_response(5)
+    else {
+        return Err(anyhow::anyhow!(
+            "failed to get capability audit log from kernel"
+        ));
+    };
+    let Ok(CapAuditResponse::CapAudit(entries)) = serde_json::from_slice(&body) else {
+        return Err(anyhow::anyhow!("got malformed response from kernel"));
+    };
+    Ok(entries)
+}
+
 wit_bindgen::generate!({
     path: "wit",
     world: "process",
@@ -379,6 +428,38 @@ fn handle_settings_request(
                 return SettingsResponse::Err(SettingsError::KernelNonresponsive);
             }
         }
+        SettingsRequest::SuspendProcess(pid) => {
+            // hold a process's messages until it is resumed, persisting them
+            // in case the node restarts in the meantime
+            if let Err(_) = Request::to(("our", "kernel", "distro", "sys"))
+                .body(
+                    serde_json::to_vec(&serde_json::json!({
+                        "SuspendProcess": { "target": pid, "persist": true }
+                    }))
+                    .unwrap(),
+                )
+                .send_and_await_response(30)
+                .unwrap()
+            {
+                return SettingsResponse::Err(SettingsError::KernelNonresponsive);
+            }
+        }
+        SettingsRequest::ResumeProcess(pid) => {
+            if let Err(_) = Request::to(("our", "kernel", "distro", "sys"))
+                .body(serde_json::to_vec(&serde_json::json!({ "ResumeProcess": pid })).unwrap())
+                .send_and_await_response(30)
+                .unwrap()
+            {
+                return SettingsResponse::Err(SettingsError::KernelNonresponsive);
+            }
+        }
+        SettingsRequest::CapAudit(query) => {
+            // read the audit log without refreshing the page
+            return match get_cap_audit(query) {
+                Ok(entries) => Ok(Some(SettingsData::CapAudit(entries))),
+                Err(_) => Err(SettingsError::KernelNonresponsive),
+            };
+        }
     }
 
     state.fetch().map_err(|_| SettingsError::StateFetchFailed)?;
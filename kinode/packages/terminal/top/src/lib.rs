@@ -20,7 +20,31 @@ fn init(_our: Address) {
         return;
     };
 
-    if proc_id.is_empty() {
+    if proc_id == "--queues" {
+        // not yet in kernel_types: print the depth of every process's message queue
+        let _ = Request::new()
+            .target(("our", "kernel", "distro", "sys"))
+            .body(serde_json::to_vec(&serde_json::json!({ "Debug": "QueueMetrics" })).unwrap())
+            .send();
+    } else if let Some(trace) = proc_id.strip_prefix("--trace") {
+        // not yet in kernel_types: turn message tracing on or off, or print traces
+        let body = match trace.trim() {
+            "on" => serde_json::json!({ "SetTracing": true }),
+            "off" => serde_json::json!({ "SetTracing": false }),
+            "" => serde_json::json!({ "Debug": { "Trace": null } }),
+            message_id => match message_id.parse::<u64>() {
+                Ok(message_id) => serde_json::json!({ "Debug": { "Trace": message_id } }),
+                Err(_) => {
+                    println!("usage: top --trace [on | off | <message-id>]");
+                    return;
+                }
+            },
+        };
+        let _ = Request::new()
+            .target(("our", "kernel", "distro", "sys"))
+            .body(serde_json::to_vec(&body).unwrap())
+            .send();
+    } else if proc_id.is_empty() {
         let _ = Request::new()
             .target(("our", "kernel", "distro", "sys"))
             .body(serde_json::to_vec(&KernelCommand::Debug(KernelPrint::ProcessMap)).unwrap())
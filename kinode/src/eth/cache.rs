@@ -0,0 +1,369 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use lib::types::eth::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// how long to keep results that depend on the head of the chain
+const LATEST_TTL: Duration = Duration::from_secs(2);
+/// blocks at least this far behind the newest one we've seen are treated as
+/// final, and results about them are cached until evicted
+const FINALITY_DEPTH: u64 = 64;
+const MAX_ENTRIES: usize = 10_000;
+
+/// (chain_id, method, params)
+pub type CacheKey = (u64, String, String);
+
+struct CachedValue {
+    value: serde_json::Value,
+    /// None if the value can never change
+    expires: Option<Instant>,
+}
+
+/// In-memory cache of RPC responses, plus the set of requests currently being
+/// fulfilled so that identical concurrent requests share one provider call.
+pub struct ResponseCache {
+    entries: DashMap<CacheKey, CachedValue>,
+    in_flight: DashMap<CacheKey, broadcast::Sender<EthResponse>>,
+    /// newest block number we've seen for each chain
+    latest_blocks: DashMap<u64, u64>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+pub enum Lookup {
+    /// answered from the cache
+    Hit(serde_json::Value),
+    /// an identical request is already in flight: await its response
+    Wait(broadcast::Receiver<EthResponse>),
+    /// caller must fulfill the request, then call [`ResponseCache::complete`]
+    Miss,
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            entries: DashMap::new(),
+            in_flight: DashMap::new(),
+            latest_blocks: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the cache key for a request, if its result may be cached or shared.
+    pub fn key(eth_action: &EthAction) -> Option<CacheKey> {
+        let EthAction::Request {
+            chain_id,
+            method,
+            params,
+        } = eth_action
+        else {
+            return None;
+        };
+        if !is_cacheable_method(method) {
+            return None;
+        }
+        Some((*chain_id, method.clone(), params.to_string()))
+    }
+
+    pub fn lookup(&self, key: &CacheKey) -> Lookup {
+        if let Some(cached) = self.entries.get(key) {
+            if cached.expires.map(|e| Instant::now() < e).unwrap_or(true) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Hit(cached.value.clone());
+            }
+        }
+        match self.in_flight.entry(key.clone()) {
+            Entry::Occupied(sender) => {
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                Lookup::Wait(sender.get().subscribe())
+            }
+            Entry::Vacant(slot) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                slot.insert(broadcast::channel(1).0);
+                Lookup::Miss
+            }
+        }
+    }
+
+    /// Record the response to a request that got [`Lookup::Miss`], caching
+    /// it if possible and passing it on to any coalesced requests.
+    pub fn complete(&self, key: CacheKey, response: &EthResponse) {
+        if let EthResponse::Response { value } = response {
+            let (chain_id, ref method, ref params) = key;
+            self.observe_block(chain_id, method, value);
+            let params = serde_json::from_str(params).unwrap_or_default();
+            if let Some(ttl) = self.ttl(chain_id, method, &params, value) {
+                self.make_room();
+                self.entries.insert(
+                    key.clone(),
+                    CachedValue {
+                        value: value.clone(),
+                        expires: ttl.map(|ttl| Instant::now() + ttl),
+                    },
+                );
+            }
+        }
+        if let Some((_key, sender)) = self.in_flight.remove(&key) {
+            let _ = sender.send(response.clone());
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            entries: self.entries.len() as u64,
+        }
+    }
+
+    fn observe_block(&self, chain_id: u64, method: &str, value: &serde_json::Value) {
+        let number = match method {
+            "eth_blockNumber" => value.as_str(),
+            "eth_getBlockByNumber" => value.get("number").and_then(|n| n.as_str()),
+            _ => None,
+        };
+        if let Some(number) = number.and_then(parse_hex) {
+            let mut latest = self.latest_blocks.entry(chain_id).or_insert(0);
+            *latest = (*latest).max(number);
+        }
+    }
+
+    fn is_final(&self, chain_id: u64, block: u64) -> bool {
+        self.latest_blocks
+            .get(&chain_id)
+            .map(|latest| block + FINALITY_DEPTH <= *latest)
+            .unwrap_or(false)
+    }
+
+    /// How long a result may be cached: None if it may not be cached at all,
+    /// Some(None) if it is immutable.
+    fn ttl(
+        &self,
+        chain_id: u64,
+        method: &str,
+        params: &serde_json::Value,
+        value: &serde_json::Value,
+    ) -> Option<Option<Duration>> {
+        if value.is_null() {
+            // not found, or not yet mined: likely to change soon
+            return None;
+        }
+        match method {
+            "eth_chainId" => Some(None),
+            "eth_blockNumber" | "eth_gasPrice" | "eth_estimateGas" => Some(Some(LATEST_TTL)),
+            "eth_getBlockByHash" => self.block_ttl(chain_id, value.get("number")),
+            "eth_getTransactionReceipt" | "eth_getTransactionByHash" => {
+                self.block_ttl(chain_id, value.get("blockNumber"))
+            }
+            "eth_getBlockByNumber" => self.block_ttl(chain_id, params.get(0)),
+            "eth_call" | "eth_getBalance" | "eth_getCode" | "eth_getTransactionCount" => {
+                self.block_ttl(chain_id, params.get(1))
+            }
+            "eth_getStorageAt" => self.block_ttl(chain_id, params.get(2)),
+            "eth_getLogs" => {
+                let filter = params.get(0)?;
+                if filter.get("blockHash").is_some() {
+                    Some(None)
+                } else {
+                    self.block_ttl(chain_id, filter.get("toBlock"))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// TTL for a result that depends on the given block tag, number, or hash.
+    fn block_ttl(
+        &self,
+        chain_id: u64,
+        block: Option<&serde_json::Value>,
+    ) -> Option<Option<Duration>> {
+        // EIP-1898 block parameters are objects
+        let block = match block {
+            Some(serde_json::Value::Object(object)) => {
+                if object.contains_key("blockHash") {
+                    return Some(None);
+                }
+                object.get("blockNumber")
+            }
+            block => block,
+        };
+        match block.and_then(|b| b.as_str()) {
+            None | Some("latest") | Some("safe") | Some("finalized") => Some(Some(LATEST_TTL)),
+            Some("pending") => None,
+            Some("earliest") => Some(None),
+            // a block hash
+            Some(hash) if hash.len() == 66 => Some(None),
+            Some(number) => match parse_hex(number) {
+                Some(number) if self.is_final(chain_id, number) => Some(None),
+                Some(_) => Some(Some(LATEST_TTL)),
+                None => None,
+            },
+        }
+    }
+
+    /// drop expired entries, then arbitrary ones if still full
+    fn make_room(&self) {
+        if self.entries.len() < MAX_ENTRIES {
+            return;
+        }
+        let now = Instant::now();
+        self.entries
+            .retain(|_, cached| cached.expires.map(|e| now < e).unwrap_or(true));
+        if self.entries.len() >= MAX_ENTRIES {
+            let evict: Vec<CacheKey> = self
+                .entries
+                .iter()
+                .take(MAX_ENTRIES / 10)
+                .map(|entry| entry.key().clone())
+                .collect();
+            for key in evict {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// methods whose results can be shared between callers
+fn is_cacheable_method(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_blockNumber"
+            | "eth_call"
+            | "eth_chainId"
+            | "eth_estimateGas"
+            | "eth_gasPrice"
+            | "eth_getBalance"
+            | "eth_getBlockByHash"
+            | "eth_getBlockByNumber"
+            | "eth_getCode"
+            | "eth_getLogs"
+            | "eth_getStorageAt"
+            | "eth_getTransactionByHash"
+            | "eth_getTransactionCount"
+            | "eth_getTransactionReceipt"
+    )
+}
+
+/// a `0x`-prefixed hex quantity, such as a block number
+pub fn parse_hex(number: &str) -> Option<u64> {
+    u64::from_str_radix(number.strip_prefix("0x")?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(method: &str, params: serde_json::Value) -> EthAction {
+        EthAction::Request {
+            chain_id: 1,
+            method: method.into(),
+            params,
+        }
+    }
+
+    fn response(value: serde_json::Value) -> EthResponse {
+        EthResponse::Response { value }
+    }
+
+    #[test]
+    fn only_reads_are_keyed() {
+        assert!(ResponseCache::key(&request("eth_blockNumber", json!([]))).is_some());
+        assert!(ResponseCache::key(&request("eth_sendRawTransaction", json!(["0x00"]))).is_none());
+        assert!(ResponseCache::key(&EthAction::UnsubscribeLogs(1)).is_none());
+    }
+
+    #[test]
+    fn identical_requests_share_one_call() {
+        let cache = ResponseCache::new();
+        let key = ResponseCache::key(&request("eth_chainId", json!([]))).unwrap();
+
+        assert!(matches!(cache.lookup(&key), Lookup::Miss));
+        let Lookup::Wait(mut waiting) = cache.lookup(&key) else {
+            panic!("expected to wait on the request in flight");
+        };
+        cache.complete(key.clone(), &response(json!("0x1")));
+        assert!(matches!(
+            waiting.try_recv(),
+            Ok(EthResponse::Response { value }) if value == json!("0x1")
+        ));
+        assert!(matches!(cache.lookup(&key), Lookup::Hit(value) if value == json!("0x1")));
+
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.hits, stats.misses, stats.coalesced, stats.entries),
+            (1, 1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn errors_and_nulls_are_not_cached() {
+        let cache = ResponseCache::new();
+        let key = ResponseCache::key(&request(
+            "eth_getTransactionReceipt",
+            json!(["0x".to_string() + &"00".repeat(32)]),
+        ))
+        .unwrap();
+        assert!(matches!(cache.lookup(&key), Lookup::Miss));
+        cache.complete(key.clone(), &response(serde_json::Value::Null));
+        assert!(matches!(cache.lookup(&key), Lookup::Miss));
+        cache.complete(key.clone(), &EthResponse::Err(EthError::RpcTimeout));
+        assert!(matches!(cache.lookup(&key), Lookup::Miss));
+    }
+
+    #[test]
+    fn final_blocks_never_expire() {
+        let cache = ResponseCache::new();
+        let value = json!({});
+        // nothing is final until we know how far the chain has got
+        assert_eq!(
+            cache.ttl(1, "eth_getBlockByNumber", &json!(["0x10", false]), &value),
+            Some(Some(LATEST_TTL))
+        );
+        cache.observe_block(1, "eth_blockNumber", &json!("0x100"));
+        assert_eq!(
+            cache.ttl(1, "eth_getBlockByNumber", &json!(["0x10", false]), &value),
+            Some(None)
+        );
+        assert_eq!(
+            cache.ttl(1, "eth_getBlockByNumber", &json!(["0xff", false]), &value),
+            Some(Some(LATEST_TTL))
+        );
+        // on another chain, the same block is recent
+        assert_eq!(
+            cache.ttl(2, "eth_getBlockByNumber", &json!(["0x10", false]), &value),
+            Some(Some(LATEST_TTL))
+        );
+        assert_eq!(
+            cache.ttl(1, "eth_getBalance", &json!(["0x00", "pending"]), &value),
+            None
+        );
+        assert_eq!(
+            cache.ttl(
+                1,
+                "eth_getBalance",
+                &json!(["0x00", {"blockHash": "0x00"}]),
+                &value
+            ),
+            Some(None)
+        );
+        assert_eq!(
+            cache.ttl(1, "eth_getLogs", &json!([{"toBlock": "latest"}]), &value),
+            Some(Some(LATEST_TTL))
+        );
+    }
+
+    #[test]
+    fn hex_quantities() {
+        assert_eq!(parse_hex("0x0"), Some(0));
+        assert_eq!(parse_hex("0xff"), Some(255));
+        assert_eq!(parse_hex("ff"), None);
+        assert_eq!(parse_hex("0xzz"), None);
+    }
+}
@@ -9,10 +9,23 @@ use lib::types::eth::*;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 use url::Url;
 
+mod cache;
+mod signer;
 mod subscription;
+mod usage;
+
+/// config files in the home directory, written whenever an [`EthConfigAction`]
+/// changes them and loaded at boot
+pub const PROVIDERS_FILE: &str = ".eth_providers";
+const ACCESS_SETTINGS_FILE: &str = ".eth_access_settings";
+const QUORUMS_FILE: &str = ".eth_quorums";
+const METHOD_POLICY_FILE: &str = ".eth_method_policy";
+/// most distinct RPC method names we will intern, see [`static_method`]
+const MAX_METHODS: usize = 1024;
 
 /// meta-type for all incoming requests we need to handle
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,22 +44,33 @@ enum IncomingReq {
 /// mapping of chain id to ordered lists of providers
 type Providers = Arc<DashMap<u64, ActiveProviders>>;
 
+/// a provider that fails is passed over for this long, doubling with each
+/// consecutive failure up to [`MAX_COOLDOWN`]
+const BASE_COOLDOWN: Duration = Duration::from_secs(2);
+const MAX_COOLDOWN: Duration = Duration::from_secs(300);
+
 #[derive(Debug)]
 struct ActiveProviders {
     pub urls: Vec<UrlProvider>,
     pub nodes: Vec<NodeProvider>,
+    /// number of untrusted providers that must agree on the result of a read
+    pub quorum: usize,
 }
 
 #[derive(Debug)]
 struct UrlProvider {
+    /// responses from trusted providers are accepted without a quorum,
+    /// and trusted providers are tried before untrusted ones
     pub trusted: bool,
     pub url: String,
     pub pubsub: Option<Provider<PubSubFrontend>>,
+    pub health: ProviderHealth,
 }
 
 #[derive(Debug)]
 struct NodeProvider {
-    /// NOT CURRENTLY USED
+    /// responses from trusted providers are accepted without a quorum,
+    /// and trusted providers are tried before untrusted ones
     pub trusted: bool,
     /// semi-temporary flag to mark if this provider is currently usable
     /// future updates will make this more dynamic
@@ -54,9 +78,86 @@ struct NodeProvider {
     /// the KNS update that describes this node provider
     /// kept so we can re-serialize to SavedConfigs
     pub kns_update: KnsUpdate,
+    pub health: ProviderHealth,
+}
+
+#[derive(Debug, Default)]
+struct ProviderHealth {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// if set, don't prefer this provider until this time
+    pub cooldown_until: Option<Instant>,
+    pub latency: Option<Duration>,
+}
+
+impl ProviderHealth {
+    fn record_success(&mut self, latency: Duration) {
+        self.successes += 1;
+        self.consecutive_failures = 0;
+        self.cooldown_until = None;
+        self.latency = Some(latency);
+    }
+
+    fn record_failure(&mut self) {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        let cooldown = BASE_COOLDOWN
+            .saturating_mul(1 << (self.consecutive_failures - 1).min(16))
+            .min(MAX_COOLDOWN);
+        self.cooldown_until = Some(Instant::now() + cooldown);
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.cooldown_until
+            .map(|until| Instant::now() >= until)
+            .unwrap_or(true)
+    }
+
+    fn to_status(&self, chain_id: u64, provider: String, trusted: bool) -> ProviderStatus {
+        ProviderStatus {
+            chain_id,
+            provider,
+            trusted,
+            healthy: self.is_healthy(),
+            successes: self.successes,
+            failures: self.failures,
+            consecutive_failures: self.consecutive_failures,
+            latency_ms: self.latency.map(|l| l.as_millis() as u64),
+        }
+    }
+}
+
+/// Order in which to try a list of providers: healthy before cooling-down,
+/// then trusted before untrusted, otherwise keeping list order (which puts
+/// the most recently successful provider first).
+fn failover_order(providers: impl Iterator<Item = (bool, bool)>) -> Vec<usize> {
+    let mut order: Vec<(usize, (bool, bool))> = providers.enumerate().collect();
+    order.sort_by_key(|(_, (healthy, trusted))| (!healthy, !trusted));
+    order.into_iter().map(|(index, _)| index).collect()
 }
 
 impl ActiveProviders {
+    fn new() -> Self {
+        Self {
+            urls: vec![],
+            nodes: vec![],
+            quorum: 1,
+        }
+    }
+
+    fn url_order(&self) -> Vec<usize> {
+        failover_order(self.urls.iter().map(|u| (u.health.is_healthy(), u.trusted)))
+    }
+
+    fn node_order(&self) -> Vec<usize> {
+        failover_order(
+            self.nodes
+                .iter()
+                .map(|n| (n.health.is_healthy(), n.trusted)),
+        )
+    }
+
     fn add_provider_config(&mut self, new: ProviderConfig) {
         match new.provider {
             NodeOrRpcUrl::Node {
@@ -70,6 +171,7 @@ impl ActiveProviders {
                         trusted: new.trusted,
                         usable: use_as_provider,
                         kns_update,
+                        health: ProviderHealth::default(),
                     },
                 );
             }
@@ -81,6 +183,7 @@ impl ActiveProviders {
                         trusted: new.trusted,
                         url,
                         pubsub: None,
+                        health: ProviderHealth::default(),
                     },
                 );
             }
@@ -147,12 +250,20 @@ struct ModuleState {
     home_directory_path: String,
     /// the access settings for this provider
     access_settings: AccessSettings,
+    /// which RPC methods may be called, and by whom
+    method_policy: MethodPolicy,
     /// the set of providers we have available for all chains
     providers: Providers,
     /// the set of active subscriptions we are currently maintaining
     active_subscriptions: ActiveSubscriptions,
     /// the set of response channels we have open for outstanding request tasks
     response_channels: ResponseChannels,
+    /// recent responses, and requests currently being fulfilled
+    response_cache: Arc<cache::ResponseCache>,
+    /// what remote nodes have used of our provider
+    usage: usage::UsageMeter,
+    /// keys we sign transactions with for our processes
+    signers: Arc<signer::Signers>,
     /// our sender for kernel event loop
     send_to_loop: MessageSender,
     /// our sender for terminal prints
@@ -165,6 +276,7 @@ struct ModuleState {
 pub async fn provider(
     our: String,
     home_directory_path: String,
+    file_key: Vec<u8>,
     configs: SavedConfigs,
     send_to_loop: MessageSender,
     mut recv_in_client: MessageReceiver,
@@ -176,18 +288,14 @@ pub async fn provider(
     // this merely describes whether our provider is available to other nodes
     // and if so, which nodes are allowed to access it (public/whitelist/blacklist)
     let access_settings: AccessSettings =
-        match tokio::fs::read_to_string(format!("{}/.eth_access_settings", home_directory_path))
-            .await
-        {
-            Ok(contents) => serde_json::from_str(&contents).unwrap(),
-            Err(_) => {
-                let access_settings = AccessSettings {
-                    public: false,
-                    allow: HashSet::new(),
-                    deny: HashSet::new(),
-                };
-                access_settings
-            }
+        match load_from_disk(&home_directory_path, ACCESS_SETTINGS_FILE, &print_tx).await {
+            Some(access_settings) => access_settings,
+            None => AccessSettings {
+                public: false,
+                allow: HashSet::new(),
+                deny: HashSet::new(),
+                quota: RemoteQuota::default(),
+            },
         };
     verbose_print(
         &print_tx,
@@ -198,13 +306,24 @@ pub async fn provider(
     // initialize module state
     // fill out providers based on saved configs (possibly persisted, given to us)
     // this can be a mix of node providers and rpc providers
+    let method_policy: MethodPolicy =
+        load_from_disk(&home_directory_path, METHOD_POLICY_FILE, &print_tx)
+            .await
+            .unwrap_or_default();
+
+    let signers = Arc::new(signer::Signers::load(&home_directory_path, &file_key, &print_tx).await);
+
     let mut state = ModuleState {
         our: Arc::new(our),
         home_directory_path,
         access_settings,
+        method_policy,
         providers: Arc::new(DashMap::new()),
         active_subscriptions: Arc::new(DashMap::new()),
         response_channels: Arc::new(DashMap::new()),
+        response_cache: Arc::new(cache::ResponseCache::new()),
+        usage: usage::UsageMeter::default(),
+        signers,
         send_to_loop,
         print_tx,
     };
@@ -214,12 +333,19 @@ pub async fn provider(
         let mut ap = state
             .providers
             .entry(entry.chain_id)
-            .or_insert(ActiveProviders {
-                urls: vec![],
-                nodes: vec![],
-            });
+            .or_insert(ActiveProviders::new());
         ap.add_provider_config(entry);
     }
+    // quorum settings are saved separately, as they are per-chain
+    let quorums: HashMap<u64, usize> =
+        load_from_disk(&state.home_directory_path, QUORUMS_FILE, &state.print_tx)
+            .await
+            .unwrap_or_default();
+    for (chain_id, quorum) in quorums {
+        if let Some(mut aps) = state.providers.get_mut(&chain_id) {
+            aps.quorum = quorum.max(1);
+        }
+    }
 
     verbose_print(&state.print_tx, "eth: provider initialized").await;
 
@@ -330,7 +456,7 @@ async fn handle_message(
             };
             match req {
                 IncomingReq::EthAction(eth_action) => {
-                    return handle_eth_action(state, km, timeout, eth_action).await;
+                    return handle_eth_action(state, km, timeout, eth_action, caps_oracle).await;
                 }
                 IncomingReq::EthConfigAction(eth_config_action) => {
                     kernel_message(
@@ -432,9 +558,14 @@ async fn handle_eth_action(
     km: KernelMessage,
     timeout: u64,
     eth_action: EthAction,
+    caps_oracle: &CapMessageSender,
 ) -> Result<(), EthError> {
     // check our access settings if the request is from a remote node
     if km.source.node != *state.our {
+        if let EthAction::SendTransaction { .. } = eth_action {
+            // our signers are never available to other nodes
+            return Err(EthError::PermissionDenied);
+        }
         if state.access_settings.deny.contains(&km.source.node)
             || (!state.access_settings.public
                 && !state.access_settings.allow.contains(&km.source.node))
@@ -446,6 +577,10 @@ async fn handle_eth_action(
             .await;
             return Err(EthError::PermissionDenied);
         }
+        // nodes we haven't explicitly allowed are held to our quota
+        if !state.access_settings.allow.contains(&km.source.node) {
+            check_quota(state, &km.source.node, &eth_action).await?;
+        }
     }
 
     verbose_print(
@@ -456,6 +591,7 @@ async fn handle_eth_action(
                 EthAction::SubscribeLogs { .. } => "subscribe",
                 EthAction::UnsubscribeLogs(_) => "unsubscribe",
                 EthAction::Request { .. } => "request",
+                EthAction::SendTransaction { .. } => "send_transaction",
             },
             km.source
         ),
@@ -512,16 +648,70 @@ async fn handle_eth_action(
                 .await;
             }
         }
-        EthAction::Request { .. } => {
+        EthAction::Request { ref method, .. } => {
+            let remote = (km.source.node != *state.our).then_some(km.source.node.as_str());
+            if !state.method_policy.allows(method, remote) {
+                return Err(EthError::InvalidMethod(method.clone()));
+            }
+            // answer from the cache, or piggyback on an identical request
+            // that's already underway, if we can
+            let cache_key = cache::ResponseCache::key(&eth_action);
+            if let Some(ref key) = cache_key {
+                match state.response_cache.lookup(key) {
+                    cache::Lookup::Hit(value) => {
+                        kernel_message(
+                            &state.our,
+                            km.id,
+                            km.rsvp.unwrap_or(km.source),
+                            None,
+                            false,
+                            None,
+                            EthResponse::Response { value },
+                            &state.send_to_loop,
+                        )
+                        .await;
+                        return Ok(());
+                    }
+                    cache::Lookup::Wait(mut coalesced) => {
+                        let our = state.our.to_string();
+                        let send_to_loop = state.send_to_loop.clone();
+                        tokio::spawn(async move {
+                            let response = match tokio::time::timeout(
+                                std::time::Duration::from_secs(timeout),
+                                coalesced.recv(),
+                            )
+                            .await
+                            {
+                                Ok(Ok(response)) => response,
+                                _ => EthResponse::Err(EthError::RpcTimeout),
+                            };
+                            kernel_message(
+                                &our,
+                                km.id,
+                                km.rsvp.unwrap_or(km.source),
+                                None,
+                                false,
+                                None,
+                                response,
+                                &send_to_loop,
+                            )
+                            .await;
+                        });
+                        return Ok(());
+                    }
+                    cache::Lookup::Miss => {}
+                }
+            }
             let (sender, receiver) = tokio::sync::mpsc::channel(1);
             state.response_channels.insert(km.id, sender);
             let our = state.our.to_string();
             let send_to_loop = state.send_to_loop.clone();
             let providers = state.providers.clone();
             let response_channels = state.response_channels.clone();
+            let response_cache = state.response_cache.clone();
             let print_tx = state.print_tx.clone();
             tokio::spawn(async move {
-                match tokio::time::timeout(
+                let response = match tokio::time::timeout(
                     std::time::Duration::from_secs(timeout),
                     fulfill_request(
                         &our,
@@ -535,32 +725,175 @@ async fn handle_eth_action(
                 )
                 .await
                 {
-                    Ok(response) => {
-                        kernel_message(
-                            &our,
-                            km.id,
-                            km.rsvp.unwrap_or(km.source),
-                            None,
-                            false,
-                            None,
-                            response,
-                            &send_to_loop,
-                        )
-                        .await;
-                    }
-                    Err(_) => {
-                        // task timeout
-                        error_message(&our, km.id, km.source, EthError::RpcTimeout, &send_to_loop)
-                            .await;
-                    }
+                    Ok(response) => response,
+                    // task timeout
+                    Err(_) => EthResponse::Err(EthError::RpcTimeout),
+                };
+                // always complete, even on error, to release coalesced requests
+                if let Some(key) = cache_key {
+                    response_cache.complete(key, &response);
                 }
+                kernel_message(
+                    &our,
+                    km.id,
+                    km.rsvp.unwrap_or(km.source),
+                    None,
+                    false,
+                    None,
+                    response,
+                    &send_to_loop,
+                )
+                .await;
                 response_channels.remove(&km.id);
             });
         }
+        EthAction::SendTransaction {
+            chain_id, ref from, ..
+        } => {
+            if !check_for_cap(
+                &state.our,
+                &km.source.process,
+                signer::send_transaction_cap(from)?,
+                caps_oracle,
+            )
+            .await
+            {
+                return Err(EthError::PermissionDenied);
+            }
+            let from = from.clone();
+            let signers = state.signers.clone();
+            let rpc = RpcContext::new(state);
+            tokio::spawn(async move {
+                // bound first, so that an abandoned send gives up its nonce lock
+                let sent = tokio::time::timeout(
+                    std::time::Duration::from_secs(timeout),
+                    signer::send_transaction(&signers, &rpc, eth_action),
+                )
+                .await;
+                let response = match sent {
+                    Ok(Ok(value)) => EthResponse::Response { value },
+                    Ok(Err(e)) => EthResponse::Err(e),
+                    Err(_) => {
+                        // the transaction may have been broadcast before we gave up on it
+                        signer::resync_nonce(&signers, &rpc, chain_id, &from).await;
+                        EthResponse::Err(EthError::RpcTimeout)
+                    }
+                };
+                kernel_message(
+                    &rpc.our,
+                    km.id,
+                    km.rsvp.unwrap_or(km.source),
+                    None,
+                    false,
+                    None,
+                    response,
+                    &rpc.send_to_loop,
+                )
+                .await;
+            });
+        }
     }
     Ok(())
 }
 
+/// Everything needed to make RPC requests through our providers from a
+/// spawned task.
+struct RpcContext {
+    our: Arc<String>,
+    send_to_loop: MessageSender,
+    providers: Providers,
+    response_channels: ResponseChannels,
+    print_tx: PrintSender,
+}
+
+impl RpcContext {
+    fn new(state: &ModuleState) -> Self {
+        Self {
+            our: state.our.clone(),
+            send_to_loop: state.send_to_loop.clone(),
+            providers: state.providers.clone(),
+            response_channels: state.response_channels.clone(),
+            print_tx: state.print_tx.clone(),
+        }
+    }
+
+    async fn request(
+        &self,
+        chain_id: u64,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, EthError> {
+        let km_id = rand::random();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        self.response_channels.insert(km_id, sender);
+        let response = fulfill_request(
+            &self.our,
+            km_id,
+            &self.send_to_loop,
+            EthAction::Request {
+                chain_id,
+                method: method.to_string(),
+                params,
+            },
+            self.providers.clone(),
+            receiver,
+            &self.print_tx,
+        )
+        .await;
+        self.response_channels.remove(&km_id);
+        match response {
+            EthResponse::Response { value } => Ok(value),
+            EthResponse::Err(e) => Err(e),
+            EthResponse::Ok => Err(EthError::RpcMalformedResponse),
+        }
+    }
+}
+
+/// meter an action from a remote node, refusing it if over quota
+async fn check_quota(
+    state: &mut ModuleState,
+    node: &str,
+    eth_action: &EthAction,
+) -> Result<(), EthError> {
+    let quota = &state.access_settings.quota;
+    if let EthAction::SubscribeLogs { .. } = eth_action {
+        let subscriptions = subscriptions_by_node(&state.active_subscriptions)
+            .remove(node)
+            .unwrap_or(0);
+        if quota.max_subscriptions != 0 && subscriptions >= quota.max_subscriptions {
+            state.usage.reject(node);
+            verbose_print(
+                &state.print_tx,
+                &format!("eth: {node} is at its subscription limit"),
+            )
+            .await;
+            return Err(EthError::PermissionDenied);
+        }
+    }
+    if let EthAction::UnsubscribeLogs(_) = eth_action {
+        // always let nodes free up resources
+        return Ok(());
+    }
+    if !state.usage.try_request(node, quota) {
+        verbose_print(
+            &state.print_tx,
+            &format!("eth: {node} is over its request quota"),
+        )
+        .await;
+        return Err(EthError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// number of subscriptions held by each node, including our own
+fn subscriptions_by_node(active_subscriptions: &ActiveSubscriptions) -> HashMap<String, u32> {
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    for entry in active_subscriptions.iter() {
+        *counts.entry(entry.key().node.clone()).or_default() += entry.value().len() as u32;
+    }
+    counts
+}
+
 async fn fulfill_request(
     our: &str,
     km_id: u64,
@@ -578,41 +911,59 @@ async fn fulfill_request(
     else {
         return EthResponse::Err(EthError::PermissionDenied); // will never hit
     };
-    let Some(method) = to_static_str(&method) else {
+    let Some(method) = static_method(method) else {
         return EthResponse::Err(EthError::InvalidMethod(method.to_string()));
     };
     let Some(mut aps) = providers.get_mut(&chain_id) else {
         return EthResponse::Err(EthError::NoRpcForChain);
     };
+    let quorum = if is_quorum_read(method, params) {
+        aps.quorum
+    } else {
+        1
+    };
+    // responses from untrusted providers, and how many gave each
+    let mut votes: Vec<(serde_json::Value, usize)> = vec![];
+    // whether any url provider told us it doesn't support this method
+    let mut unsupported = false;
 
     // first, try any url providers we have for this chain,
     // then if we have none or they all fail, go to node providers.
     // finally, if no provider works, return an error.
 
     // bump the successful provider to the front of the list for future requests
-    for (index, url_provider) in aps.urls.iter_mut().enumerate() {
-        let pubsub = match &url_provider.pubsub {
-            Some(pubsub) => pubsub,
-            None => {
-                if let Ok(()) = activate_url_provider(url_provider).await {
-                    verbose_print(
-                        print_tx,
-                        &format!("eth: activated url provider {}", url_provider.url),
-                    )
-                    .await;
-                    url_provider.pubsub.as_ref().unwrap()
-                } else {
-                    verbose_print(
-                        print_tx,
-                        &format!("eth: could not activate url provider {}", url_provider.url),
-                    )
-                    .await;
-                    continue;
-                }
+    for index in aps.url_order() {
+        let url_provider = &mut aps.urls[index];
+        if url_provider.pubsub.is_none() {
+            if let Ok(()) = activate_url_provider(url_provider).await {
+                verbose_print(
+                    print_tx,
+                    &format!("eth: activated url provider {}", url_provider.url),
+                )
+                .await;
+            } else {
+                verbose_print(
+                    print_tx,
+                    &format!("eth: could not activate url provider {}", url_provider.url),
+                )
+                .await;
+                url_provider.health.record_failure();
+                continue;
             }
-        };
+        }
+        let pubsub = url_provider.pubsub.as_ref().unwrap();
+        let start = Instant::now();
         match pubsub.inner().prepare(method, params.clone()).await {
             Ok(value) => {
+                url_provider.health.record_success(start.elapsed());
+                let value = if url_provider.trusted || quorum == 1 {
+                    value
+                } else {
+                    match add_vote(&mut votes, value, quorum) {
+                        Some(value) => value,
+                        None => continue,
+                    }
+                };
                 let successful_provider = aps.urls.remove(index);
                 aps.urls.insert(0, successful_provider);
                 return EthResponse::Response { value };
@@ -626,12 +977,22 @@ async fn fulfill_request(
                     ),
                 )
                 .await;
+                if rpc_error.to_string().contains(METHOD_NOT_FOUND) {
+                    // the provider is fine, it just doesn't offer this method
+                    unsupported = true;
+                    continue;
+                }
                 // this provider failed and needs to be reset
                 url_provider.pubsub = None;
+                url_provider.health.record_failure();
             }
         }
     }
-    for node_provider in &mut aps.nodes {
+    for index in aps.node_order() {
+        let node_provider = &mut aps.nodes[index];
+        if !node_provider.usable || node_provider.kns_update.name == our {
+            continue;
+        }
         verbose_print(
             print_tx,
             &format!(
@@ -640,6 +1001,7 @@ async fn fulfill_request(
             ),
         )
         .await;
+        let start = Instant::now();
         let response = forward_to_node_provider(
             our,
             km_id,
@@ -650,17 +1012,117 @@ async fn fulfill_request(
             &mut remote_request_receiver,
         )
         .await;
-        if let EthResponse::Err(e) = response {
-            if e == EthError::RpcMalformedResponse {
-                node_provider.usable = false;
+        match response {
+            EthResponse::Response { value } => {
+                node_provider.health.record_success(start.elapsed());
+                if node_provider.trusted || quorum == 1 {
+                    return EthResponse::Response { value };
+                }
+                if let Some(value) = add_vote(&mut votes, value, quorum) {
+                    return EthResponse::Response { value };
+                }
+            }
+            EthResponse::Err(e) => {
+                node_provider.health.record_failure();
+                if e == EthError::RpcMalformedResponse {
+                    node_provider.usable = false;
+                }
+            }
+            EthResponse::Ok => {
+                // a Request must be answered with a Response
+                node_provider.health.record_failure();
             }
-        } else {
-            return response;
         }
     }
+    if !votes.is_empty() {
+        verbose_print(
+            print_tx,
+            &format!("eth: providers failed to reach quorum of {quorum} for {method}"),
+        )
+        .await;
+        return EthResponse::Err(EthError::NoQuorum);
+    }
+    if unsupported {
+        return EthResponse::Err(EthError::InvalidMethod(method.to_string()));
+    }
     EthResponse::Err(EthError::NoRpcForChain)
 }
 
+/// JSON-RPC error code for a method the server doesn't support
+const METHOD_NOT_FOUND: &str = "-32601";
+
+/// Our RPC client needs method names with static lifetimes: leak each distinct
+/// name once. Which methods reach here is limited by [`MethodPolicy`], but
+/// prefix rules allow arbitrarily many, so the total is capped.
+fn static_method(method: &str) -> Option<&'static str> {
+    static METHODS: std::sync::OnceLock<std::sync::Mutex<HashSet<&'static str>>> =
+        std::sync::OnceLock::new();
+    let mut methods = METHODS.get_or_init(Default::default).lock().unwrap();
+    if let Some(interned) = methods.get(method) {
+        return Some(*interned);
+    }
+    if methods.len() >= MAX_METHODS {
+        return None;
+    }
+    let interned: &'static str = Box::leak(method.to_string().into_boxed_str());
+    methods.insert(interned);
+    Some(interned)
+}
+
+/// Record a provider's response to a quorum read, returning the value once
+/// `quorum` providers have given it.
+fn add_vote(
+    votes: &mut Vec<(serde_json::Value, usize)>,
+    value: serde_json::Value,
+    quorum: usize,
+) -> Option<serde_json::Value> {
+    let count = match votes.iter_mut().find(|(v, _)| *v == value) {
+        Some((_, count)) => {
+            *count += 1;
+            *count
+        }
+        None => {
+            votes.push((value.clone(), 1));
+            1
+        }
+    };
+    (count >= quorum).then_some(value)
+}
+
+/// Reads whose result should be identical across honest providers, and so
+/// can be checked against each other when a chain has a quorum set. A read
+/// of a block given by a tag like "latest" depends on how far along each
+/// provider is, so only reads of a block given by number or hash qualify.
+fn is_quorum_read(method: &str, params: &serde_json::Value) -> bool {
+    let pinned = |index: usize| params.get(index).is_some_and(is_pinned_block);
+    match method {
+        "eth_chainId"
+        | "eth_getBlockByHash"
+        | "eth_getTransactionByHash"
+        | "eth_getTransactionReceipt" => true,
+        "eth_getBlockByNumber" => pinned(0),
+        "eth_call" | "eth_getBalance" | "eth_getCode" | "eth_getTransactionCount" => pinned(1),
+        "eth_getStorageAt" => pinned(2),
+        "eth_getLogs" => params.get(0).is_some_and(|filter| {
+            filter.get("blockHash").is_some()
+                || (filter.get("fromBlock").is_some_and(is_pinned_block)
+                    && filter.get("toBlock").is_some_and(is_pinned_block))
+        }),
+        _ => false,
+    }
+}
+
+/// a block parameter given by number or hash (EIP-1898), rather than by tag
+fn is_pinned_block(block: &serde_json::Value) -> bool {
+    match block {
+        serde_json::Value::String(block) => block.starts_with("0x") || block == "earliest",
+        serde_json::Value::Object(block) => {
+            block.contains_key("blockHash") || block.get("blockNumber").is_some_and(is_pinned_block)
+        }
+        _ => false,
+    }
+}
+
 /// take an EthAction and send it to a node provider, then await a response.
 async fn forward_to_node_provider(
     our: &str,
@@ -674,11 +1136,33 @@ async fn forward_to_node_provider(
     if !node_provider.usable || node_provider.kns_update.name == our {
         return EthResponse::Err(EthError::PermissionDenied);
     }
+    forward_to_node(
+        our,
+        km_id,
+        rsvp,
+        &node_provider.kns_update.name,
+        eth_action,
+        send_to_loop,
+        receiver,
+    )
+    .await
+}
+
+/// Send an action to another node's eth module and wait for its response.
+async fn forward_to_node(
+    our: &str,
+    km_id: u64,
+    rsvp: Option<Address>,
+    node: &str,
+    eth_action: EthAction,
+    send_to_loop: &MessageSender,
+    receiver: &mut ProcessMessageReceiver,
+) -> EthResponse {
     kernel_message(
         our,
         km_id,
         Address {
-            node: node_provider.kns_update.name.clone(),
+            node: node.to_string(),
             process: ETH_PROCESS_ID.clone(),
         },
         rsvp,
@@ -729,12 +1213,19 @@ async fn handle_eth_config_action(
 
     verbose_print(
         &state.print_tx,
-        &format!("eth: handling eth_config_action {eth_config_action:?}"),
+        &match eth_config_action {
+            // never print private keys
+            EthConfigAction::ImportSigner(_) => {
+                "eth: handling eth_config_action ImportSigner".to_string()
+            }
+            _ => format!("eth: handling eth_config_action {eth_config_action:?}"),
+        },
     )
     .await;
 
     let mut save_settings = false;
     let mut save_providers = false;
+    let mut save_quorums = false;
 
     // modify our providers and access settings based on config action
     match eth_config_action {
@@ -742,10 +1233,7 @@ async fn handle_eth_config_action(
             let mut aps = state
                 .providers
                 .entry(provider.chain_id)
-                .or_insert(ActiveProviders {
-                    urls: vec![],
-                    nodes: vec![],
-                });
+                .or_insert(ActiveProviders::new());
             aps.add_provider_config(provider);
             save_providers = true;
         }
@@ -782,12 +1270,17 @@ async fn handle_eth_config_action(
         EthConfigAction::SetProviders(new_providers) => {
             let new_map = DashMap::new();
             for entry in new_providers {
-                let mut aps = new_map.entry(entry.chain_id).or_insert(ActiveProviders {
-                    urls: vec![],
-                    nodes: vec![],
-                });
+                let mut aps = new_map
+                    .entry(entry.chain_id)
+                    .or_insert(ActiveProviders::new());
                 aps.add_provider_config(entry);
             }
+            // keep quorum settings for chains that still have providers
+            for mut aps in new_map.iter_mut() {
+                if let Some(old) = state.providers.get(aps.key()) {
+                    aps.quorum = old.quorum;
+                }
+            }
             state.providers = Arc::new(new_map);
             save_providers = true;
         }
@@ -823,33 +1316,175 @@ async fn handle_eth_config_action(
                     })
                     .collect(),
                 outstanding_requests: state.response_channels.iter().map(|e| *e.key()).collect(),
+                providers: state
+                    .providers
+                    .iter()
+                    .flat_map(|aps| {
+                        let chain_id = *aps.key();
+                        aps.urls
+                            .iter()
+                            .map(|u| u.health.to_status(chain_id, u.url.clone(), u.trusted))
+                            .chain(aps.nodes.iter().map(|n| {
+                                n.health
+                                    .to_status(chain_id, n.kns_update.name.clone(), n.trusted)
+                            }))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect(),
+                quorums: state
+                    .providers
+                    .iter()
+                    .map(|aps| (*aps.key(), aps.quorum))
+                    .collect(),
+                cache: state.response_cache.stats(),
+            };
+        }
+        EthConfigAction::SetQuota(quota) => {
+            state.access_settings.quota = quota;
+            save_settings = true;
+        }
+        EthConfigAction::GetUsage => {
+            // our own processes aren't metered: don't start a meter for us
+            let mut subscriptions = subscriptions_by_node(&state.active_subscriptions);
+            subscriptions.remove(state.our.as_str());
+            return EthConfigResponse::Usage(state.usage.report(&subscriptions));
+        }
+        EthConfigAction::CreateSigner => {
+            return match state.signers.add(None, &state.print_tx).await {
+                Ok(address) => EthConfigResponse::Signer(address),
+                Err(e) => EthConfigResponse::Err(e),
+            };
+        }
+        EthConfigAction::ImportSigner(private_key) => {
+            return match state.signers.add(Some(private_key), &state.print_tx).await {
+                Ok(address) => EthConfigResponse::Signer(address),
+                Err(e) => EthConfigResponse::Err(e),
+            };
+        }
+        EthConfigAction::RemoveSigner(address) => {
+            state.signers.remove(&address, &state.print_tx).await;
+        }
+        EthConfigAction::GetSigners => {
+            return EthConfigResponse::Signers(state.signers.addresses());
+        }
+        EthConfigAction::SetMethodPolicy(method_policy) => {
+            state.method_policy = method_policy;
+            save_to_disk(
+                &state.home_directory_path,
+                METHOD_POLICY_FILE,
+                &state.method_policy,
+                &state.print_tx,
+            )
+            .await;
+        }
+        EthConfigAction::GetMethodPolicy => {
+            return EthConfigResponse::MethodPolicy(state.method_policy.clone());
+        }
+        EthConfigAction::SetQuorum { chain_id, quorum } => {
+            // quorums are only saved for chains with providers, so one set
+            // on any other chain would be lost at the next save
+            let Some(mut aps) = state.providers.get_mut(&chain_id) else {
+                return EthConfigResponse::Err(EthError::NoRpcForChain);
             };
+            aps.quorum = quorum.max(1);
+            save_quorums = true;
         }
     }
-    // save providers and/or access settings, depending on necessity, to disk
+    // save providers, access settings, and/or quorums, depending on necessity, to disk
     if save_settings {
-        if let Ok(()) = tokio::fs::write(
-            format!("{}/.eth_access_settings", state.home_directory_path),
-            serde_json::to_string(&state.access_settings).unwrap(),
+        save_to_disk(
+            &state.home_directory_path,
+            ACCESS_SETTINGS_FILE,
+            &state.access_settings,
+            &state.print_tx,
         )
-        .await
-        {
-            verbose_print(&state.print_tx, "eth: saved new access settings").await;
-        };
+        .await;
     }
     if save_providers {
-        if let Ok(()) = tokio::fs::write(
-            format!("{}/.eth_providers", state.home_directory_path),
-            serde_json::to_string(&providers_to_saved_configs(&state.providers)).unwrap(),
+        save_to_disk(
+            &state.home_directory_path,
+            PROVIDERS_FILE,
+            &providers_to_saved_configs(&state.providers),
+            &state.print_tx,
         )
-        .await
-        {
-            verbose_print(&state.print_tx, "eth: saved new provider settings").await;
-        };
+        .await;
+    }
+    if save_quorums {
+        let quorums: HashMap<u64, usize> = state
+            .providers
+            .iter()
+            .filter(|aps| aps.quorum > 1)
+            .map(|aps| (*aps.key(), aps.quorum))
+            .collect();
+        save_to_disk(
+            &state.home_directory_path,
+            QUORUMS_FILE,
+            &quorums,
+            &state.print_tx,
+        )
+        .await;
     }
     EthConfigResponse::Ok
 }
 
+/// Read a JSON config file from the home directory. Returns None if the file
+/// doesn't exist or can't be parsed, in which case defaults should be used.
+async fn load_from_disk<T: serde::de::DeserializeOwned>(
+    home_directory_path: &str,
+    file: &str,
+    print_tx: &PrintSender,
+) -> Option<T> {
+    let contents = tokio::fs::read_to_string(format!("{home_directory_path}/{file}"))
+        .await
+        .ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            let _ = print_tx
+                .send(Printout {
+                    verbosity: 0,
+                    content: format!("eth: ignoring malformed {file}: {e}"),
+                })
+                .await;
+            None
+        }
+    }
+}
+
+/// Write a JSON config file to the home directory. Writes to a temporary file
+/// first so that a crash mid-write can't leave a truncated config behind.
+pub async fn write_config<T: Serialize>(
+    home_directory_path: &str,
+    file: &str,
+    value: &T,
+) -> anyhow::Result<()> {
+    let path = format!("{home_directory_path}/{file}");
+    let tmp_path = format!("{path}.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(value)?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+/// [`write_config`], printing the outcome.
+async fn save_to_disk<T: Serialize>(
+    home_directory_path: &str,
+    file: &str,
+    value: &T,
+    print_tx: &PrintSender,
+) {
+    match write_config(home_directory_path, file, value).await {
+        Ok(()) => verbose_print(print_tx, &format!("eth: saved {file}")).await,
+        Err(e) => {
+            let _ = print_tx
+                .send(Printout {
+                    verbosity: 0,
+                    content: format!("eth: failed to save {file}: {e}"),
+                })
+                .await;
+        }
+    }
+}
+
 async fn activate_url_provider(provider: &mut UrlProvider) -> Result<()> {
     match Url::parse(&provider.url)?.scheme() {
         "ws" | "wss" => {
@@ -901,6 +1536,21 @@ async fn check_for_root_cap(
     our: &str,
     process: &ProcessId,
     caps_oracle: &CapMessageSender,
+) -> bool {
+    check_for_cap(
+        our,
+        process,
+        serde_json::json!({ "root": true }),
+        caps_oracle,
+    )
+    .await
+}
+
+async fn check_for_cap(
+    our: &str,
+    process: &ProcessId,
+    params: serde_json::Value,
+    caps_oracle: &CapMessageSender,
 ) -> bool {
     let (send_cap_bool, recv_cap_bool) = tokio::sync::oneshot::channel();
     caps_oracle
@@ -911,10 +1561,7 @@ async fn check_for_root_cap(
                     node: our.to_string(),
                     process: ETH_PROCESS_ID.clone(),
                 },
-                params: serde_json::to_string(&serde_json::json!({
-                    "root": true,
-                }))
-                .unwrap(),
+                params: serde_json::to_string(&params).unwrap(),
             },
             responder: send_cap_bool,
         })
@@ -978,6 +1625,8 @@ async fn kernel_message<T: Serialize>(
                     body: serde_json::to_vec(&body).unwrap(),
                     metadata: None,
                     capabilities: vec![],
+                    priority: MessagePriority::System,
+                    deadline: None,
                 })
             } else {
                 Message::Response((
@@ -0,0 +1,255 @@
+use crate::eth::*;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key,
+};
+use alloy_consensus::TxLegacy;
+use alloy_network::{Transaction, TxKind};
+use alloy_primitives::{Address as EthAddress, U256};
+use alloy_signer::{LocalWallet, Signer, SignerSync};
+use digest::generic_array::GenericArray;
+use std::str::FromStr;
+use tokio::sync::Mutex;
+
+const SIGNERS_FILE: &str = ".eth_signers";
+/// added to estimated gas, in percent, in case state changes before inclusion
+const GAS_ESTIMATE_MARGIN: u64 = 20;
+
+/// Signing keys held by the node on behalf of its processes. Keys are
+/// encrypted on disk with the node's file key, like the keyfile itself.
+pub struct Signers {
+    cipher: Aes256Gcm,
+    home_directory_path: String,
+    /// each wallet along with its encrypted private key
+    wallets: DashMap<EthAddress, (LocalWallet, Vec<u8>)>,
+    /// next nonce to use for each (chain_id, account), None until fetched.
+    /// the lock is held for the whole of a send, serializing sends per account.
+    nonces: DashMap<(u64, EthAddress), Arc<Mutex<Option<u64>>>>,
+}
+
+impl Signers {
+    pub async fn load(home_directory_path: &str, file_key: &[u8], print_tx: &PrintSender) -> Self {
+        let signers = Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(file_key)),
+            home_directory_path: home_directory_path.to_string(),
+            wallets: DashMap::new(),
+            nonces: DashMap::new(),
+        };
+        let saved: Vec<Vec<u8>> = load_from_disk(home_directory_path, SIGNERS_FILE, print_tx)
+            .await
+            .unwrap_or_default();
+        for encrypted in saved {
+            let Some(wallet) = signers.decrypt(&encrypted) else {
+                verbose_print(print_tx, "eth: failed to decrypt a saved signer").await;
+                continue;
+            };
+            signers
+                .wallets
+                .insert(wallet.address(), (wallet, encrypted));
+        }
+        signers
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.wallets.iter().map(|w| w.key().to_string()).collect()
+    }
+
+    /// Add a signer from a hex-encoded private key, or a new random one.
+    /// Returns its address.
+    pub async fn add(
+        &self,
+        private_key: Option<String>,
+        print_tx: &PrintSender,
+    ) -> Result<String, EthError> {
+        let private_key = private_key.unwrap_or_else(|| hex::encode(rand::random::<[u8; 32]>()));
+        let wallet = LocalWallet::from_str(&private_key).map_err(|_| EthError::InvalidParams)?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, private_key.as_bytes())
+            .map_err(|_| EthError::InvalidParams)?;
+        let address = wallet.address();
+        self.wallets
+            .insert(address, (wallet, [nonce.to_vec(), ciphertext].concat()));
+        self.save(print_tx).await;
+        Ok(address.to_string())
+    }
+
+    pub async fn remove(&self, address: &str, print_tx: &PrintSender) {
+        let Ok(address) = EthAddress::from_str(address) else {
+            return;
+        };
+        if self.wallets.remove(&address).is_some() {
+            self.nonces.retain(|(_, account), _| *account != address);
+            self.save(print_tx).await;
+        }
+    }
+
+    fn decrypt(&self, encrypted: &[u8]) -> Option<LocalWallet> {
+        if encrypted.len() < 12 {
+            return None;
+        }
+        let private_key = self
+            .cipher
+            .decrypt(GenericArray::from_slice(&encrypted[..12]), &encrypted[12..])
+            .ok()?;
+        LocalWallet::from_str(std::str::from_utf8(&private_key).ok()?).ok()
+    }
+
+    async fn save(&self, print_tx: &PrintSender) {
+        let encrypted: Vec<Vec<u8>> = self.wallets.iter().map(|w| w.value().1.clone()).collect();
+        save_to_disk(
+            &self.home_directory_path,
+            SIGNERS_FILE,
+            &encrypted,
+            print_tx,
+        )
+        .await;
+    }
+}
+
+/// Sign and send a transaction from one of our signers, filling in the
+/// nonce, gas price, and gas limit as needed. Returns the transaction hash.
+pub async fn send_transaction(
+    signers: &Signers,
+    rpc: &RpcContext,
+    eth_action: EthAction,
+) -> Result<serde_json::Value, EthError> {
+    let EthAction::SendTransaction {
+        chain_id,
+        from,
+        to,
+        value,
+        data,
+        gas_limit,
+        gas_price,
+    } = eth_action
+    else {
+        return Err(EthError::PermissionDenied); // will never hit
+    };
+    let from = EthAddress::from_str(&from).map_err(|_| EthError::InvalidParams)?;
+    let to = match to {
+        Some(to) => Some(EthAddress::from_str(&to).map_err(|_| EthError::InvalidParams)?),
+        None => None,
+    };
+    let wallet = signers
+        .wallets
+        .get(&from)
+        .map(|w| w.value().0.clone())
+        .ok_or(EthError::InvalidParams)?;
+
+    let nonce_lock = signers.nonces.entry((chain_id, from)).or_default().clone();
+    let mut next_nonce = nonce_lock.lock().await;
+    let nonce = match *next_nonce {
+        Some(nonce) => nonce,
+        None => fetch_nonce(rpc, chain_id, from).await?,
+    };
+    let gas_price = match gas_price {
+        Some(gas_price) => gas_price,
+        None => parse_quantity(
+            &rpc.request(chain_id, "eth_gasPrice", serde_json::json!([]))
+                .await?,
+        )?,
+    };
+    let gas_limit = match gas_limit {
+        Some(gas_limit) => gas_limit,
+        None => {
+            let estimate = u64::try_from(parse_quantity(
+                &rpc.request(
+                    chain_id,
+                    "eth_estimateGas",
+                    serde_json::json!([{
+                        "from": from,
+                        "to": to,
+                        "value": format!("0x{value:x}"),
+                        "input": format!("0x{}", hex::encode(&data)),
+                    }]),
+                )
+                .await?,
+            )?)
+            .map_err(|_| EthError::RpcMalformedResponse)?;
+            estimate
+                .checked_mul(GAS_ESTIMATE_MARGIN)
+                .and_then(|margin| estimate.checked_add(margin / 100))
+                .ok_or(EthError::RpcMalformedResponse)?
+        }
+    };
+
+    let mut tx = TxLegacy {
+        to: match to {
+            Some(to) => TxKind::Call(to),
+            None => TxKind::Create,
+        },
+        nonce,
+        value: U256::from(value),
+        input: data.into(),
+        chain_id: Some(chain_id),
+        gas_limit,
+        gas_price,
+    };
+    let sig = wallet
+        .sign_transaction_sync(&mut tx)
+        .map_err(|_| EthError::InvalidParams)?;
+    let signed_tx = tx.into_signed(sig);
+    let mut buf = vec![];
+    signed_tx.encode_signed(&mut buf);
+
+    match rpc
+        .request(
+            chain_id,
+            "eth_sendRawTransaction",
+            serde_json::json!([format!("0x{}", hex::encode(buf))]),
+        )
+        .await
+    {
+        Ok(tx_hash) => {
+            *next_nonce = nonce.checked_add(1);
+            Ok(tx_hash)
+        }
+        Err(e) => {
+            // the transaction may or may not have gone out, so our nonce may
+            // be stale either way: ask the chain what it should be
+            *next_nonce = fetch_nonce(rpc, chain_id, from).await.ok();
+            Err(e)
+        }
+    }
+}
+
+/// The capability, issued by eth:distro:sys, that lets a process send
+/// transactions from the signer at `from`.
+pub fn send_transaction_cap(from: &str) -> Result<serde_json::Value, EthError> {
+    let from = EthAddress::from_str(from).map_err(|_| EthError::InvalidParams)?;
+    Ok(serde_json::json!({ "send_transaction": from.to_string() }))
+}
+
+/// Fetch the next nonce for a signer from the chain, for when a send was
+/// abandoned and may or may not have been broadcast.
+pub async fn resync_nonce(signers: &Signers, rpc: &RpcContext, chain_id: u64, from: &str) {
+    let Ok(from) = EthAddress::from_str(from) else {
+        return;
+    };
+    let nonce_lock = signers.nonces.entry((chain_id, from)).or_default().clone();
+    let mut next_nonce = nonce_lock.lock().await;
+    *next_nonce = fetch_nonce(rpc, chain_id, from).await.ok();
+}
+
+/// the account's nonce including transactions still in the mempool
+async fn fetch_nonce(rpc: &RpcContext, chain_id: u64, from: EthAddress) -> Result<u64, EthError> {
+    u64::try_from(parse_quantity(
+        &rpc.request(
+            chain_id,
+            "eth_getTransactionCount",
+            serde_json::json!([from, "pending"]),
+        )
+        .await?,
+    )?)
+    .map_err(|_| EthError::RpcMalformedResponse)
+}
+
+fn parse_quantity(value: &serde_json::Value) -> Result<u128, EthError> {
+    value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .and_then(|s| u128::from_str_radix(s, 16).ok())
+        .ok_or(EthError::RpcMalformedResponse)
+}
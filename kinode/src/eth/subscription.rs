@@ -1,6 +1,16 @@
 use crate::eth::*;
 use alloy_pubsub::RawSubscription;
-use alloy_rpc_types::pubsub::SubscriptionResult;
+use alloy_rpc_types::pubsub::{SubscriptionKind, SubscriptionResult};
+use std::collections::BTreeMap;
+
+/// how many blocks back we remember delivered logs, to detect reorgs that
+/// happened while a subscription was disconnected
+const REORG_WINDOW: u64 = 64;
+/// the most blocks we ask for logs from in one request while backfilling,
+/// since providers limit the range a single `eth_getLogs` may cover
+const BACKFILL_CHUNK_BLOCKS: u64 = 1_000;
+/// times we try to re-establish a dropped subscription before giving up
+const RESUBSCRIBE_ATTEMPTS: u32 = 5;
 
 /// cleans itself up when the subscription is closed or fails.
 pub async fn create_new_subscription(
@@ -70,6 +80,7 @@ pub async fn create_new_subscription(
                 let send_to_loop = send_to_loop.clone();
                 let print_tx = print_tx.clone();
                 let active_subscriptions = active_subscriptions.clone();
+                let providers = providers.clone();
                 match maybe_raw_sub {
                     Ok(rx) => {
                         subs.insert(
@@ -83,8 +94,11 @@ pub async fn create_new_subscription(
                                     rx,
                                     &target,
                                     &rsvp,
+                                    &eth_action,
+                                    &providers,
                                     &send_to_loop,
                                     &active_subscriptions,
+                                    &print_tx,
                                 )
                                 .await;
                                 verbose_print(
@@ -128,9 +142,12 @@ pub async fn create_new_subscription(
                                         rx,
                                         keepalive_err_receiver,
                                         &target,
+                                        &eth_action,
+                                        &providers,
                                         &send_to_loop,
                                         &active_subscriptions,
                                         &response_channels,
+                                        &print_tx,
                                     )
                                     .await;
                                     verbose_print(
@@ -187,54 +204,10 @@ async fn build_subscription(
     // then if we have none or they all fail, go to node providers.
     // finally, if no provider works, return an error.
 
-    // bump the successful provider to the front of the list for future requests
-    for (index, url_provider) in aps.urls.iter_mut().enumerate() {
-        let pubsub = match &url_provider.pubsub {
-            Some(pubsub) => pubsub,
-            None => {
-                if let Ok(()) = activate_url_provider(url_provider).await {
-                    verbose_print(
-                        &print_tx,
-                        &format!("eth: activated url provider {}", url_provider.url),
-                    )
-                    .await;
-                    url_provider.pubsub.as_ref().unwrap()
-                } else {
-                    verbose_print(
-                        &print_tx,
-                        &format!("eth: could not activate url provider {}", url_provider.url),
-                    )
-                    .await;
-                    continue;
-                }
-            }
-        };
-        let kind = serde_json::to_value(&kind).unwrap();
-        let params = serde_json::to_value(&params).unwrap();
-        match pubsub
-            .inner()
-            .prepare("eth_subscribe", [kind, params])
-            .await
-        {
-            Ok(id) => {
-                let rx = pubsub.inner().get_raw_subscription(id).await;
-                let successful_provider = aps.urls.remove(index);
-                aps.urls.insert(0, successful_provider);
-                return Ok(Ok(rx));
-            }
-            Err(rpc_error) => {
-                verbose_print(
-                    &print_tx,
-                    &format!(
-                        "eth: got error from url provider {}: {}",
-                        url_provider.url, rpc_error
-                    ),
-                )
-                .await;
-                // this provider failed and needs to be reset
-                url_provider.pubsub = None;
-            }
-        }
+    let kind_json = serde_json::to_value(&kind).unwrap();
+    let params_json = serde_json::to_value(&params).unwrap();
+    if let Some(rx) = subscribe_via_url(&mut aps, &kind_json, &params_json, print_tx).await {
+        return Ok(Ok(rx));
     }
 
     let (sender, mut response_receiver) = tokio::sync::mpsc::channel(1);
@@ -242,7 +215,11 @@ async fn build_subscription(
     // we need to create our own unique sub id because in the remote provider node,
     // all subs will be identified under our process address.
     let remote_sub_id = rand::random();
-    for node_provider in &mut aps.nodes {
+    for index in aps.node_order() {
+        let node_provider = &mut aps.nodes[index];
+        if !node_provider.usable || node_provider.kns_update.name == our {
+            continue;
+        }
         verbose_print(
             &print_tx,
             &format!(
@@ -251,6 +228,7 @@ async fn build_subscription(
             ),
         )
         .await;
+        let start = Instant::now();
         match forward_to_node_provider(
             &our,
             km_id,
@@ -268,6 +246,7 @@ async fn build_subscription(
         .await
         {
             EthResponse::Ok => {
+                node_provider.health.record_success(start.elapsed());
                 kernel_message(
                     &our,
                     km_id,
@@ -284,9 +263,11 @@ async fn build_subscription(
             }
             EthResponse::Response { .. } => {
                 // the response to a SubscribeLogs request must be an 'ok'
+                node_provider.health.record_failure();
                 node_provider.usable = false;
             }
             EthResponse::Err(e) => {
+                node_provider.health.record_failure();
                 if e == EthError::RpcMalformedResponse {
                     node_provider.usable = false;
                 }
@@ -297,25 +278,53 @@ async fn build_subscription(
     return Err(EthError::NoRpcForChain);
 }
 
+/// Forward updates from a subscription to one of our url providers. If the
+/// provider drops the subscription, resubscribe, and for log subscriptions,
+/// deliver any logs missed in the meantime, retracting (with `removed: true`)
+/// any delivered logs whose blocks were reorged out.
 async fn maintain_local_subscription(
     our: &str,
     sub_id: u64,
     mut rx: RawSubscription,
     target: &Address,
     rsvp: &Option<Address>,
+    eth_action: &EthAction,
+    providers: &Providers,
     send_to_loop: &MessageSender,
     active_subscriptions: &ActiveSubscriptions,
+    print_tx: &PrintSender,
 ) -> EthSubError {
-    while let Ok(value) = rx.recv().await {
-        let result: SubscriptionResult = match serde_json::from_str(value.get()) {
-            Ok(res) => res,
-            Err(e) => {
-                return EthSubError {
-                    id: sub_id,
-                    error: e.to_string(),
-                }
-            }
-        };
+    let EthAction::SubscribeLogs {
+        chain_id,
+        kind,
+        params,
+        ..
+    } = eth_action
+    else {
+        return EthSubError {
+            id: sub_id,
+            error: "not a subscription".to_string(),
+        }; // will never hit
+    };
+    let is_logs = matches!(kind, SubscriptionKind::Logs);
+    let mut delivered = DeliveredLogs::default();
+    if is_logs {
+        // if the subscription drops before any logs arrive, backfill from here
+        delivered.resume_from = request_via_url(
+            providers,
+            *chain_id,
+            "eth_blockNumber",
+            serde_json::json!([]),
+            print_tx,
+        )
+        .await
+        .and_then(|n| n.as_str().and_then(cache::parse_hex));
+    }
+    let deliver = |log: serde_json::Value| async move {
+        let result: SubscriptionResult = serde_json::from_value(log).map_err(|e| EthSubError {
+            id: sub_id,
+            error: e.to_string(),
+        })?;
         kernel_message(
             our,
             rand::random(),
@@ -324,27 +333,461 @@ async fn maintain_local_subscription(
             true,
             None,
             EthSubResult::Ok(EthSub { id: sub_id, result }),
-            &send_to_loop,
+            send_to_loop,
         )
         .await;
-    }
+        Ok::<(), EthSubError>(())
+    };
+
+    let e = 'outer: loop {
+        while let Ok(value) = rx.recv().await {
+            let value: serde_json::Value = match serde_json::from_str(value.get()) {
+                Ok(value) => value,
+                Err(e) => {
+                    break 'outer EthSubError {
+                        id: sub_id,
+                        error: e.to_string(),
+                    }
+                }
+            };
+            if is_logs && !delivered.record(&value) {
+                continue;
+            }
+            if let Err(e) = deliver(value).await {
+                break 'outer e;
+            }
+        }
+        verbose_print(
+            print_tx,
+            &format!("eth: local subscription {sub_id} dropped, resubscribing"),
+        )
+        .await;
+        let kind_json = serde_json::to_value(kind).unwrap();
+        let params_json = serde_json::to_value(params).unwrap();
+        let mut attempt = 0;
+        rx = loop {
+            if attempt == RESUBSCRIBE_ATTEMPTS {
+                break 'outer EthSubError {
+                    id: sub_id,
+                    error: "subscription closed unexpectedly".to_string(),
+                };
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+            attempt += 1;
+            let Some(mut aps) = providers.get_mut(chain_id) else {
+                continue;
+            };
+            if let Some(rx) = subscribe_via_url(&mut aps, &kind_json, &params_json, print_tx).await
+            {
+                break rx;
+            }
+        };
+        if is_logs {
+            // the new subscription is live: fill in what we missed. logs that
+            // arrive on both are deduplicated by `delivered`
+            match backfill(
+                &RequestVia::Urls,
+                *chain_id,
+                &params_json,
+                &mut delivered,
+                providers,
+                print_tx,
+            )
+            .await
+            {
+                Some(logs) => {
+                    for log in logs {
+                        if let Err(e) = deliver(log).await {
+                            break 'outer e;
+                        }
+                    }
+                }
+                None => {
+                    break 'outer EthSubError {
+                        id: sub_id,
+                        error: "failed to backfill logs after resubscribing".to_string(),
+                    };
+                }
+            }
+        }
+    };
     active_subscriptions
         .entry(target.clone())
         .and_modify(|sub_map| {
             sub_map.remove(&sub_id);
         });
-    EthSubError {
-        id: sub_id,
-        error: "subscription closed unexpectedly".to_string(),
+    e
+}
+
+/// Logs delivered on a subscription in recent blocks, by block number.
+#[derive(Default)]
+struct DeliveredLogs {
+    blocks: BTreeMap<u64, DeliveredBlock>,
+    /// block to backfill from if no logs have been delivered yet
+    resume_from: Option<u64>,
+}
+
+struct DeliveredBlock {
+    hash: String,
+    logs: Vec<serde_json::Value>,
+}
+
+impl DeliveredLogs {
+    /// Record a log we are about to deliver. Returns false if it was already
+    /// delivered and should be skipped.
+    fn record(&mut self, log: &serde_json::Value) -> bool {
+        let (Some(number), Some(hash)) = (
+            log.get("blockNumber")
+                .and_then(|n| n.as_str())
+                .and_then(cache::parse_hex),
+            log.get("blockHash").and_then(|h| h.as_str()),
+        ) else {
+            // pending logs have no block: nothing to track
+            return true;
+        };
+        let removed = log.get("removed").and_then(|r| r.as_bool()) == Some(true);
+        let log_index = log.get("logIndex");
+        if removed {
+            if let Some(block) = self.blocks.get_mut(&number) {
+                if block.hash == hash {
+                    block.logs.retain(|l| l.get("logIndex") != log_index);
+                }
+            }
+            return true;
+        }
+        let block = self.blocks.entry(number).or_insert_with(|| DeliveredBlock {
+            hash: hash.to_string(),
+            logs: vec![],
+        });
+        if block.hash != hash {
+            // the block was replaced: the provider will have retracted the
+            // old logs, so start over with the new block
+            block.hash = hash.to_string();
+            block.logs.clear();
+        } else if block.logs.iter().any(|l| l.get("logIndex") == log_index) {
+            return false;
+        }
+        block.logs.push(log.clone());
+        let newest = *self.blocks.keys().next_back().unwrap();
+        self.blocks = self.blocks.split_off(&newest.saturating_sub(REORG_WINDOW));
+        true
+    }
+}
+
+/// After resubscribing, work out what changed while we were disconnected:
+/// returns retractions for delivered logs in blocks that were reorged out,
+/// followed by every log since the last block we know to be delivered.
+/// The returned logs are already recorded in `delivered`.
+async fn backfill(
+    via: &RequestVia<'_>,
+    chain_id: u64,
+    filter: &serde_json::Value,
+    delivered: &mut DeliveredLogs,
+    providers: &Providers,
+    print_tx: &PrintSender,
+) -> Option<Vec<serde_json::Value>> {
+    let mut logs = vec![];
+    // walk back from our newest delivered block until its hash still matches
+    while let Some((&number, block)) = delivered.blocks.iter().next_back() {
+        let canonical = request_via(
+            via,
+            providers,
+            chain_id,
+            "eth_getBlockByNumber",
+            serde_json::json!([format!("0x{number:x}"), false]),
+            print_tx,
+        )
+        .await?;
+        if canonical.get("hash").and_then(|h| h.as_str()) == Some(block.hash.as_str()) {
+            break;
+        }
+        let (_, block) = delivered.blocks.pop_last().unwrap();
+        for mut log in block.logs.into_iter().rev() {
+            log["removed"] = serde_json::Value::Bool(true);
+            logs.push(log);
+        }
+        delivered.resume_from = Some(number);
+    }
+    let from_block = match delivered.blocks.keys().next_back() {
+        // re-fetching this block is harmless: its logs are deduplicated
+        Some(&number) => number,
+        None => delivered.resume_from?,
+    };
+    // logs after the latest block arrive on the new subscription
+    let latest = request_via(
+        via,
+        providers,
+        chain_id,
+        "eth_blockNumber",
+        serde_json::json!([]),
+        print_tx,
+    )
+    .await?;
+    let latest = latest.as_str().and_then(cache::parse_hex)?;
+    let mut filter = filter.clone();
+    let mut chunk_start = from_block;
+    while chunk_start <= latest {
+        let chunk_end = latest.min(chunk_start + BACKFILL_CHUNK_BLOCKS - 1);
+        filter["fromBlock"] = serde_json::Value::String(format!("0x{chunk_start:x}"));
+        filter["toBlock"] = serde_json::Value::String(format!("0x{chunk_end:x}"));
+        let missed = request_via(
+            via,
+            providers,
+            chain_id,
+            "eth_getLogs",
+            serde_json::json!([filter]),
+            print_tx,
+        )
+        .await?;
+        for log in missed.as_array()?.iter() {
+            if delivered.record(log) {
+                logs.push(log.clone());
+            }
+        }
+        chunk_start = chunk_end + 1;
+    }
+    Some(logs)
+}
+
+/// Try each of our url providers for a chain in turn until one subscribes.
+async fn subscribe_via_url(
+    aps: &mut ActiveProviders,
+    kind: &serde_json::Value,
+    params: &serde_json::Value,
+    print_tx: &PrintSender,
+) -> Option<RawSubscription> {
+    // bump the successful provider to the front of the list for future requests
+    for index in aps.url_order() {
+        let url_provider = &mut aps.urls[index];
+        if url_provider.pubsub.is_none() {
+            if let Ok(()) = activate_url_provider(url_provider).await {
+                verbose_print(
+                    print_tx,
+                    &format!("eth: activated url provider {}", url_provider.url),
+                )
+                .await;
+            } else {
+                verbose_print(
+                    print_tx,
+                    &format!("eth: could not activate url provider {}", url_provider.url),
+                )
+                .await;
+                url_provider.health.record_failure();
+                continue;
+            }
+        }
+        let pubsub = url_provider.pubsub.as_ref().unwrap();
+        let start = Instant::now();
+        match pubsub
+            .inner()
+            .prepare("eth_subscribe", [kind.clone(), params.clone()])
+            .await
+        {
+            Ok(id) => {
+                let rx = pubsub.inner().get_raw_subscription(id).await;
+                url_provider.health.record_success(start.elapsed());
+                let successful_provider = aps.urls.remove(index);
+                aps.urls.insert(0, successful_provider);
+                return Some(rx);
+            }
+            Err(rpc_error) => {
+                verbose_print(
+                    print_tx,
+                    &format!(
+                        "eth: got error from url provider {}: {}",
+                        url_provider.url, rpc_error
+                    ),
+                )
+                .await;
+                // this provider failed and needs to be reset
+                url_provider.pubsub = None;
+                url_provider.health.record_failure();
+            }
+        }
     }
+    None
+}
+
+/// Make a request through the first of our url providers for a chain that
+/// will answer it.
+async fn request_via_url(
+    providers: &Providers,
+    chain_id: u64,
+    method: &'static str,
+    params: serde_json::Value,
+    print_tx: &PrintSender,
+) -> Option<serde_json::Value> {
+    let mut aps = providers.get_mut(&chain_id)?;
+    for index in aps.url_order() {
+        let url_provider = &mut aps.urls[index];
+        if url_provider.pubsub.is_none() && activate_url_provider(url_provider).await.is_err() {
+            url_provider.health.record_failure();
+            continue;
+        }
+        let pubsub = url_provider.pubsub.as_ref().unwrap();
+        let start = Instant::now();
+        let result: Result<serde_json::Value, _> =
+            pubsub.inner().prepare(method, params.clone()).await;
+        match result {
+            Ok(value) => {
+                url_provider.health.record_success(start.elapsed());
+                return Some(value);
+            }
+            Err(rpc_error) => {
+                verbose_print(
+                    print_tx,
+                    &format!(
+                        "eth: got error from url provider {}: {}",
+                        url_provider.url, rpc_error
+                    ),
+                )
+                .await;
+                url_provider.pubsub = None;
+                url_provider.health.record_failure();
+            }
+        }
+    }
+    None
+}
+
+/// Where the requests that catch a subscription up after it drops go.
+enum RequestVia<'a> {
+    /// our url providers for the chain
+    Urls,
+    /// the node provider a remote subscription is with
+    Node {
+        our: &'a str,
+        node: &'a str,
+        send_to_loop: &'a MessageSender,
+        response_channels: &'a ResponseChannels,
+    },
+}
+
+async fn request_via(
+    via: &RequestVia<'_>,
+    providers: &Providers,
+    chain_id: u64,
+    method: &'static str,
+    params: serde_json::Value,
+    print_tx: &PrintSender,
+) -> Option<serde_json::Value> {
+    match via {
+        RequestVia::Urls => request_via_url(providers, chain_id, method, params, print_tx).await,
+        RequestVia::Node {
+            our,
+            node,
+            send_to_loop,
+            response_channels,
+        } => {
+            // let go of the providers before waiting on the node to respond
+            let usable = providers.get(&chain_id)?.nodes.iter().any(|node_provider| {
+                node_provider.kns_update.name == *node && node_provider.usable
+            });
+            if !usable || node == our {
+                return None;
+            }
+            let km_id = rand::random();
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+            response_channels.insert(km_id, sender);
+            let response = forward_to_node(
+                our,
+                km_id,
+                None,
+                node,
+                EthAction::Request {
+                    chain_id,
+                    method: method.to_string(),
+                    params,
+                },
+                send_to_loop,
+                &mut receiver,
+            )
+            .await;
+            response_channels.remove(&km_id);
+            match response {
+                EthResponse::Response { value } => Some(value),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Ask our node providers for a chain, in turn, to take on a subscription
+/// we had with another. Returns the node that took it on.
+async fn resubscribe_via_node(
+    our: &str,
+    target: &Address,
+    remote_sub_id: u64,
+    eth_action: &EthAction,
+    providers: &Providers,
+    send_to_loop: &MessageSender,
+    response_channels: &ResponseChannels,
+) -> Option<String> {
+    let EthAction::SubscribeLogs {
+        chain_id,
+        kind,
+        params,
+        ..
+    } = eth_action
+    else {
+        return None;
+    };
+    let mut aps = providers.get_mut(chain_id)?;
+    let km_id = rand::random();
+    let (sender, mut response_receiver) = tokio::sync::mpsc::channel(1);
+    response_channels.insert(km_id, sender);
+    let mut accepted = None;
+    for index in aps.node_order() {
+        let node_provider = &mut aps.nodes[index];
+        if !node_provider.usable || node_provider.kns_update.name == our {
+            continue;
+        }
+        let start = Instant::now();
+        match forward_to_node_provider(
+            our,
+            km_id,
+            Some(target.clone()),
+            node_provider,
+            EthAction::SubscribeLogs {
+                sub_id: remote_sub_id,
+                chain_id: *chain_id,
+                kind: kind.clone(),
+                params: params.clone(),
+            },
+            send_to_loop,
+            &mut response_receiver,
+        )
+        .await
+        {
+            EthResponse::Ok => {
+                node_provider.health.record_success(start.elapsed());
+                accepted = Some(node_provider.kns_update.name.clone());
+                break;
+            }
+            EthResponse::Response { .. } => {
+                node_provider.health.record_failure();
+                node_provider.usable = false;
+            }
+            EthResponse::Err(e) => {
+                node_provider.health.record_failure();
+                if e == EthError::RpcMalformedResponse {
+                    node_provider.usable = false;
+                }
+            }
+        }
+    }
+    response_channels.remove(&km_id);
+    accepted
 }
 
 /// handle the subscription updates from a remote provider,
 /// and also perform keepalive checks on that provider.
 /// current keepalive is 30s, this can be adjusted as desired
 ///
-/// if the subscription goes more than 2 hours without an update,
-/// the provider will be considered dead and the subscription will be closed.
+/// if the provider fails keepalive, sends an error, or goes more than 2 hours
+/// without an update, the subscription is moved to another node provider
+/// (or the same one, if it recovers), and as with a local subscription, any
+/// logs missed in the meantime are delivered and reorged ones retracted.
 async fn maintain_remote_subscription(
     our: &str,
     provider_node: &str,
@@ -354,74 +797,214 @@ async fn maintain_remote_subscription(
     mut rx: tokio::sync::mpsc::Receiver<EthSubResult>,
     mut net_error_rx: ProcessMessageReceiver,
     target: &Address,
+    eth_action: &EthAction,
+    providers: &Providers,
     send_to_loop: &MessageSender,
     active_subscriptions: &ActiveSubscriptions,
     response_channels: &ResponseChannels,
+    print_tx: &PrintSender,
 ) -> EthSubError {
+    let EthAction::SubscribeLogs {
+        chain_id,
+        kind,
+        params,
+        ..
+    } = eth_action
+    else {
+        return EthSubError {
+            id: sub_id,
+            error: "not a subscription".to_string(),
+        }; // will never hit
+    };
+    let mut provider_node = provider_node.to_string();
     let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
     let mut last_received = tokio::time::Instant::now();
     let two_hours = tokio::time::Duration::from_secs(2 * 3600);
 
-    let e = loop {
-        tokio::select! {
-            incoming = rx.recv() => {
-                match incoming {
-                    Some(EthSubResult::Ok(upd)) => {
-                        // Update the last received time on any successful sub result
-                        last_received = tokio::time::Instant::now();
-                        kernel_message(
-                            &our,
-                            rand::random(),
-                            target.clone(),
-                            None,
-                            true,
-                            None,
-                            EthSubResult::Ok(EthSub {
+    let is_logs = matches!(kind, SubscriptionKind::Logs);
+    let params_json = serde_json::to_value(params).unwrap();
+    let mut delivered = DeliveredLogs::default();
+    if is_logs {
+        // if the subscription drops before any logs arrive, backfill from here
+        delivered.resume_from = request_via(
+            &RequestVia::Node {
+                our,
+                node: &provider_node,
+                send_to_loop,
+                response_channels,
+            },
+            providers,
+            *chain_id,
+            "eth_blockNumber",
+            serde_json::json!([]),
+            print_tx,
+        )
+        .await
+        .and_then(|n| n.as_str().and_then(cache::parse_hex));
+    }
+    let deliver = |result: SubscriptionResult| async move {
+        kernel_message(
+            our,
+            rand::random(),
+            target.clone(),
+            None,
+            true,
+            None,
+            EthSubResult::Ok(EthSub { id: sub_id, result }),
+            send_to_loop,
+        )
+        .await;
+    };
+
+    let e = 'outer: loop {
+        let dropped = loop {
+            tokio::select! {
+                incoming = rx.recv() => {
+                    match incoming {
+                        Some(EthSubResult::Ok(upd)) => {
+                            // Update the last received time on any successful sub result
+                            last_received = tokio::time::Instant::now();
+                            if is_logs {
+                                let Ok(log) = serde_json::to_value(&upd.result) else {
+                                    continue;
+                                };
+                                if !delivered.record(&log) {
+                                    continue;
+                                }
+                            }
+                            deliver(upd.result).await;
+                        }
+                        Some(EthSubResult::Err(e)) => {
+                            break EthSubError {
                                 id: sub_id,
-                                result: upd.result,
-                            }),
-                            &send_to_loop,
-                        )
-                        .await;
-                    }
-                    Some(EthSubResult::Err(e)) => {
-                        break EthSubError {
-                            id: sub_id,
-                            error: e.error,
-                        };
-                    }
-                    None => {
-                        break EthSubError {
-                            id: sub_id,
-                            error: "subscription closed unexpectedly".to_string(),
-                        };
+                                error: e.error,
+                            };
+                        }
+                        None => {
+                            // the subscription was closed on our end
+                            break 'outer EthSubError {
+                                id: sub_id,
+                                error: "subscription closed unexpectedly".to_string(),
+                            };
+                        }
                     }
                 }
+                _ = interval.tick() => {
+                    // perform keepalive
+                    kernel_message(
+                        &our,
+                        keepalive_km_id,
+                        Address { node: provider_node.clone(), process: ETH_PROCESS_ID.clone() },
+                        None,
+                        true,
+                        Some(30),
+                        IncomingReq::SubKeepalive(remote_sub_id),
+                        &send_to_loop,
+                    ).await;
+                }
+                _incoming = net_error_rx.recv() => {
+                    break EthSubError {
+                        id: sub_id,
+                        error: "subscription node-provider failed keepalive".to_string(),
+                    };
+                }
+                _ = tokio::time::sleep_until(last_received + two_hours) => {
+                    break EthSubError {
+                        id: sub_id,
+                        error: "No updates received for 2 hours, subscription considered dead.".to_string(),
+                    };
+                }
             }
-            _ = interval.tick() => {
-                // perform keepalive
-                kernel_message(
-                    &our,
-                    keepalive_km_id,
-                    Address { node: provider_node.to_string(), process: ETH_PROCESS_ID.clone() },
-                    None,
-                    true,
-                    Some(30),
-                    IncomingReq::SubKeepalive(remote_sub_id),
-                    &send_to_loop,
-                ).await;
+        };
+        verbose_print(
+            print_tx,
+            &format!(
+                "eth: subscription {sub_id} with {provider_node} dropped ({}), resubscribing",
+                dropped.error
+            ),
+        )
+        .await;
+        // in case the provider is still sending updates
+        kernel_message(
+            our,
+            rand::random(),
+            Address {
+                node: provider_node.clone(),
+                process: ETH_PROCESS_ID.clone(),
+            },
+            None,
+            true,
+            None,
+            EthAction::UnsubscribeLogs(remote_sub_id),
+            send_to_loop,
+        )
+        .await;
+        let mut attempt = 0;
+        provider_node = loop {
+            if attempt == RESUBSCRIBE_ATTEMPTS {
+                break 'outer dropped;
             }
-            _incoming = net_error_rx.recv() => {
-                break EthSubError {
-                    id: sub_id,
-                    error: "subscription node-provider failed keepalive".to_string(),
-                };
+            tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+            attempt += 1;
+            if let Some(node) = resubscribe_via_node(
+                our,
+                target,
+                remote_sub_id,
+                eth_action,
+                providers,
+                send_to_loop,
+                response_channels,
+            )
+            .await
+            {
+                break node;
+            }
+        };
+        // only accept updates from the new provider
+        if let Some(mut sub_map) = active_subscriptions.get_mut(target) {
+            if let Some(ActiveSub::Remote {
+                provider_node: node,
+                ..
+            }) = sub_map.get_mut(&remote_sub_id)
+            {
+                *node = provider_node.clone();
             }
-            _ = tokio::time::sleep_until(last_received + two_hours) => {
-                break EthSubError {
+        }
+        last_received = tokio::time::Instant::now();
+        if is_logs {
+            // the new subscription is live: fill in what we missed. logs that
+            // arrive on both are deduplicated by `delivered`
+            let via = RequestVia::Node {
+                our,
+                node: &provider_node,
+                send_to_loop,
+                response_channels,
+            };
+            let Some(logs) = backfill(
+                &via,
+                *chain_id,
+                &params_json,
+                &mut delivered,
+                providers,
+                print_tx,
+            )
+            .await
+            else {
+                break 'outer EthSubError {
                     id: sub_id,
-                    error: "No updates received for 2 hours, subscription considered dead.".to_string(),
+                    error: "failed to backfill logs after resubscribing".to_string(),
                 };
+            };
+            for log in logs {
+                match serde_json::from_value(log) {
+                    Ok(result) => deliver(result).await,
+                    Err(e) => {
+                        break 'outer EthSubError {
+                            id: sub_id,
+                            error: e.to_string(),
+                        }
+                    }
+                }
             }
         }
     };
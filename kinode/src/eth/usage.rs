@@ -0,0 +1,174 @@
+use lib::types::eth::*;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+/// a node that has sent us nothing for this long is forgotten, totals and all
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Rolling accounting of what remote nodes use of our provider.
+#[derive(Default)]
+pub struct UsageMeter {
+    nodes: HashMap<String, NodeMeter>,
+    last_sweep: Option<Instant>,
+}
+
+struct NodeMeter {
+    /// times of accepted requests within the window
+    recent: VecDeque<Instant>,
+    requests_total: u64,
+    rejected_total: u64,
+    last_seen: Instant,
+}
+
+impl NodeMeter {
+    fn new(now: Instant) -> Self {
+        Self {
+            recent: VecDeque::new(),
+            requests_total: 0,
+            rejected_total: 0,
+            last_seen: now,
+        }
+    }
+
+    fn trim(&mut self, now: Instant) {
+        while let Some(oldest) = self.recent.front() {
+            if now.duration_since(*oldest) < WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+}
+
+impl UsageMeter {
+    /// Count a request from `node`, returning false and counting a rejection
+    /// instead if it would put the node over its per-minute limit.
+    pub fn try_request(&mut self, node: &str, quota: &RemoteQuota) -> bool {
+        let now = Instant::now();
+        let meter = self.meter(node, now);
+        meter.trim(now);
+        if quota.requests_per_minute != 0
+            && meter.recent.len() >= quota.requests_per_minute as usize
+        {
+            meter.rejected_total += 1;
+            return false;
+        }
+        meter.recent.push_back(now);
+        meter.requests_total += 1;
+        true
+    }
+
+    /// count a request from `node` refused for reasons other than rate
+    pub fn reject(&mut self, node: &str) {
+        self.meter(node, Instant::now()).rejected_total += 1;
+    }
+
+    /// Usage of every node we've metered, given the number of subscriptions
+    /// each currently holds. A node holding subscriptions is reported even
+    /// if it has been idle long enough to be forgotten.
+    pub fn report(&mut self, subscriptions: &HashMap<String, u32>) -> HashMap<String, NodeUsage> {
+        let now = Instant::now();
+        self.sweep(now);
+        for node in subscriptions.keys() {
+            if !self.nodes.contains_key(node) {
+                self.nodes.insert(node.clone(), NodeMeter::new(now));
+            }
+        }
+        self.nodes
+            .iter_mut()
+            .map(|(node, meter)| {
+                meter.trim(now);
+                (
+                    node.clone(),
+                    NodeUsage {
+                        requests_last_minute: meter.recent.len() as u32,
+                        requests_total: meter.requests_total,
+                        rejected_total: meter.rejected_total,
+                        subscriptions: subscriptions.get(node).copied().unwrap_or(0),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// the meter for `node`, noting that it was just seen
+    fn meter(&mut self, node: &str, now: Instant) -> &mut NodeMeter {
+        self.sweep(now);
+        let meter = self
+            .nodes
+            .entry(node.to_string())
+            .or_insert_with(|| NodeMeter::new(now));
+        meter.last_seen = now;
+        meter
+    }
+
+    /// forget nodes that have been idle for too long, at most once per window
+    fn sweep(&mut self, now: Instant) {
+        if self
+            .last_sweep
+            .is_some_and(|last| now.duration_since(last) < WINDOW)
+        {
+            return;
+        }
+        self.last_sweep = Some(now);
+        self.nodes
+            .retain(|_, meter| now.duration_since(meter.last_seen) < IDLE_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(requests_per_minute: u32) -> RemoteQuota {
+        RemoteQuota {
+            requests_per_minute,
+            max_subscriptions: 0,
+        }
+    }
+
+    #[test]
+    fn requests_over_quota_are_rejected() {
+        let mut usage = UsageMeter::default();
+        assert!(usage.try_request("a.os", &quota(2)));
+        assert!(usage.try_request("a.os", &quota(2)));
+        assert!(!usage.try_request("a.os", &quota(2)));
+        // each node has its own quota
+        assert!(usage.try_request("b.os", &quota(2)));
+        usage.reject("b.os");
+
+        let report = usage.report(&HashMap::new());
+        assert_eq!(report["a.os"].requests_last_minute, 2);
+        assert_eq!(report["a.os"].requests_total, 2);
+        assert_eq!(report["a.os"].rejected_total, 1);
+        assert_eq!(report["b.os"].requests_total, 1);
+        assert_eq!(report["b.os"].rejected_total, 1);
+    }
+
+    #[test]
+    fn zero_quota_is_unlimited() {
+        let mut usage = UsageMeter::default();
+        for _ in 0..1_000 {
+            assert!(usage.try_request("a.os", &quota(0)));
+        }
+    }
+
+    #[test]
+    fn idle_nodes_are_forgotten() {
+        let mut usage = UsageMeter::default();
+        assert!(usage.try_request("a.os", &quota(0)));
+        usage.sweep(Instant::now() + WINDOW);
+        assert!(usage.nodes.contains_key("a.os"));
+        usage.sweep(Instant::now() + IDLE_TIMEOUT);
+        assert!(usage.nodes.is_empty());
+    }
+
+    #[test]
+    fn subscribers_are_reported() {
+        let mut usage = UsageMeter::default();
+        let report = usage.report(&HashMap::from([("a.os".to_string(), 3)]));
+        assert_eq!(report["a.os"].subscriptions, 3);
+        assert_eq!(report["a.os"].requests_total, 0);
+    }
+}
@@ -0,0 +1,1018 @@
+use crate::{CHAIN_ID, KNS_ADDRESS};
+use alloy_consensus::TxLegacy;
+use alloy_network::{Transaction, TxKind};
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use alloy_sol_macro::sol;
+use alloy_sol_types::{SolCall, SolEvent, SolValue};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+use super::{
+    encode_namehash, ipCall, multicallCall, ownerOfCall, registerCall, setAllIpCall, setKeyCall,
+    setRoutersCall, FAKE_DOTDEV,
+};
+use PackageStore::*;
+
+/// blocks are timestamped from here, so that every run produces the same chain
+const GENESIS_TIMESTAMP: u64 = 1_700_000_000;
+const BLOCK_TIME: u64 = 2;
+const GAS_PRICE: u64 = 1_000_000_000;
+const GAS_ESTIMATE: u64 = 3_000_000;
+/// where `app_store` looks for its listings contract on a local chain
+const FAKE_LISTINGS: &str = "0x8A791620dd6260079BF849Dc5567aDC3F2FdC318";
+
+sol! {
+    event NodeRegistered(bytes32 indexed node, bytes name);
+    event KeyUpdate(bytes32 indexed node, bytes32 key);
+    event IpUpdate(bytes32 indexed node, uint128 ip);
+    event WsUpdate(bytes32 indexed node, uint16 port);
+    event RoutingUpdate(bytes32 indexed node, bytes32[] routers);
+
+    interface PackageStore {
+        function registerApp(
+            string packageName,
+            bytes publisherName,
+            string metadataUrl,
+            bytes32 metadataHash
+        );
+        function updateMetadata(uint256 package, string metadataUrl, bytes32 metadataHash);
+        function unlistPacakge(uint256 package);
+        function transferFrom(address from, address to, uint256 tokenId);
+        function safeTransferFrom(address from, address to, uint256 tokenId);
+        function safeTransferFrom(address from, address to, uint256 tokenId, bytes data);
+        function balanceOf(address owner) returns (uint256);
+        function getPackageId(string packageName, bytes publisherName) returns (uint256);
+
+        event AppRegistered(
+            uint256 indexed package,
+            string packageName,
+            bytes publisherName,
+            string metadataUrl,
+            bytes32 metadataHash
+        );
+        event AppMetadataUpdated(uint256 indexed package, string metadataUrl, bytes32 metadataHash);
+        event Transfer(address indexed from, address indexed to, uint256 indexed tokenId);
+    }
+}
+
+/// Serve an in-process stand-in for the local anvil chain at
+/// `ws://localhost:{port}`, so that simulations can run without one.
+///
+/// It holds the KNS contract and the `.dev` registrar that [`super::register_local`]
+/// uses, as well as the `app_store` listings contract, mines one block per
+/// transaction, and answers the JSON-RPC methods that registration, the
+/// `kns_indexer` process and `app_store` rely on. Listings can be registered,
+/// updated, transferred by their owner and unlisted; approvals are not
+/// supported. Each transaction's sender is recovered from its signature to
+/// count that account's nonce, but nonces are not checked.
+pub async fn serve(port: u16) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let chain = Arc::new(Mutex::new(MockChain::new()));
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_connection(chain.clone(), stream));
+        }
+    });
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+struct MockLog {
+    address: Address,
+    topics: Vec<B256>,
+    data: Vec<u8>,
+    block: u64,
+    log_index: u64,
+    tx_hash: B256,
+}
+
+struct Receipt {
+    to: Address,
+    block: u64,
+    logs: Vec<MockLog>,
+}
+
+struct MockChain {
+    kns: Address,
+    dotdev: Address,
+    listings: Address,
+    block: u64,
+    /// transactions sent from each account
+    nonces: HashMap<Address, u64>,
+    logs: Vec<MockLog>,
+    receipts: HashMap<B256, Receipt>,
+    owners: HashMap<B256, Address>,
+    /// (ip, ws, wt, tcp, udp) for each node
+    ips: HashMap<B256, (u128, u16, u16, u16, u16)>,
+    /// owner of each package listed with the `app_store` contract
+    packages: HashMap<U256, Address>,
+    new_logs: broadcast::Sender<MockLog>,
+    new_heads: broadcast::Sender<u64>,
+}
+
+impl MockChain {
+    fn new() -> Self {
+        Self {
+            kns: Address::from_str(KNS_ADDRESS).unwrap(),
+            dotdev: Address::from_str(FAKE_DOTDEV).unwrap(),
+            listings: Address::from_str(FAKE_LISTINGS).unwrap(),
+            block: 0,
+            nonces: HashMap::new(),
+            logs: vec![],
+            receipts: HashMap::new(),
+            owners: HashMap::new(),
+            ips: HashMap::new(),
+            packages: HashMap::new(),
+            new_logs: broadcast::channel(1_000).0,
+            new_heads: broadcast::channel(100).0,
+        }
+    }
+
+    fn call(&self, to: Address, input: &[u8]) -> Result<Vec<u8>, String> {
+        if to == self.dotdev && input.starts_with(&ownerOfCall::SELECTOR) {
+            let call = ownerOfCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            let node = B256::from(call.node);
+            return match self.owners.get(&node) {
+                Some(owner) => Ok(owner.abi_encode()),
+                None => Err("ERC721: invalid token ID".into()),
+            };
+        }
+        if to == self.kns && input.starts_with(&ipCall::SELECTOR) {
+            let call = ipCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            let ip = self.ips.get(&call._0).cloned().unwrap_or_default();
+            return Ok(ip.abi_encode());
+        }
+        if to == self.listings {
+            return self.listings_view(input);
+        }
+        if to != self.dotdev && to != self.kns {
+            return Err(format!("no contract at {to}"));
+        }
+        Ok(vec![])
+    }
+
+    /// Execute a transaction in a new block, returning its hash.
+    fn transact(&mut self, raw: &[u8]) -> Result<B256, String> {
+        let (from, tx) = decode_legacy_tx(raw)
+            .ok_or("only signed, RLP-encoded legacy transactions are supported".to_string())?;
+        let TxKind::Call(to) = tx.to else {
+            return Err("contract creation is not supported".into());
+        };
+
+        let mut events: Vec<(Vec<B256>, Vec<u8>)> = vec![];
+        if to == self.dotdev {
+            self.register(&tx.input, &mut events)?;
+        } else if to == self.kns {
+            self.kns_call(&tx.input, &mut events)?;
+        } else if to == self.listings {
+            self.listings_call(from, &tx.input, &mut events)?;
+        }
+        // the registrar's events are emitted by the KNS contract it calls into
+        let address = if to == self.listings {
+            self.listings
+        } else {
+            self.kns
+        };
+
+        let tx_hash = keccak256(raw);
+        self.block += 1;
+        *self.nonces.entry(from).or_default() += 1;
+        let logs: Vec<MockLog> = events
+            .into_iter()
+            .enumerate()
+            .map(|(i, (topics, data))| MockLog {
+                address,
+                topics,
+                data,
+                block: self.block,
+                log_index: i as u64,
+                tx_hash,
+            })
+            .collect();
+        for log in logs.iter() {
+            let _ = self.new_logs.send(log.clone());
+        }
+        let _ = self.new_heads.send(self.block);
+        self.logs.extend(logs.iter().cloned());
+        self.receipts.insert(
+            tx_hash,
+            Receipt {
+                to,
+                block: self.block,
+                logs,
+            },
+        );
+        Ok(tx_hash)
+    }
+
+    fn register(
+        &mut self,
+        input: &[u8],
+        events: &mut Vec<(Vec<B256>, Vec<u8>)>,
+    ) -> Result<(), String> {
+        if !input.starts_with(&registerCall::SELECTOR) {
+            return Err("unsupported registrar call".into());
+        }
+        let call = registerCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+        let name = dns_decode(&call._name).ok_or("malformed name".to_string())?;
+        let node = B256::from(encode_namehash(&name));
+        if self.owners.contains_key(&node) {
+            return Err("name already registered".into());
+        }
+        self.owners.insert(node, call._to);
+        events.push((
+            vec![NodeRegistered::SIGNATURE_HASH, node],
+            NodeRegistered {
+                node,
+                name: call._name.clone(),
+            }
+            .encode_data(),
+        ));
+        for data in call._data.iter() {
+            self.kns_call(data, events)?;
+        }
+        Ok(())
+    }
+
+    fn kns_call(
+        &mut self,
+        input: &[u8],
+        events: &mut Vec<(Vec<B256>, Vec<u8>)>,
+    ) -> Result<(), String> {
+        if input.starts_with(&multicallCall::SELECTOR) {
+            let call = multicallCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            for data in call.data.iter() {
+                self.kns_call(data, events)?;
+            }
+        } else if input.starts_with(&setKeyCall::SELECTOR) {
+            let call = setKeyCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            events.push((
+                vec![KeyUpdate::SIGNATURE_HASH, call._node],
+                KeyUpdate {
+                    node: call._node,
+                    key: call._key,
+                }
+                .encode_data(),
+            ));
+        } else if input.starts_with(&setAllIpCall::SELECTOR) {
+            let call = setAllIpCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            self.ips.insert(
+                call._node,
+                (call._ip, call._ws, call._wt, call._tcp, call._udp),
+            );
+            events.push((
+                vec![IpUpdate::SIGNATURE_HASH, call._node],
+                IpUpdate {
+                    node: call._node,
+                    ip: call._ip,
+                }
+                .encode_data(),
+            ));
+            events.push((
+                vec![WsUpdate::SIGNATURE_HASH, call._node],
+                WsUpdate {
+                    node: call._node,
+                    port: call._ws,
+                }
+                .encode_data(),
+            ));
+        } else if input.starts_with(&setRoutersCall::SELECTOR) {
+            let call = setRoutersCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            self.ips.remove(&call._node);
+            events.push((
+                vec![RoutingUpdate::SIGNATURE_HASH, call._node],
+                RoutingUpdate {
+                    node: call._node,
+                    routers: call._routers.clone(),
+                }
+                .encode_data(),
+            ));
+        } else {
+            return Err("unsupported KNS call".into());
+        }
+        Ok(())
+    }
+
+    fn listings_call(
+        &mut self,
+        sender: Address,
+        input: &[u8],
+        events: &mut Vec<(Vec<B256>, Vec<u8>)>,
+    ) -> Result<(), String> {
+        if input.starts_with(&registerAppCall::SELECTOR) {
+            let call = registerAppCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            let package = package_id(&call.packageName, &call.publisherName);
+            if self.packages.contains_key(&package) {
+                return Err("package already registered".into());
+            }
+            self.packages.insert(package, sender);
+            events.push(transfer_event(Address::ZERO, sender, package));
+            events.push((
+                vec![AppRegistered::SIGNATURE_HASH, B256::from(package)],
+                AppRegistered {
+                    package,
+                    packageName: call.packageName,
+                    publisherName: call.publisherName,
+                    metadataUrl: call.metadataUrl,
+                    metadataHash: call.metadataHash,
+                }
+                .encode_data(),
+            ));
+        } else if input.starts_with(&updateMetadataCall::SELECTOR) {
+            let call = updateMetadataCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            self.check_package_owner(call.package, sender)?;
+            events.push((
+                vec![AppMetadataUpdated::SIGNATURE_HASH, B256::from(call.package)],
+                AppMetadataUpdated {
+                    package: call.package,
+                    metadataUrl: call.metadataUrl,
+                    metadataHash: call.metadataHash,
+                }
+                .encode_data(),
+            ));
+        } else if input.starts_with(&unlistPacakgeCall::SELECTOR) {
+            let call = unlistPacakgeCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            self.check_package_owner(call.package, sender)?;
+            self.packages.remove(&call.package);
+            events.push(transfer_event(sender, Address::ZERO, call.package));
+        } else {
+            let (from, to, package) = if input.starts_with(&transferFromCall::SELECTOR) {
+                let call = transferFromCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+                (call.from, call.to, call.tokenId)
+            } else if input.starts_with(&safeTransferFrom_0Call::SELECTOR) {
+                let call =
+                    safeTransferFrom_0Call::abi_decode(input, true).map_err(|e| e.to_string())?;
+                (call.from, call.to, call.tokenId)
+            } else if input.starts_with(&safeTransferFrom_1Call::SELECTOR) {
+                let call =
+                    safeTransferFrom_1Call::abi_decode(input, true).map_err(|e| e.to_string())?;
+                (call.from, call.to, call.tokenId)
+            } else {
+                return Err("unsupported listings call".into());
+            };
+            self.check_package_owner(package, sender)?;
+            if from != sender {
+                return Err("ERC721: transfer from incorrect owner".into());
+            }
+            if to == Address::ZERO {
+                return Err("ERC721: invalid receiver".into());
+            }
+            self.packages.insert(package, to);
+            events.push(transfer_event(from, to, package));
+        }
+        Ok(())
+    }
+
+    fn listings_view(&self, input: &[u8]) -> Result<Vec<u8>, String> {
+        if input.starts_with(&ownerOfCall::SELECTOR) {
+            let call = ownerOfCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            return match self.packages.get(&call.node) {
+                Some(owner) => Ok(owner.abi_encode()),
+                None => Err("ERC721: invalid token ID".into()),
+            };
+        }
+        if input.starts_with(&balanceOfCall::SELECTOR) {
+            let call = balanceOfCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            let balance = self
+                .packages
+                .values()
+                .filter(|owner| **owner == call.owner)
+                .count();
+            return Ok(U256::from(balance).abi_encode());
+        }
+        if input.starts_with(&getPackageIdCall::SELECTOR) {
+            let call = getPackageIdCall::abi_decode(input, true).map_err(|e| e.to_string())?;
+            return Ok(package_id(&call.packageName, &call.publisherName).abi_encode());
+        }
+        Err("unsupported listings call".into())
+    }
+
+    fn check_package_owner(&self, package: U256, sender: Address) -> Result<(), String> {
+        match self.packages.get(&package) {
+            None => Err("ERC721: invalid token ID".into()),
+            Some(owner) if *owner != sender => Err("caller is not the package owner".into()),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn block_number(&self, tag: Option<&Value>) -> Result<u64, String> {
+        match tag.and_then(|t| t.as_str()) {
+            None | Some("latest") | Some("safe") | Some("finalized") | Some("pending") => {
+                Ok(self.block)
+            }
+            Some("earliest") => Ok(0),
+            Some(number) => parse_quantity(number).ok_or(format!("invalid block: {number}")),
+        }
+    }
+}
+
+struct LogFilter {
+    addresses: Option<Vec<Address>>,
+    /// for each position, the topics allowed there, or None for any
+    topics: Vec<Option<Vec<B256>>>,
+    from_block: u64,
+    to_block: u64,
+}
+
+impl LogFilter {
+    fn parse(chain: &MockChain, filter: &Value) -> Result<Self, String> {
+        let addresses = match filter.get("address") {
+            None | Some(Value::Null) => None,
+            Some(address) => Some(parse_one_or_many(address)?),
+        };
+        let topics = match filter.get("topics") {
+            Some(Value::Array(topics)) => topics
+                .iter()
+                .map(|topic| match topic {
+                    Value::Null => Ok(None),
+                    topic => parse_one_or_many(topic).map(Some),
+                })
+                .collect::<Result<_, String>>()?,
+            _ => vec![],
+        };
+        let (from_block, to_block) = match filter.get("blockHash").and_then(|h| h.as_str()) {
+            Some(hash) => {
+                let block = (0..=chain.block)
+                    .find(|n| format!("{}", block_hash(*n)) == hash)
+                    .ok_or("unknown block hash".to_string())?;
+                (block, block)
+            }
+            None => (
+                chain.block_number(filter.get("fromBlock"))?,
+                chain.block_number(filter.get("toBlock"))?,
+            ),
+        };
+        Ok(Self {
+            addresses,
+            topics,
+            from_block,
+            to_block,
+        })
+    }
+
+    /// whether a log matches, ignoring the block range
+    fn matches(&self, log: &MockLog) -> bool {
+        if let Some(addresses) = &self.addresses {
+            if !addresses.contains(&log.address) {
+                return false;
+            }
+        }
+        self.topics
+            .iter()
+            .enumerate()
+            .all(|(i, allowed)| match allowed {
+                None => true,
+                Some(allowed) => log.topics.get(i).is_some_and(|t| allowed.contains(t)),
+            })
+    }
+}
+
+async fn handle_connection(chain: Arc<Mutex<MockChain>>, stream: TcpStream) {
+    let Ok(websocket) = accept_async(stream).await else {
+        return;
+    };
+    let (mut write, mut read) = websocket.split();
+    let (send_to_client, mut outgoing) = mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        while let Some(text) = outgoing.recv().await {
+            if write.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut subscriptions: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    while let Some(Ok(message)) = read.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let Ok(request) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+        let response = match request {
+            Value::Array(batch) => {
+                let mut responses = vec![];
+                for request in batch.iter() {
+                    responses.push(
+                        handle_rpc(&chain, request, &send_to_client, &mut subscriptions).await,
+                    );
+                }
+                Value::Array(responses)
+            }
+            request => handle_rpc(&chain, &request, &send_to_client, &mut subscriptions).await,
+        };
+        let _ = send_to_client.send(response.to_string());
+    }
+
+    for (_, subscription) in subscriptions {
+        subscription.abort();
+    }
+    writer.abort();
+}
+
+async fn handle_rpc(
+    chain: &Arc<Mutex<MockChain>>,
+    request: &Value,
+    send_to_client: &mpsc::UnboundedSender<String>,
+    subscriptions: &mut HashMap<String, JoinHandle<()>>,
+) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = request.get("method").and_then(|m| m.as_str()).unwrap_or("");
+    let params = request.get("params").cloned().unwrap_or(json!([]));
+    let param = |i: usize| params.get(i);
+
+    let result: Result<Value, (i64, String)> = match method {
+        "eth_chainId" => Ok(quantity(CHAIN_ID)),
+        "net_version" => Ok(json!(CHAIN_ID.to_string())),
+        "web3_clientVersion" => Ok(json!("kinode-mock-chain")),
+        "eth_syncing" => Ok(json!(false)),
+        "eth_accounts" => Ok(json!([])),
+        "eth_blockNumber" => Ok(quantity(chain.lock().await.block)),
+        "eth_gasPrice" | "eth_maxPriorityFeePerGas" => Ok(quantity(GAS_PRICE)),
+        "eth_estimateGas" => Ok(quantity(GAS_ESTIMATE)),
+        "eth_getBalance" => Ok(json!(
+            U256::from(10_000u64) * U256::from(10u64).pow(U256::from(18u64))
+        )),
+        "eth_getCode" => Ok(json!("0x")),
+        "eth_getTransactionCount" => match param(0)
+            .and_then(|address| address.as_str())
+            .and_then(|address| Address::from_str(address).ok())
+        {
+            None => Err((-32602, "invalid address".into())),
+            Some(address) => Ok(quantity(
+                chain
+                    .lock()
+                    .await
+                    .nonces
+                    .get(&address)
+                    .copied()
+                    .unwrap_or_default(),
+            )),
+        },
+        "eth_call" => {
+            let chain = chain.lock().await;
+            match param(0).and_then(parse_call) {
+                None => Err((-32602, "invalid call".into())),
+                Some((to, input)) => chain
+                    .call(to, &input)
+                    .map(|output| json!(Bytes::from(output)))
+                    .map_err(|e| (3, format!("execution reverted: {e}"))),
+            }
+        }
+        "eth_sendRawTransaction" => {
+            match param(0)
+                .and_then(|raw| raw.as_str())
+                .and_then(|raw| hex::decode(raw.trim_start_matches("0x")).ok())
+            {
+                None => Err((-32602, "invalid transaction".into())),
+                Some(raw) => chain
+                    .lock()
+                    .await
+                    .transact(&raw)
+                    .map(|hash| json!(hash))
+                    .map_err(|e| (3, format!("execution reverted: {e}"))),
+            }
+        }
+        "eth_getTransactionReceipt" => {
+            let chain = chain.lock().await;
+            match param(0)
+                .and_then(|hash| hash.as_str())
+                .and_then(|hash| B256::from_str(hash).ok())
+            {
+                None => Err((-32602, "invalid transaction hash".into())),
+                Some(hash) => Ok(chain
+                    .receipts
+                    .get(&hash)
+                    .map(|receipt| receipt_json(hash, receipt))
+                    .unwrap_or(Value::Null)),
+            }
+        }
+        "eth_getBlockByNumber" => {
+            let chain = chain.lock().await;
+            match chain.block_number(param(0)) {
+                Ok(number) if number <= chain.block => Ok(block_header(number)),
+                Ok(_) => Ok(Value::Null),
+                Err(e) => Err((-32602, e)),
+            }
+        }
+        "eth_getBlockByHash" => {
+            let chain = chain.lock().await;
+            let hash = param(0).and_then(|h| h.as_str()).unwrap_or("");
+            Ok((0..=chain.block)
+                .find(|n| format!("{}", block_hash(*n)) == hash)
+                .map(block_header)
+                .unwrap_or(Value::Null))
+        }
+        "eth_getLogs" => {
+            let chain = chain.lock().await;
+            match LogFilter::parse(&chain, param(0).unwrap_or(&Value::Null)) {
+                Err(e) => Err((-32602, e)),
+                Ok(filter) => Ok(Value::Array(
+                    chain
+                        .logs
+                        .iter()
+                        .filter(|log| {
+                            filter.from_block <= log.block
+                                && log.block <= filter.to_block
+                                && filter.matches(log)
+                        })
+                        .map(log_json)
+                        .collect(),
+                )),
+            }
+        }
+        "eth_subscribe" => {
+            let subscription_id = format!("0x{:x}", rand::random::<u64>());
+            let chain_guard = chain.lock().await;
+            match param(0).and_then(|kind| kind.as_str()) {
+                Some("logs") => {
+                    match LogFilter::parse(&chain_guard, param(1).unwrap_or(&json!({}))) {
+                        Err(e) => Err((-32602, e)),
+                        Ok(filter) => {
+                            let mut new_logs = chain_guard.new_logs.subscribe();
+                            let send_to_client = send_to_client.clone();
+                            let sub_id = subscription_id.clone();
+                            subscriptions.insert(
+                                subscription_id.clone(),
+                                tokio::spawn(async move {
+                                    loop {
+                                        match new_logs.recv().await {
+                                            Ok(log) if filter.matches(&log) => {
+                                                let _ = send_to_client
+                                                    .send(notification(&sub_id, log_json(&log)));
+                                            }
+                                            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                                            }
+                                            Err(broadcast::error::RecvError::Closed) => break,
+                                        }
+                                    }
+                                }),
+                            );
+                            Ok(json!(subscription_id))
+                        }
+                    }
+                }
+                Some("newHeads") => {
+                    let mut new_heads = chain_guard.new_heads.subscribe();
+                    let send_to_client = send_to_client.clone();
+                    let sub_id = subscription_id.clone();
+                    subscriptions.insert(
+                        subscription_id.clone(),
+                        tokio::spawn(async move {
+                            loop {
+                                match new_heads.recv().await {
+                                    Ok(number) => {
+                                        let _ = send_to_client
+                                            .send(notification(&sub_id, block_header(number)));
+                                    }
+                                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                                    Err(broadcast::error::RecvError::Closed) => break,
+                                }
+                            }
+                        }),
+                    );
+                    Ok(json!(subscription_id))
+                }
+                _ => Err((-32602, "unsupported subscription".into())),
+            }
+        }
+        "eth_unsubscribe" => {
+            let subscription_id = param(0).and_then(|s| s.as_str()).unwrap_or("");
+            match subscriptions.remove(subscription_id) {
+                Some(subscription) => {
+                    subscription.abort();
+                    Ok(json!(true))
+                }
+                None => Ok(json!(false)),
+            }
+        }
+        method => Err((-32601, format!("the method {method} does not exist"))),
+    };
+
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+fn notification(subscription_id: &str, result: Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "eth_subscription",
+        "params": { "subscription": subscription_id, "result": result },
+    })
+    .to_string()
+}
+
+fn quantity(n: u64) -> Value {
+    json!(format!("0x{n:x}"))
+}
+
+fn parse_quantity(n: &str) -> Option<u64> {
+    u64::from_str_radix(n.strip_prefix("0x")?, 16).ok()
+}
+
+fn parse_one_or_many<T: FromStr>(value: &Value) -> Result<Vec<T>, String> {
+    let parse = |v: &Value| {
+        v.as_str()
+            .and_then(|s| T::from_str(s).ok())
+            .ok_or(format!("invalid filter value: {v}"))
+    };
+    match value {
+        Value::Array(values) => values.iter().map(parse).collect(),
+        value => Ok(vec![parse(value)?]),
+    }
+}
+
+/// the `to` and `input` of an `eth_call` transaction object
+fn parse_call(tx: &Value) -> Option<(Address, Vec<u8>)> {
+    let to = Address::from_str(tx.get("to")?.as_str()?).ok()?;
+    let input = tx
+        .get("input")
+        .or(tx.get("data"))
+        .and_then(|i| i.as_str())
+        .unwrap_or("0x");
+    Some((to, hex::decode(input.trim_start_matches("0x")).ok()?))
+}
+
+fn block_hash(number: u64) -> B256 {
+    keccak256(number.to_be_bytes())
+}
+
+fn block_header(number: u64) -> Value {
+    json!({
+        "number": quantity(number),
+        "hash": block_hash(number),
+        "parentHash": if number == 0 { B256::ZERO } else { block_hash(number - 1) },
+        "sha3Uncles": B256::ZERO,
+        "miner": Address::ZERO,
+        "stateRoot": B256::ZERO,
+        "transactionsRoot": B256::ZERO,
+        "receiptsRoot": B256::ZERO,
+        "logsBloom": format!("0x{}", "0".repeat(512)),
+        "difficulty": "0x0",
+        "gasLimit": quantity(30_000_000),
+        "gasUsed": "0x0",
+        "timestamp": quantity(GENESIS_TIMESTAMP + number * BLOCK_TIME),
+        "extraData": "0x",
+        "mixHash": B256::ZERO,
+        "nonce": "0x0000000000000000",
+        "baseFeePerGas": quantity(GAS_PRICE),
+        "transactions": [],
+        "uncles": [],
+    })
+}
+
+fn log_json(log: &MockLog) -> Value {
+    json!({
+        "address": log.address,
+        "topics": log.topics,
+        "data": Bytes::from(log.data.clone()),
+        "blockNumber": quantity(log.block),
+        "blockHash": block_hash(log.block),
+        "transactionHash": log.tx_hash,
+        "transactionIndex": "0x0",
+        "logIndex": quantity(log.log_index),
+        "removed": false,
+    })
+}
+
+fn receipt_json(hash: B256, receipt: &Receipt) -> Value {
+    json!({
+        "transactionHash": hash,
+        "transactionIndex": "0x0",
+        "blockHash": block_hash(receipt.block),
+        "blockNumber": quantity(receipt.block),
+        "to": receipt.to,
+        "cumulativeGasUsed": "0x0",
+        "gasUsed": "0x0",
+        "effectiveGasPrice": quantity(GAS_PRICE),
+        "contractAddress": null,
+        "logs": receipt.logs.iter().map(log_json).collect::<Vec<_>>(),
+        "logsBloom": format!("0x{}", "0".repeat(512)),
+        "status": "0x1",
+        "type": "0x0",
+    })
+}
+
+/// the dotted name for a DNS wire-format name
+fn dns_decode(wire: &[u8]) -> Option<String> {
+    let mut labels = vec![];
+    let mut i = 0;
+    while *wire.get(i)? != 0 {
+        let len = wire[i] as usize;
+        labels.push(std::str::from_utf8(wire.get(i + 1..i + 1 + len)?).ok()?);
+        i += 1 + len;
+    }
+    Some(labels.join("."))
+}
+
+/// The id the listings contract gives a package: the hash of its name
+/// followed by its publisher's DNS wire format name.
+fn package_id(package_name: &str, publisher_name: &[u8]) -> U256 {
+    U256::from_be_bytes(keccak256([package_name.as_bytes(), publisher_name].concat()).0)
+}
+
+fn transfer_event(from: Address, to: Address, package: U256) -> (Vec<B256>, Vec<u8>) {
+    (
+        vec![
+            Transfer::SIGNATURE_HASH,
+            from.into_word(),
+            to.into_word(),
+            B256::from(package),
+        ],
+        vec![],
+    )
+}
+
+/// Decode a signed, RLP-encoded legacy transaction, recovering its sender
+/// from the signature.
+fn decode_legacy_tx(mut raw: &[u8]) -> Option<(Address, TxLegacy)> {
+    let signed = TxLegacy::decode_signed(&mut raw).ok()?;
+    let from = signed
+        .signature()
+        .recover_address_from_prehash(&signed.tx().signature_hash())
+        .ok()?;
+    Some((from, signed.tx().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakenet::dns_encode_fqdn;
+    use alloy_signer::{LocalWallet, Signer, SignerSync};
+
+    fn wallet() -> LocalWallet {
+        LocalWallet::from_str("0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80")
+            .unwrap()
+    }
+
+    fn signed_tx(wallet: &LocalWallet, to: Address, nonce: u64, input: Vec<u8>) -> Vec<u8> {
+        let mut tx = TxLegacy {
+            to: TxKind::Call(to),
+            nonce,
+            input: input.into(),
+            chain_id: Some(CHAIN_ID),
+            gas_limit: 3000000,
+            gas_price: 100000000000,
+            ..Default::default()
+        };
+        let sig = wallet.sign_transaction_sync(&mut tx).unwrap();
+        let mut buf = vec![];
+        tx.into_signed(sig).encode_signed(&mut buf);
+        buf
+    }
+
+    fn register_call(name: &str, owner: Address) -> Vec<u8> {
+        registerCall {
+            _name: dns_encode_fqdn(name).into(),
+            _to: owner,
+            _data: vec![],
+        }
+        .abi_encode()
+    }
+
+    #[test]
+    fn decodes_signed_legacy_tx() {
+        let wallet = wallet();
+        let dotdev = Address::from_str(FAKE_DOTDEV).unwrap();
+        let input = register_call("fake.dev", wallet.address());
+        let raw = signed_tx(&wallet, dotdev, 7, input.clone());
+
+        let (from, tx) = decode_legacy_tx(&raw).unwrap();
+        assert_eq!(from, wallet.address());
+        assert_eq!(tx.to, TxKind::Call(dotdev));
+        assert_eq!(tx.nonce, 7);
+        assert_eq!(tx.input.to_vec(), input);
+
+        // a truncated transaction doesn't decode
+        assert!(decode_legacy_tx(&raw[..raw.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn register_counts_sender_nonce() {
+        let wallet = wallet();
+        let mut chain = MockChain::new();
+        let raw = signed_tx(
+            &wallet,
+            chain.dotdev,
+            0,
+            register_call("fake.dev", wallet.address()),
+        );
+
+        let hash = chain.transact(&raw).unwrap();
+        assert_eq!(chain.block, 1);
+        assert_eq!(chain.nonces.get(&wallet.address()), Some(&1));
+        assert_eq!(chain.receipts[&hash].logs.len(), 1);
+
+        let node = B256::from(encode_namehash("fake.dev"));
+        let owner = chain
+            .call(
+                chain.dotdev,
+                &ownerOfCall { node: node.into() }.abi_encode(),
+            )
+            .unwrap();
+        assert_eq!(owner, wallet.address().abi_encode());
+
+        // registering the same name again fails, and doesn't mine a block
+        let raw = signed_tx(
+            &wallet,
+            chain.dotdev,
+            1,
+            register_call("fake.dev", wallet.address()),
+        );
+        assert!(chain.transact(&raw).is_err());
+        assert_eq!(chain.block, 1);
+    }
+
+    #[test]
+    fn listings_follow_their_owner() {
+        let wallet = wallet();
+        let buyer = Address::repeat_byte(0x42);
+        let mut chain = MockChain::new();
+        let publisher = dns_encode_fqdn("fake.dev");
+        let register = registerAppCall {
+            packageName: "chess".into(),
+            publisherName: publisher.clone().into(),
+            metadataUrl: "http://localhost/chess.json".into(),
+            metadataHash: B256::ZERO,
+        }
+        .abi_encode();
+
+        let hash = chain
+            .transact(&signed_tx(&wallet, chain.listings, 0, register))
+            .unwrap();
+        let logs = &chain.receipts[&hash].logs;
+        assert_eq!(logs.len(), 2);
+        assert!(logs.iter().all(|log| log.address == chain.listings));
+        assert_eq!(logs[0].topics[0], Transfer::SIGNATURE_HASH);
+        assert_eq!(logs[0].topics[1], Address::ZERO.into_word());
+        assert_eq!(logs[1].topics[0], AppRegistered::SIGNATURE_HASH);
+
+        // the id matches the one `app_store` derives from the event
+        let package = U256::from_be_bytes(logs[1].topics[1].0);
+        let id = chain
+            .call(
+                chain.listings,
+                &getPackageIdCall {
+                    packageName: "chess".into(),
+                    publisherName: publisher.into(),
+                }
+                .abi_encode(),
+            )
+            .unwrap();
+        assert_eq!(id, package.abi_encode());
+        assert_eq!(
+            logs[1].topics[1],
+            keccak256([&b"chess"[..], &dns_encode_fqdn("fake.dev")[..]].concat())
+        );
+
+        let owner_of = ownerOfCall { node: package }.abi_encode();
+        assert_eq!(
+            chain.call(chain.listings, &owner_of).unwrap(),
+            wallet.address().abi_encode()
+        );
+
+        // only the owner can transfer or unlist
+        let transfer = transferFromCall {
+            from: wallet.address(),
+            to: buyer,
+            tokenId: package,
+        }
+        .abi_encode();
+        let hash = chain
+            .transact(&signed_tx(&wallet, chain.listings, 1, transfer.clone()))
+            .unwrap();
+        assert_eq!(chain.receipts[&hash].logs[0].topics[2], buyer.into_word());
+        assert_eq!(
+            chain.call(chain.listings, &owner_of).unwrap(),
+            buyer.abi_encode()
+        );
+        assert!(chain
+            .transact(&signed_tx(&wallet, chain.listings, 2, transfer))
+            .is_err());
+        let unlist = unlistPacakgeCall { package }.abi_encode();
+        assert!(chain
+            .transact(&signed_tx(&wallet, chain.listings, 2, unlist))
+            .is_err());
+    }
+
+    #[test]
+    fn call_to_unknown_address_fails() {
+        let chain = MockChain::new();
+        assert!(chain.call(Address::ZERO, &[]).is_err());
+    }
+}
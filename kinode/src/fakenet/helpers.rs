@@ -12,6 +12,8 @@ sol! {
 
         function setKey(bytes32 _node, bytes32 _key);
 
+        function setRouters(bytes32 _node, bytes32[] calldata _routers);
+
         function setAllIp(
             bytes32 _node,
             uint128 _ip,
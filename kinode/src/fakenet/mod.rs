@@ -10,6 +10,7 @@ use alloy_transport_ws::WsConnect;
 use lib::core::Identity;
 use std::str::FromStr;
 
+pub mod chain;
 pub mod helpers;
 
 use crate::{keygen, KNS_ADDRESS};
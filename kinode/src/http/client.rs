@@ -680,6 +680,8 @@ async fn handle_ws_message(
                 expects_response: None,
                 metadata: None,
                 capabilities: vec![],
+                priority: MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: blob,
         })
@@ -1,6 +1,7 @@
 use crate::http::server_types::*;
 use crate::http::utils::*;
 use crate::keygen;
+use crate::logging::LogLevel;
 use anyhow::Result;
 use base64::{engine::general_purpose::STANDARD as base64_standard, Engine};
 use dashmap::DashMap;
@@ -24,6 +25,10 @@ const HTTP_SELF_IMPOSED_TIMEOUT: u64 = 600;
 
 const LOGIN_HTML: &str = include_str!("login.html");
 
+/// Prefix of the runtime's own diagnostic paths. Paths bound by processes
+/// start with the process id, which has colons, so they can't collide.
+const RUNTIME_PATH: &str = "_runtime";
+
 /// mapping from a given HTTP request (assigned an ID) to the oneshot
 /// channel that will get a response from the app that handles the request,
 /// and a string which contains the path that the request was made to.
@@ -177,6 +182,7 @@ pub async fn http_server(
     our_port: u16,
     encoded_keyfile: Vec<u8>,
     jwt_secret_bytes: Vec<u8>,
+    trust_loopback: bool,
     mut recv_in_server: MessageReceiver,
     send_to_loop: MessageSender,
     print_tx: PrintSender,
@@ -213,6 +219,7 @@ pub async fn http_server(
         ws_senders.clone(),
         encoded_keyfile.clone(),
         jwt_secret_bytes.clone(),
+        trust_loopback,
         send_to_loop.clone(),
         print_tx.clone(),
     ));
@@ -244,6 +251,7 @@ async fn serve(
     ws_senders: WebSocketSenders,
     encoded_keyfile: Arc<Vec<u8>>,
     jwt_secret_bytes: Arc<Vec<u8>>,
+    trust_loopback: bool,
     send_to_loop: MessageSender,
     print_tx: PrintSender,
 ) {
@@ -294,6 +302,53 @@ async fn serve(
                 .and_then(login_handler)),
     );
 
+    // filter to serve node metrics to logged-in users and scrapers holding their token
+    let cloned_our = our.clone();
+    let cloned_jwt_secret_bytes = jwt_secret_bytes.clone();
+    let metrics = warp::path(RUNTIME_PATH)
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::addr::remote())
+        .and(warp::filters::header::optional::<String>("cookie"))
+        .and(warp::filters::header::optional::<String>("authorization"))
+        .and(warp::any().map(move || cloned_our.clone()))
+        .and(warp::any().map(move || cloned_jwt_secret_bytes.clone()))
+        .and(warp::any().map(move || trust_loopback))
+        .and_then(metrics_handler);
+
+    // filter to query process logs, for the same users as metrics
+    let cloned_our = our.clone();
+    let cloned_jwt_secret_bytes = jwt_secret_bytes.clone();
+    let logs = warp::path(RUNTIME_PATH)
+        .and(warp::path("logs"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::addr::remote())
+        .and(warp::filters::header::optional::<String>("cookie"))
+        .and(warp::filters::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || cloned_our.clone()))
+        .and(warp::any().map(move || cloned_jwt_secret_bytes.clone()))
+        .and(warp::any().map(move || trust_loopback))
+        .and_then(logs_handler);
+
+    // filter to export message traces, for the same users as metrics
+    let cloned_our = our.clone();
+    let cloned_jwt_secret_bytes = jwt_secret_bytes.clone();
+    let traces = warp::path(RUNTIME_PATH)
+        .and(warp::path("traces"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::addr::remote())
+        .and(warp::filters::header::optional::<String>("cookie"))
+        .and(warp::filters::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || cloned_our.clone()))
+        .and(warp::any().map(move || cloned_jwt_secret_bytes.clone()))
+        .and(warp::any().map(move || trust_loopback))
+        .and_then(traces_handler);
+
     // filter to receive all other HTTP requests
     let filter = warp::filters::method::method()
         .and(warp::addr::remote())
@@ -310,7 +365,12 @@ async fn serve(
         .and(warp::any().map(move || print_tx.clone()))
         .and_then(http_handler);
 
-    let filter_with_ws = ws_route.or(login).or(filter);
+    let filter_with_ws = ws_route
+        .or(login)
+        .or(metrics)
+        .or(logs)
+        .or(traces)
+        .or(filter);
     warp::serve(filter_with_ws)
         .run(([0, 0, 0, 0], our_port))
         .await;
@@ -368,6 +428,141 @@ async fn login_handler(
     }
 }
 
+/// whether a request to one of the runtime's own diagnostic paths carries
+/// the auth cookie or the same token as a bearer token, or, if the node was
+/// started trusting loopback, comes from this machine
+fn authorized(
+    socket_addr: Option<SocketAddr>,
+    cookie: Option<String>,
+    authorization: Option<String>,
+    our: &str,
+    jwt_secret_bytes: &[u8],
+    trust_loopback: bool,
+) -> bool {
+    let is_local = socket_addr
+        .map(|addr| addr.ip().is_loopback())
+        .unwrap_or(false);
+    let bearer = authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| auth_token_valid(our, token.trim(), jwt_secret_bytes));
+    (trust_loopback && is_local)
+        || bearer
+        || auth_cookie_valid(our, &cookie.unwrap_or_default(), jwt_secret_bytes)
+}
+
+/// serve the metrics collected across the runtime, in OpenMetrics text format
+async fn metrics_handler(
+    socket_addr: Option<SocketAddr>,
+    cookie: Option<String>,
+    authorization: Option<String>,
+    our: Arc<String>,
+    jwt_secret_bytes: Arc<Vec<u8>>,
+    trust_loopback: bool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !authorized(
+        socket_addr,
+        cookie,
+        authorization,
+        &our,
+        &jwt_secret_bytes,
+        trust_loopback,
+    ) {
+        return Ok(warp::reply::with_status(vec![], StatusCode::FORBIDDEN).into_response());
+    }
+    Ok(warp::http::Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", crate::metrics::CONTENT_TYPE)
+        .body(crate::metrics::render().into_bytes())
+        .into_response())
+}
+
+/// serve the last records of process logs as JSON. takes the query params
+/// `process` (all processes if absent), `level` (the least severe level to
+/// include, default `trace`), and `tail` (how many records, default 100).
+async fn logs_handler(
+    socket_addr: Option<SocketAddr>,
+    cookie: Option<String>,
+    authorization: Option<String>,
+    query_params: HashMap<String, String>,
+    our: Arc<String>,
+    jwt_secret_bytes: Arc<Vec<u8>>,
+    trust_loopback: bool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !authorized(
+        socket_addr,
+        cookie,
+        authorization,
+        &our,
+        &jwt_secret_bytes,
+        trust_loopback,
+    ) {
+        return Ok(warp::reply::with_status(vec![], StatusCode::FORBIDDEN).into_response());
+    }
+    let process = match query_params.get("process").map(|p| p.parse::<ProcessId>()) {
+        None => None,
+        Some(Ok(process)) => Some(process),
+        Some(Err(_)) => {
+            return Ok(warp::reply::with_status(vec![], StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    let level = match query_params.get("level").map(|l| l.parse::<LogLevel>()) {
+        None => LogLevel::Trace,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            return Ok(warp::reply::with_status(vec![], StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    let tail = match query_params.get("tail").map(|t| t.parse::<usize>()) {
+        None => 100,
+        Some(Ok(tail)) => tail,
+        Some(Err(_)) => {
+            return Ok(warp::reply::with_status(vec![], StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    // reading logs back touches the disk, so keep it off the async runtime
+    let Ok(records) =
+        tokio::task::spawn_blocking(move || crate::logging::query(process.as_ref(), level, tail))
+            .await
+    else {
+        return Ok(
+            warp::reply::with_status(vec![], StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        );
+    };
+    Ok(warp::reply::json(&records).into_response())
+}
+
+/// serve message traces in OpenTelemetry's OTLP JSON format: the trace of the
+/// message given by the query param `message`, or the most recent traces
+async fn traces_handler(
+    socket_addr: Option<SocketAddr>,
+    cookie: Option<String>,
+    authorization: Option<String>,
+    query_params: HashMap<String, String>,
+    our: Arc<String>,
+    jwt_secret_bytes: Arc<Vec<u8>>,
+    trust_loopback: bool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !authorized(
+        socket_addr,
+        cookie,
+        authorization,
+        &our,
+        &jwt_secret_bytes,
+        trust_loopback,
+    ) {
+        return Ok(warp::reply::with_status(vec![], StatusCode::FORBIDDEN).into_response());
+    }
+    let message_id = match query_params.get("message").map(|m| m.parse::<u64>()) {
+        None => None,
+        Some(Ok(message_id)) => Some(message_id),
+        Some(Err(_)) => {
+            return Ok(warp::reply::with_status(vec![], StatusCode::BAD_REQUEST).into_response())
+        }
+    };
+    Ok(warp::reply::json(&crate::trace::export_otlp(&our, message_id)).into_response())
+}
+
 async fn ws_handler(
     ws_connection: Ws,
     socket_addr: Option<SocketAddr>,
@@ -630,6 +825,8 @@ async fn http_handler(
                     .unwrap(),
                     metadata: None,
                     capabilities: vec![],
+                    priority: MessagePriority::System,
+                    deadline: None,
                 }),
                 lazy_load_blob: Some(LazyLoadBlob {
                     mime: None,
@@ -770,6 +967,8 @@ async fn handle_rpc_message(
                 },
                 metadata: rpc_message.metadata,
                 capabilities: vec![],
+                priority: MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: blob,
         },
@@ -805,6 +1004,8 @@ fn make_websocket_message(
             .unwrap(),
             metadata: None,
             capabilities: vec![],
+            priority: MessagePriority::System,
+            deadline: None,
         }),
         lazy_load_blob: Some(LazyLoadBlob {
             mime: None,
@@ -833,6 +1034,8 @@ fn make_ext_websocket_message(
                 .unwrap(),
                 metadata: None,
                 capabilities: vec![],
+                priority: MessagePriority::System,
+                deadline: None,
             }),
             Some(LazyLoadBlob {
                 mime: None,
@@ -856,6 +1059,8 @@ fn make_ext_websocket_message(
                     .unwrap(),
                     metadata: None,
                     capabilities: vec![],
+                    priority: MessagePriority::System,
+                    deadline: None,
                 }),
                 MessageType::Response => Message::Response((
                     Response {
@@ -944,6 +1149,8 @@ async fn maintain_websocket(
                     .unwrap(),
                 metadata: None,
                 capabilities: vec![],
+                priority: MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: None,
         })
@@ -1035,6 +1242,8 @@ async fn websocket_close(
                 body: serde_json::to_vec(&HttpServerRequest::WebSocketClose(channel_id)).unwrap(),
                 metadata: None,
                 capabilities: vec![],
+                priority: MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: Some(LazyLoadBlob {
                 mime: None,
@@ -51,10 +51,17 @@ pub fn auth_cookie_valid(our_node: &str, cookie: &str, jwt_secret: &[u8]) -> boo
         }
     }
 
-    let auth_token = match auth_token {
-        Some(token) if !token.is_empty() => token,
-        _ => return false,
-    };
+    match auth_token {
+        Some(token) => auth_token_valid(our_node, &token, jwt_secret),
+        None => false,
+    }
+}
+
+/// whether a token, as set in the auth cookie at login, was issued by this node
+pub fn auth_token_valid(our_node: &str, auth_token: &str, jwt_secret: &[u8]) -> bool {
+    if auth_token.is_empty() {
+        return false;
+    }
 
     let Ok(secret) = Hmac::<Sha256>::new_from_slice(jwt_secret) else {
         return false;
@@ -0,0 +1,144 @@
+use super::now_ms;
+use lib::types::core as t;
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// The column family of the kernel's database the audit log is kept in.
+pub const CAP_AUDIT_CF: &str = "cap_audit";
+
+/// How many entries a query returns if it doesn't set a limit.
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// How many entries a query looks at, at most, before giving up on finding
+/// more that match its filters.
+pub const MAX_QUERY_SCAN: usize = 100_000;
+
+/// How long entries are kept before they are deleted.
+const RETENTION_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+
+/// How often entries past their retention are deleted.
+const PRUNE_INTERVAL_MS: u64 = 60 * 1000;
+
+/// The most entries written to the database at once.
+const MAX_BATCH: usize = 1024;
+
+/// An append-only log of every change to, and use of, a capability.
+/// Entries are keyed by the time they were recorded, followed by a counter
+/// to keep entries recorded in the same millisecond apart. They are written
+/// in batches by a task of their own, and kept for `RETENTION_MS`.
+pub struct AuditLog {
+    db: Arc<DB>,
+    counter: u64,
+    writes: UnboundedSender<(Vec<u8>, Vec<u8>)>,
+}
+
+impl AuditLog {
+    pub fn new(db: Arc<DB>) -> Self {
+        let (writes, entries) = unbounded_channel();
+        tokio::spawn(write_entries(db.clone(), entries));
+        Self {
+            db,
+            counter: 0,
+            writes,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        process: &t::ProcessId,
+        caps: Vec<t::Capability>,
+        event: t::CapAuditEvent,
+    ) {
+        if caps.is_empty() {
+            return;
+        }
+        let entry = t::CapAuditEntry {
+            timestamp: now_ms(),
+            process: process.clone(),
+            caps,
+            event,
+        };
+        self.counter = self.counter.wrapping_add(1);
+        let key = [entry.timestamp.to_be_bytes(), self.counter.to_be_bytes()].concat();
+        let _ = self.writes.send((key, bincode::serialize(&entry).unwrap()));
+    }
+
+    /// start a scan for entries matching the query. the scan runs on a
+    /// blocking task with its own handle to the database, so awaiting it
+    /// doesn't hold up the kernel's event loop.
+    pub fn query(&self, query: t::CapAuditQuery) -> JoinHandle<Vec<t::CapAuditEntry>> {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || query_entries(&db, &query))
+    }
+}
+
+/// entries matching the query, newest first. at most `MAX_QUERY_SCAN`
+/// entries are looked at, starting from the newest in range.
+fn query_entries(db: &DB, query: &t::CapAuditQuery) -> Vec<t::CapAuditEntry> {
+    let Some(cf) = db.cf_handle(CAP_AUDIT_CF) else {
+        return vec![];
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    let until_key = query
+        .until
+        .map(|until| [until.to_be_bytes(), u64::MAX.to_be_bytes()].concat());
+    let mode = match &until_key {
+        Some(key) => IteratorMode::From(key, Direction::Reverse),
+        None => IteratorMode::End,
+    };
+    let mut entries = vec![];
+    for item in db.iterator_cf(&cf, mode).take(MAX_QUERY_SCAN) {
+        if entries.len() >= limit {
+            break;
+        }
+        let Ok((_key, value)) = item else {
+            break;
+        };
+        let Ok(entry) = bincode::deserialize::<t::CapAuditEntry>(&value) else {
+            continue;
+        };
+        if query.since.is_some_and(|since| entry.timestamp < since) {
+            break;
+        }
+        if query.process.as_ref().is_some_and(|p| *p != entry.process) {
+            continue;
+        }
+        if let Some(issuer) = &query.issuer {
+            if !entry.caps.iter().any(|cap| cap.issuer.process == *issuer) {
+                continue;
+            }
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Write recorded entries to the database, as many at a time as have piled
+/// up, deleting those past their retention every so often.
+async fn write_entries(db: Arc<DB>, mut entries: UnboundedReceiver<(Vec<u8>, Vec<u8>)>) {
+    let mut last_pruned = 0;
+    while let Some((key, value)) = entries.recv().await {
+        let Some(cf) = db.cf_handle(CAP_AUDIT_CF) else {
+            continue;
+        };
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&cf, key, value);
+        while batch.len() < MAX_BATCH {
+            let Ok((key, value)) = entries.try_recv() else {
+                break;
+            };
+            batch.put_cf(&cf, key, value);
+        }
+        let now = now_ms();
+        if now.saturating_sub(last_pruned) >= PRUNE_INTERVAL_MS {
+            let cutoff = now.saturating_sub(RETENTION_MS);
+            batch.delete_range_cf(&cf, [0u8; 16], [cutoff.to_be_bytes(), [0u8; 8]].concat());
+            last_pruned = now;
+        }
+        if let Err(e) = db.write(batch) {
+            println!("kernel: failed to write capability audit log: {e}\r");
+        }
+    }
+}
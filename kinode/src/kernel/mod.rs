@@ -1,6 +1,8 @@
+use crate::metrics;
+use crate::trace;
 use crate::KERNEL_PROCESS_ID;
 use anyhow::Result;
-use ring::signature;
+use ring::signature::{self, KeyPair};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
@@ -11,12 +13,18 @@ use wasmtime::{Config, Engine, WasmBacktraceDetails};
 
 use lib::types::core::{self as t, STATE_PROCESS_ID, VFS_PROCESS_ID};
 
+/// Record every change to, and use of, a capability.
+pub mod audit;
 /// Manipulate a single process.
 pub mod process;
+/// Bound the message queue of each process.
+pub mod queues;
+/// Pick the order in which waiting messages are handled.
+pub mod scheduler;
 /// Implement the functions served to processes by `kinode.wit`.
 mod standard_host;
-
-const PROCESS_CHANNEL_CAPACITY: usize = 100;
+/// Hold messages for processes that have been suspended.
+pub mod suspended;
 
 const DEFAULT_WIT_VERSION: u32 = 0;
 
@@ -67,6 +75,8 @@ async fn persist_state(
                     .unwrap(),
                 metadata: None,
                 capabilities: vec![],
+                priority: t::MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: Some(t::LazyLoadBlob { mime: None, bytes }),
         })
@@ -74,6 +84,106 @@ async fn persist_state(
     Ok(())
 }
 
+/// The length of an ed25519 signature.
+const SIGNATURE_LEN: usize = 64;
+
+/// Sign a capability along with the limits it was granted under, if any.
+/// The limits follow the signature, so that they travel with the cap to
+/// other nodes and can be checked when it comes back.
+pub fn sign_capability(
+    keypair: &signature::Ed25519KeyPair,
+    cap: &t::Capability,
+    limits: Option<&t::CapLimits>,
+) -> Vec<u8> {
+    match limits {
+        None => keypair
+            .sign(&rmp_serde::to_vec(cap).unwrap())
+            .as_ref()
+            .to_vec(),
+        Some(limits) => {
+            let mut sig = keypair
+                .sign(&rmp_serde::to_vec(&(cap, limits)).unwrap())
+                .as_ref()
+                .to_vec();
+            sig.extend(rmp_serde::to_vec(limits).unwrap());
+            sig
+        }
+    }
+}
+
+/// Check a signature made by [`sign_capability`], returning the limits it
+/// was made with, if any. Returns None if the signature is bad, or if the
+/// limits it carries have run out.
+pub fn verify_capability(
+    public_key: &[u8],
+    cap: &t::Capability,
+    sig: &[u8],
+    now: u64,
+) -> Option<Option<t::CapLimits>> {
+    let public_key = signature::UnparsedPublicKey::new(&signature::ED25519, public_key);
+    if sig.len() < SIGNATURE_LEN {
+        return None;
+    }
+    let (sig, limits) = sig.split_at(SIGNATURE_LEN);
+    if limits.is_empty() {
+        public_key.verify(&rmp_serde::to_vec(cap).ok()?, sig).ok()?;
+        return Some(None);
+    }
+    let limits: t::CapLimits = rmp_serde::from_slice(limits).ok()?;
+    public_key
+        .verify(&rmp_serde::to_vec(&(cap, &limits)).ok()?, sig)
+        .ok()?;
+    limits.is_valid(now).then_some(Some(limits))
+}
+
+/// seconds since the unix epoch, which capability expiry is measured in
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// milliseconds since the unix epoch, which request deadlines are measured in
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// respond to a kernel command
+async fn send_kernel_response(
+    our_name: &str,
+    id: u64,
+    target: t::Address,
+    response: t::KernelResponse,
+    send_to_loop: &t::MessageSender,
+) {
+    send_to_loop
+        .send(t::KernelMessage {
+            id,
+            source: t::Address {
+                node: our_name.to_string(),
+                process: KERNEL_PROCESS_ID.clone(),
+            },
+            target,
+            rsvp: None,
+            message: t::Message::Response((
+                t::Response {
+                    inherit: false,
+                    body: serde_json::to_vec(&response).unwrap(),
+                    metadata: None,
+                    capabilities: vec![],
+                },
+                None,
+            )),
+            lazy_load_blob: None,
+        })
+        .await
+        .expect("event loop: fatal: sender died");
+}
+
 /// handle commands inside messages sent directly to kernel. source is always our own node.
 /// returns Some(()) if the kernel should shut down.
 async fn handle_kernel_request(
@@ -89,6 +199,10 @@ async fn handle_kernel_request(
     caps_oracle: t::CapMessageSender,
     engine: &Engine,
     home_directory_path: &str,
+    audit: &mut audit::AuditLog,
+    suspended: &mut suspended::Suspended,
+    queues: &mut queues::Queues,
+    scheduler: &mut scheduler::Scheduler,
 ) -> Option<()> {
     let t::Message::Request(request) = km.message else {
         return None;
@@ -111,28 +225,35 @@ async fn handle_kernel_request(
                 let ProcessSender::Userspace(sender) = process_sender else {
                     continue;
                 };
-                let _ = sender
-                    .send(Ok(t::KernelMessage {
-                        id: km.id,
-                        source: t::Address {
-                            node: our_name.clone(),
-                            process: KERNEL_PROCESS_ID.clone(),
-                        },
-                        target: t::Address {
-                            node: our_name.clone(),
-                            process: process_id.clone(),
-                        },
-                        rsvp: None,
-                        message: t::Message::Request(t::Request {
-                            inherit: false,
-                            expects_response: None,
-                            body: b"run".to_vec(),
-                            metadata: None,
-                            capabilities: vec![],
-                        }),
-                        lazy_load_blob: None,
-                    }))
-                    .await;
+                let run = t::KernelMessage {
+                    id: km.id,
+                    source: t::Address {
+                        node: our_name.clone(),
+                        process: KERNEL_PROCESS_ID.clone(),
+                    },
+                    target: t::Address {
+                        node: our_name.clone(),
+                        process: process_id.clone(),
+                    },
+                    rsvp: None,
+                    message: t::Message::Request(t::Request {
+                        inherit: false,
+                        expects_response: None,
+                        body: b"run".to_vec(),
+                        metadata: None,
+                        capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
+                    }),
+                    lazy_load_blob: None,
+                };
+                // a process that was suspended when we shut down only
+                // starts running once it is resumed
+                if suspended.is_suspended(process_id) {
+                    let _ = suspended.push(process_id, Ok(run));
+                    continue;
+                }
+                let _ = sender.send(Ok(run)).await;
             }
         }
         t::KernelCommand::Shutdown => {
@@ -189,21 +310,40 @@ async fn handle_kernel_request(
             };
 
             // check cap sigs & transform valid to unsigned to be plugged into procs
-            let parent_caps: &HashMap<t::Capability, Vec<u8>> =
-                &process_map.get(&km.source.process).unwrap().capabilities;
+            let parent = process_map.get_mut(&km.source.process).unwrap();
             let mut valid_capabilities: HashMap<t::Capability, Vec<u8>> = HashMap::new();
+            let mut valid_cap_limits: HashMap<t::Capability, t::CapLimits> = HashMap::new();
             if km.source.process == "kernel:distro:sys" {
                 for cap in initial_capabilities {
                     let sig = keypair.sign(&rmp_serde::to_vec(&cap).unwrap());
                     valid_capabilities.insert(cap, sig.as_ref().to_vec());
                 }
             } else {
+                let now = now();
                 for cap in initial_capabilities {
-                    match parent_caps.get(&cap) {
+                    match parent.capabilities.get(&cap) {
                         // NOTE: verifying sigs here would be unnecessary
-                        Some(sig) => {
-                            valid_capabilities.insert(cap, sig.to_vec());
-                        }
+                        Some(sig) => match parent.cap_limits.get_mut(&cap) {
+                            None => {
+                                valid_capabilities.insert(cap, sig.to_vec());
+                            }
+                            Some(limits) => {
+                                // a limited cap gives the child a share of the
+                                // parent's uses, so the parent's is re-signed
+                                let Some(delegated) = limits.delegate(&km.source, now) else {
+                                    println!(
+                                        "kernel: InitializeProcess caller {} can't pass on capability\r",
+                                        km.source.process
+                                    );
+                                    continue;
+                                };
+                                let parent_sig = sign_capability(&keypair, &cap, Some(limits));
+                                parent.capabilities.insert(cap.clone(), parent_sig);
+                                let sig = sign_capability(&keypair, &cap, Some(&delegated));
+                                valid_capabilities.insert(cap.clone(), sig);
+                                valid_cap_limits.insert(cap, delegated);
+                            }
+                        },
                         None => {
                             println!(
                                 "kernel: InitializeProcess caller {} doesn't have capability\r",
@@ -246,6 +386,12 @@ async fn handle_kernel_request(
                 .await
                 .expect("event loop: fatal: sender died");
 
+            audit.record(
+                &id,
+                valid_capabilities.keys().cloned().collect(),
+                t::CapAuditEvent::Added,
+            );
+
             // fires "success" response back if successful
             match start_process(
                 our_name.clone(),
@@ -257,6 +403,7 @@ async fn handle_kernel_request(
                 senders,
                 process_handles,
                 process_map,
+                queues,
                 engine,
                 caps_oracle,
                 &StartProcessMetadata {
@@ -272,6 +419,7 @@ async fn handle_kernel_request(
                         on_exit,
                         capabilities: valid_capabilities,
                         public,
+                        cap_limits: valid_cap_limits,
                     },
                     reboot: false,
                 },
@@ -337,7 +485,11 @@ async fn handle_kernel_request(
                     )
                 })
                 .collect();
+            for (cap, _) in &signed_caps {
+                entry.cap_limits.remove(cap);
+            }
             entry.capabilities.extend(signed_caps.clone());
+            audit.record(&target, capabilities, t::CapAuditEvent::Added);
             // add these to reverse cap index
             for (cap, _) in &signed_caps {
                 reverse_cap_index
@@ -365,8 +517,45 @@ async fn handle_kernel_request(
                     .await;
                 return None;
             };
-            for cap in capabilities {
-                entry.capabilities.remove(&cap);
+            for cap in &capabilities {
+                entry.capabilities.remove(cap);
+                entry.cap_limits.remove(cap);
+            }
+            audit.record(&target, capabilities, t::CapAuditEvent::Dropped);
+            let _ = persist_state(&our_name, &send_to_loop, process_map).await;
+        }
+        t::KernelCommand::GrantLimitedCapabilities {
+            target,
+            capabilities,
+        } => {
+            let Some(entry) = process_map.get_mut(&target) else {
+                let _ = send_to_terminal
+                    .send(t::Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "kernel: no such process {:?} to GrantLimitedCapabilities",
+                            target
+                        ),
+                    })
+                    .await;
+                return None;
+            };
+            audit.record(
+                &target,
+                capabilities.iter().map(|(cap, _)| cap.clone()).collect(),
+                t::CapAuditEvent::Added,
+            );
+            for (cap, limits) in capabilities {
+                entry
+                    .capabilities
+                    .insert(cap.clone(), sign_capability(&keypair, &cap, Some(&limits)));
+                entry.cap_limits.insert(cap.clone(), limits);
+                reverse_cap_index
+                    .entry(cap.issuer.process.clone())
+                    .or_insert_with(HashMap::new)
+                    .entry(target.clone())
+                    .or_insert_with(Vec::new)
+                    .push(cap);
             }
             let _ = persist_state(&our_name, &send_to_loop, process_map).await;
         }
@@ -391,6 +580,8 @@ async fn handle_kernel_request(
                             body: b"run".to_vec(),
                             metadata: None,
                             capabilities: vec![],
+                            priority: t::MessagePriority::System,
+                            deadline: None,
                         }),
                         lazy_load_blob: None,
                     }))
@@ -458,6 +649,10 @@ async fn handle_kernel_request(
             // do not do this to a process if you don't want to risk
             // dropped messages / un-replied-to-requests / revoked caps
             let _ = senders.remove(&process_id);
+            // any messages held for the process while suspended are dropped too
+            suspended.forget(&process_id);
+            queues.remove(&process_id);
+            metrics::process_killed(&process_id);
             let process_handle = match process_handles.remove(&process_id) {
                 Some(ph) => ph,
                 None => {
@@ -471,7 +666,13 @@ async fn handle_kernel_request(
                 }
             };
             process_handle.abort();
-            process_map.remove(&process_id);
+            // a process without OnExit behavior, like a worker that has
+            // finished, won't be initialized again, so its priority goes too
+            if let Some(persisted) = process_map.remove(&process_id) {
+                if persisted.on_exit.is_none() {
+                    scheduler.retain(|id| id != &process_id);
+                }
+            }
             caps_oracle
                 .send(t::CapMessage::RevokeAll {
                     on: process_id.clone(),
@@ -518,12 +719,194 @@ async fn handle_kernel_request(
                 .await
                 .expect("event loop: fatal: sender died");
         }
+        t::KernelCommand::SuspendProcess { target, persist } => {
+            let response = match senders.get(&target) {
+                Some(ProcessSender::Userspace(_)) => {
+                    if !suspended.suspend(&target, persist) {
+                        let _ = send_to_terminal
+                            .send(t::Printout {
+                                verbosity: 2,
+                                content: format!("kernel: process {target} is already suspended"),
+                            })
+                            .await;
+                    }
+                    t::KernelResponse::SuspendedProcess(target)
+                }
+                _ => {
+                    let _ = send_to_terminal
+                        .send(t::Printout {
+                            verbosity: 0,
+                            content: format!("kernel: no such process {target} to suspend"),
+                        })
+                        .await;
+                    t::KernelResponse::SuspendProcessError
+                }
+            };
+            if request.expects_response.is_none() {
+                return None;
+            }
+            send_kernel_response(
+                &our_name,
+                km.id,
+                km.rsvp.unwrap_or(km.source),
+                response,
+                &send_to_loop,
+            )
+            .await;
+        }
+        t::KernelCommand::ResumeProcess(process_id) => {
+            let response = match suspended.resume(&process_id) {
+                Some(_) => {
+                    // whatever doesn't fit in the process's queue now is
+                    // handed over as it makes room
+                    let delivered = deliver_held(suspended, queues, &process_id);
+                    t::KernelResponse::ResumedProcess(process_id, delivered)
+                }
+                None => {
+                    let _ = send_to_terminal
+                        .send(t::Printout {
+                            verbosity: 0,
+                            content: format!("kernel: no suspended process {process_id} to resume"),
+                        })
+                        .await;
+                    t::KernelResponse::ResumeProcessError
+                }
+            };
+            if request.expects_response.is_none() {
+                return None;
+            }
+            send_kernel_response(
+                &our_name,
+                km.id,
+                km.rsvp.unwrap_or(km.source),
+                response,
+                &send_to_loop,
+            )
+            .await;
+        }
+        t::KernelCommand::UpgradeProcess {
+            id,
+            wasm_bytes_handle,
+        } => {
+            let respond_to = km.rsvp.unwrap_or(km.source);
+            let Some(ProcessSender::Userspace(sender)) = senders.get(&id) else {
+                if request.expects_response.is_some() {
+                    send_kernel_response(
+                        &our_name,
+                        km.id,
+                        respond_to,
+                        t::KernelResponse::UpgradeProcessNotRunning(id),
+                        &send_to_loop,
+                    )
+                    .await;
+                }
+                return None;
+            };
+            let sender = sender.clone();
+            let wasm_path = format!("{home_directory_path}/vfs/{wasm_bytes_handle}");
+            let wasm_bytes = match tokio::fs::read(wasm_path).await {
+                Ok(wasm_bytes) => wasm_bytes,
+                Err(e) => {
+                    let _ = send_to_terminal
+                        .send(t::Printout {
+                            verbosity: 0,
+                            content: format!(
+                                "kernel: can't upgrade process {id}: couldn't read wasm bytes: {e}"
+                            ),
+                        })
+                        .await;
+                    if request.expects_response.is_some() {
+                        send_kernel_response(
+                            &our_name,
+                            km.id,
+                            respond_to,
+                            t::KernelResponse::UpgradeProcessError,
+                            &send_to_loop,
+                        )
+                        .await;
+                    }
+                    return None;
+                }
+            };
+            // the process handles everything already sent to it with its old
+            // code, then stops at this message to swap in the new code. it
+            // responds to the upgrade itself, once the new code is running.
+            let _ = sender
+                .send(Ok(t::KernelMessage {
+                    id: km.id,
+                    source: t::Address {
+                        node: our_name.clone(),
+                        process: KERNEL_PROCESS_ID.clone(),
+                    },
+                    target: t::Address {
+                        node: our_name.clone(),
+                        process: id,
+                    },
+                    rsvp: request.expects_response.map(|_| respond_to),
+                    message: t::Message::Request(t::Request {
+                        inherit: false,
+                        expects_response: None,
+                        body: b"upgrade".to_vec(),
+                        metadata: Some(wasm_bytes_handle),
+                        capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
+                    }),
+                    lazy_load_blob: Some(t::LazyLoadBlob {
+                        mime: None,
+                        bytes: wasm_bytes,
+                    }),
+                }))
+                .await;
+        }
+        t::KernelCommand::RecordUpgrade {
+            id,
+            wasm_bytes_handle,
+        } => {
+            let Some(entry) = process_map.get_mut(&id) else {
+                return None;
+            };
+            entry.wasm_bytes_handle = wasm_bytes_handle;
+            let _ = persist_state(&our_name, &send_to_loop, process_map).await;
+        }
+        t::KernelCommand::SetQueueConfig { target, config } => {
+            queues.set_config(&target, config);
+        }
+        t::KernelCommand::SetPriority { target, priority } => {
+            scheduler.set_priority(&target, priority);
+        }
+        t::KernelCommand::SetTracing(enabled) => {
+            trace::set_enabled(enabled);
+            let _ = send_to_terminal
+                .send(t::Printout {
+                    verbosity: 0,
+                    content: format!("tracing: {}", if enabled { "on" } else { "off" }),
+                })
+                .await;
+        }
+        t::KernelCommand::GetQueueMetrics => {
+            if request.expects_response.is_none() {
+                return None;
+            }
+            send_kernel_response(
+                &our_name,
+                km.id,
+                km.rsvp.unwrap_or(km.source),
+                t::KernelResponse::QueueMetrics(queues.metrics()),
+                &send_to_loop,
+            )
+            .await;
+        }
         t::KernelCommand::Debug(kind) => match kind {
             t::KernelPrint::ProcessMap => {
                 let mut process_map_string = "".to_string();
                 for (id, process) in &mut *process_map {
                     process_map_string.push_str(&format!("{}: {}\r\n", id, process));
                 }
+                for (id, held) in suspended.suspended() {
+                    process_map_string
+                        .push_str(&format!("{id} is suspended, holding {held} messages\r\n"));
+                }
                 let _ = send_to_terminal
                     .send(t::Printout {
                         verbosity: 0,
@@ -557,13 +940,101 @@ async fn handle_kernel_request(
                             on,
                             process_map
                                 .get(&on)
-                                .map(|p| p.capabilities.contains_key(&cap))
+                                .map(|p| p.has_cap(&cap, now()))
                                 .unwrap_or(false)
                         ),
                     })
                     .await;
             }
+            t::KernelPrint::CapAudit(query) => {
+                let entries = audit.query(query);
+                tokio::spawn(async move {
+                    let entries = entries.await.unwrap_or_default();
+                    let mut audit_string = "".to_string();
+                    for entry in &entries {
+                        let caps: Vec<String> =
+                            entry.caps.iter().map(|cap| cap.to_string()).collect();
+                        audit_string.push_str(&format!(
+                            "{} {}: {:?} [{}]\r\n",
+                            entry.timestamp,
+                            entry.process,
+                            entry.event,
+                            caps.join(", ")
+                        ));
+                    }
+                    let _ = send_to_terminal
+                        .send(t::Printout {
+                            verbosity: 0,
+                            content: format!(
+                                "capability audit log:\r\n{audit_string}\r\nfound {} entries",
+                                entries.len()
+                            ),
+                        })
+                        .await;
+                });
+            }
+            t::KernelPrint::QueueMetrics => {
+                let metrics = queues.metrics();
+                let mut metrics_string = "".to_string();
+                for m in &metrics {
+                    metrics_string.push_str(&format!(
+                        "{}: {}/{} queued, {} at most, {} rejected, {} dropped\r\n",
+                        m.process, m.depth, m.capacity, m.high_water, m.rejected, m.dropped
+                    ));
+                }
+                let _ = send_to_terminal
+                    .send(t::Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "process message queues:\r\n{metrics_string}\r\nfound {} queues",
+                            metrics.len()
+                        ),
+                    })
+                    .await;
+            }
+            t::KernelPrint::Trace(message_id) => {
+                let _ = send_to_terminal
+                    .send(t::Printout {
+                        verbosity: 0,
+                        content: trace::render_tree(message_id),
+                    })
+                    .await;
+            }
         },
+        t::KernelCommand::GetCapAudit(query) => {
+            if request.expects_response.is_none() {
+                return None;
+            }
+            let entries = audit.query(query);
+            let id = km.id;
+            let target = km.rsvp.unwrap_or(km.source);
+            tokio::spawn(async move {
+                let entries = entries.await.unwrap_or_default();
+                send_to_loop
+                    .send(t::KernelMessage {
+                        id,
+                        source: t::Address {
+                            node: our_name,
+                            process: KERNEL_PROCESS_ID.clone(),
+                        },
+                        target,
+                        rsvp: None,
+                        message: t::Message::Response((
+                            t::Response {
+                                inherit: false,
+                                body: serde_json::to_vec(&t::KernelResponse::CapAudit(entries))
+                                    .unwrap(),
+                                metadata: None,
+                                capabilities: vec![],
+                            },
+                            None,
+                        )),
+                        lazy_load_blob: None,
+                    })
+                    .await
+                    .expect("event loop: fatal: sender died");
+            });
+        }
     }
     None
 }
@@ -579,13 +1050,14 @@ async fn start_process(
     senders: &mut Senders,
     process_handles: &mut ProcessHandles,
     process_map: &mut t::ProcessMap,
+    queues: &mut queues::Queues,
     engine: &Engine,
     caps_oracle: t::CapMessageSender,
     process_metadata: &StartProcessMetadata,
     home_directory_path: &str,
 ) -> Result<()> {
     let (send_to_process, recv_in_process) =
-        mpsc::channel::<Result<t::KernelMessage, t::WrappedSendError>>(PROCESS_CHANNEL_CAPACITY);
+        mpsc::channel::<Result<t::KernelMessage, t::WrappedSendError>>(queues::QUEUE_CHANNEL_SIZE);
     let id = &process_metadata.process_id;
     if senders.contains_key(id) {
         let _ = send_to_terminal
@@ -600,6 +1072,7 @@ async fn start_process(
         id.clone(),
         ProcessSender::Userspace(send_to_process.clone()),
     );
+    let deferred = queues.register(id, send_to_process.clone());
     let metadata = t::ProcessMetadata {
         our: t::Address {
             node: our_name.clone(),
@@ -622,6 +1095,7 @@ async fn start_process(
             send_to_terminal.clone(),
             recv_in_process,
             send_to_process,
+            deferred,
             km_blob_bytes,
             caps_oracle,
             engine.clone(),
@@ -681,6 +1155,7 @@ pub async fn kernel(
         bool,
     )>,
     default_pki_entries: Vec<t::KnsUpdate>,
+    db: Arc<rocksdb::DB>,
 ) -> Result<()> {
     let mut config = Config::new();
     config.cache_config_load_default().unwrap();
@@ -716,11 +1191,32 @@ pub async fn kernel(
     let mut process_handles: ProcessHandles = HashMap::new();
 
     let mut is_debug: bool = false;
+    let mut suspended = suspended::Suspended::load(db.clone());
+    let mut queues = queues::Queues::load(db.clone());
+    let mut scheduler = scheduler::Scheduler::load(our.name.clone(), db.clone());
+    let mut audit = audit::AuditLog::new(db);
+    // limited caps that one local process has attached to a message for
+    // another, held until the recipient saves them. keyed by recipient.
+    let mut delegations: HashMap<(t::ProcessId, t::Capability), t::CapLimits> = HashMap::new();
     let mut reboot_processes: Vec<(t::ProcessId, StartProcessMetadata, Vec<u8>)> = vec![];
 
     // filter out OnExit::None processes from process_map
     process_map.retain(|_, persisted| !persisted.on_exit.is_none());
 
+    // drop any held messages for processes that no longer exist
+    let gone: Vec<t::ProcessId> = suspended
+        .suspended()
+        .map(|(process_id, _)| process_id.clone())
+        .filter(|process_id| !process_map.contains_key(process_id))
+        .collect();
+    for process_id in gone {
+        suspended.forget(&process_id);
+    }
+    // priorities are kept across restarts, which kill a process and then
+    // initialize it again, so they are only dropped here
+    scheduler.retain(|process_id| process_map.contains_key(process_id));
+    crate::logging::retain(|process_id| process_map.contains_key(process_id));
+
     for (process_id, persisted) in &process_map {
         // runtime extensions will have a bytes_handle of "", because they have no
         // WASM code saved in filesystem.
@@ -800,6 +1296,7 @@ pub async fn kernel(
             &mut senders,
             &mut process_handles,
             &mut process_map,
+            &mut queues,
             &engine,
             caps_oracle_sender.clone(),
             &metadata,
@@ -841,6 +1338,8 @@ pub async fn kernel(
                 body: serde_json::to_vec(&t::KernelCommand::Booted).unwrap(),
                 metadata: None,
                 capabilities: vec![],
+                priority: t::MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: None,
         })
@@ -866,15 +1365,42 @@ pub async fn kernel(
                     .unwrap(),
                 metadata: None,
                 capabilities: vec![],
+                priority: t::MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: None,
         })
         .await
         .expect("fatal: kernel event loop died");
 
+    // how often queue depths are sampled for metrics
+    let mut metrics_interval = tokio::time::interval(std::time::Duration::from_secs(5));
+    // how often resumed processes are offered the rest of their held messages
+    let mut resume_interval = tokio::time::interval(std::time::Duration::from_millis(50));
+    // how often the process map is persisted after uses of limited caps,
+    // which are counted as they happen but would be too many to save one by one
+    let mut persist_interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut persist_pending = false;
+
     // main event loop
     loop {
         tokio::select! {
+            _ = metrics_interval.tick() => {
+                metrics::sample_queues(queues.metrics());
+                metrics::prune();
+                // forget delegations that expired before their recipient saved them
+                let now = now();
+                delegations.retain(|_, limits| limits.is_valid(now));
+            },
+            _ = persist_interval.tick(), if persist_pending => {
+                persist_pending = false;
+                let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
+            },
+            _ = resume_interval.tick(), if suspended.is_resuming() => {
+                for process_id in suspended.resuming() {
+                    deliver_held(&mut suspended, &mut queues, &process_id);
+                }
+            },
             // debug mode toggle: when on, this loop becomes a manual step-through
             debug = recv_debug_in_loop.recv() => {
                 if let Some(t::DebugCommand::Toggle) = debug {
@@ -891,10 +1417,28 @@ pub async fn kernel(
                         content: format!("{wrapped_network_error:?}")
                     }
                 ).await;
+                trace::failed(wrapped_network_error.id, wrapped_network_error.error.kind);
                 // forward the error to the relevant process
                 match senders.get(&wrapped_network_error.source.process) {
                     Some(ProcessSender::Userspace(sender)) => {
-                        let _ = sender.send(Err(wrapped_network_error)).await;
+                        let process_id = wrapped_network_error.source.process.clone();
+                        if suspended.is_suspended(&process_id) {
+                            let _ = suspended.push(&process_id, Err(wrapped_network_error));
+                        } else {
+                            // errors aren't held to the queue's capacity, so one is
+                            // dropped only if the headroom kept for them is used up too
+                            if let Err((_, Err(wrapped_network_error))) = queues.deliver(&process_id, Err(wrapped_network_error)) {
+                                let _ = send_to_terminal
+                                    .send(t::Printout {
+                                        verbosity: 0,
+                                        content: format!(
+                                            "event loop: queue for {process_id} is full; dropped network error for message {}",
+                                            wrapped_network_error.id,
+                                        )
+                                    })
+                                    .await;
+                            }
+                        }
                     }
                     Some(ProcessSender::Runtime { net_errors, .. }) => {
                         if let Some(net_errors) = net_errors {
@@ -911,6 +1455,7 @@ pub async fn kernel(
                                     match wrapped_network_error.error.kind {
                                         t::SendErrorKind::Timeout => "due to timeout",
                                         t::SendErrorKind::Offline => "because the receiver is offline",
+                                        t::SendErrorKind::QueueFull => "because the receiver's queue is full",
                                     },
                                 )
                             })
@@ -919,8 +1464,26 @@ pub async fn kernel(
                 }
             },
             // main message receiver: kernel filters and dispatches messages
-            kernel_message = recv_in_loop.recv() => {
+            kernel_message = scheduler.next(&mut recv_in_loop) => {
                 let mut kernel_message = kernel_message.expect("fatal: event loop died");
+                // a request that waited past its deadline is failed, not delivered
+                if let t::Message::Request(request) = &kernel_message.message {
+                    if request.deadline.is_some_and(|deadline| deadline <= now_ms()) {
+                        throw_timeout(&our.name, &senders, &kernel_message).await;
+                        let _ = send_to_terminal
+                            .send(t::Printout {
+                                verbosity: 2,
+                                content: format!(
+                                    "event loop: request {} from {} to {} passed its deadline",
+                                    kernel_message.id,
+                                    kernel_message.source,
+                                    kernel_message.target,
+                                ),
+                            })
+                            .await;
+                        continue;
+                    }
+                }
                 // the kernel treats the node-string "our" as a special case,
                 // and replaces it with the name of the node this kernel is running.
                 if kernel_message.source.node == "our" {
@@ -936,18 +1499,30 @@ pub async fn kernel(
                 // enforce that if message is directed over the network, process has capability to do so
                 if kernel_message.source.node == our.name
                   && kernel_message.target.node != our.name {
-                    let Some(proc) = process_map.get(&kernel_message.source.process) else {
+                    if !process_map.contains_key(&kernel_message.source.process) {
                         continue
+                    }
+                    let network_cap = t::Capability {
+                        issuer: t::Address {
+                            node: our.name.clone(),
+                            process: KERNEL_PROCESS_ID.clone(),
+                        },
+                        params: "\"network\"".into(),
                     };
-                    if !proc.capabilities.contains_key(
-                        &t::Capability {
-                            issuer: t::Address {
-                                node: our.name.clone(),
-                                process: KERNEL_PROCESS_ID.clone(),
-                            },
-                            params: "\"network\"".into(),
-                        }
-                    ) {
+                    let allowed = use_cap(
+                        &keypair,
+                        &mut process_map,
+                        &mut audit,
+                        &mut persist_pending,
+                        &kernel_message.source.process,
+                        &network_cap,
+                    );
+                    audit.record(
+                        &kernel_message.source.process,
+                        vec![network_cap],
+                        t::CapAuditEvent::Sent { target: kernel_message.target.clone(), allowed },
+                    );
+                    if !allowed {
                         // capabilities are not correct! skip this message.
                         throw_timeout(&our.name, &senders, &kernel_message).await;
                         let _ = send_to_terminal.send(
@@ -965,7 +1540,7 @@ pub async fn kernel(
                     // note that messaging restrictions only apply to *local* processes:
                     // your process can be messaged by any process remotely if it has
                     // networking capabilities.
-                    let Some(persisted) = process_map.get(&kernel_message.target.process) else {
+                    if !process_map.contains_key(&kernel_message.target.process) {
                         let _ = send_to_terminal
                             .send(t::Printout {
                                 verbosity: 0,
@@ -985,15 +1560,28 @@ pub async fn kernel(
                             })
                             .await;
                         continue;
+                    }
+                    let network_cap = t::Capability {
+                        issuer: t::Address {
+                            node: our.name.clone(),
+                            process: KERNEL_PROCESS_ID.clone(),
+                        },
+                        params: "\"network\"".into(),
                     };
-                    if !persisted.capabilities.contains_key(
-                            &t::Capability {
-                                issuer: t::Address {
-                                node: our.name.clone(),
-                                process: KERNEL_PROCESS_ID.clone(),
-                            },
-                            params: "\"network\"".into(),
-                    }) {
+                    let allowed = use_cap(
+                        &keypair,
+                        &mut process_map,
+                        &mut audit,
+                        &mut persist_pending,
+                        &kernel_message.target.process,
+                        &network_cap,
+                    );
+                    audit.record(
+                        &kernel_message.target.process,
+                        vec![network_cap],
+                        t::CapAuditEvent::Received { source: kernel_message.source.clone(), allowed },
+                    );
+                    if !allowed {
                         // capabilities are not correct! skip this message.
                         let _ = send_to_terminal.send(
                             t::Printout {
@@ -1013,37 +1601,111 @@ pub async fn kernel(
                         && kernel_message.source.process != *STATE_PROCESS_ID
                         && kernel_message.source.process != *VFS_PROCESS_ID
                     {
-                        let Some(persisted_source) = process_map.get(&kernel_message.source.process) else {
+                        if !process_map.contains_key(&kernel_message.source.process) {
                             throw_timeout(&our.name, &senders, &kernel_message).await;
                             continue
-                        };
+                        }
                         let Some(persisted_target) = process_map.get(&kernel_message.target.process) else {
                             throw_timeout(&our.name, &senders, &kernel_message).await;
                             continue
                         };
-                        if !persisted_target.public && !persisted_source.capabilities.contains_key(&t::Capability {
+                        if !persisted_target.public {
+                            let messaging_cap = t::Capability {
                                 issuer: t::Address {
                                     node: our.name.clone(),
                                     process: kernel_message.target.process.clone(),
                                 },
                                 params: "\"messaging\"".into(),
-                            }) {
-                            // capabilities are not correct! skip this message.
-                            throw_timeout(&our.name, &senders, &kernel_message).await;
-                            let _ = send_to_terminal.send(
-                                t::Printout {
-                                    verbosity: 0,
-                                    content: format!(
-                                        "event loop: process {} doesn't have capability to message process {}",
-                                        kernel_message.source.process, kernel_message.target.process
-                                    )
-                                }
-                            ).await;
-                            continue;
+                            };
+                            let allowed = use_cap(
+                                &keypair,
+                                &mut process_map,
+                                &mut audit,
+                                &mut persist_pending,
+                                &kernel_message.source.process,
+                                &messaging_cap,
+                            );
+                            audit.record(
+                                &kernel_message.source.process,
+                                vec![messaging_cap],
+                                t::CapAuditEvent::Sent { target: kernel_message.target.clone(), allowed },
+                            );
+                            if !allowed {
+                                // capabilities are not correct! skip this message.
+                                throw_timeout(&our.name, &senders, &kernel_message).await;
+                                let _ = send_to_terminal.send(
+                                    t::Printout {
+                                        verbosity: 0,
+                                        content: format!(
+                                            "event loop: process {} doesn't have capability to message process {}",
+                                            kernel_message.source.process, kernel_message.target.process
+                                        )
+                                    }
+                                ).await;
+                                continue;
+                            }
                         }
                     }
                 }
                 // end capabilities checks
+                // the recipient's share of a limited cap's uses is taken from the
+                // sender, and a limited cap that can't be shared is taken off the
+                // message. a local recipient gets its limits once it saves the cap;
+                // a remote one gets them in the cap's signature.
+                let caps = match &mut kernel_message.message {
+                    t::Message::Request(request) => &mut request.capabilities,
+                    t::Message::Response((response, _)) => &mut response.capabilities,
+                };
+                if kernel_message.source.node == our.name {
+                    let to_local = kernel_message.target.node == our.name;
+                    if let Some(source) = process_map.get_mut(&kernel_message.source.process) {
+                        let now = now();
+                        let mut delegated_any = false;
+                        caps.retain_mut(|(cap, sig)| {
+                            let Some(limits) = source.cap_limits.get_mut(cap) else {
+                                return true;
+                            };
+                            let Some(delegated) = limits.delegate(&kernel_message.source, now) else {
+                                return false;
+                            };
+                            let source_sig = sign_capability(&keypair, cap, Some(limits));
+                            source.capabilities.insert(cap.clone(), source_sig);
+                            delegated_any = true;
+                            if !to_local {
+                                *sig = sign_capability(&keypair, cap, Some(&delegated));
+                                return true;
+                            }
+                            delegations
+                                .entry((kernel_message.target.process.clone(), cap.clone()))
+                                .and_modify(|pending| {
+                                    // uses already passed on and not yet saved aren't lost
+                                    pending.uses_left = pending
+                                        .uses_left
+                                        .zip(delegated.uses_left)
+                                        .map(|(a, b)| a + b);
+                                })
+                                .or_insert(delegated);
+                            true
+                        });
+                        if delegated_any {
+                            persist_pending = true;
+                        }
+                    }
+                } else if kernel_message.target.node == our.name {
+                    // a limited cap we issued that comes back from another node
+                    // keeps the limits it was signed with once the recipient saves it
+                    let now = now();
+                    for (cap, sig) in caps.iter() {
+                        if cap.issuer.node != our.name {
+                            continue;
+                        }
+                        if let Some(Some(limits)) =
+                            verify_capability(keypair.public_key().as_ref(), cap, sig, now)
+                        {
+                            delegations.insert((kernel_message.target.process.clone(), cap.clone()), limits);
+                        }
+                    }
+                }
                 // if debug mode is on, wait for user to step through
                 while is_debug {
                     let debug = recv_debug_in_loop.recv().await.expect("event loop: debug channel died");
@@ -1059,6 +1721,16 @@ pub async fn kernel(
                             content: format!("{kernel_message}")
                         }
                     ).await;
+                if kernel_message.source.node == our.name {
+                    metrics::message_sent(&kernel_message.source.process);
+                    if let t::Message::Response(_) = kernel_message.message {
+                        metrics::runtime_response(kernel_message.id, &kernel_message.source.process);
+                    }
+                }
+                if kernel_message.target.node == our.name {
+                    metrics::message_received(&kernel_message.target.process);
+                }
+                trace::routed(&our.name, &kernel_message);
 
                 if our.name != kernel_message.target.node {
                     send_to_net.send(kernel_message).await.expect("fatal: net module died");
@@ -1080,17 +1752,77 @@ pub async fn kernel(
                         caps_oracle_sender.clone(),
                         &engine,
                         &home_directory_path,
+                        &mut audit,
+                        &mut suspended,
+                        &mut queues,
+                        &mut scheduler,
                     ).await {
                         // shut down the node
                         return Ok(());
                     }
+                } else if suspended.is_suspended(&kernel_message.target.process) {
+                    // hold the message until the process is resumed
+                    let target = kernel_message.target.process.clone();
+                    if let Err(Ok(kernel_message)) = suspended.push(&target, Ok(kernel_message)) {
+                        throw_send_error(&our.name, &senders, &kernel_message, t::SendErrorKind::QueueFull).await;
+                        let _ = send_to_terminal
+                            .send(t::Printout {
+                                verbosity: 0,
+                                content: format!(
+                                    "event loop: process {target} is suspended and holding too many messages; dropped one from {}",
+                                    kernel_message.source.process,
+                                )
+                            })
+                            .await;
+                    }
                 } else {
                     // pass message to appropriate runtime module or process
                     match senders.get(&kernel_message.target.process) {
-                        Some(ProcessSender::Userspace(sender)) => {
-                            let _ = sender.send(Ok(kernel_message)).await;
+                        Some(ProcessSender::Userspace(_)) => {
+                            let target = kernel_message.target.process.clone();
+                            match queues.deliver(&target, Ok(kernel_message)) {
+                                Ok(()) => {}
+                                Err((policy, Ok(kernel_message))) => {
+                                    let is_request = matches!(kernel_message.message, t::Message::Request(_));
+                                    if !is_request {
+                                        // a response is refused only once the headroom
+                                        // kept for responses is used up too
+                                        trace::failed(kernel_message.id, t::SendErrorKind::QueueFull);
+                                    } else if policy == t::QueuePolicy::Reject {
+                                        throw_send_error(&our.name, &senders, &kernel_message, t::SendErrorKind::QueueFull).await;
+                                    }
+                                    let _ = send_to_terminal
+                                        .send(t::Printout {
+                                            verbosity: if is_request { 2 } else { 0 },
+                                            content: format!(
+                                                "event loop: queue for {target} is full; {} {} from {}",
+                                                match (is_request, policy) {
+                                                    (true, t::QueuePolicy::Reject) => "rejected",
+                                                    _ => "dropped",
+                                                },
+                                                if is_request { "request" } else { "response" },
+                                                kernel_message.source.process,
+                                            )
+                                        })
+                                        .await;
+                                }
+                                Err((_, Err(wrapped_send_error))) => {
+                                    let _ = send_to_terminal
+                                        .send(t::Printout {
+                                            verbosity: 0,
+                                            content: format!(
+                                                "event loop: queue for {target} is full; dropped send error for message {}",
+                                                wrapped_send_error.id,
+                                            )
+                                        })
+                                        .await;
+                                }
+                            }
                         }
                         Some(ProcessSender::Runtime { sender, .. }) => {
+                            if let t::Message::Request(t::Request { expects_response: Some(timeout), .. }) = kernel_message.message {
+                                metrics::runtime_request(kernel_message.id, &kernel_message.target.process, timeout);
+                            }
                             sender.send(kernel_message).await.expect("event loop: fatal: runtime module died");
                         }
                         None => {
@@ -1130,11 +1862,20 @@ pub async fn kernel(
                             let _ = responder.send(false);
                             continue;
                         };
-                        let signed_caps: Vec<(t::Capability, Vec<u8>)> =
-                            caps.iter().map(|cap| (
-                                cap.clone(),
-                                keypair.sign(&rmp_serde::to_vec(&cap).unwrap()).as_ref().to_vec()
-                            )).collect();
+                        let mut signed_caps: Vec<(t::Capability, Vec<u8>)> = vec![];
+                        for cap in caps {
+                            // a cap that was delegated to this process keeps its limits,
+                            // unless the process already holds it without any
+                            let limits = delegations.remove(&(on.clone(), cap.clone())).filter(|_| {
+                                !entry.capabilities.contains_key(&cap) || entry.cap_limits.contains_key(&cap)
+                            });
+                            let sig = sign_capability(&keypair, &cap, limits.as_ref());
+                            match limits {
+                                Some(limits) => entry.cap_limits.insert(cap.clone(), limits),
+                                None => entry.cap_limits.remove(&cap),
+                            };
+                            signed_caps.push((cap, sig));
+                        }
                         entry.capabilities.extend(signed_caps.clone());
                         // now we have to insert all caps into the reverse cap index
                         for (cap, _) in &signed_caps {
@@ -1145,6 +1886,11 @@ pub async fn kernel(
                                 .or_insert_with(Vec::new)
                                 .push(cap.clone());
                         }
+                        audit.record(
+                            &on,
+                            signed_caps.into_iter().map(|(cap, _)| cap).collect(),
+                            t::CapAuditEvent::Added,
+                        );
                         let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
                         let _ = responder.send(true);
                     },
@@ -1156,29 +1902,45 @@ pub async fn kernel(
                         };
                         for cap in &caps {
                             entry.capabilities.remove(&cap);
+                            entry.cap_limits.remove(&cap);
                         }
+                        audit.record(&on, caps, t::CapAuditEvent::Dropped);
                         let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
                         let _ = responder.send(true);
                     },
                     t::CapMessage::Has { on, cap, responder } => {
-                        // return boolean on responder
-                        let _ = responder.send(
-                            match process_map.get(&on) {
-                                None => false,
-                                Some(p) => p.capabilities.contains_key(&cap),
-                            }
+                        // return boolean on responder, counting this check as a use
+                        // of the cap if its uses are limited
+                        let has_cap = use_cap(
+                            &keypair,
+                            &mut process_map,
+                            &mut audit,
+                            &mut persist_pending,
+                            &on,
+                            &cap,
                         );
+                        let _ = responder.send(has_cap);
+                        audit.record(&on, vec![cap], t::CapAuditEvent::Checked { allowed: has_cap });
                     },
                     t::CapMessage::GetAll { on, responder } => {
                         // return all caps, signed, on responder
-                        let _ = responder.send(
-                            match process_map.get(&on) {
-                                None => vec![],
-                                Some(p) => p.capabilities.clone().into_iter().collect(),
-                            }
-                        );
+                        let Some(p) = process_map.get_mut(&on) else {
+                            let _ = responder.send(vec![]);
+                            continue;
+                        };
+                        let pruned = p.prune_caps(now());
+                        let _ = responder.send(p.capabilities.clone().into_iter().collect());
+                        if !pruned.is_empty() {
+                            audit.record(&on, pruned, t::CapAuditEvent::Expired);
+                            let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
+                        }
                     },
                     t::CapMessage::RevokeAll { on, responder } => {
+                        // the process is being killed: drop any caps it or others
+                        // passed it that it never saved, and any it passed on
+                        delegations.retain(|(recipient, cap), _| {
+                            *recipient != on && cap.issuer.process != on
+                        });
                         let Some(granter) = reverse_cap_index.get(&on) else {
                             let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
                             let _ = responder.send(true);
@@ -1188,31 +1950,40 @@ pub async fn kernel(
                             if let Some(entry) = process_map.get_mut(&grantee) {
                                 for cap in caps {
                                     entry.capabilities.remove(&cap);
+                                    entry.cap_limits.remove(&cap);
                                 }
                             };
+                            audit.record(grantee, caps.clone(), t::CapAuditEvent::Revoked);
                         }
                         let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
                         let _ = responder.send(true);
                     }
                     t::CapMessage::FilterCaps { on, caps, responder } => {
+                        let Some(p) = process_map.get_mut(&on) else {
+                            let _ = responder.send(vec![]);
+                            continue;
+                        };
+                        let pruned = p.prune_caps(now());
                         let _ = responder.send(
-                            match process_map.get(&on) {
-                                None => vec![],
-                                Some(p) => {
-                                    caps.into_iter().filter_map(|cap| {
-                                        // if issuer is message source, then sign the cap
-                                        if cap.issuer.process == on {
-                                            let sig = keypair.sign(&rmp_serde::to_vec(&cap).unwrap());
-                                            Some((cap, sig.as_ref().to_vec()))
-                                        // otherwise, only attach previously saved caps
-                                        // NOTE we don't need to verify the sigs!
-                                        } else {
-                                            p.capabilities.get(&cap).map(|sig| (cap, sig.clone()))
-                                        }
-                                    }).collect()
-                                },
-                            }
+                            caps.into_iter().filter_map(|cap| {
+                                // if issuer is message source, then sign the cap
+                                if cap.issuer.process == on {
+                                    let sig = keypair.sign(&rmp_serde::to_vec(&cap).unwrap());
+                                    Some((cap, sig.as_ref().to_vec()))
+                                // a limited cap may only be passed on if it was granted as delegable
+                                } else if p.cap_limits.get(&cap).is_some_and(|limits| !limits.delegable) {
+                                    None
+                                // otherwise, only attach previously saved caps
+                                // NOTE we don't need to verify the sigs!
+                                } else {
+                                    p.capabilities.get(&cap).map(|sig| (cap, sig.clone()))
+                                }
+                            }).collect()
                         );
+                        if !pruned.is_empty() {
+                            audit.record(&on, pruned, t::CapAuditEvent::Expired);
+                            let _ = persist_state(&our.name, &send_to_loop, &process_map).await;
+                        }
                     },
                 }
             }
@@ -1220,11 +1991,66 @@ pub async fn kernel(
     }
 }
 
+/// Check that a process holds a valid `cap`, counting this as one use of it
+/// if its uses are limited. A limited cap is then re-signed over the uses it
+/// has left, or dropped if that was its last, and `persist_pending` set so
+/// the process map is persisted on the next tick rather than on every check.
+fn use_cap(
+    keypair: &signature::Ed25519KeyPair,
+    process_map: &mut t::ProcessMap,
+    audit: &mut audit::AuditLog,
+    persist_pending: &mut bool,
+    process_id: &t::ProcessId,
+    cap: &t::Capability,
+) -> bool {
+    let Some(p) = process_map.get_mut(process_id) else {
+        return false;
+    };
+    let limited = p.cap_limits.contains_key(cap);
+    let has_cap = p.use_cap(cap, now());
+    if has_cap && limited {
+        match p.cap_limits.get(cap) {
+            Some(limits) => {
+                let sig = sign_capability(keypair, cap, Some(limits));
+                p.capabilities.insert(cap.clone(), sig);
+            }
+            None => audit.record(process_id, vec![cap.clone()], t::CapAuditEvent::Expired),
+        }
+        *persist_pending = true;
+    }
+    has_cap
+}
+
+/// Hand a resumed process as many of its held messages as fit in its queue,
+/// in order. The rest stay held, and new messages wait behind them, until
+/// there is room. Returns how many were handed over.
+fn deliver_held(
+    suspended: &mut suspended::Suspended,
+    queues: &mut queues::Queues,
+    process_id: &t::ProcessId,
+) -> usize {
+    suspended.deliver_held(process_id, |message| {
+        queues.deliver_if_room(process_id, message)
+    })
+}
+
 async fn throw_timeout(
     our_name: &str,
     senders: &HashMap<t::ProcessId, ProcessSender>,
     km: &t::KernelMessage,
 ) {
+    throw_send_error(our_name, senders, km, t::SendErrorKind::Timeout).await
+}
+
+/// if the sender of a request that couldn't be delivered expects a response,
+/// give it a send error instead
+async fn throw_send_error(
+    our_name: &str,
+    senders: &HashMap<t::ProcessId, ProcessSender>,
+    km: &t::KernelMessage,
+    kind: t::SendErrorKind,
+) {
+    trace::failed(km.id, kind);
     if let t::Message::Request(req) = &km.message {
         if req.expects_response.is_some() {
             match senders.get(&km.source.process) {
@@ -1237,7 +2063,7 @@ async fn throw_timeout(
                                 process: KERNEL_PROCESS_ID.clone(),
                             },
                             error: t::SendError {
-                                kind: t::SendErrorKind::Timeout,
+                                kind,
                                 target: km.target.clone(),
                                 lazy_load_blob: km.lazy_load_blob.clone(),
                                 message: km.message.clone(),
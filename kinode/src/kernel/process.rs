@@ -1,3 +1,6 @@
+use crate::logging::{self, LogLevel, LogRecord};
+use crate::metrics;
+use crate::trace;
 use crate::KERNEL_PROCESS_ID;
 use anyhow::Result;
 use lib::types::core as t;
@@ -6,6 +9,7 @@ pub use lib::wit::Host as StandardHost;
 pub use lib::Process;
 use ring::signature::{self, KeyPair};
 use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::fs;
 use tokio::task::JoinHandle;
@@ -52,8 +56,28 @@ pub struct ProcessState {
     /// store the messages that we've gotten from event loop but haven't processed yet
     /// TODO make this an ordered map for O(1) retrieval by ID
     pub message_queue: VecDeque<Result<t::KernelMessage, t::WrappedSendError>>,
+    /// the length of `message_queue`, shared with the kernel so it can bound
+    /// the number of messages waiting for this process
+    pub deferred: Arc<AtomicUsize>,
     /// pipe for getting info about capabilities
     pub caps_oracle: t::CapMessageSender,
+    /// set when the kernel tells us to swap in new code: the process stops
+    /// at its next call to `receive` and is started again with the new code
+    pub upgrade: Option<Upgrade>,
+    /// set while new code is starting after an upgrade: the id and rsvp of
+    /// the `UpgradeProcess` command, to respond to once the new code is running
+    pub upgrading: Option<(u64, Option<t::Address>)>,
+    /// when the process last got control back from `receive`, so the time
+    /// it spends running can be counted in metrics
+    pub busy_since: Option<std::time::Instant>,
+}
+
+/// New code for a running process, sent by the kernel on `UpgradeProcess`
+pub struct Upgrade {
+    pub wasm_bytes_handle: String,
+    pub wasm_bytes: Vec<u8>,
+    pub id: u64,
+    pub rsvp: Option<t::Address>,
 }
 
 pub struct ProcessWasi {
@@ -105,14 +129,117 @@ pub async fn send_and_await_response(
 impl ProcessState {
     /// Ingest latest message directed to this process, and save it as the current message.
     /// If there is no message in the queue, wait async until one is received.
+    /// Returns None if the kernel has told the process to upgrade.
     pub async fn get_next_message_for_process(
         &mut self,
-    ) -> Result<(wit::Address, wit::Message), (wit::SendError, Option<wit::Context>)> {
+    ) -> Option<Result<(wit::Address, wit::Message), (wit::SendError, Option<wit::Context>)>> {
+        // new code started by an upgrade has reached its message loop,
+        // so the upgrade succeeded
+        if let Some((id, rsvp)) = self.upgrading.take() {
+            self.finish_upgrade(id, rsvp, true).await;
+        }
+        if let Some(busy_since) = self.busy_since.take() {
+            metrics::wasm_time(&self.metadata.our.process, busy_since.elapsed());
+        }
         let res = match self.message_queue.pop_front() {
-            Some(message_from_queue) => message_from_queue,
+            Some(message_from_queue) => {
+                self.deferred
+                    .store(self.message_queue.len(), Ordering::Relaxed);
+                message_from_queue
+            }
             None => self.ingest_message().await,
         };
-        self.kernel_message_to_process_receive(res)
+        match res {
+            Ok(km) if self.is_upgrade(&km) => {
+                let t::Message::Request(request) = km.message else {
+                    unreachable!()
+                };
+                self.upgrade = Some(Upgrade {
+                    wasm_bytes_handle: request.metadata.unwrap_or_default(),
+                    wasm_bytes: km.lazy_load_blob.map(|blob| blob.bytes).unwrap_or_default(),
+                    id: km.id,
+                    rsvp: km.rsvp,
+                });
+                None
+            }
+            res => {
+                self.busy_since = Some(std::time::Instant::now());
+                Some(self.kernel_message_to_process_receive(res))
+            }
+        }
+    }
+
+    /// whether a message is the kernel telling us to swap in new code
+    fn is_upgrade(&self, km: &t::KernelMessage) -> bool {
+        km.source.node == self.metadata.our.node
+            && km.source.process == *KERNEL_PROCESS_ID
+            && match &km.message {
+                t::Message::Request(request) => {
+                    request.body == b"upgrade" && request.metadata.is_some()
+                }
+                t::Message::Response(_) => false,
+            }
+    }
+
+    /// report how an upgrade went: to the kernel, so it persists the new
+    /// code, and to whoever asked for the upgrade
+    async fn finish_upgrade(&self, id: u64, rsvp: Option<t::Address>, success: bool) {
+        let our_kernel = t::Address {
+            node: self.metadata.our.node.clone(),
+            process: KERNEL_PROCESS_ID.clone(),
+        };
+        if success {
+            let _ = self
+                .send_to_loop
+                .send(t::KernelMessage {
+                    id: rand::random(),
+                    source: our_kernel.clone(),
+                    target: our_kernel.clone(),
+                    rsvp: None,
+                    message: t::Message::Request(t::Request {
+                        inherit: false,
+                        expects_response: None,
+                        body: serde_json::to_vec(&t::KernelCommand::RecordUpgrade {
+                            id: self.metadata.our.process.clone(),
+                            wasm_bytes_handle: self.metadata.wasm_bytes_handle.clone(),
+                        })
+                        .unwrap(),
+                        metadata: None,
+                        capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
+                    }),
+                    lazy_load_blob: None,
+                })
+                .await;
+        }
+        let Some(rsvp) = rsvp else {
+            return;
+        };
+        let response = if success {
+            t::KernelResponse::UpgradedProcess(self.metadata.our.process.clone())
+        } else {
+            t::KernelResponse::UpgradeProcessError
+        };
+        let _ = self
+            .send_to_loop
+            .send(t::KernelMessage {
+                id,
+                source: our_kernel,
+                target: rsvp,
+                rsvp: None,
+                message: t::Message::Response((
+                    t::Response {
+                        inherit: false,
+                        body: serde_json::to_vec(&response).unwrap(),
+                        metadata: None,
+                        capabilities: vec![],
+                    },
+                    None,
+                )),
+                lazy_load_blob: None,
+            })
+            .await;
     }
 
     /// instead of ingesting latest, wait for a specific ID and queue all others
@@ -125,6 +252,8 @@ impl ProcessState {
             match message {
                 Ok(ref km) if km.id == awaited_message_id => {
                     let km = self.message_queue.remove(i).unwrap();
+                    self.deferred
+                        .store(self.message_queue.len(), Ordering::Relaxed);
                     return self.kernel_message_to_process_receive(km);
                 }
                 _ => continue,
@@ -141,6 +270,8 @@ impl ProcessState {
                 return self.kernel_message_to_process_receive(res);
             } else {
                 self.message_queue.push_back(res);
+                self.deferred
+                    .store(self.message_queue.len(), Ordering::Relaxed);
             }
         }
     }
@@ -164,7 +295,14 @@ impl ProcessState {
                             return message;
                         }
                     }
-                    _ => {
+                    t::Message::Request(request) => {
+                        // its sender has already stopped waiting for a response
+                        if request
+                            .deadline
+                            .is_some_and(|deadline| deadline <= super::now_ms())
+                        {
+                            continue;
+                        }
                         return message;
                     }
                 },
@@ -216,10 +354,8 @@ impl ProcessState {
             },
         };
 
-        let pk = signature::UnparsedPublicKey::new(
-            &signature::ED25519,
-            self.keypair.as_ref().public_key(),
-        );
+        let public_key = self.keypair.as_ref().public_key().as_ref();
+        let now = crate::kernel::now();
 
         // prune any invalid capabilities before handing to process
         // where invalid = supposedly issued by us, but not signed properly by us
@@ -231,10 +367,7 @@ impl ProcessState {
                     if km.source.node != self.metadata.our.node
                         && cap.issuer.node == self.metadata.our.node
                     {
-                        match pk.verify(&rmp_serde::to_vec(&cap).unwrap_or_default(), sig) {
-                            Ok(_) => true,
-                            Err(_) => false,
-                        }
+                        crate::kernel::verify_capability(public_key, cap, sig, now).is_some()
                     } else {
                         return true;
                     }
@@ -247,10 +380,7 @@ impl ProcessState {
                     if km.source.node != self.metadata.our.node
                         && cap.issuer.node == self.metadata.our.node
                     {
-                        match pk.verify(&rmp_serde::to_vec(&cap).unwrap_or_default(), sig) {
-                            Ok(_) => true,
-                            Err(_) => false,
-                        }
+                        crate::kernel::verify_capability(public_key, cap, sig, now).is_some()
                     } else {
                         return true;
                     }
@@ -301,6 +431,9 @@ impl ProcessState {
                 }
             }
         };
+        if let Some(prompting_message) = &self.prompting_message {
+            trace::link(request_id, prompting_message.id);
+        }
 
         // if a blob is provided, it will be used; otherwise, if inherit is true,
         // and a predecessor exists, its blob will be used; otherwise, no blob will be used.
@@ -335,6 +468,11 @@ impl ProcessState {
             };
         }
 
+        // the kernel won't deliver a request after its sender stops waiting for a response
+        request.deadline = request
+            .expects_response
+            .map(|timeout_secs| super::now_ms().saturating_add(timeout_secs.saturating_mul(1000)));
+
         // if the request expects a response, modify the process' context map as needed
         // and set a timer.
         // TODO optimize this SIGNIFICANTLY: stop spawning tasks
@@ -487,6 +625,7 @@ pub async fn make_process_loop(
     send_to_terminal: t::PrintSender,
     mut recv_in_process: t::ProcessMessageReceiver,
     send_to_process: t::ProcessMessageSender,
+    deferred: Arc<AtomicUsize>,
     wasm_bytes: Vec<u8>,
     caps_oracle: t::CapMessageSender,
     engine: Engine,
@@ -513,6 +652,8 @@ pub async fn make_process_loop(
                             body: b"run".to_vec(),
                             metadata: None,
                             capabilities: vec![],
+                            priority: t::MessagePriority::System,
+                            deadline: None,
                         }))
                 {
                     break;
@@ -526,120 +667,91 @@ pub async fn make_process_loop(
         send_to_process.send(message).await?;
     }
 
-    let component =
-        Component::new(&engine, wasm_bytes.clone()).expect("make_process_loop: couldn't read file");
-
-    let mut linker = Linker::new(&engine);
-    Process::add_to_linker(&mut linker, |state: &mut ProcessWasi| state).unwrap();
-
-    let table = Table::new();
-    let wasi_stderr = MemoryOutputPipe::new(STACK_TRACE_SIZE);
-
-    let tmp_path = format!(
-        "{}/vfs/{}:{}/tmp",
-        home_directory_path,
-        metadata.our.process.package(),
-        metadata.our.process.publisher()
-    );
+    let mut process = ProcessState {
+        keypair: keypair.clone(),
+        metadata: metadata.clone(),
+        recv_in_process,
+        self_sender: send_to_process,
+        send_to_loop: send_to_loop.clone(),
+        send_to_terminal: send_to_terminal.clone(),
+        prompting_message: None,
+        last_blob: None,
+        contexts: HashMap::new(),
+        message_queue: VecDeque::new(),
+        deferred,
+        caps_oracle: caps_oracle.clone(),
+        upgrade: None,
+        upgrading: None,
+        busy_since: Some(std::time::Instant::now()),
+    };
+    let mut wasm_bytes = wasm_bytes;
+    // while a process is being upgraded, its old code, to roll back to
+    let mut previous: Option<(String, Vec<u8>)> = None;
 
-    let mut wasi = WasiCtxBuilder::new();
+    // the process will run until it returns from init() or crashes.
+    // if it is upgraded, it is started again with its new code.
+    loop {
+        let (state, result) = run_process_code(
+            &engine,
+            &wasm_bytes,
+            process,
+            &home_directory_path,
+            previous.as_ref().map(|(handle, _)| handle.as_str()),
+        )
+        .await;
+        process = state;
 
-    // TODO make guarantees about this
-    if let Ok(Ok(())) = tokio::time::timeout(
-        std::time::Duration::from_secs(5),
-        fs::create_dir_all(&tmp_path),
-    )
-    .await
-    {
-        if let Ok(wasi_tempdir) =
-            Dir::open_ambient_dir(tmp_path.clone(), wasi_common::sync::ambient_authority())
-        {
-            wasi.preopened_dir(
-                wasi_tempdir,
-                DirPerms::all(),
-                FilePerms::all(),
-                tmp_path.clone(),
-            )
-            .env("TEMP_DIR", tmp_path);
+        // the old code has stopped to be upgraded: start the new code
+        if let Some(upgrade) = process.upgrade.take() {
+            let old_handle = std::mem::replace(
+                &mut process.metadata.wasm_bytes_handle,
+                upgrade.wasm_bytes_handle,
+            );
+            let old_bytes = std::mem::replace(&mut wasm_bytes, upgrade.wasm_bytes);
+            previous = Some((old_handle, old_bytes));
+            process.upgrading = Some((upgrade.id, upgrade.rsvp));
+            continue;
         }
-    }
-
-    let wasi = wasi.stderr(wasi_stderr.clone()).build();
 
-    wasmtime_wasi::command::add_to_linker(&mut linker).unwrap();
-
-    let mut store = Store::new(
-        &engine,
-        ProcessWasi {
-            process: ProcessState {
-                keypair: keypair.clone(),
-                metadata: metadata.clone(),
-                recv_in_process,
-                self_sender: send_to_process,
-                send_to_loop: send_to_loop.clone(),
-                send_to_terminal: send_to_terminal.clone(),
-                prompting_message: None,
-                last_blob: None,
-                contexts: HashMap::new(),
-                message_queue: VecDeque::new(),
-                caps_oracle: caps_oracle.clone(),
-            },
-            table,
-            wasi,
-        },
-    );
-
-    let (bindings, _bindings) =
-        match Process::instantiate_async(&mut store, &component, &linker).await {
-            Ok(b) => b,
-            Err(e) => {
+        let failed = match &result {
+            Ok(Ok(())) => false,
+            Ok(Err(_)) | Err(_) => true,
+        };
+        // the new code failed to start: roll back to the old code
+        if failed {
+            if let (Some((id, rsvp)), Some((old_handle, old_bytes))) =
+                (process.upgrading.take(), previous.take())
+            {
                 let _ = send_to_terminal
                     .send(t::Printout {
                         verbosity: 0,
                         content: format!(
-                            "mk: process {:?} failed to instantiate: {:?}",
-                            metadata.our.process, e,
+                            "process {} failed to upgrade to {}, rolling back to {}",
+                            process.metadata.our.process,
+                            process.metadata.wasm_bytes_handle,
+                            old_handle,
                         ),
                     })
                     .await;
-                return Err(e);
+                process.finish_upgrade(id, rsvp, false).await;
+                process.metadata.wasm_bytes_handle = old_handle;
+                wasm_bytes = old_bytes;
+                continue;
             }
-        };
-
-    // the process will run until it returns from init() or crashes
-    match bindings
-        .call_init(&mut store, &metadata.our.to_string())
-        .await
-    {
-        Ok(()) => {
-            let _ = send_to_terminal
-                .send(t::Printout {
-                    verbosity: 1,
-                    content: format!("process {} returned without error", metadata.our.process),
-                })
-                .await;
         }
-        Err(_) => {
-            let stderr = wasi_stderr.contents().into();
-            let stderr = String::from_utf8(stderr)?;
-            let _ = send_to_terminal
-                .send(t::Printout {
-                    verbosity: 0,
-                    content: format!(
-                        "\x1b[38;5;196mprocess {} ended with error:\x1b[0m\n{}",
-                        metadata.our.process, stderr,
-                    ),
-                })
-                .await;
+        // couldn't even start: there is nothing to clean up
+        if let Err(e) = result {
+            return Err(e);
         }
-    };
+        break;
+    }
 
     //
     // the process has completed, time to perform cleanup
     //
 
     // update metadata to what was mutated by process in store
-    let metadata = store.data().process.metadata.to_owned();
+    let metadata = process.metadata.to_owned();
 
     let our_kernel = t::Address {
         node: metadata.our.node.clone(),
@@ -681,6 +793,8 @@ pub async fn make_process_loop(
                         .unwrap(),
                         metadata: None,
                         capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
                     }),
                     lazy_load_blob: None,
                 })
@@ -709,6 +823,8 @@ pub async fn make_process_loop(
                         .unwrap(),
                         metadata: None,
                         capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
                     }),
                     lazy_load_blob: None,
                 })
@@ -722,6 +838,7 @@ pub async fn make_process_loop(
                     ),
                 })
                 .await;
+            metrics::process_restarted(&metadata.our.process);
             send_to_loop
                 .send(t::KernelMessage {
                     id: rand::random(),
@@ -742,6 +859,8 @@ pub async fn make_process_loop(
                         .unwrap(),
                         metadata: None,
                         capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
                     }),
                     lazy_load_blob: Some(t::LazyLoadBlob {
                         mime: None,
@@ -764,6 +883,8 @@ pub async fn make_process_loop(
                         .unwrap(),
                         metadata: None,
                         capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
                     }),
                     lazy_load_blob: None,
                 })
@@ -809,6 +930,8 @@ pub async fn make_process_loop(
                         .unwrap(),
                         metadata: None,
                         capabilities: vec![],
+                        priority: t::MessagePriority::System,
+                        deadline: None,
                     }),
                     lazy_load_blob: None,
                 })
@@ -818,6 +941,181 @@ pub async fn make_process_loop(
     Ok(())
 }
 
+/// instantiate a process's code and run its init() until it returns, crashes,
+/// or stops to be upgraded. if `migrate_from` is set, the process is being
+/// upgraded from the code at that handle, and the new code's `migrate` export
+/// is called first, if it has one. gives back the process's state, along with
+/// an error if the code couldn't be started, or else the result of init().
+async fn run_process_code(
+    engine: &Engine,
+    wasm_bytes: &[u8],
+    process: ProcessState,
+    home_directory_path: &str,
+    migrate_from: Option<&str>,
+) -> (ProcessState, Result<Result<()>>) {
+    let metadata = process.metadata.clone();
+    let send_to_terminal = process.send_to_terminal.clone();
+
+    let component = match Component::new(engine, wasm_bytes) {
+        Ok(component) => component,
+        Err(e) => {
+            let _ = send_to_terminal
+                .send(t::Printout {
+                    verbosity: 0,
+                    content: format!(
+                        "mk: process {:?} couldn't read wasm: {:?}",
+                        metadata.our.process, e,
+                    ),
+                })
+                .await;
+            return (process, Err(e));
+        }
+    };
+
+    let mut linker = Linker::new(engine);
+    Process::add_to_linker(&mut linker, |state: &mut ProcessWasi| state).unwrap();
+
+    let table = Table::new();
+    let wasi_stderr = MemoryOutputPipe::new(STACK_TRACE_SIZE);
+
+    let tmp_path = format!(
+        "{}/vfs/{}:{}/tmp",
+        home_directory_path,
+        metadata.our.process.package(),
+        metadata.our.process.publisher()
+    );
+
+    let mut wasi = WasiCtxBuilder::new();
+
+    // TODO make guarantees about this
+    if let Ok(Ok(())) = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        fs::create_dir_all(&tmp_path),
+    )
+    .await
+    {
+        if let Ok(wasi_tempdir) =
+            Dir::open_ambient_dir(tmp_path.clone(), wasi_common::sync::ambient_authority())
+        {
+            wasi.preopened_dir(
+                wasi_tempdir,
+                DirPerms::all(),
+                FilePerms::all(),
+                tmp_path.clone(),
+            )
+            .env("TEMP_DIR", tmp_path);
+        }
+    }
+
+    let wasi = wasi.stderr(wasi_stderr.clone()).build();
+
+    wasmtime_wasi::command::add_to_linker(&mut linker).unwrap();
+
+    let mut store = Store::new(
+        engine,
+        ProcessWasi {
+            process,
+            table,
+            wasi,
+        },
+    );
+
+    let (bindings, instance) =
+        match Process::instantiate_async(&mut store, &component, &linker).await {
+            Ok(b) => b,
+            Err(e) => {
+                let _ = send_to_terminal
+                    .send(t::Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "mk: process {:?} failed to instantiate: {:?}",
+                            metadata.our.process, e,
+                        ),
+                    })
+                    .await;
+                return (store.into_data().process, Err(e));
+            }
+        };
+
+    if let Some(previous) = migrate_from {
+        if let Err(e) = migrate(&mut store, &instance, &metadata.our.to_string(), previous).await {
+            let _ = send_to_terminal
+                .send(t::Printout {
+                    verbosity: 0,
+                    content: format!(
+                        "mk: process {:?} failed to migrate from {}: {:?}",
+                        metadata.our.process, previous, e,
+                    ),
+                })
+                .await;
+            return (store.into_data().process, Err(e));
+        }
+    }
+
+    let result = match bindings
+        .call_init(&mut store, &metadata.our.to_string())
+        .await
+    {
+        Ok(()) => {
+            logging::log(LogRecord::new(
+                &metadata.our.process,
+                LogLevel::Info,
+                "returned without error".into(),
+            ));
+            let _ = send_to_terminal
+                .send(t::Printout {
+                    verbosity: 1,
+                    content: format!("process {} returned without error", metadata.our.process),
+                })
+                .await;
+            Ok(())
+        }
+        // a process that stopped to be upgraded didn't crash
+        Err(e) if store.data().process.upgrade.is_some() => Err(e),
+        Err(e) => {
+            let stderr = wasi_stderr.contents().into();
+            let stderr = String::from_utf8(stderr).unwrap_or_default();
+            let mut record = LogRecord::new(
+                &metadata.our.process,
+                LogLevel::Error,
+                format!("ended with error: {e}"),
+            );
+            record.fields.insert("stderr".into(), stderr.clone().into());
+            logging::log(record);
+            let _ = send_to_terminal
+                .send(t::Printout {
+                    verbosity: 0,
+                    content: format!(
+                        "\x1b[38;5;196mprocess {} ended with error:\x1b[0m\n{}",
+                        metadata.our.process, stderr,
+                    ),
+                })
+                .await;
+            Err(e)
+        }
+    };
+    (store.into_data().process, Ok(result))
+}
+
+/// call the `migrate` export of a process's new code, if it has one
+async fn migrate(
+    store: &mut Store<ProcessWasi>,
+    instance: &Instance,
+    our: &str,
+    previous: &str,
+) -> Result<()> {
+    if instance.get_func(&mut *store, "migrate").is_none() {
+        return Ok(());
+    }
+    let migrate = instance
+        .get_typed_func::<(String, String), (Result<(), String>,)>(&mut *store, "migrate")?;
+    let (result,) = migrate
+        .call_async(&mut *store, (our.to_string(), previous.to_string()))
+        .await?;
+    migrate.post_return_async(&mut *store).await?;
+    result.map_err(|e| anyhow::anyhow!("migration failed: {e}"))
+}
+
 async fn print(sender: &t::PrintSender, verbosity: u8, content: String) {
     let _ = sender
         .send(t::Printout { verbosity, content })
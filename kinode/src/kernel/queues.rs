@@ -0,0 +1,419 @@
+use lib::types::core as t;
+use rocksdb::DB;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The column family of the kernel's database that queue configs are kept in.
+pub const QUEUE_CONFIG_CF: &str = "queue_config";
+
+/// The most requests any process may have queued.
+pub const MAX_QUEUE_CAPACITY: usize = 16_384;
+
+/// Room kept in every queue beyond its capacity for responses and send
+/// errors, which aren't held to the capacity: refusing one would leave a
+/// process waiting on an answer it will never get.
+pub const RESPONSE_HEADROOM: usize = 1_024;
+
+/// The size of the channel each process receives messages on, so that the
+/// configured capacity can be changed while the process is running.
+pub const QUEUE_CHANNEL_SIZE: usize = MAX_QUEUE_CAPACITY + RESPONSE_HEADROOM;
+
+type QueuedMessage = Result<t::KernelMessage, t::WrappedSendError>;
+
+/// whether a message counts against a queue's capacity
+fn is_request(message: &QueuedMessage) -> bool {
+    matches!(
+        message,
+        Ok(t::KernelMessage {
+            message: t::Message::Request(_),
+            ..
+        })
+    )
+}
+
+struct Queue {
+    sender: t::ProcessMessageSender,
+    /// messages the process has taken off its channel, but set aside while
+    /// it awaits a specific response
+    deferred: Arc<AtomicUsize>,
+    high_water: usize,
+    rejected: u64,
+    dropped: u64,
+}
+
+impl Queue {
+    fn depth(&self) -> usize {
+        (self.sender.max_capacity() - self.sender.capacity())
+            + self.deferred.load(Ordering::Relaxed)
+    }
+}
+
+/// The message queue of every running process, and the bounds on each.
+/// A process's queue is everything that has been delivered to it but that
+/// it hasn't received yet.
+pub struct Queues {
+    db: Arc<DB>,
+    configs: HashMap<t::ProcessId, t::QueueConfig>,
+    queues: HashMap<t::ProcessId, Queue>,
+}
+
+impl Queues {
+    /// load the queue configs that were set before the last shutdown
+    pub fn load(db: Arc<DB>) -> Self {
+        let mut configs = HashMap::new();
+        if let Some(cf) = db.cf_handle(QUEUE_CONFIG_CF) {
+            for item in db.iterator_cf(&cf, rocksdb::IteratorMode::Start) {
+                let Ok((key, value)) = item else {
+                    break;
+                };
+                let Ok(process_id) = String::from_utf8_lossy(&key).parse::<t::ProcessId>() else {
+                    continue;
+                };
+                let Ok(config) = bincode::deserialize::<t::QueueConfig>(&value) else {
+                    continue;
+                };
+                configs.insert(process_id, config);
+            }
+        }
+        Self {
+            db,
+            configs,
+            queues: HashMap::new(),
+        }
+    }
+
+    /// start tracking the queue of a process that is starting up. returns
+    /// the counter the process keeps its set-aside messages in.
+    pub fn register(
+        &mut self,
+        process_id: &t::ProcessId,
+        sender: t::ProcessMessageSender,
+    ) -> Arc<AtomicUsize> {
+        let deferred = Arc::new(AtomicUsize::new(0));
+        self.queues.insert(
+            process_id.clone(),
+            Queue {
+                sender,
+                deferred: deferred.clone(),
+                high_water: 0,
+                rejected: 0,
+                dropped: 0,
+            },
+        );
+        deferred
+    }
+
+    /// stop tracking the queue of a process that has been killed.
+    /// its config is kept, in case it is started again.
+    pub fn remove(&mut self, process_id: &t::ProcessId) {
+        self.queues.remove(process_id);
+    }
+
+    pub fn config(&self, process_id: &t::ProcessId) -> t::QueueConfig {
+        self.configs.get(process_id).cloned().unwrap_or_default()
+    }
+
+    pub fn set_config(&mut self, process_id: &t::ProcessId, mut config: t::QueueConfig) {
+        config.capacity = config.capacity.clamp(1, MAX_QUEUE_CAPACITY);
+        if let Some(cf) = self.db.cf_handle(QUEUE_CONFIG_CF) {
+            if let Err(e) = self.db.put_cf(
+                &cf,
+                process_id.to_string(),
+                bincode::serialize(&config).unwrap(),
+            ) {
+                println!("kernel: failed to persist queue config for {process_id}: {e}\r");
+            }
+        }
+        self.configs.insert(process_id.clone(), config);
+    }
+
+    /// queue a message for a process, unless its queue is full, in which
+    /// case the message is handed back along with what the process's
+    /// policy says to do with it. responses and send errors are queued
+    /// past the capacity, into the headroom kept for them.
+    pub fn deliver(
+        &mut self,
+        process_id: &t::ProcessId,
+        message: QueuedMessage,
+    ) -> Result<(), (t::QueuePolicy, QueuedMessage)> {
+        let config = self.config(process_id);
+        let Some(queue) = self.queues.get_mut(process_id) else {
+            return Err((config.policy, message));
+        };
+        let depth = queue.depth();
+        if depth >= config.capacity && is_request(&message) {
+            match config.policy {
+                t::QueuePolicy::Reject => queue.rejected += 1,
+                t::QueuePolicy::Drop => queue.dropped += 1,
+            }
+            return Err((config.policy, message));
+        }
+        match queue.sender.try_send(message) {
+            Ok(()) => {
+                queue.high_water = queue.high_water.max(depth + 1);
+                Ok(())
+            }
+            // the process has exited, so the message would never be received
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Ok(()),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(message)) => {
+                match config.policy {
+                    t::QueuePolicy::Reject => queue.rejected += 1,
+                    t::QueuePolicy::Drop => queue.dropped += 1,
+                }
+                Err((config.policy, message))
+            }
+        }
+    }
+
+    /// queue a message for a process only if there is room for it, handing
+    /// it back otherwise. unlike `deliver`, a message that doesn't fit isn't
+    /// counted against the queue, since the caller will offer it again.
+    pub fn deliver_if_room(
+        &mut self,
+        process_id: &t::ProcessId,
+        message: QueuedMessage,
+    ) -> Result<(), QueuedMessage> {
+        let capacity = self.config(process_id).capacity;
+        let Some(queue) = self.queues.get_mut(process_id) else {
+            return Err(message);
+        };
+        let depth = queue.depth();
+        if depth >= capacity && is_request(&message) {
+            return Err(message);
+        }
+        match queue.sender.try_send(message) {
+            Ok(()) => {
+                queue.high_water = queue.high_water.max(depth + 1);
+                Ok(())
+            }
+            // the process has exited, so the message would never be received
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Ok(()),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(message)) => Err(message),
+        }
+    }
+
+    pub fn metrics(&self) -> Vec<t::QueueMetrics> {
+        let mut metrics: Vec<t::QueueMetrics> = self
+            .queues
+            .iter()
+            .map(|(process_id, queue)| t::QueueMetrics {
+                process: process_id.clone(),
+                depth: queue.depth(),
+                capacity: self.config(process_id).capacity,
+                high_water: queue.high_water,
+                rejected: queue.rejected,
+                dropped: queue.dropped,
+            })
+            .collect();
+        metrics.sort_by(|a, b| b.depth.cmp(&a.depth));
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(path: &std::path::Path) -> Arc<DB> {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        Arc::new(DB::open_cf(&opts, path, [QUEUE_CONFIG_CF]).unwrap())
+    }
+
+    fn db_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kinode-queues-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn process() -> t::ProcessId {
+        "a:b:c".parse().unwrap()
+    }
+
+    fn message(id: u64) -> QueuedMessage {
+        kernel_message(
+            id,
+            t::Message::Request(t::Request {
+                inherit: false,
+                expects_response: Some(5),
+                body: vec![],
+                metadata: None,
+                capabilities: vec![],
+                priority: t::MessagePriority::User,
+                deadline: None,
+            }),
+        )
+    }
+
+    fn response(id: u64) -> QueuedMessage {
+        kernel_message(
+            id,
+            t::Message::Response((
+                t::Response {
+                    inherit: false,
+                    body: vec![],
+                    metadata: None,
+                    capabilities: vec![],
+                },
+                None,
+            )),
+        )
+    }
+
+    fn kernel_message(id: u64, message: t::Message) -> QueuedMessage {
+        let address = t::Address {
+            node: "fake.os".into(),
+            process: process(),
+        };
+        Ok(t::KernelMessage {
+            id,
+            source: address.clone(),
+            target: address,
+            rsvp: None,
+            message,
+            lazy_load_blob: None,
+        })
+    }
+
+    fn config(capacity: usize, policy: t::QueuePolicy) -> t::QueueConfig {
+        t::QueueConfig { capacity, policy }
+    }
+
+    #[test]
+    fn full_queue_refuses_by_policy() {
+        let path = db_path("full");
+        let mut queues = Queues::load(open_db(&path));
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(QUEUE_CHANNEL_SIZE);
+        queues.register(&process(), sender);
+        queues.set_config(&process(), config(2, t::QueuePolicy::Reject));
+
+        assert!(queues.deliver(&process(), message(1)).is_ok());
+        assert!(queues.deliver(&process(), message(2)).is_ok());
+        assert!(matches!(
+            queues.deliver(&process(), message(3)),
+            Err((t::QueuePolicy::Reject, Ok(km))) if km.id == 3
+        ));
+        queues.set_config(&process(), config(2, t::QueuePolicy::Drop));
+        assert!(matches!(
+            queues.deliver(&process(), message(4)),
+            Err((t::QueuePolicy::Drop, _))
+        ));
+
+        // receiving a message makes room for another
+        assert!(receiver.try_recv().is_ok());
+        assert!(queues.deliver(&process(), message(5)).is_ok());
+
+        let metrics = queues.metrics();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].depth, 2);
+        assert_eq!(metrics[0].capacity, 2);
+        assert_eq!(metrics[0].high_water, 2);
+        assert_eq!((metrics[0].rejected, metrics[0].dropped), (1, 1));
+        drop(queues);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn responses_and_errors_go_past_capacity() {
+        let path = db_path("headroom");
+        let mut queues = Queues::load(open_db(&path));
+        let (sender, _receiver) = tokio::sync::mpsc::channel(QUEUE_CHANNEL_SIZE);
+        queues.register(&process(), sender);
+        queues.set_config(&process(), config(1, t::QueuePolicy::Reject));
+
+        assert!(queues.deliver(&process(), message(1)).is_ok());
+        assert!(queues.deliver(&process(), message(2)).is_err());
+        assert!(queues.deliver(&process(), response(3)).is_ok());
+        let error = t::WrappedSendError {
+            id: 4,
+            source: t::Address {
+                node: "fake.os".into(),
+                process: process(),
+            },
+            error: t::SendError {
+                kind: t::SendErrorKind::Offline,
+                target: t::Address {
+                    node: "other.os".into(),
+                    process: process(),
+                },
+                message: t::Message::Response((
+                    t::Response {
+                        inherit: false,
+                        body: vec![],
+                        metadata: None,
+                        capabilities: vec![],
+                    },
+                    None,
+                )),
+                lazy_load_blob: None,
+            },
+        };
+        assert!(queues.deliver(&process(), Err(error)).is_ok());
+        assert!(queues.deliver_if_room(&process(), response(5)).is_ok());
+        assert!(queues.deliver_if_room(&process(), message(6)).is_err());
+
+        let metrics = queues.metrics();
+        assert_eq!(metrics[0].depth, 4);
+        assert_eq!((metrics[0].rejected, metrics[0].dropped), (1, 0));
+        drop(queues);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn deferred_messages_count_towards_depth() {
+        let path = db_path("deferred");
+        let mut queues = Queues::load(open_db(&path));
+        let (sender, _receiver) = tokio::sync::mpsc::channel(QUEUE_CHANNEL_SIZE);
+        let deferred = queues.register(&process(), sender);
+        queues.set_config(&process(), config(2, t::QueuePolicy::Reject));
+
+        deferred.store(2, Ordering::Relaxed);
+        assert!(queues.deliver(&process(), message(1)).is_err());
+        deferred.store(1, Ordering::Relaxed);
+        assert!(queues.deliver(&process(), message(2)).is_ok());
+        drop(queues);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn offering_without_room_is_not_counted() {
+        let path = db_path("room");
+        let mut queues = Queues::load(open_db(&path));
+        let (sender, _receiver) = tokio::sync::mpsc::channel(QUEUE_CHANNEL_SIZE);
+        queues.register(&process(), sender);
+        queues.set_config(&process(), config(1, t::QueuePolicy::Reject));
+
+        assert!(queues.deliver_if_room(&process(), message(1)).is_ok());
+        assert!(queues.deliver_if_room(&process(), message(2)).is_err());
+        let metrics = queues.metrics();
+        assert_eq!((metrics[0].rejected, metrics[0].dropped), (0, 0));
+
+        // a process that isn't running has no room
+        let other: t::ProcessId = "d:e:f".parse().unwrap();
+        assert!(queues.deliver_if_room(&other, message(3)).is_err());
+        drop(queues);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn configs_are_clamped_and_persisted() {
+        let path = db_path("config");
+        let mut queues = Queues::load(open_db(&path));
+        assert_eq!(queues.config(&process()), t::QueueConfig::default());
+        queues.set_config(&process(), config(0, t::QueuePolicy::Drop));
+        assert_eq!(queues.config(&process()).capacity, 1);
+        queues.set_config(&process(), config(usize::MAX, t::QueuePolicy::Drop));
+        drop(queues);
+
+        let queues = Queues::load(open_db(&path));
+        assert_eq!(
+            queues.config(&process()),
+            config(MAX_QUEUE_CAPACITY, t::QueuePolicy::Drop)
+        );
+        drop(queues);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
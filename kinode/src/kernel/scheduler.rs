@@ -0,0 +1,309 @@
+use crate::KERNEL_PROCESS_ID;
+use lib::types::core as t;
+use rocksdb::DB;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+
+/// The column family of the kernel's database that process priorities are kept in.
+pub const PRIORITY_CF: &str = "priority";
+
+/// The most messages the kernel takes off its channel at once to pick the
+/// most urgent from. Bounds how much work is done before a newly-arrived
+/// message can be considered.
+const MAX_WAITING: usize = 256;
+
+/// How many messages that arrive after a message of each priority may be
+/// handled before it. Once a message has waited out its slack it goes ahead
+/// of anything more urgent that arrived since, so `Bulk` traffic is never
+/// starved.
+fn slack(priority: t::MessagePriority) -> u64 {
+    match priority {
+        t::MessagePriority::System => 0,
+        t::MessagePriority::User => MAX_WAITING as u64,
+        t::MessagePriority::Bulk => 16 * MAX_WAITING as u64,
+    }
+}
+
+struct Waiting {
+    /// arrival order plus slack: the message with the lowest goes next
+    deadline: u64,
+    /// order of arrival, so that messages of the same deadline stay in order
+    seq: u64,
+    message: t::KernelMessage,
+}
+
+impl Waiting {
+    fn key(&self) -> Reverse<(u64, u64)> {
+        Reverse((self.deadline, self.seq))
+    }
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Waiting {}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Picks which message the kernel handles next: the one whose arrival plus
+/// the slack of its priority is lowest, and of those, the one that
+/// arrived first. Urgent messages jump ahead of less urgent ones, but only
+/// so far.
+pub struct Scheduler {
+    our: String,
+    db: Arc<DB>,
+    /// the priority of requests sent by, and responses from, each local
+    /// process. processes not in here are `User`.
+    priorities: HashMap<t::ProcessId, t::MessagePriority>,
+    waiting: BinaryHeap<Waiting>,
+    seq: u64,
+}
+
+impl Scheduler {
+    /// load the process priorities that were set before the last shutdown
+    pub fn load(our: String, db: Arc<DB>) -> Self {
+        let mut priorities = HashMap::new();
+        if let Some(cf) = db.cf_handle(PRIORITY_CF) {
+            for item in db.iterator_cf(&cf, rocksdb::IteratorMode::Start) {
+                let Ok((key, value)) = item else {
+                    break;
+                };
+                let Ok(process_id) = String::from_utf8_lossy(&key).parse::<t::ProcessId>() else {
+                    continue;
+                };
+                let Ok(priority) = bincode::deserialize::<t::MessagePriority>(&value) else {
+                    continue;
+                };
+                priorities.insert(process_id, priority);
+            }
+        }
+        Self {
+            our,
+            db,
+            priorities,
+            waiting: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    pub fn set_priority(&mut self, process_id: &t::ProcessId, priority: t::MessagePriority) {
+        if let Some(cf) = self.db.cf_handle(PRIORITY_CF) {
+            if let Err(e) = self.db.put_cf(
+                &cf,
+                process_id.to_string(),
+                bincode::serialize(&priority).unwrap(),
+            ) {
+                println!("kernel: failed to persist priority for {process_id}: {e}\r");
+            }
+        }
+        self.priorities.insert(process_id.clone(), priority);
+    }
+
+    /// drop the priorities of processes that no longer exist
+    pub fn retain(&mut self, exists: impl Fn(&t::ProcessId) -> bool) {
+        let gone: Vec<t::ProcessId> = self
+            .priorities
+            .keys()
+            .filter(|process_id| !exists(process_id))
+            .cloned()
+            .collect();
+        for process_id in gone {
+            self.priorities.remove(&process_id);
+            if let Some(cf) = self.db.cf_handle(PRIORITY_CF) {
+                let _ = self.db.delete_cf(&cf, process_id.to_string());
+            }
+        }
+    }
+
+    /// a request keeps the priority it was sent with, unless that is the
+    /// default, in which case it takes the priority of the process that
+    /// sent it. so does a response.
+    fn priority_of(&self, km: &t::KernelMessage) -> t::MessagePriority {
+        if let t::Message::Request(request) = &km.message {
+            if request.priority != t::MessagePriority::User {
+                return request.priority;
+            }
+        }
+        if km.source.node != self.our && km.source.node != "our" {
+            return t::MessagePriority::User;
+        }
+        if km.source.process == *KERNEL_PROCESS_ID {
+            return t::MessagePriority::System;
+        }
+        self.priorities
+            .get(&km.source.process)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn push(&mut self, message: t::KernelMessage) {
+        self.seq += 1;
+        self.waiting.push(Waiting {
+            deadline: self.seq + slack(self.priority_of(&message)),
+            seq: self.seq,
+            message,
+        });
+    }
+
+    /// wait for the next message to handle. returns None once the channel
+    /// is closed and no messages are left waiting.
+    ///
+    /// cancel-safe: messages taken off the channel are kept until returned.
+    pub async fn next(&mut self, recv: &mut t::MessageReceiver) -> Option<t::KernelMessage> {
+        if self.waiting.is_empty() {
+            let message = recv.recv().await?;
+            self.push(message);
+        }
+        while self.waiting.len() < MAX_WAITING {
+            let Ok(message) = recv.try_recv() else {
+                break;
+            };
+            self.push(message);
+        }
+        self.waiting.pop().map(|waiting| waiting.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(path: &std::path::Path) -> Arc<DB> {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        Arc::new(DB::open_cf(&opts, path, [PRIORITY_CF]).unwrap())
+    }
+
+    fn db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "kinode-scheduler-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn request(id: u64, process: &str, priority: t::MessagePriority) -> t::KernelMessage {
+        let address = t::Address {
+            node: "fake.os".into(),
+            process: process.parse().unwrap(),
+        };
+        t::KernelMessage {
+            id,
+            source: address.clone(),
+            target: address,
+            rsvp: None,
+            message: t::Message::Request(t::Request {
+                inherit: false,
+                expects_response: None,
+                body: vec![],
+                metadata: None,
+                capabilities: vec![],
+                priority,
+                deadline: None,
+            }),
+            lazy_load_blob: None,
+        }
+    }
+
+    fn pop(scheduler: &mut Scheduler) -> u64 {
+        scheduler.waiting.pop().unwrap().message.id
+    }
+
+    #[test]
+    fn urgent_messages_go_first() {
+        let path = db_path("urgent");
+        let mut scheduler = Scheduler::load("fake.os".into(), open_db(&path));
+        scheduler.push(request(1, "a:b:c", t::MessagePriority::Bulk));
+        scheduler.push(request(2, "a:b:c", t::MessagePriority::User));
+        scheduler.push(request(3, "a:b:c", t::MessagePriority::User));
+        scheduler.push(request(4, "a:b:c", t::MessagePriority::System));
+        let order: Vec<u64> = (0..4).map(|_| pop(&mut scheduler)).collect();
+        assert_eq!(order, vec![4, 2, 3, 1]);
+        drop(scheduler);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn bulk_messages_are_not_starved() {
+        let path = db_path("starved");
+        let mut scheduler = Scheduler::load("fake.os".into(), open_db(&path));
+        scheduler.push(request(0, "a:b:c", t::MessagePriority::Bulk));
+        let mut overtaken = 0;
+        for id in 1.. {
+            scheduler.push(request(id, "a:b:c", t::MessagePriority::System));
+            if pop(&mut scheduler) == 0 {
+                break;
+            }
+            overtaken += 1;
+            assert!(overtaken < slack(t::MessagePriority::Bulk));
+        }
+        assert!(overtaken >= slack(t::MessagePriority::User));
+        drop(scheduler);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn process_priorities_apply_and_persist() {
+        let path = db_path("persist");
+        let mut scheduler = Scheduler::load("fake.os".into(), open_db(&path));
+        let process: t::ProcessId = "a:b:c".parse().unwrap();
+        scheduler.set_priority(&process, t::MessagePriority::Bulk);
+        drop(scheduler);
+
+        let mut scheduler = Scheduler::load("fake.os".into(), open_db(&path));
+        let bulk = request(1, "a:b:c", t::MessagePriority::User);
+        assert_eq!(scheduler.priority_of(&bulk), t::MessagePriority::Bulk);
+        // a priority sent with the request wins
+        let urgent = request(2, "a:b:c", t::MessagePriority::System);
+        assert_eq!(scheduler.priority_of(&urgent), t::MessagePriority::System);
+        // as does the kernel's
+        let kernel = request(3, "kernel:distro:sys", t::MessagePriority::User);
+        assert_eq!(scheduler.priority_of(&kernel), t::MessagePriority::System);
+
+        // a process that still exists keeps its priority
+        scheduler.retain(|process_id| process_id == &process);
+        assert_eq!(scheduler.priority_of(&bulk), t::MessagePriority::Bulk);
+        scheduler.retain(|_| false);
+        assert_eq!(scheduler.priority_of(&bulk), t::MessagePriority::User);
+        drop(scheduler);
+        let scheduler = Scheduler::load("fake.os".into(), open_db(&path));
+        assert!(scheduler.priorities.is_empty());
+        drop(scheduler);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[tokio::test]
+    async fn next_picks_from_everything_waiting() {
+        let path = db_path("next");
+        let mut scheduler = Scheduler::load("fake.os".into(), open_db(&path));
+        let (send, mut recv) = tokio::sync::mpsc::channel(16);
+        send.send(request(1, "a:b:c", t::MessagePriority::Bulk))
+            .await
+            .unwrap();
+        send.send(request(2, "a:b:c", t::MessagePriority::System))
+            .await
+            .unwrap();
+        drop(send);
+        assert_eq!(scheduler.next(&mut recv).await.unwrap().id, 2);
+        assert_eq!(scheduler.next(&mut recv).await.unwrap().id, 1);
+        assert!(scheduler.next(&mut recv).await.is_none());
+        drop(scheduler);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::kernel::process;
+use crate::logging::{self, LogRecord};
 use crate::KERNEL_PROCESS_ID;
 use crate::VFS_PROCESS_ID;
 use anyhow::Result;
@@ -29,6 +30,11 @@ impl StandardHost for process::ProcessWasi {
     /// Print a message to the runtime terminal. Add the name of the process to the
     /// beginning of the string, so user can verify source.
     async fn print_to_terminal(&mut self, verbosity: u8, content: String) -> Result<()> {
+        logging::log(LogRecord::from_print(
+            &self.process.metadata.our.process,
+            verbosity,
+            &content,
+        ));
         self.process
             .send_to_terminal
             .send(t::Printout {
@@ -455,7 +461,14 @@ impl StandardHost for process::ProcessWasi {
     async fn receive(
         &mut self,
     ) -> Result<Result<(wit::Address, wit::Message), (wit::SendError, Option<wit::Context>)>> {
-        Ok(self.process.get_next_message_for_process().await)
+        match self.process.get_next_message_for_process().await {
+            Some(message) => Ok(message),
+            // stop the process so it can be started again with its new code
+            None => Err(anyhow::anyhow!(
+                "process {} is being upgraded",
+                self.process.metadata.our.process
+            )),
+        }
     }
 
     /// from a process: grab the blob part of the current prompting message.
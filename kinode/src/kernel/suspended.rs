@@ -0,0 +1,407 @@
+use lib::types::core as t;
+use rocksdb::DB;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// The column family of the kernel's database that persisted queues are kept in.
+/// A suspended process has a marker at its process id, and each message held
+/// for it is kept at `{process_id}/{seq}`.
+pub const SUSPENDED_CF: &str = "suspended";
+
+/// How many messages the kernel will hold for a suspended process before
+/// it starts refusing them.
+pub const SUSPENDED_QUEUE_CAPACITY: usize = 1024;
+
+type QueuedMessage = Result<t::KernelMessage, t::WrappedSendError>;
+
+struct Queue {
+    persist: bool,
+    /// set once the process has been resumed: its held messages are being
+    /// handed to it as its queue makes room, and new ones wait behind them
+    resuming: bool,
+    next_seq: u64,
+    messages: VecDeque<(u64, QueuedMessage)>,
+}
+
+impl Queue {
+    fn new(persist: bool, resuming: bool) -> Self {
+        Self {
+            persist,
+            resuming,
+            next_seq: 0,
+            messages: VecDeque::new(),
+        }
+    }
+}
+
+/// Processes that have been suspended, and the messages sent to them since.
+/// A queue that is persisted survives a reboot, and the process stays
+/// suspended until it is resumed.
+pub struct Suspended {
+    db: Arc<DB>,
+    queues: HashMap<t::ProcessId, Queue>,
+}
+
+impl Suspended {
+    /// load the queues that were persisted before the last shutdown. a queue
+    /// whose process was resumed but not yet handed all its messages goes on
+    /// resuming.
+    pub fn load(db: Arc<DB>) -> Self {
+        let mut queues: HashMap<t::ProcessId, Queue> = HashMap::new();
+        if let Some(cf) = db.cf_handle(SUSPENDED_CF) {
+            for item in db.iterator_cf(&cf, rocksdb::IteratorMode::Start) {
+                let Ok((key, value)) = item else {
+                    break;
+                };
+                let key = String::from_utf8_lossy(&key);
+                match key.split_once('/') {
+                    None => {
+                        let Ok(process_id) = key.parse::<t::ProcessId>() else {
+                            continue;
+                        };
+                        queues
+                            .entry(process_id)
+                            .or_insert_with(|| Queue::new(true, false))
+                            .resuming = false;
+                    }
+                    Some((process_id, seq)) => {
+                        let (Ok(process_id), Ok(seq)) =
+                            (process_id.parse::<t::ProcessId>(), seq.parse::<u64>())
+                        else {
+                            continue;
+                        };
+                        let Ok(message) = bincode::deserialize::<QueuedMessage>(&value) else {
+                            continue;
+                        };
+                        let queue = queues
+                            .entry(process_id)
+                            .or_insert_with(|| Queue::new(true, true));
+                        queue.next_seq = queue.next_seq.max(seq + 1);
+                        queue.messages.push_back((seq, message));
+                    }
+                }
+            }
+        }
+        for queue in queues.values_mut() {
+            queue
+                .messages
+                .make_contiguous()
+                .sort_by_key(|(seq, _)| *seq);
+        }
+        Self { db, queues }
+    }
+
+    /// true while messages to the process are being held, including while
+    /// held messages are still being handed to a resumed process
+    pub fn is_suspended(&self, process_id: &t::ProcessId) -> bool {
+        self.queues.contains_key(process_id)
+    }
+
+    pub fn suspended(&self) -> impl Iterator<Item = (&t::ProcessId, usize)> {
+        self.queues
+            .iter()
+            .map(|(process_id, queue)| (process_id, queue.messages.len()))
+    }
+
+    /// processes that have been resumed but still have messages held
+    pub fn resuming(&self) -> Vec<t::ProcessId> {
+        self.queues
+            .iter()
+            .filter(|(_, queue)| queue.resuming)
+            .map(|(process_id, _)| process_id.clone())
+            .collect()
+    }
+
+    pub fn is_resuming(&self) -> bool {
+        self.queues.values().any(|queue| queue.resuming)
+    }
+
+    /// returns false if the process was already suspended. a process that
+    /// is still being handed its held messages stops being handed them.
+    pub fn suspend(&mut self, process_id: &t::ProcessId, persist: bool) -> bool {
+        match self.queues.get_mut(process_id) {
+            Some(queue) if queue.resuming => queue.resuming = false,
+            Some(_) => return false,
+            None => {
+                self.queues
+                    .insert(process_id.clone(), Queue::new(persist, false));
+            }
+        }
+        self.put_marker(process_id);
+        true
+    }
+
+    /// hold a message for a suspended process, handing it back if the
+    /// process's queue is full
+    pub fn push(
+        &mut self,
+        process_id: &t::ProcessId,
+        message: QueuedMessage,
+    ) -> Result<(), QueuedMessage> {
+        let Some(queue) = self.queues.get_mut(process_id) else {
+            return Err(message);
+        };
+        if queue.messages.len() >= SUSPENDED_QUEUE_CAPACITY {
+            return Err(message);
+        }
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        if queue.persist {
+            if let Some(cf) = self.db.cf_handle(SUSPENDED_CF) {
+                if let Err(e) = self.db.put_cf(
+                    &cf,
+                    message_key(process_id, seq),
+                    bincode::serialize(&message).unwrap(),
+                ) {
+                    println!(
+                        "kernel: failed to persist message for suspended process {process_id}: {e}\r"
+                    );
+                }
+            }
+        }
+        queue.messages.push_back((seq, message));
+        Ok(())
+    }
+
+    /// start handing a suspended process the messages held for it, returning
+    /// how many there are. None if the process isn't suspended.
+    pub fn resume(&mut self, process_id: &t::ProcessId) -> Option<usize> {
+        let queue = self.queues.get_mut(process_id)?;
+        if queue.resuming {
+            return None;
+        }
+        queue.resuming = true;
+        let held = queue.messages.len();
+        if queue.persist {
+            if let Some(cf) = self.db.cf_handle(SUSPENDED_CF) {
+                let _ = self.db.delete_cf(&cf, process_id.to_string());
+            }
+        }
+        self.finish_if_empty(process_id);
+        Some(held)
+    }
+
+    /// hand a resumed process its held messages in the order they arrived,
+    /// until `deliver` hands one back. returns how many were delivered.
+    pub fn deliver_held(
+        &mut self,
+        process_id: &t::ProcessId,
+        mut deliver: impl FnMut(QueuedMessage) -> Result<(), QueuedMessage>,
+    ) -> usize {
+        let Some(queue) = self.queues.get_mut(process_id) else {
+            return 0;
+        };
+        if !queue.resuming {
+            return 0;
+        }
+        let mut delivered = 0;
+        while let Some((seq, message)) = queue.messages.pop_front() {
+            if let Err(message) = deliver(message) {
+                queue.messages.push_front((seq, message));
+                break;
+            }
+            delivered += 1;
+            if queue.persist {
+                if let Some(cf) = self.db.cf_handle(SUSPENDED_CF) {
+                    let _ = self.db.delete_cf(&cf, message_key(process_id, seq));
+                }
+            }
+        }
+        self.finish_if_empty(process_id);
+        delivered
+    }
+
+    /// drop a process's queue and every message held in it
+    pub fn forget(&mut self, process_id: &t::ProcessId) {
+        let Some(queue) = self.queues.remove(process_id) else {
+            return;
+        };
+        if !queue.persist {
+            return;
+        }
+        let Some(cf) = self.db.cf_handle(SUSPENDED_CF) else {
+            return;
+        };
+        let _ = self.db.delete_cf(&cf, process_id.to_string());
+        for (seq, _) in queue.messages {
+            let _ = self.db.delete_cf(&cf, message_key(process_id, seq));
+        }
+    }
+
+    fn finish_if_empty(&mut self, process_id: &t::ProcessId) {
+        if self
+            .queues
+            .get(process_id)
+            .is_some_and(|queue| queue.resuming && queue.messages.is_empty())
+        {
+            self.queues.remove(process_id);
+        }
+    }
+
+    fn put_marker(&self, process_id: &t::ProcessId) {
+        if !self
+            .queues
+            .get(process_id)
+            .is_some_and(|queue| queue.persist)
+        {
+            return;
+        }
+        let Some(cf) = self.db.cf_handle(SUSPENDED_CF) else {
+            return;
+        };
+        if let Err(e) = self.db.put_cf(&cf, process_id.to_string(), []) {
+            println!("kernel: failed to persist suspension of {process_id}: {e}\r");
+        }
+    }
+}
+
+/// zero-padded so that a process's messages sort in the order they arrived
+fn message_key(process_id: &t::ProcessId, seq: u64) -> String {
+    format!("{process_id}/{seq:020}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(path: &std::path::Path) -> Arc<DB> {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        Arc::new(DB::open_cf(&opts, path, [SUSPENDED_CF]).unwrap())
+    }
+
+    fn db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "kinode-suspended-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn process() -> t::ProcessId {
+        "a:b:c".parse().unwrap()
+    }
+
+    fn message(id: u64) -> QueuedMessage {
+        let address = t::Address {
+            node: "fake.os".into(),
+            process: process(),
+        };
+        Ok(t::KernelMessage {
+            id,
+            source: address.clone(),
+            target: address,
+            rsvp: None,
+            message: t::Message::Request(t::Request {
+                inherit: false,
+                expects_response: None,
+                body: vec![],
+                metadata: None,
+                capabilities: vec![],
+                priority: t::MessagePriority::User,
+                deadline: None,
+            }),
+            lazy_load_blob: None,
+        })
+    }
+
+    fn id(message: QueuedMessage) -> u64 {
+        message.unwrap().id
+    }
+
+    #[test]
+    fn held_messages_are_delivered_in_order_until_refused() {
+        let path = db_path("order");
+        let mut suspended = Suspended::load(open_db(&path));
+        assert!(suspended.suspend(&process(), false));
+        assert!(!suspended.suspend(&process(), false));
+        for i in 0..3 {
+            assert!(suspended.push(&process(), message(i)).is_ok());
+        }
+        assert_eq!(suspended.resume(&process()), Some(3));
+        assert!(suspended.is_resuming());
+
+        let mut delivered = vec![];
+        let count = suspended.deliver_held(&process(), |message| {
+            if delivered.len() == 2 {
+                return Err(message);
+            }
+            delivered.push(id(message));
+            Ok(())
+        });
+        assert_eq!(count, 2);
+        assert_eq!(delivered, vec![0, 1]);
+        // still suspended until the last one is handed over
+        assert!(suspended.is_suspended(&process()));
+
+        suspended.deliver_held(&process(), |message| {
+            delivered.push(id(message));
+            Ok(())
+        });
+        assert_eq!(delivered, vec![0, 1, 2]);
+        assert!(!suspended.is_suspended(&process()));
+        drop(suspended);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn full_queue_hands_messages_back() {
+        let path = db_path("full");
+        let mut suspended = Suspended::load(open_db(&path));
+        assert!(suspended.push(&process(), message(0)).is_err());
+        suspended.suspend(&process(), false);
+        for i in 0..SUSPENDED_QUEUE_CAPACITY as u64 {
+            assert!(suspended.push(&process(), message(i)).is_ok());
+        }
+        assert!(matches!(
+            suspended.push(&process(), message(0)),
+            Err(Ok(km)) if km.id == 0
+        ));
+        drop(suspended);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn persisted_queues_survive_a_reboot() {
+        let path = db_path("persist");
+        let mut suspended = Suspended::load(open_db(&path));
+        suspended.suspend(&process(), true);
+        // more than nine, so that message keys must sort numerically
+        for i in 0..12 {
+            suspended.push(&process(), message(i)).unwrap();
+        }
+        drop(suspended);
+
+        let mut suspended = Suspended::load(open_db(&path));
+        assert!(suspended.is_suspended(&process()));
+        assert!(!suspended.is_resuming());
+        assert_eq!(suspended.resume(&process()), Some(12));
+        let mut delivered = vec![];
+        suspended.deliver_held(&process(), |message| {
+            if delivered.len() == 4 {
+                return Err(message);
+            }
+            delivered.push(id(message));
+            Ok(())
+        });
+        drop(suspended);
+
+        // a process resumed before the reboot goes on being handed the rest
+        let mut suspended = Suspended::load(open_db(&path));
+        assert_eq!(suspended.resuming(), vec![process()]);
+        suspended.deliver_held(&process(), |message| {
+            delivered.push(id(message));
+            Ok(())
+        });
+        assert_eq!(delivered, (0..12).collect::<Vec<_>>());
+        drop(suspended);
+
+        // and once it has been, nothing is left behind
+        let suspended = Suspended::load(open_db(&path));
+        assert!(!suspended.is_suspended(&process()));
+        drop(suspended);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,307 @@
+use lib::types::core::ProcessId;
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// The directory, within the home directory, that process logs are kept in.
+const LOGS_DIR: &str = "logs";
+
+/// How large a process's log file may grow before it is rotated.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How many rotated log files are kept for each process, besides the current one.
+const MAX_ROTATED_LOGS: usize = 3;
+
+/// How long a process's log file is kept open after it last logged.
+const IDLE_LOG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The most records a query returns.
+pub const MAX_QUERY_RECORDS: usize = 10_000;
+
+static LOGS: OnceLock<Logs> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// the level of a plain `print_to_terminal` call
+    pub fn from_verbosity(verbosity: u8) -> Self {
+        match verbosity {
+            0 => LogLevel::Info,
+            1 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("unknown log level: {s}")),
+        }
+    }
+}
+
+/// One line of a process's log file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// unix time in milliseconds
+    pub timestamp: u64,
+    pub process: ProcessId,
+    pub level: LogLevel,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl LogRecord {
+    pub fn new(process: &ProcessId, level: LogLevel, message: String) -> Self {
+        Self {
+            timestamp: crate::kernel::now_ms(),
+            process: process.clone(),
+            level,
+            message,
+            fields: serde_json::Map::new(),
+        }
+    }
+
+    /// Make a record of something a process printed. A process can log
+    /// structured records by printing a JSON object with a `message`:
+    /// its `level`, if any, overrides the one given by `verbosity`, and its
+    /// other keys become the record's fields.
+    pub fn from_print(process: &ProcessId, verbosity: u8, content: &str) -> Self {
+        let mut record = Self::new(
+            process,
+            LogLevel::from_verbosity(verbosity),
+            content.to_string(),
+        );
+        if let Ok(serde_json::Value::Object(mut object)) = serde_json::from_str(content) {
+            if let Some(serde_json::Value::String(message)) = object.remove("message") {
+                if let Some(level) = object
+                    .remove("level")
+                    .and_then(|value| value.as_str().and_then(|s| s.parse().ok()))
+                {
+                    record.level = level;
+                }
+                record.message = message;
+                record.fields = object;
+            }
+        }
+        record
+    }
+}
+
+struct LogFile {
+    writer: BufWriter<File>,
+    size: u64,
+    last_write: Instant,
+}
+
+/// Log files for every process that has printed since boot. Records are
+/// written by a single task, which owns the open files.
+struct Logs {
+    dir: PathBuf,
+    records: UnboundedSender<LogRecord>,
+}
+
+fn log_path(dir: &Path, process: &ProcessId, rotation: usize) -> PathBuf {
+    match rotation {
+        0 => dir.join(format!("{process}.log")),
+        n => dir.join(format!("{process}.log.{n}")),
+    }
+}
+
+/// Write records to their process's log file, flushing whenever no more are
+/// waiting, and rotating files as they fill up. Files of processes that have
+/// stopped logging are closed.
+async fn write_records(dir: PathBuf, mut records: UnboundedReceiver<LogRecord>) {
+    let mut files: HashMap<ProcessId, LogFile> = HashMap::new();
+    let mut close_idle = tokio::time::interval(IDLE_LOG_TIMEOUT);
+    loop {
+        tokio::select! {
+            record = records.recv() => {
+                let Some(record) = record else {
+                    break;
+                };
+                let mut next = Some(record);
+                while let Some(record) = next {
+                    if let Err(e) = write_record(&dir, &mut files, &record) {
+                        println!("logging: couldn't write log for {}: {e}\r", record.process);
+                    }
+                    next = records.try_recv().ok();
+                }
+                for (process, log_file) in files.iter_mut() {
+                    if let Err(e) = log_file.writer.flush() {
+                        println!("logging: couldn't write log for {process}: {e}\r");
+                    }
+                }
+            }
+            _ = close_idle.tick() => {
+                // every file was flushed after its last write
+                files.retain(|_, log_file| log_file.last_write.elapsed() < IDLE_LOG_TIMEOUT);
+            }
+        }
+    }
+}
+
+fn write_record(
+    dir: &Path,
+    files: &mut HashMap<ProcessId, LogFile>,
+    record: &LogRecord,
+) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    if let Some(log_file) = files.get_mut(&record.process) {
+        if log_file.size + line.len() as u64 > MAX_LOG_SIZE {
+            log_file.writer.flush()?;
+            files.remove(&record.process);
+            rotate(dir, &record.process)?;
+        }
+    }
+    let log_file = match files.entry(record.process.clone()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let path = log_path(dir, &record.process, 0);
+            let file = OpenOptions::new().append(true).create(true).open(path)?;
+            let size = file.metadata()?.len();
+            entry.insert(LogFile {
+                writer: BufWriter::new(file),
+                size,
+                last_write: Instant::now(),
+            })
+        }
+    };
+    log_file.writer.write_all(&line)?;
+    log_file.size += line.len() as u64;
+    log_file.last_write = Instant::now();
+    Ok(())
+}
+
+/// move each of a process's log files up by one, dropping the oldest
+fn rotate(dir: &Path, process: &ProcessId) -> std::io::Result<()> {
+    for rotation in (0..MAX_ROTATED_LOGS).rev() {
+        let from = log_path(dir, process, rotation);
+        if from.exists() {
+            std::fs::rename(from, log_path(dir, process, rotation + 1))?;
+        }
+    }
+    Ok(())
+}
+
+impl Logs {
+    fn path(&self, process: &ProcessId, rotation: usize) -> PathBuf {
+        log_path(&self.dir, process, rotation)
+    }
+
+    /// the processes that have log files, from their file names
+    fn processes(&self) -> Vec<ProcessId> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect()
+    }
+
+    /// the records of a process at or above a level, oldest first,
+    /// reading back through rotated files until `tail` are found
+    fn read(&self, process: &ProcessId, level: LogLevel, tail: usize) -> Vec<LogRecord> {
+        let mut records = vec![];
+        for rotation in 0..=MAX_ROTATED_LOGS {
+            let Ok(file) = File::open(self.path(process, rotation)) else {
+                break;
+            };
+            let mut older: Vec<LogRecord> = BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str::<LogRecord>(&line).ok())
+                .filter(|record| record.level <= level)
+                .collect();
+            older.append(&mut records);
+            records = older;
+            if records.len() >= tail {
+                break;
+            }
+        }
+        let skip = records.len().saturating_sub(tail);
+        records.split_off(skip)
+    }
+}
+
+/// Start keeping process logs in the home directory.
+pub fn init(home_directory_path: &str) {
+    let dir = PathBuf::from(home_directory_path).join(LOGS_DIR);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        println!("logging: couldn't create logs directory: {e}\r");
+        return;
+    }
+    let (records, records_rx) = unbounded_channel();
+    tokio::spawn(write_records(dir.clone(), records_rx));
+    let _ = LOGS.set(Logs { dir, records });
+}
+
+/// Delete the log files of processes that no longer exist. Only call this
+/// before those processes could have logged since boot.
+pub fn retain(exists: impl Fn(&ProcessId) -> bool) {
+    let Some(logs) = LOGS.get() else {
+        return;
+    };
+    for process in logs.processes() {
+        if exists(&process) {
+            continue;
+        }
+        for rotation in 0..=MAX_ROTATED_LOGS {
+            let _ = std::fs::remove_file(logs.path(&process, rotation));
+        }
+    }
+}
+
+/// Append a record to its process's log file.
+pub fn log(record: LogRecord) {
+    let Some(logs) = LOGS.get() else {
+        return;
+    };
+    let _ = logs.records.send(record);
+}
+
+/// The last `tail` records at or above `level`, of one process or of all of
+/// them, oldest first.
+pub fn query(process: Option<&ProcessId>, level: LogLevel, tail: usize) -> Vec<LogRecord> {
+    let Some(logs) = LOGS.get() else {
+        return vec![];
+    };
+    let tail = tail.min(MAX_QUERY_RECORDS);
+    let processes = match process {
+        Some(process) => vec![process.clone()],
+        None => logs.processes(),
+    };
+    let mut records: Vec<LogRecord> = processes
+        .iter()
+        .flat_map(|process| logs.read(process, level, tail))
+        .collect();
+    records.sort_by_key(|record| record.timestamp);
+    let skip = records.len().saturating_sub(tail);
+    records.split_off(skip)
+}
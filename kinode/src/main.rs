@@ -16,6 +16,8 @@ mod http;
 mod kernel;
 mod keygen;
 mod kv;
+mod logging;
+mod metrics;
 mod net;
 #[cfg(not(feature = "simulation-mode"))]
 mod register;
@@ -23,6 +25,7 @@ mod sqlite;
 mod state;
 mod terminal;
 mod timer;
+mod trace;
 mod vfs;
 
 const EVENT_LOOP_CHANNEL_CAPACITY: usize = 10_000;
@@ -57,6 +60,7 @@ async fn main() {
         .get_one::<String>("home")
         .expect("home directory required");
     create_home_directory(&home_directory_path).await;
+    logging::init(&home_directory_path);
     let http_server_port = set_http_server_port(matches.get_one::<u16>("port")).await;
     let ws_networking_port = matches.get_one::<u16>("ws-port");
     let verbose_mode = *matches
@@ -78,10 +82,17 @@ async fn main() {
 
     // default eth providers/routers
     let mut eth_provider_config: lib::eth::SavedConfigs =
-        match tokio::fs::read_to_string(format!("{}/.eth_providers", home_directory_path)).await {
-            Ok(contents) => {
+        match tokio::fs::read_to_string(format!("{}/{}", home_directory_path, eth::PROVIDERS_FILE))
+            .await
+            .map(|contents| serde_json::from_str(&contents))
+        {
+            Ok(Ok(saved)) => {
                 println!("loaded saved eth providers\r");
-                serde_json::from_str(&contents).unwrap()
+                saved
+            }
+            Ok(Err(e)) => {
+                println!("saved eth providers are malformed, using defaults: {e}\r");
+                serde_json::from_str(DEFAULT_ETH_PROVIDERS).unwrap()
             }
             Err(_) => serde_json::from_str(DEFAULT_ETH_PROVIDERS).unwrap(),
         };
@@ -92,9 +103,10 @@ async fn main() {
             provider: lib::eth::NodeOrRpcUrl::RpcUrl(rpc.to_string()),
         });
         // save the new provider config
-        tokio::fs::write(
-            format!("{}/.eth_providers", home_directory_path),
-            serde_json::to_string(&eth_provider_config).unwrap(),
+        eth::write_config(
+            home_directory_path,
+            eth::PROVIDERS_FILE,
+            &eth_provider_config,
         )
         .await
         .expect("failed to save new eth provider config!");
@@ -106,6 +118,12 @@ async fn main() {
             .get_one::<u16>("fakechain-port")
             .cloned()
             .unwrap_or(8545);
+        if *matches.get_one::<bool>("mock-chain").unwrap() {
+            fakenet::chain::serve(local_chain_port)
+                .await
+                .expect("failed to serve mock chain");
+            println!("serving mock chain at ws://localhost:{local_chain_port}\r");
+        }
         eth_provider_config.insert(lib::eth::ProviderConfig {
             chain_id: 31337,
             trusted: true,
@@ -288,6 +306,7 @@ async fn main() {
                 }
             })
             .collect(),
+        db.clone(),
     ));
     tasks.spawn(net::ws::networking(
         our.clone(),
@@ -295,10 +314,13 @@ async fn main() {
         networking_keypair_arc.clone(),
         kernel_message_sender.clone(),
         network_error_sender,
+        caps_oracle_sender.clone(),
         print_sender.clone(),
         net_message_sender,
         net_message_receiver,
         *matches.get_one::<bool>("reveal-ip").unwrap_or(&true),
+        *matches.get_one::<bool>("nat-traversal").unwrap_or(&true),
+        home_directory_path.clone(),
     ));
     tasks.spawn(state::state_sender(
         our.name.clone(),
@@ -329,6 +351,7 @@ async fn main() {
         http_server_port,
         encoded_keyfile,
         decoded_keyfile.jwt_secret_bytes.clone(),
+        *matches.get_one::<bool>("trust-loopback").unwrap(),
         http_server_receiver,
         kernel_message_sender.clone(),
         print_sender.clone(),
@@ -348,6 +371,7 @@ async fn main() {
     tasks.spawn(eth::provider(
         our.name.clone(),
         home_directory_path.clone(),
+        decoded_keyfile.file_key.clone(),
         eth_provider_config,
         kernel_message_sender.clone(),
         eth_provider_receiver,
@@ -407,6 +431,8 @@ async fn main() {
                             body: serde_json::to_vec(&KernelCommand::Shutdown).unwrap(),
                             metadata: None,
                             capabilities: vec![],
+                            priority: MessagePriority::System,
+                            deadline: None,
                         }),
                         lazy_load_blob: None,
                     })
@@ -621,7 +647,16 @@ fn build_command() -> Command {
                 .default_value("true")
                 .value_parser(value_parser!(bool)),
         )
-        .arg(arg!(--rpc <RPC> "Add a WebSockets RPC URL at boot"));
+        .arg(
+            arg!(--"nat-traversal" "If set to false, never attempt to map ports on our gateway with UPnP or NAT-PMP, or to punch through NATs to peers.")
+                .default_value("true")
+                .value_parser(value_parser!(bool)),
+        )
+        .arg(arg!(--rpc <RPC> "Add a WebSockets RPC URL at boot"))
+        .arg(
+            arg!(--"trust-loopback" "Serve metrics, logs and traces to requests from this machine without authentication.")
+                .action(clap::ArgAction::SetTrue),
+        );
 
     #[cfg(feature = "simulation-mode")]
     let app = app
@@ -631,6 +666,10 @@ fn build_command() -> Command {
             arg!(--"fakechain-port" <FAKECHAIN_PORT> "Port to bind to for fakechain")
                 .value_parser(value_parser!(u16)),
         )
+        .arg(
+            arg!(--"mock-chain" "Serve an in-process mock of the fakechain on the fakechain port, instead of using anvil. Other fake nodes can use it by passing the same port.")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             arg!(--detached <IS_DETACHED> "Run in detached mode (don't accept input)")
                 .action(clap::ArgAction::SetTrue),
@@ -0,0 +1,402 @@
+use dashmap::DashMap;
+use lib::types::core::{ProcessId, QueueMetrics};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The content type metrics are served with.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Upper bounds, in seconds, of the buckets runtime module latencies are counted in.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+lazy_static::lazy_static! {
+    static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Counters collected from across the runtime, to be scraped from http_server.
+/// Queue depths are sampled by the kernel rather than counted, so they may
+/// be a few seconds old.
+#[derive(Default)]
+struct Metrics {
+    processes: DashMap<ProcessId, ProcessCounters>,
+    /// kept when a process is killed, since a restart kills it first
+    restarts: DashMap<ProcessId, u64>,
+    queues: Mutex<Vec<QueueMetrics>>,
+    /// requests to local runtime modules awaiting a response: the module,
+    /// when the request was sent, and when to stop waiting for the response
+    pending: DashMap<u64, (ProcessId, Instant, Instant)>,
+    latencies: DashMap<ProcessId, Histogram>,
+    net_bytes_sent: AtomicU64,
+    net_bytes_received: AtomicU64,
+}
+
+#[derive(Default)]
+struct ProcessCounters {
+    sent: u64,
+    received: u64,
+    /// time spent running the process's code, including calls it makes to
+    /// the runtime, but not time spent waiting for its next message
+    wasm_time: Duration,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+fn with_process(process: &ProcessId, f: impl FnOnce(&mut ProcessCounters)) {
+    if let Some(mut counters) = METRICS.processes.get_mut(process) {
+        f(&mut counters);
+        return;
+    }
+    f(&mut METRICS.processes.entry(process.clone()).or_default());
+}
+
+/// the kernel routed a message from a local process
+pub fn message_sent(process: &ProcessId) {
+    with_process(process, |counters| counters.sent += 1);
+}
+
+/// the kernel routed a message to a local process
+pub fn message_received(process: &ProcessId) {
+    with_process(process, |counters| counters.received += 1);
+}
+
+pub fn wasm_time(process: &ProcessId, elapsed: Duration) {
+    with_process(process, |counters| counters.wasm_time += elapsed);
+}
+
+pub fn process_restarted(process: &ProcessId) {
+    *METRICS.restarts.entry(process.clone()).or_default() += 1;
+}
+
+/// drop the counters of a process that has been killed, so that short-lived
+/// processes don't pile up. its restarts are kept.
+pub fn process_killed(process: &ProcessId) {
+    METRICS.processes.remove(process);
+}
+
+pub fn sample_queues(queues: Vec<QueueMetrics>) {
+    *METRICS.queues.lock().unwrap() = queues;
+}
+
+/// the kernel passed a request that expects a response to a local runtime module
+pub fn runtime_request(id: u64, module: &ProcessId, timeout_secs: u64) {
+    let now = Instant::now();
+    METRICS.pending.insert(
+        id,
+        (
+            module.clone(),
+            now,
+            now + Duration::from_secs(timeout_secs.min(3600)),
+        ),
+    );
+}
+
+/// the kernel routed a response from a local process. if it answers a
+/// request to a runtime module, record how long the module took.
+pub fn runtime_response(id: u64, module: &ProcessId) {
+    let Some((_, (pending_module, sent, _))) = METRICS
+        .pending
+        .remove_if(&id, |_, (pending_module, _, _)| pending_module == module)
+    else {
+        return;
+    };
+    METRICS
+        .latencies
+        .entry(pending_module)
+        .or_default()
+        .observe(sent.elapsed().as_secs_f64());
+}
+
+/// forget requests to runtime modules that were never answered
+pub fn prune() {
+    let now = Instant::now();
+    METRICS.pending.retain(|_, (_, _, give_up)| *give_up > now);
+}
+
+pub fn net_bytes_sent(bytes: usize) {
+    METRICS
+        .net_bytes_sent
+        .fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn net_bytes_received(bytes: usize) {
+    METRICS
+        .net_bytes_received
+        .fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "# HELP {name} {help}");
+}
+
+/// all metrics, in OpenMetrics text format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let mut processes: Vec<(ProcessId, u64, u64, f64)> = METRICS
+        .processes
+        .iter()
+        .map(|entry| {
+            (
+                entry.key().clone(),
+                entry.sent,
+                entry.received,
+                entry.wasm_time.as_secs_f64(),
+            )
+        })
+        .collect();
+    processes.sort_by(|a, b| a.0.to_string().cmp(&b.0.to_string()));
+    family(
+        &mut out,
+        "kinode_process_messages_sent",
+        "counter",
+        "Messages the kernel routed from each local process.",
+    );
+    for (process, sent, _, _) in &processes {
+        let process = escape(&process.to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_messages_sent_total{{process=\"{process}\"}} {sent}"
+        );
+    }
+    family(
+        &mut out,
+        "kinode_process_messages_received",
+        "counter",
+        "Messages the kernel routed to each local process.",
+    );
+    for (process, _, received, _) in &processes {
+        let process = escape(&process.to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_messages_received_total{{process=\"{process}\"}} {received}"
+        );
+    }
+    family(
+        &mut out,
+        "kinode_process_wasm_seconds",
+        "counter",
+        "Time spent running each process's code, excluding waiting for messages.",
+    );
+    for (process, _, _, wasm_seconds) in &processes {
+        let process = escape(&process.to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_wasm_seconds_total{{process=\"{process}\"}} {wasm_seconds}"
+        );
+    }
+
+    family(
+        &mut out,
+        "kinode_process_restarts",
+        "counter",
+        "Times each process has been restarted after exiting.",
+    );
+    for entry in METRICS.restarts.iter() {
+        let process = escape(&entry.key().to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_restarts_total{{process=\"{process}\"}} {}",
+            entry.value()
+        );
+    }
+
+    let queues = METRICS.queues.lock().unwrap();
+    family(
+        &mut out,
+        "kinode_process_queue_depth",
+        "gauge",
+        "Messages waiting for each process.",
+    );
+    for queue in queues.iter() {
+        let process = escape(&queue.process.to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_queue_depth{{process=\"{process}\"}} {}",
+            queue.depth
+        );
+    }
+    family(
+        &mut out,
+        "kinode_process_queue_capacity",
+        "gauge",
+        "Most messages that may wait for each process.",
+    );
+    for queue in queues.iter() {
+        let process = escape(&queue.process.to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_queue_capacity{{process=\"{process}\"}} {}",
+            queue.capacity
+        );
+    }
+    family(
+        &mut out,
+        "kinode_process_queue_refused",
+        "counter",
+        "Messages each process's full queue rejected or dropped.",
+    );
+    for queue in queues.iter() {
+        let process = escape(&queue.process.to_string());
+        let _ = writeln!(
+            out,
+            "kinode_process_queue_refused_total{{process=\"{process}\",policy=\"reject\"}} {}",
+            queue.rejected
+        );
+        let _ = writeln!(
+            out,
+            "kinode_process_queue_refused_total{{process=\"{process}\",policy=\"drop\"}} {}",
+            queue.dropped
+        );
+    }
+    drop(queues);
+
+    family(
+        &mut out,
+        "kinode_runtime_request_duration_seconds",
+        "histogram",
+        "Time each runtime module took to respond to a local request.",
+    );
+    for entry in METRICS.latencies.iter() {
+        let module = escape(&entry.key().to_string());
+        let histogram = entry.value();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "kinode_runtime_request_duration_seconds_bucket{{module=\"{module}\",le=\"{bound:?}\"}} {count}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "kinode_runtime_request_duration_seconds_bucket{{module=\"{module}\",le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "kinode_runtime_request_duration_seconds_count{{module=\"{module}\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "kinode_runtime_request_duration_seconds_sum{{module=\"{module}\"}} {}",
+            histogram.sum
+        );
+    }
+
+    family(
+        &mut out,
+        "kinode_net_bytes_sent",
+        "counter",
+        "Bytes sent to other nodes, including traffic routed for them.",
+    );
+    let _ = writeln!(
+        out,
+        "kinode_net_bytes_sent_total {}",
+        METRICS.net_bytes_sent.load(Ordering::Relaxed)
+    );
+    family(
+        &mut out,
+        "kinode_net_bytes_received",
+        "counter",
+        "Bytes received from other nodes, including traffic routed for them.",
+    );
+    let _ = writeln!(
+        out,
+        "kinode_net_bytes_received_total {}",
+        METRICS.net_bytes_received.load(Ordering::Relaxed)
+    );
+
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.5);
+        histogram.observe(10.0);
+        assert_eq!(histogram.buckets, [0, 0, 1, 1, 1, 1, 1, 1, 2, 2]);
+        assert_eq!(histogram.count, 3);
+        assert!((histogram.sum - 10.503).abs() < 1e-9);
+    }
+
+    #[test]
+    fn render_reports_what_was_recorded() {
+        let process: ProcessId = "render-test:metrics:sys".parse().unwrap();
+        let module: ProcessId = "render-test-module:metrics:sys".parse().unwrap();
+        message_sent(&process);
+        message_sent(&process);
+        message_received(&process);
+        process_restarted(&process);
+        runtime_request(u64::MAX, &module, 10);
+        // a response from another process doesn't answer the request
+        runtime_response(u64::MAX, &process);
+        runtime_response(u64::MAX, &module);
+
+        let out = render();
+        assert!(out.ends_with("# EOF\n"));
+        assert!(out.contains(
+            "kinode_process_messages_sent_total{process=\"render-test:metrics:sys\"} 2\n"
+        ));
+        assert!(out.contains(
+            "kinode_process_messages_received_total{process=\"render-test:metrics:sys\"} 1\n"
+        ));
+        assert!(
+            out.contains("kinode_process_restarts_total{process=\"render-test:metrics:sys\"} 1\n")
+        );
+        assert!(out.contains(
+            "kinode_runtime_request_duration_seconds_count{module=\"render-test-module:metrics:sys\"} 1\n"
+        ));
+        assert!(out.contains(
+            "kinode_runtime_request_duration_seconds_bucket{module=\"render-test-module:metrics:sys\",le=\"+Inf\"} 1\n"
+        ));
+        // every family is declared before its samples
+        for line in out.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            assert!(
+                out.lines().any(|declared| declared.starts_with("# TYPE ")
+                    && name.starts_with(declared.split(' ').nth(2).unwrap())),
+                "undeclared sample: {line}"
+            );
+        }
+
+        process_killed(&process);
+        assert!(!render().contains("kinode_process_messages_sent_total{process=\"render-test"));
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -1,3 +1,5 @@
+pub mod nat;
+pub mod outbox;
 pub mod types;
 pub mod utils;
 pub mod ws;
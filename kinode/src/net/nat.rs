@@ -0,0 +1,394 @@
+use crate::net::utils::print_debug;
+use anyhow::{anyhow, Result};
+use lib::types::core::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::time::{timeout, Instant};
+
+/// lifetime we request for port mappings. mappings are renewed at half this.
+const LEASE_SECS: u32 = 60 * 60;
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const NAT_PMP_PORT: u16 = 5351;
+/// how long each connection attempt of a hole punch waits for an answer
+const PUNCH_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+/// how long to wait between the connection attempts of a hole punch
+const PUNCH_ATTEMPT_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MappingProtocol {
+    Upnp,
+    NatPmp,
+    /// not a mapping on our gateway, but the address one of our routers saw
+    /// us connect from `internal_port`. peers can only reach us there if we
+    /// punch a hole to them at the same time.
+    Router,
+}
+
+/// A TCP port mapping on our gateway, making `internal_port` on this machine
+/// reachable from the internet at `external_ip:external_port`.
+#[derive(Clone, Debug)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    pub external_ip: String,
+    pub external_port: u16,
+    pub internal_port: u16,
+}
+
+/// Try to map `internal_port` on our gateway, first with UPnP-IGD, then NAT-PMP.
+pub async fn map_port(internal_port: u16) -> Result<PortMapping> {
+    match upnp_map_port(internal_port).await {
+        Ok(mapping) => Ok(mapping),
+        Err(upnp_error) => nat_pmp_map_port(internal_port)
+            .await
+            .map_err(|e| anyhow!("UPnP failed: {upnp_error}; NAT-PMP failed: {e}")),
+    }
+}
+
+/// Map `internal_port` and keep renewing the mapping. If given, `on_mapped` is
+/// sent the mapping each time it is (re)established, and the task exits once
+/// its receiver is dropped. Should always be spawned on its own task.
+pub async fn maintain_port_mapping(
+    internal_port: u16,
+    on_mapped: Option<tokio::sync::mpsc::UnboundedSender<PortMapping>>,
+    print_tx: PrintSender,
+) {
+    loop {
+        match map_port(internal_port).await {
+            Ok(mapping) => {
+                let _ = print_tx
+                    .send(Printout {
+                        verbosity: 0,
+                        content: format!(
+                            "net: mapped port {} to {}:{} using {:?}",
+                            internal_port,
+                            mapping.external_ip,
+                            mapping.external_port,
+                            mapping.protocol,
+                        ),
+                    })
+                    .await;
+                if let Some(ref on_mapped) = on_mapped {
+                    if on_mapped.send(mapping).is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(LEASE_SECS as u64 / 2)).await;
+            }
+            Err(e) => {
+                print_debug(&print_tx, &format!("net: couldn't map port: {e}")).await;
+                // most likely there's no gateway that supports mapping: try
+                // again much later in case the network changes
+                tokio::time::sleep(Duration::from_secs(LEASE_SECS as u64)).await;
+            }
+        }
+    }
+}
+
+//
+// TCP hole punching
+//
+
+/// A TCP socket on `local_port` that can share it with a listener and with
+/// other connections, so that every connection we make from it goes out
+/// through the same mapping on our NAT.
+fn reusable_socket(addr: SocketAddr, local_port: u16) -> std::io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    let local_ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    socket.bind(SocketAddr::new(local_ip, local_port))?;
+    Ok(socket)
+}
+
+/// Listen on a port that connections can also be made from with
+/// [`connect_from`]. Pass 0 to have one assigned.
+pub fn reusable_listener(port: u16) -> std::io::Result<TcpListener> {
+    reusable_socket(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), port)?.listen(1024)
+}
+
+/// Connect to `addr` from `local_port`, which may be shared with a listener.
+pub async fn connect_from(local_port: u16, addr: SocketAddr) -> std::io::Result<TcpStream> {
+    reusable_socket(addr, local_port)?.connect(addr).await
+}
+
+/// Try to connect from `local_port` to a peer behind a NAT, over and over
+/// until `window` has passed. The peer does the same towards us, so that
+/// each side's NAT sees the other's packets as answers to its own. Returns
+/// the first connection made, if any.
+pub async fn punch(local_port: u16, addr: SocketAddr, window: Duration) -> Option<TcpStream> {
+    let deadline = Instant::now() + window;
+    while Instant::now() < deadline {
+        if let Ok(Ok(stream)) = timeout(PUNCH_ATTEMPT_TIMEOUT, connect_from(local_port, addr)).await
+        {
+            return Some(stream);
+        }
+        tokio::time::sleep(PUNCH_ATTEMPT_INTERVAL).await;
+    }
+    None
+}
+
+/// Whether an address can be reached over the internet: not loopback,
+/// private, link-local, or otherwise reserved.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // shared address space for carrier-grade NAT (RFC 6598)
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    // unique local (fc00::/7) and link-local (fe80::/10)
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+//
+// UPnP Internet Gateway Device protocol
+//
+
+async fn upnp_map_port(internal_port: u16) -> Result<PortMapping> {
+    let (gateway, location) = ssdp_discover().await?;
+    let client = reqwest::Client::builder()
+        .timeout(DISCOVERY_TIMEOUT)
+        .build()?;
+    let description = client.get(location.clone()).send().await?.text().await?;
+    let (service_type, control_url) =
+        find_wan_service(&description).ok_or(anyhow!("gateway has no WAN connection service"))?;
+    let control_url = location.join(&control_url)?;
+    let local_ip = local_ip_towards(gateway).await?;
+
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{internal_port}</NewExternalPort>\
+         <NewProtocol>TCP</NewProtocol>\
+         <NewInternalPort>{internal_port}</NewInternalPort>\
+         <NewInternalClient>{local_ip}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>kinode</NewPortMappingDescription>\
+         <NewLeaseDuration>{LEASE_SECS}</NewLeaseDuration>"
+    );
+    soap_request(
+        &client,
+        &control_url,
+        &service_type,
+        "AddPortMapping",
+        &args,
+    )
+    .await?;
+    let response = soap_request(
+        &client,
+        &control_url,
+        &service_type,
+        "GetExternalIPAddress",
+        "",
+    )
+    .await?;
+    let external_ip = xml_tag(&response, "NewExternalIPAddress")
+        .ok_or(anyhow!("gateway didn't report an external IP"))?;
+    Ok(PortMapping {
+        protocol: MappingProtocol::Upnp,
+        external_ip,
+        external_port: internal_port,
+        internal_port,
+    })
+}
+
+/// multicast an M-SEARCH for an internet gateway and return the address
+/// of the first to respond along with its device description URL
+async fn ssdp_discover() -> Result<(Ipv4Addr, url::Url)> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = "M-SEARCH * HTTP/1.1\r\n\
+                  HOST: 239.255.255.250:1900\r\n\
+                  MAN: \"ssdp:discover\"\r\n\
+                  MX: 2\r\n\
+                  ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+    socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+    let mut buf = [0u8; 2048];
+    let (len, from) = timeout(DISCOVERY_TIMEOUT, socket.recv_from(&mut buf)).await??;
+    let SocketAddr::V4(from) = from else {
+        return Err(anyhow!("gateway responded over IPv6"));
+    };
+    let response = std::str::from_utf8(&buf[..len])?;
+    let location = response
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        })
+        .ok_or(anyhow!("SSDP response had no location"))?;
+    Ok((*from.ip(), url::Url::parse(&location)?))
+}
+
+/// find the first WANIPConnection or WANPPPConnection service in a device
+/// description, returning its service type and control URL
+fn find_wan_service(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_tag(service, "serviceType")?;
+        if !service_type.contains(":service:WANIPConnection:")
+            && !service_type.contains(":service:WANPPPConnection:")
+        {
+            return None;
+        }
+        Some((service_type, xml_tag(service, "controlURL")?))
+    })
+}
+
+async fn soap_request(
+    client: &reqwest::Client,
+    control_url: &url::Url,
+    service_type: &str,
+    action: &str,
+    args: &str,
+) -> Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service_type}\">{args}</u:{action}></s:Body>\
+         </s:Envelope>"
+    );
+    let response = client
+        .post(control_url.clone())
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{service_type}#{action}\""))
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("{action} failed with status {}", response.status()));
+    }
+    Ok(response.text().await?)
+}
+
+fn xml_tag(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(xml[start..end].trim().to_string())
+}
+
+//
+// NAT Port Mapping Protocol (RFC 6886)
+//
+
+async fn nat_pmp_map_port(internal_port: u16) -> Result<PortMapping> {
+    let gateway = default_gateway().await?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    let mut buf = [0u8; 16];
+
+    // external address request
+    socket.send(&[0, 0]).await?;
+    let len = timeout(DISCOVERY_TIMEOUT, socket.recv(&mut buf)).await??;
+    if len < 12 || buf[1] != 128 || u16::from_be_bytes([buf[2], buf[3]]) != 0 {
+        return Err(anyhow!("gateway refused external address request"));
+    }
+    let external_ip = Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11]);
+
+    // TCP mapping request
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&LEASE_SECS.to_be_bytes());
+    socket.send(&request).await?;
+    let len = timeout(DISCOVERY_TIMEOUT, socket.recv(&mut buf)).await??;
+    if len < 16 || buf[1] != 130 || u16::from_be_bytes([buf[2], buf[3]]) != 0 {
+        return Err(anyhow!("gateway refused mapping request"));
+    }
+    Ok(PortMapping {
+        protocol: MappingProtocol::NatPmp,
+        external_ip: external_ip.to_string(),
+        external_port: u16::from_be_bytes([buf[10], buf[11]]),
+        internal_port,
+    })
+}
+
+/// read the default gateway from the routing table where available, otherwise
+/// assume the gateway is the `.1` address of our local subnet
+async fn default_gateway() -> Result<Ipv4Addr> {
+    if let Ok(routes) = tokio::fs::read_to_string("/proc/net/route").await {
+        for line in routes.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() > 2 && fields[1] == "00000000" {
+                if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+                    // the routing table prints addresses in host byte order
+                    return Ok(Ipv4Addr::from(gateway.to_ne_bytes()));
+                }
+            }
+        }
+    }
+    let local = local_ip_towards(Ipv4Addr::new(1, 1, 1, 1)).await?;
+    let [a, b, c, _] = local.octets();
+    Ok(Ipv4Addr::new(a, b, c, 1))
+}
+
+/// the local address we would use to reach `target`. no packets are sent.
+async fn local_ip_towards(target: Ipv4Addr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((target, 9)).await?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => Err(anyhow!("no local IPv4 address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_internet_addresses_are_public() {
+        for ip in [
+            "1.1.1.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use anyhow::Result;
+use lib::types::core::*;
+use rocksdb::{IteratorMode, DB};
+use std::collections::{HashMap, HashSet};
+
+/// longest we will agree to hold a message for an indirect node we route for
+pub const MAX_HOLD_SECS: u64 = 60 * 60 * 24;
+/// most messages we will hold, as a router, from any one source node
+pub const MAX_HELD_PER_SOURCE: usize = 100;
+/// most messages we will hold, as a router, from all source nodes together
+pub const MAX_HELD: usize = 10_000;
+/// most bytes of messages we will hold, as a router, from all source nodes
+pub const MAX_HELD_BYTES: u64 = 256 * 1024 * 1024;
+/// how far past our clock a held message's deadline may be, to allow for
+/// the source's clock being ahead of ours
+pub const MAX_CLOCK_SKEW_SECS: u64 = 60;
+/// delay before the first retry; doubles with each failed attempt
+const BASE_BACKOFF_SECS: u64 = 5;
+const MAX_BACKOFF_SECS: u64 = 60 * 10;
+/// prefix of the keys that held messages delivered to us are recorded under.
+/// outbox entries are keyed by a bare 8-byte key of our own choosing, since
+/// the ids of held messages are chosen by other nodes.
+const DELIVERED_PREFIX: &[u8] = b"delivered:";
+
+/// Requests awaiting store-and-forward delivery. Entries are kept in memory
+/// and mirrored to a RocksDB instance in the home directory, so they survive
+/// a reboot.
+pub struct Outbox {
+    db: DB,
+    /// by key
+    entries: HashMap<u64, OutboxEntry>,
+    /// keys of entries with a delivery attempt currently underway
+    in_flight: HashSet<u64>,
+    /// count and total size of the messages we are holding as a router
+    held: usize,
+    held_bytes: u64,
+    /// held messages our routers have delivered to us, by source node and
+    /// id, with the deadline they were signed with. each is remembered until
+    /// its deadline so that it can't be delivered to us twice.
+    delivered: HashMap<(NodeId, u64), u64>,
+}
+
+impl Outbox {
+    pub async fn open(home_directory_path: &str) -> Result<Self> {
+        let outbox_path = format!("{}/net/outbox", home_directory_path);
+        tokio::fs::create_dir_all(&outbox_path).await?;
+        let db = DB::open_default(outbox_path)?;
+        let mut entries = HashMap::new();
+        let mut delivered = HashMap::new();
+        for item in db.iterator(IteratorMode::Start) {
+            let (key, value) = item?;
+            if key.starts_with(DELIVERED_PREFIX) {
+                let (source, id, deadline): (NodeId, u64, u64) = bincode::deserialize(&value)?;
+                delivered.insert((source, id), deadline);
+                continue;
+            }
+            let Ok(key) = <[u8; 8]>::try_from(&*key) else {
+                continue;
+            };
+            let entry: OutboxEntry = bincode::deserialize(&value)?;
+            entries.insert(u64::from_be_bytes(key), entry);
+        }
+        let mut outbox = Self {
+            db,
+            entries: HashMap::new(),
+            in_flight: HashSet::new(),
+            held: 0,
+            held_bytes: 0,
+            delivered,
+        };
+        for (key, entry) in entries {
+            outbox.track(key, entry);
+        }
+        Ok(outbox)
+    }
+
+    /// Add an entry, returning the key it is kept under.
+    pub fn insert(&mut self, entry: OutboxEntry) -> Result<u64> {
+        let mut key: u64 = rand::random();
+        while self.entries.contains_key(&key) {
+            key = rand::random();
+        }
+        self.write(key, entry)?;
+        Ok(key)
+    }
+
+    /// Remove an entry. It is forgotten even if it can't be deleted from
+    /// disk, in which case it will be attempted again after a reboot.
+    pub fn remove(&mut self, key: u64) -> Result<()> {
+        self.in_flight.remove(&key);
+        self.untrack(key);
+        self.db.delete(key.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Vec<OutboxEntry> {
+        self.entries.values().cloned().collect()
+    }
+
+    /// number and total size of the messages we are holding as a router
+    pub fn held(&self) -> (usize, u64) {
+        (self.held, self.held_bytes)
+    }
+
+    /// number of messages we are holding, as a router, that came from `node`
+    pub fn held_from(&self, node: &str) -> usize {
+        self.entries
+            .values()
+            .filter(|e| e.held_signature.is_some() && e.message.source.node == node)
+            .count()
+    }
+
+    /// Get all entries due for a delivery attempt, with their keys, marking
+    /// them in flight. Each must later be passed to [`Outbox::remove`],
+    /// [`Outbox::release`], or [`Outbox::reschedule`].
+    pub fn take_due(&mut self, now: u64) -> Vec<(u64, OutboxEntry)> {
+        let due: Vec<(u64, OutboxEntry)> = self
+            .entries
+            .iter()
+            .filter(|(key, e)| e.next_attempt <= now && !self.in_flight.contains(key))
+            .map(|(key, e)| (*key, e.clone()))
+            .collect();
+        for (key, _) in &due {
+            self.in_flight.insert(*key);
+        }
+        due
+    }
+
+    /// leave an entry as-is, to be tried again on the next pass
+    pub fn release(&mut self, key: u64) {
+        self.in_flight.remove(&key);
+    }
+
+    /// record a failed delivery attempt and schedule the next one with backoff
+    pub fn reschedule(&mut self, key: u64, now: u64) -> Result<()> {
+        self.in_flight.remove(&key);
+        let Some(mut entry) = self.entries.get(&key).cloned() else {
+            return Ok(());
+        };
+        entry.attempts += 1;
+        entry.next_attempt = now
+            + BASE_BACKOFF_SECS
+                .saturating_mul(1 << entry.attempts.min(16))
+                .min(MAX_BACKOFF_SECS);
+        self.write(key, entry)
+    }
+
+    fn write(&mut self, key: u64, entry: OutboxEntry) -> Result<()> {
+        self.db
+            .put(key.to_be_bytes(), bincode::serialize(&entry)?)?;
+        self.untrack(key);
+        self.track(key, entry);
+        Ok(())
+    }
+
+    fn track(&mut self, key: u64, entry: OutboxEntry) {
+        if entry.held_signature.is_some() {
+            self.held += 1;
+            self.held_bytes += held_size(&entry);
+        }
+        self.entries.insert(key, entry);
+    }
+
+    fn untrack(&mut self, key: u64) {
+        let Some(entry) = self.entries.remove(&key) else {
+            return;
+        };
+        if entry.held_signature.is_some() {
+            self.held -= 1;
+            self.held_bytes -= held_size(&entry);
+        }
+    }
+
+    /// Record that one of our routers delivered us a held message, signed with
+    /// `deadline`. Returns false if it has already been delivered, or if its
+    /// deadline has passed and so it may have been delivered and forgotten.
+    pub fn record_delivery(
+        &mut self,
+        source: &str,
+        id: u64,
+        deadline: u64,
+        now: u64,
+    ) -> Result<bool> {
+        let expired: Vec<(NodeId, u64)> = self
+            .delivered
+            .iter()
+            .filter(|(_, deadline)| **deadline < now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (source, id) in expired {
+            self.delivered.remove(&(source.clone(), id));
+            self.db.delete(delivered_key(&source, id))?;
+        }
+        if deadline < now || self.delivered.contains_key(&(source.to_string(), id)) {
+            return Ok(false);
+        }
+        self.db.put(
+            delivered_key(source, id),
+            bincode::serialize(&(source, id, deadline))?,
+        )?;
+        self.delivered.insert((source.to_string(), id), deadline);
+        Ok(true)
+    }
+}
+
+/// how many bytes a held message counts for against [`MAX_HELD_BYTES`]
+pub fn held_size(entry: &OutboxEntry) -> u64 {
+    bincode::serialized_size(entry).unwrap_or_default()
+}
+
+fn delivered_key(source: &str, id: u64) -> Vec<u8> {
+    [DELIVERED_PREFIX, source.as_bytes(), b":", &id.to_be_bytes()].concat()
+}
+
+/// The bytes a source node signs to have a router hold a message for a node,
+/// binding the deadline to the message so the router can't extend it.
+pub fn held_message_bytes(message: &KernelMessage, deadline: u64) -> Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec(&(message, deadline))?)
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn home(name: &str) -> String {
+        let home =
+            std::env::temp_dir().join(format!("kinode-outbox-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&home);
+        home.to_string_lossy().into_owned()
+    }
+
+    fn entry(id: u64, next_attempt: u64) -> OutboxEntry {
+        let address = |node: &str| Address {
+            node: node.into(),
+            process: "a:b:c".parse().unwrap(),
+        };
+        OutboxEntry {
+            message: KernelMessage {
+                id,
+                source: address("source.os"),
+                target: address("target.os"),
+                rsvp: None,
+                message: Message::Request(Request {
+                    inherit: false,
+                    expects_response: None,
+                    body: vec![],
+                    metadata: None,
+                    capabilities: vec![],
+                    priority: MessagePriority::User,
+                    deadline: None,
+                }),
+                lazy_load_blob: None,
+            },
+            deadline: 1_000,
+            router_hold: false,
+            attempts: 0,
+            next_attempt,
+            held_signature: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn due_entries_are_taken_once() {
+        let home = home("due");
+        let mut outbox = Outbox::open(&home).await.unwrap();
+        let first = outbox.insert(entry(1, 10)).unwrap();
+        let second = outbox.insert(entry(2, 20)).unwrap();
+
+        let due = outbox.take_due(10);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, first);
+        assert_eq!(due[0].1.message.id, 1);
+        // in flight until released
+        assert!(outbox.take_due(10).is_empty());
+        outbox.release(first);
+        assert_eq!(outbox.take_due(20).len(), 2);
+
+        outbox.remove(first).unwrap();
+        outbox.remove(second).unwrap();
+        assert!(outbox.entries().is_empty());
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_back_off() {
+        let home = home("backoff");
+        let mut outbox = Outbox::open(&home).await.unwrap();
+        let key = outbox.insert(entry(1, 0)).unwrap();
+
+        outbox.take_due(0);
+        outbox.reschedule(key, 100).unwrap();
+        assert_eq!(outbox.entries()[0].attempts, 1);
+        assert_eq!(
+            outbox.entries()[0].next_attempt,
+            100 + 2 * BASE_BACKOFF_SECS
+        );
+
+        for _ in 0..20 {
+            outbox.reschedule(key, 100).unwrap();
+        }
+        assert_eq!(outbox.entries()[0].next_attempt, 100 + MAX_BACKOFF_SECS);
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[tokio::test]
+    async fn entries_survive_reopening() {
+        let home = home("reopen");
+        {
+            let mut outbox = Outbox::open(&home).await.unwrap();
+            outbox.insert(entry(1, 0)).unwrap();
+            let removed = outbox.insert(entry(2, 0)).unwrap();
+            outbox.remove(removed).unwrap();
+            assert!(outbox.record_delivery("source.os", 7, 50, 0).unwrap());
+        }
+        let mut outbox = Outbox::open(&home).await.unwrap();
+        let entries = outbox.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].message.id, 1);
+        // a delivery recorded before the reboot is still remembered
+        assert!(!outbox.record_delivery("source.os", 7, 50, 0).unwrap());
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[tokio::test]
+    async fn held_deliveries_are_accepted_once() {
+        let home = home("delivered");
+        let mut outbox = Outbox::open(&home).await.unwrap();
+        assert!(outbox.record_delivery("source.os", 1, 50, 10).unwrap());
+        assert!(!outbox.record_delivery("source.os", 1, 50, 20).unwrap());
+        // the same id from another source is a different message
+        assert!(outbox.record_delivery("other.os", 1, 50, 20).unwrap());
+        // an expired message may have been delivered and forgotten
+        assert!(!outbox.record_delivery("source.os", 2, 5, 10).unwrap());
+        // once its deadline passes, a delivery is forgotten
+        assert!(!outbox.record_delivery("source.os", 1, 50, 60).unwrap());
+        assert!(outbox.delivered.is_empty());
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[tokio::test]
+    async fn held_messages_are_counted_by_source() {
+        let home = home("held");
+        let mut outbox = Outbox::open(&home).await.unwrap();
+        let mut held = entry(1, 0);
+        held.held_signature = Some(vec![]);
+        let size = held_size(&held);
+        let key = outbox.insert(held.clone()).unwrap();
+        // a held message can't replace another with the same id
+        let other_key = outbox.insert(held).unwrap();
+        assert_ne!(key, other_key);
+        outbox.insert(entry(1, 0)).unwrap();
+        assert_eq!(outbox.entries().len(), 3);
+        assert_eq!(outbox.held_from("source.os"), 2);
+        assert_eq!(outbox.held_from("other.os"), 0);
+        assert_eq!(outbox.held(), (2, 2 * size));
+
+        // rescheduling doesn't count a message twice
+        outbox.reschedule(key, 0).unwrap();
+        assert_eq!(outbox.held(), (2, 2 * size));
+        outbox.remove(key).unwrap();
+        assert_eq!(outbox.held(), (1, size));
+        drop(outbox);
+        let outbox = Outbox::open(&home).await.unwrap();
+        assert_eq!(outbox.held(), (1, size));
+        let _ = std::fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn held_message_bytes_bind_the_deadline() {
+        let message = entry(1, 0).message;
+        assert_ne!(
+            held_message_bytes(&message, 100).unwrap(),
+            held_message_bytes(&message, 101).unwrap()
+        );
+    }
+}
@@ -1,9 +1,12 @@
+use crate::net::nat::PortMapping;
 use dashmap::DashMap;
 use futures::stream::{SplitSink, SplitStream};
 use lib::types::core::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
@@ -60,6 +63,8 @@ pub struct PeerConnection {
 }
 
 pub struct PassthroughConnection {
+    pub source: NodeId,
+    pub target: NodeId,
     pub write_stream_1: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Message>,
     pub read_stream_1: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     pub write_stream_2: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Message>,
@@ -76,6 +81,27 @@ pub type Peers = Arc<DashMap<String, Peer>>;
 pub type PKINames = Arc<DashMap<String, NodeId>>;
 pub type OnchainPKI = Arc<DashMap<String, Identity>>;
 pub type PendingPassthroughs = HashMap<(NodeId, NodeId), PendingPassthroughConnection>;
+/// connection id -> (source, target, stats) for passthroughs we are currently
+/// forwarding. keyed by connection, since a pair may briefly have two while
+/// a new passthrough replaces an old one.
+pub type Passthroughs = Arc<DashMap<u64, (NodeId, NodeId, Arc<ConnectionStats>)>>;
+
+/// What we need to replace routed connections with direct ones, on either
+/// side of a [`NetAction::DirectOffer`].
+#[derive(Default)]
+pub struct DirectUpgrades {
+    /// peer -> when we last tried to take up a direct connection offer from them
+    pub tried: HashMap<NodeId, Instant>,
+    /// the port we listen on for peers taking up our offers, and make our
+    /// connections to routers and punches to peers from. None if we don't
+    /// offer direct connections.
+    pub local_port: Option<u16>,
+    /// where peers can reach `local_port`: a mapping on our gateway, or else
+    /// the address one of our routers saw us connect from
+    pub ours: Option<PortMapping>,
+    /// routed peers we've sent `ours` to
+    pub offered_to: HashSet<NodeId>,
+}
 
 #[derive(Clone)]
 pub struct Peer {
@@ -83,5 +109,96 @@ pub struct Peer {
     /// If true, we are routing for them and have a RoutingClientConnection
     /// associated with them. We can send them prompts to establish Passthroughs.
     pub routing_for: bool,
+    pub connection_type: ConnectionType,
     pub sender: UnboundedSender<KernelMessage>,
+    pub stats: Arc<ConnectionStats>,
+}
+
+/// Traffic counters for a single connection, shared between the task
+/// maintaining the connection and diagnostics requests.
+#[derive(Debug)]
+pub struct ConnectionStats {
+    pub started: Instant,
+    pub messages_sent: AtomicU64,
+    pub messages_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    /// microseconds since `started` at which our outstanding keepalive ping
+    /// was sent, or 0 if there is none
+    ping_sent_at: AtomicU64,
+    /// round-trip time of the last answered ping in microseconds, or 0 if none
+    rtt_micros: AtomicU64,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            ping_sent_at: AtomicU64::new(0),
+            rtt_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        crate::metrics::net_bytes_sent(bytes);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        crate::metrics::net_bytes_received(bytes);
+    }
+
+    pub fn ping_sent(&self) {
+        // add one so that a ping sent at the exact start doesn't read as "none"
+        let now = self.started.elapsed().as_micros() as u64 + 1;
+        self.ping_sent_at.store(now, Ordering::Relaxed);
+    }
+
+    pub fn pong_received(&self) {
+        let sent_at = self.ping_sent_at.swap(0, Ordering::Relaxed);
+        if sent_at == 0 {
+            return;
+        }
+        let now = self.started.elapsed().as_micros() as u64 + 1;
+        self.rtt_micros
+            .store(now.saturating_sub(sent_at).max(1), Ordering::Relaxed);
+    }
+
+    pub fn to_diagnostics(
+        &self,
+        name: &str,
+        connection_type: ConnectionType,
+        routing_for: bool,
+    ) -> PeerDiagnostics {
+        let rtt_micros = self.rtt_micros.load(Ordering::Relaxed);
+        PeerDiagnostics {
+            name: name.to_string(),
+            connection_type,
+            routing_for,
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            rtt_ms: if rtt_micros == 0 {
+                None
+            } else {
+                Some(rtt_micros / 1000)
+            },
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
 }
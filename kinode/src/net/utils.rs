@@ -4,7 +4,9 @@ use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
 use lib::types::core::*;
 use ring::signature::{self, Ed25519KeyPair};
+use serde::Deserialize;
 use snow::params::NoiseParams;
+use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 use tokio::time::timeout;
@@ -19,6 +21,7 @@ lazy_static::lazy_static! {
 pub async fn save_new_peer(
     identity: &Identity,
     routing_for: bool,
+    connection_type: ConnectionType,
     peers: Peers,
     conn: PeerConnection,
     km: Option<KernelMessage>,
@@ -33,7 +36,9 @@ pub async fn save_new_peer(
     let peer = Peer {
         identity: identity.clone(),
         routing_for,
+        connection_type,
         sender: peer_tx,
+        stats: Arc::new(ConnectionStats::new()),
     };
     peers.insert(identity.name.clone(), peer.clone());
     tokio::spawn(maintain_connection(
@@ -55,11 +60,16 @@ pub async fn maintain_connection(
     kernel_message_tx: MessageSender,
     print_tx: PrintSender,
 ) {
-    let peer_name = peer.identity.name;
+    // don't hold on to our own sender: if this peer is replaced in `peers`
+    // by a new connection, `peer_rx` will close and this one will shut down
+    let Peer {
+        identity, stats, ..
+    } = peer;
+    let peer_name = identity.name;
     let mut last_message = std::time::Instant::now();
     loop {
         tokio::select! {
-            recv_result = recv_protocol_message(&mut conn) => {
+            recv_result = recv_protocol_message(&mut conn, &stats) => {
                 match recv_result {
                     Ok(km) => {
                         if km.source.node != peer_name {
@@ -81,7 +91,7 @@ pub async fn maintain_connection(
             maybe_recv = peer_rx.recv() => {
                 match maybe_recv {
                     Some(km) => {
-                        match send_protocol_message(&km, &mut conn).await {
+                        match send_protocol_message(&km, &mut conn, &stats).await {
                             Ok(()) => {
                                 last_message = std::time::Instant::now();
                                 continue
@@ -109,7 +119,10 @@ pub async fn maintain_connection(
             // keepalive ping -- can adjust time based on testing
             _ = tokio::time::sleep(std::time::Duration::from_secs(30)) => {
                 match conn.write_stream.send(tungstenite::Message::Ping(vec![])).await {
-                    Ok(()) => continue,
+                    Ok(()) => {
+                        stats.ping_sent();
+                        continue
+                    }
                     Err(_) => break,
                 }
             }
@@ -129,20 +142,31 @@ pub async fn maintain_connection(
         &format!("net: connection with {peer_name} closed"),
     )
     .await;
-    peers.remove(&peer_name);
+    peers.remove_if(&peer_name, |_, p| Arc::ptr_eq(&p.stats, &stats));
 }
 
 /// cross the streams
-pub async fn maintain_passthrough(mut conn: PassthroughConnection) {
+///
+/// traffic is counted from the perspective of the source node: frames read
+/// from the source are "received", frames forwarded back to it are "sent".
+pub async fn maintain_passthrough(mut conn: PassthroughConnection, passthroughs: Passthroughs) {
+    let id: u64 = rand::random();
+    let stats = Arc::new(ConnectionStats::new());
+    passthroughs.insert(
+        id,
+        (conn.source.clone(), conn.target.clone(), stats.clone()),
+    );
     let mut last_message = std::time::Instant::now();
     loop {
         tokio::select! {
             maybe_recv = conn.read_stream_1.next() => {
                 match maybe_recv {
                     Some(Ok(msg)) => {
+                        let len = msg.len();
                         let Ok(()) = conn.write_stream_2.send(msg).await else {
                             break
                         };
+                        stats.record_received(len);
                         last_message = std::time::Instant::now();
                     }
                     _ => break,
@@ -151,9 +175,11 @@ pub async fn maintain_passthrough(mut conn: PassthroughConnection) {
             maybe_recv = conn.read_stream_2.next() => {
                 match maybe_recv {
                     Some(Ok(msg)) => {
+                        let len = msg.len();
                         let Ok(()) = conn.write_stream_1.send(msg).await else {
                             break
                         };
+                        stats.record_sent(len);
                         last_message = std::time::Instant::now();
                     }
                     _ => break,
@@ -171,6 +197,7 @@ pub async fn maintain_passthrough(mut conn: PassthroughConnection) {
     let mut conn_2 = conn.write_stream_2.reunite(conn.read_stream_2).unwrap();
     let _ = conn_1.close(None).await;
     let _ = conn_2.close(None).await;
+    passthroughs.remove(&id);
 }
 
 pub async fn create_passthrough(
@@ -190,6 +217,8 @@ pub async fn create_passthrough(
         return Ok((
             from_id,
             Connection::Passthrough(PassthroughConnection {
+                source: from_id.name.clone(),
+                target: to_name,
                 write_stream_1,
                 read_stream_1,
                 write_stream_2: pending.write_stream,
@@ -228,6 +257,8 @@ pub async fn create_passthrough(
                 body: rmp_serde::to_vec(&NetAction::ConnectionRequest(from_id.name.clone()))?,
                 metadata: None,
                 capabilities: vec![],
+                priority: MessagePriority::System,
+                deadline: None,
             }),
             lazy_load_blob: None,
         })?;
@@ -249,8 +280,10 @@ pub async fn create_passthrough(
     };
     let (write_stream_2, read_stream_2) = websocket.split();
     Ok((
-        from_id,
+        from_id.clone(),
         Connection::Passthrough(PassthroughConnection {
+            source: from_id.name,
+            target: to_name,
             write_stream_1,
             read_stream_1,
             write_stream_2,
@@ -315,8 +348,17 @@ pub fn validate_handshake(
     Ok(())
 }
 
-pub async fn send_protocol_message(km: &KernelMessage, conn: &mut PeerConnection) -> Result<()> {
-    let serialized = rmp_serde::to_vec(km)?;
+pub async fn send_protocol_message(
+    km: &KernelMessage,
+    conn: &mut PeerConnection,
+    stats: &ConnectionStats,
+) -> Result<()> {
+    let mut serialized = rmp_serde::to_vec(km)?;
+    // trace context goes after the message, where nodes that don't read it
+    // ignore it
+    if let Some(context) = crate::trace::context(km) {
+        serialized.extend_from_slice(&context);
+    }
     if serialized.len() > MESSAGE_MAX_SIZE as usize {
         return Err(anyhow!("message too large"));
     }
@@ -332,13 +374,20 @@ pub async fn send_protocol_message(km: &KernelMessage, conn: &mut PeerConnection
             .await?;
     }
     conn.write_stream.flush().await?;
+    stats.record_sent(with_length_prefix.len());
+    if crate::trace::enabled() {
+        crate::trace::event(km.id, format!("sent to {}", km.target.node));
+    }
     Ok(())
 }
 
 /// any error in receiving a message will result in the connection being closed.
-pub async fn recv_protocol_message(conn: &mut PeerConnection) -> Result<KernelMessage> {
+pub async fn recv_protocol_message(
+    conn: &mut PeerConnection,
+    stats: &ConnectionStats,
+) -> Result<KernelMessage> {
     let outer_len = conn.noise.read_message(
-        &ws_recv(&mut conn.read_stream, &mut conn.write_stream).await?,
+        &ws_recv_with_stats(&mut conn.read_stream, &mut conn.write_stream, Some(stats)).await?,
         &mut conn.buf,
     )?;
 
@@ -356,13 +405,20 @@ pub async fn recv_protocol_message(conn: &mut PeerConnection) -> Result<KernelMe
 
     while msg.len() < msg_len as usize {
         let len = conn.noise.read_message(
-            &ws_recv(&mut conn.read_stream, &mut conn.write_stream).await?,
+            &ws_recv_with_stats(&mut conn.read_stream, &mut conn.write_stream, Some(stats)).await?,
             &mut conn.buf,
         )?;
         msg.extend_from_slice(&conn.buf[..len]);
     }
 
-    Ok(rmp_serde::from_slice(&msg)?)
+    stats.record_received(msg_len as usize + 4);
+    let mut cursor = std::io::Cursor::new(&msg[..]);
+    let km = KernelMessage::deserialize(&mut rmp_serde::Deserializer::new(&mut cursor))?;
+    let rest = &msg[cursor.position() as usize..];
+    if !rest.is_empty() {
+        crate::trace::received(&km, rest);
+    }
+    Ok(km)
 }
 
 pub async fn send_protocol_handshake(
@@ -404,6 +460,15 @@ pub async fn recv_protocol_handshake(
 pub async fn ws_recv(
     read_stream: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     write_stream: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Message>,
+) -> Result<Vec<u8>> {
+    ws_recv_with_stats(read_stream, write_stream, None).await
+}
+
+/// Same as [`ws_recv`], but uses 'PONG' messages to measure round-trip time.
+pub async fn ws_recv_with_stats(
+    read_stream: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    write_stream: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Message>,
+    stats: Option<&ConnectionStats>,
 ) -> Result<Vec<u8>> {
     loop {
         match read_stream.next().await {
@@ -413,7 +478,12 @@ pub async fn ws_recv(
                     .await?;
                 continue;
             }
-            Some(Ok(tungstenite::Message::Pong(_))) => continue,
+            Some(Ok(tungstenite::Message::Pong(_))) => {
+                if let Some(stats) = stats {
+                    stats.pong_received();
+                }
+                continue;
+            }
             Some(Ok(tungstenite::Message::Binary(bin))) => return Ok(bin),
             _ => return Err(anyhow!("websocket closed")),
         }
@@ -4,15 +4,21 @@ use {
     futures::{SinkExt, StreamExt},
     rand::seq::SliceRandom,
     ring::signature::Ed25519KeyPair,
-    std::{collections::HashMap, sync::Arc},
+    std::{
+        collections::HashMap,
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+    },
     tokio::net::TcpListener,
     tokio::task::JoinSet,
     tokio::time,
     tokio_tungstenite::{
-        accept_async, connect_async, tungstenite, MaybeTlsStream, WebSocketStream,
+        accept_async, client_async, connect_async, tungstenite, MaybeTlsStream, WebSocketStream,
     },
 };
 
+use crate::net::nat::*;
+use crate::net::outbox::*;
 use crate::net::types::*;
 use crate::net::utils::*;
 use crate::KNS_ADDRESS;
@@ -25,6 +31,15 @@ pub const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 /// note that this only applies to cross-network messages, not local ones.
 pub const MESSAGE_MAX_SIZE: u32 = 10_485_800;
 
+/// how often to check the outbox for requests due for a delivery attempt
+const OUTBOX_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// how often an indirect node with a mapped port offers it to routed peers
+const DIRECT_OFFER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// how long two nodes taking up each other's offers keep trying to connect
+const PUNCH_WINDOW: std::time::Duration = std::time::Duration::from_secs(10);
+
 /// Entry point from the main kernel task. Runs forever, spawns listener and sender tasks.
 pub async fn networking(
     our: Identity,
@@ -32,11 +47,15 @@ pub async fn networking(
     keypair: Arc<Ed25519KeyPair>,
     kernel_message_tx: MessageSender,
     network_error_tx: NetworkErrorSender,
+    caps_oracle: CapMessageSender,
     print_tx: PrintSender,
     self_message_tx: MessageSender,
     message_rx: MessageReceiver,
     reveal_ip: bool,
+    nat_traversal: bool,
+    home_directory_path: String,
 ) -> Result<()> {
+    let outbox = Outbox::open(&home_directory_path).await?;
     // branch on whether we are a direct or indirect node
     match &our.ws_routing {
         None => {
@@ -53,10 +72,13 @@ pub async fn networking(
                 keypair,
                 kernel_message_tx,
                 network_error_tx,
+                caps_oracle,
                 print_tx,
                 self_message_tx,
                 message_rx,
                 reveal_ip,
+                nat_traversal,
+                outbox,
             )
             .await
         }
@@ -84,6 +106,10 @@ pub async fn networking(
                     content: "going online as a direct node".to_string(),
                 })
                 .await?;
+            // if our gateway supports it, forward our port automatically
+            if nat_traversal {
+                tokio::spawn(maintain_port_mapping(*port, None, print_tx.clone()));
+            }
             direct_networking(
                 our,
                 our_ip,
@@ -91,9 +117,11 @@ pub async fn networking(
                 keypair,
                 kernel_message_tx,
                 network_error_tx,
+                caps_oracle,
                 print_tx,
                 self_message_tx,
                 message_rx,
+                outbox,
             )
             .await
         }
@@ -106,10 +134,13 @@ async fn indirect_networking(
     keypair: Arc<Ed25519KeyPair>,
     kernel_message_tx: MessageSender,
     network_error_tx: NetworkErrorSender,
+    caps_oracle: CapMessageSender,
     print_tx: PrintSender,
     _self_message_tx: MessageSender,
     mut message_rx: MessageReceiver,
     reveal_ip: bool,
+    nat_traversal: bool,
+    mut outbox: Outbox,
 ) -> Result<()> {
     print_debug(&print_tx, "net: starting as indirect").await;
     let pki: OnchainPKI = Arc::new(DashMap::new());
@@ -119,9 +150,38 @@ async fn indirect_networking(
     // track peers that we're already in the midst of establishing a connection with
     let mut pending_connections = JoinSet::<(NodeId, Result<()>)>::new();
     let mut peer_message_queues = HashMap::<NodeId, Vec<KernelMessage>>::new();
+    // outbox delivery attempts. the outbox decides itself when to report
+    // a failure to the source process.
+    let mut outbox_attempts = JoinSet::<(u64, Result<()>)>::new();
+    let mut outbox_interval = time::interval(OUTBOX_INTERVAL);
+    let mut direct_upgrades = DirectUpgrades::default();
+    // listen for peers upgrading their routed connections with us to direct
+    // ones, on a port that we map on our gateway if we can. our connections
+    // to routers are made from the same port, so that if we can't, our routers
+    // can tell us where peers can punch through to it.
+    let (mapping_tx, mut mapping_rx) = tokio::sync::mpsc::unbounded_channel::<PortMapping>();
+    let upgrade_listener = if nat_traversal {
+        let listener = reusable_listener(0)?;
+        let local_port = listener.local_addr()?.port();
+        direct_upgrades.local_port = Some(local_port);
+        tokio::spawn(maintain_port_mapping(
+            local_port,
+            Some(mapping_tx),
+            print_tx.clone(),
+        ));
+        Some(listener)
+    } else {
+        None
+    };
+    let mut direct_offer_interval = time::interval(DIRECT_OFFER_INTERVAL);
 
-    // some initial delay as we wait for KNS data to be piped in from kns_indexer
-    let mut router_reconnect_delay = std::time::Duration::from_secs(2);
+    // some initial delay as we wait for KNS data to be piped in from kns_indexer.
+    // this is an interval rather than a sleep so that it isn't reset each time
+    // another branch of the loop (such as the outbox) fires.
+    let mut router_reconnect = time::interval_at(
+        time::Instant::now() + std::time::Duration::from_secs(2),
+        std::time::Duration::from_secs(4),
+    );
 
     loop {
         tokio::select! {
@@ -143,6 +203,9 @@ async fn indirect_networking(
                         None,
                         None,
                         names.clone(),
+                        &mut outbox,
+                        &mut direct_upgrades,
+                        &caps_oracle,
                         &kernel_message_tx,
                         &print_tx,
                     )
@@ -177,7 +240,7 @@ async fn indirect_networking(
                     peers.clone(),
                     reveal_ip,
                     kernel_message_tx.clone(),
-                    network_error_tx.clone(),
+                    Some(network_error_tx.clone()),
                     print_tx.clone(),
                 ));
             }
@@ -204,17 +267,92 @@ async fn indirect_networking(
                     }
                 }
             }
-            // 3. periodically attempt to connect to any allowed routers that we
+            // 3. periodically attempt delivery of requests in our outbox
+            _ = outbox_interval.tick() => {
+                process_outbox(
+                    &our,
+                    &our_ip,
+                    &keypair,
+                    &mut outbox,
+                    &mut outbox_attempts,
+                    &pki,
+                    &names,
+                    &peers,
+                    reveal_ip,
+                    &kernel_message_tx,
+                    &network_error_tx,
+                    &print_tx,
+                ).await;
+            }
+            // 4. recover the result of an outbox delivery attempt
+            Some(Ok((key, result))) = outbox_attempts.join_next() => {
+                finish_outbox_attempt(&mut outbox, key, result, &print_tx).await;
+            }
+            // 5. track our port mapping, if we have one. it takes the place
+            // of any address our routers told us about.
+            Some(mapping) = mapping_rx.recv() => {
+                direct_upgrades.ours = Some(mapping);
+                direct_upgrades.offered_to.clear();
+            }
+            // 6. periodically offer our mapped port to peers we only have a
+            // routed connection with, so they can connect to us directly
+            _ = direct_offer_interval.tick() => {
+                direct_upgrades.offered_to.retain(|name| peers.contains_key(name));
+                for peer in peers.iter() {
+                    if matches!(peer.connection_type, ConnectionType::Routed { .. }) {
+                        offer_direct(&our.name, &peer, &mut direct_upgrades)?;
+                    }
+                }
+            }
+            // 7. accept direct connections from peers taking up our offer
+            Ok((stream, _socket_addr)) = async {
+                match upgrade_listener {
+                    Some(ref listener) => listener.accept().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Ok(Ok(websocket)) = time::timeout(TIMEOUT, accept_async(MaybeTlsStream::Plain(stream))).await else {
+                    continue;
+                };
+                match time::timeout(TIMEOUT, recv_connection(
+                    &our,
+                    &our_ip,
+                    &pki,
+                    &peers,
+                    None,
+                    &keypair,
+                    websocket,
+                )).await {
+                    Ok(Ok((peer_id, _routing_for, Connection::Peer(peer_conn)))) => {
+                        print_debug(
+                            &print_tx,
+                            &format!("net: upgraded connection with {} to direct", peer_id.name),
+                        ).await;
+                        save_new_peer(
+                            &peer_id,
+                            false,
+                            ConnectionType::Direct,
+                            peers.clone(),
+                            peer_conn,
+                            None,
+                            &kernel_message_tx,
+                            &print_tx,
+                        ).await;
+                    }
+                    _ => continue,
+                }
+            }
+            // 8. periodically attempt to connect to any allowed routers that we
             // are not connected to -- TODO do some exponential backoff if a router
             // is not responding.
-            _ = time::sleep(router_reconnect_delay) => {
-                router_reconnect_delay = std::time::Duration::from_secs(4);
+            _ = router_reconnect.tick() => {
                 tokio::spawn(connect_to_routers(
                     our.clone(),
                     our_ip.clone(),
                     keypair.clone(),
                     pki.clone(),
                     peers.clone(),
+                    direct_upgrades.local_port,
                     kernel_message_tx.clone(),
                     print_tx.clone()
                 ));
@@ -229,6 +367,7 @@ async fn connect_to_routers(
     keypair: Arc<Ed25519KeyPair>,
     pki: OnchainPKI,
     peers: Peers,
+    local_port: Option<u16>,
     kernel_message_tx: MessageSender,
     print_tx: PrintSender,
 ) -> Result<()> {
@@ -244,7 +383,16 @@ async fn connect_to_routers(
             &format!("net: attempting to connect to router {router}"),
         )
         .await;
-        match init_connection(&our, &our_ip, &router_id, &keypair, None, true).await {
+        let conn = match local_port {
+            Some(local_port) => {
+                init_connection_from(
+                    &our, &our_ip, &router_id, &keypair, local_port, TIMEOUT, true,
+                )
+                .await
+            }
+            None => init_connection(&our, &our_ip, &router_id, &keypair, None, true).await,
+        };
+        match conn {
             Ok(direct_conn) => {
                 print_tx
                     .send(Printout {
@@ -255,6 +403,7 @@ async fn connect_to_routers(
                 save_new_peer(
                     &router_id,
                     false,
+                    ConnectionType::Direct,
                     peers.clone(),
                     direct_conn,
                     None,
@@ -276,9 +425,11 @@ async fn direct_networking(
     keypair: Arc<Ed25519KeyPair>,
     kernel_message_tx: MessageSender,
     network_error_tx: NetworkErrorSender,
+    caps_oracle: CapMessageSender,
     print_tx: PrintSender,
     _self_message_tx: MessageSender,
     mut message_rx: MessageReceiver,
+    mut outbox: Outbox,
 ) -> Result<()> {
     print_debug(&print_tx, "net: starting as direct").await;
     let pki: OnchainPKI = Arc::new(DashMap::new());
@@ -287,10 +438,16 @@ async fn direct_networking(
     let names: PKINames = Arc::new(DashMap::new());
     // direct-specific structures
     let mut forwarding_connections = JoinSet::<()>::new();
+    let passthroughs: Passthroughs = Arc::new(DashMap::new());
     let mut pending_passthroughs: PendingPassthroughs = HashMap::new();
     // track peers that we're already in the midst of establishing a connection with
     let mut pending_connections = JoinSet::<(NodeId, Result<()>)>::new();
     let mut peer_message_queues = HashMap::<NodeId, Vec<KernelMessage>>::new();
+    // outbox delivery attempts. the outbox decides itself when to report
+    // a failure to the source process.
+    let mut outbox_attempts = JoinSet::<(u64, Result<()>)>::new();
+    let mut outbox_interval = time::interval(OUTBOX_INTERVAL);
+    let mut direct_upgrades = DirectUpgrades::default();
 
     loop {
         tokio::select! {
@@ -309,8 +466,11 @@ async fn direct_networking(
                         peers.clone(),
                         pki.clone(),
                         Some(&mut pending_passthroughs),
-                        Some(&forwarding_connections),
+                        Some(&passthroughs),
                         names.clone(),
+                        &mut outbox,
+                        &mut direct_upgrades,
+                        &caps_oracle,
                         &kernel_message_tx,
                         &print_tx,
                     )
@@ -345,7 +505,7 @@ async fn direct_networking(
                     peers.clone(),
                     true,
                     kernel_message_tx.clone(),
-                    network_error_tx.clone(),
+                    Some(network_error_tx.clone()),
                     print_tx.clone()
                 ));
             }
@@ -380,8 +540,29 @@ async fn direct_networking(
                     Err(_e) => continue,
                 }
             }
-            // 4. receive incoming TCP connections
-            Ok((stream, _socket_addr)) = tcp.accept() => {
+            // 4. periodically attempt delivery of requests in our outbox
+            _ = outbox_interval.tick() => {
+                process_outbox(
+                    &our,
+                    &our_ip,
+                    &keypair,
+                    &mut outbox,
+                    &mut outbox_attempts,
+                    &pki,
+                    &names,
+                    &peers,
+                    true,
+                    &kernel_message_tx,
+                    &network_error_tx,
+                    &print_tx,
+                ).await;
+            }
+            // 5. recover the result of an outbox delivery attempt
+            Some(Ok((key, result))) = outbox_attempts.join_next() => {
+                finish_outbox_attempt(&mut outbox, key, result, &print_tx).await;
+            }
+            // 6. receive incoming TCP connections
+            Ok((stream, socket_addr)) = tcp.accept() => {
                 // TODO we can perform some amount of validation here
                 // to prevent some amount of potential DDoS attacks.
                 // can also block based on socket_addr
@@ -394,7 +575,7 @@ async fn direct_networking(
                             &our_ip,
                             &pki,
                             &peers,
-                            &mut pending_passthroughs,
+                            Some(&mut pending_passthroughs),
                             &keypair,
                             websocket)).await
                         {
@@ -424,16 +605,34 @@ async fn direct_networking(
                             save_new_peer(
                                 &peer_id,
                                 routing_for,
+                                ConnectionType::Direct,
                                 peers.clone(),
                                 peer_conn,
                                 None,
                                 &kernel_message_tx,
                                 &print_tx
                             ).await;
+                            // tell a node we route for where its connection came
+                            // from, which is where its peers can punch through to it
+                            if routing_for {
+                                if let Some(peer) = peers.get(&peer_id.name) {
+                                    if let Ok(observed) = make_net_request(
+                                        &our.name,
+                                        &peer_id.name,
+                                        &NetAction::ObservedAddress {
+                                            ip: socket_addr.ip().to_string(),
+                                            port: socket_addr.port(),
+                                        },
+                                    ) {
+                                        let _ = peer.sender.send(observed);
+                                    }
+                                }
+                            }
                         }
                         Connection::Passthrough(passthrough_conn) => {
                             forwarding_connections.spawn(maintain_passthrough(
                                 passthrough_conn,
+                                passthroughs.clone(),
                             ));
                         }
                         Connection::PendingPassthrough(pending_conn) => {
@@ -449,6 +648,104 @@ async fn direct_networking(
     }
 }
 
+/// Send a routed peer the address we can be reached at, if we know it and
+/// haven't already.
+fn offer_direct(our_name: &str, peer: &Peer, direct_upgrades: &mut DirectUpgrades) -> Result<()> {
+    let Some(ref ours) = direct_upgrades.ours else {
+        return Ok(());
+    };
+    if direct_upgrades.offered_to.contains(&peer.identity.name) {
+        return Ok(());
+    }
+    let offer = make_net_request(
+        our_name,
+        &peer.identity.name,
+        &NetAction::DirectOffer {
+            ip: ours.external_ip.clone(),
+            port: ours.external_port,
+        },
+    )?;
+    if peer.sender.send(offer).is_ok() {
+        direct_upgrades
+            .offered_to
+            .insert(peer.identity.name.clone());
+    }
+    Ok(())
+}
+
+/// Try to reach a peer at the address they offered us, replacing our routed
+/// connection with them if it works. If we offered them ours too, both sides
+/// connect from their listening port at once to open their NATs to each
+/// other: the side whose name sorts first makes the connection that is kept,
+/// and the other only punches, dropping any connection it makes, so that the
+/// first side's next attempt reaches its listener.
+async fn upgrade_to_direct(
+    our: Identity,
+    our_ip: String,
+    keypair: Arc<Ed25519KeyPair>,
+    peer_id: Identity,
+    offered: SocketAddr,
+    local_port: Option<u16>,
+    initiator: bool,
+    peers: Peers,
+    kernel_message_tx: MessageSender,
+    print_tx: PrintSender,
+) {
+    let direct_id = Identity {
+        ws_routing: Some((offered.ip().to_string(), offered.port())),
+        ..peer_id.clone()
+    };
+    let direct_conn = match (local_port, initiator) {
+        (None, _) => {
+            time::timeout(
+                TIMEOUT,
+                init_connection(&our, &our_ip, &direct_id, &keypair, None, false),
+            )
+            .await
+        }
+        (Some(local_port), true) => {
+            time::timeout(
+                PUNCH_WINDOW + TIMEOUT,
+                init_connection_from(
+                    &our,
+                    &our_ip,
+                    &direct_id,
+                    &keypair,
+                    local_port,
+                    PUNCH_WINDOW,
+                    false,
+                ),
+            )
+            .await
+        }
+        (Some(local_port), false) => {
+            drop(punch(local_port, offered, PUNCH_WINDOW).await);
+            return;
+        }
+    };
+    let Ok(Ok(direct_conn)) = direct_conn else {
+        return;
+    };
+    print_debug(
+        &print_tx,
+        &format!("net: upgraded connection with {} to direct", peer_id.name),
+    )
+    .await;
+    save_new_peer(
+        &peer_id,
+        false,
+        ConnectionType::Direct,
+        peers,
+        direct_conn,
+        None,
+        &kernel_message_tx,
+        &print_tx,
+    )
+    .await;
+}
+
+/// Connect to the target of `km` and send it. If that fails, `km` is returned
+/// to its source as an offline error through `network_error_tx`, if given.
 async fn establish_new_peer_connection(
     our: Identity,
     our_ip: String,
@@ -459,7 +756,7 @@ async fn establish_new_peer_connection(
     peers: Peers,
     reveal_ip: bool,
     kernel_message_tx: MessageSender,
-    network_error_tx: NetworkErrorSender,
+    network_error_tx: Option<NetworkErrorSender>,
     print_tx: PrintSender,
 ) -> (NodeId, Result<()>) {
     if let Some(peer_id) = pki.get(&km.target.node) {
@@ -483,6 +780,7 @@ async fn establish_new_peer_connection(
                     save_new_peer(
                         &peer_id,
                         false,
+                        ConnectionType::Direct,
                         peers,
                         direct_conn,
                         Some(km),
@@ -493,7 +791,9 @@ async fn establish_new_peer_connection(
                     (peer_id.name.clone(), Ok(()))
                 }
                 _ => {
-                    let _ = error_offline(km, &network_error_tx).await;
+                    if let Some(network_error_tx) = &network_error_tx {
+                        let _ = error_offline(km, network_error_tx).await;
+                    }
                     (
                         peer_id.name.clone(),
                         Err(anyhow!("failed to connect to peer")),
@@ -532,7 +832,9 @@ async fn establish_new_peer_connection(
                 (peer_id.name.clone(), Ok(()))
             } else {
                 // none of the routers worked!
-                let _ = error_offline(km, &network_error_tx).await;
+                if let Some(network_error_tx) = &network_error_tx {
+                    let _ = error_offline(km, network_error_tx).await;
+                }
                 (
                     peer_id.name.clone(),
                     Err(anyhow!("failed to connect to peer")),
@@ -543,11 +845,219 @@ async fn establish_new_peer_connection(
     // peer cannot be found in PKI, throw an offline error
     else {
         let peer_name = km.target.node.clone();
-        let _ = error_offline(km, &network_error_tx).await;
+        if let Some(network_error_tx) = &network_error_tx {
+            let _ = error_offline(km, network_error_tx).await;
+        }
         (peer_name, Err(anyhow!("failed to connect to peer")))
     }
 }
 
+/// Attempt delivery of every request in the outbox that is due. Requests for
+/// peers we are connected to are sent immediately; otherwise a connection attempt
+/// is spawned into `outbox_attempts`, and the entry is removed or rescheduled
+/// when it completes. Requests past their deadline are returned to their source
+/// as offline errors. An entry that can't be attempted is rescheduled.
+async fn process_outbox(
+    our: &Identity,
+    our_ip: &str,
+    keypair: &Arc<Ed25519KeyPair>,
+    outbox: &mut Outbox,
+    outbox_attempts: &mut JoinSet<(u64, Result<()>)>,
+    pki: &OnchainPKI,
+    names: &PKINames,
+    peers: &Peers,
+    reveal_ip: bool,
+    kernel_message_tx: &MessageSender,
+    network_error_tx: &NetworkErrorSender,
+    print_tx: &PrintSender,
+) {
+    let now = now_secs();
+    for (key, entry) in outbox.take_due(now) {
+        if let Err(e) = attempt_outbox_entry(
+            our,
+            our_ip,
+            keypair,
+            key,
+            entry,
+            now,
+            outbox,
+            outbox_attempts,
+            pki,
+            names,
+            peers,
+            reveal_ip,
+            kernel_message_tx,
+            network_error_tx,
+            print_tx,
+        )
+        .await
+        {
+            finish_outbox_attempt(outbox, key, Err(e), print_tx).await;
+        }
+    }
+}
+
+async fn attempt_outbox_entry(
+    our: &Identity,
+    our_ip: &str,
+    keypair: &Arc<Ed25519KeyPair>,
+    key: u64,
+    entry: OutboxEntry,
+    now: u64,
+    outbox: &mut Outbox,
+    outbox_attempts: &mut JoinSet<(u64, Result<()>)>,
+    pki: &OnchainPKI,
+    names: &PKINames,
+    peers: &Peers,
+    reveal_ip: bool,
+    kernel_message_tx: &MessageSender,
+    network_error_tx: &NetworkErrorSender,
+    print_tx: &PrintSender,
+) -> Result<()> {
+    if entry.deadline <= now {
+        // held messages belong to another node: just drop them
+        if entry.held_signature.is_none() {
+            let _ = error_offline(entry.message, network_error_tx).await;
+        }
+        finish_outbox_attempt(outbox, key, Ok(()), print_tx).await;
+        return Ok(());
+    }
+    let target = entry.message.target.node.clone();
+    // as a router, we wait for the indirect node to connect to us
+    if let Some(signature) = entry.held_signature {
+        if !peers.contains_key(&target) {
+            outbox.release(key);
+            return Ok(());
+        }
+        let deliver = NetAction::Deliver(HeldMessage {
+            message: entry.message,
+            deadline: entry.deadline,
+            signature,
+        });
+        send_to_peer(peers, make_net_request(&our.name, &target, &deliver)?)?;
+        finish_outbox_attempt(outbox, key, Ok(()), print_tx).await;
+        return Ok(());
+    }
+    // after our first attempt fails, hand requests for indirect nodes
+    // to one of their routers if allowed. a router holds messages for
+    // a limited time, so the deadline it is asked to keep is capped.
+    let km = match pki.get(&target) {
+        Some(target_id)
+            if entry.router_hold && entry.attempts > 0 && target_id.ws_routing.is_none() =>
+        {
+            let mut routers = target_id
+                .allowed_routers
+                .iter()
+                .filter_map(|namehash| names.get(namehash).map(|n| n.clone()))
+                .collect::<Vec<_>>();
+            routers.shuffle(&mut rand::thread_rng());
+            match routers.first() {
+                Some(router) => {
+                    let deadline = entry.deadline.min(now + MAX_HOLD_SECS);
+                    make_net_request(
+                        &our.name,
+                        router,
+                        &NetAction::Hold(HeldMessage {
+                            signature: keypair
+                                .sign(&held_message_bytes(&entry.message, deadline)?)
+                                .as_ref()
+                                .to_vec(),
+                            message: entry.message,
+                            deadline,
+                        }),
+                    )?
+                }
+                None => entry.message,
+            }
+        }
+        _ => entry.message,
+    };
+    if peers.contains_key(&km.target.node) {
+        send_to_peer(peers, km)?;
+        finish_outbox_attempt(outbox, key, Ok(()), print_tx).await;
+        return Ok(());
+    }
+    let attempt = establish_new_peer_connection(
+        our.clone(),
+        our_ip.to_string(),
+        keypair.clone(),
+        km,
+        pki.clone(),
+        names.clone(),
+        peers.clone(),
+        reveal_ip,
+        kernel_message_tx.clone(),
+        None,
+        print_tx.clone(),
+    );
+    outbox_attempts.spawn(async move { (key, attempt.await.1) });
+    Ok(())
+}
+
+/// send a message over our existing connection to its target
+fn send_to_peer(peers: &Peers, km: KernelMessage) -> Result<()> {
+    let peer = peers
+        .get(&km.target.node)
+        .ok_or(anyhow!("no connection to {}", km.target.node))?;
+    peer.sender.send(km)?;
+    Ok(())
+}
+
+/// Remove an outbox entry whose delivery succeeded, or reschedule one whose
+/// delivery failed. Errors are printed rather than returned: one entry that
+/// can't be updated on disk shouldn't stop the networking module.
+async fn finish_outbox_attempt(
+    outbox: &mut Outbox,
+    key: u64,
+    result: Result<()>,
+    print_tx: &PrintSender,
+) {
+    let updated = match result {
+        Ok(()) => outbox.remove(key),
+        Err(e) => {
+            print_debug(
+                print_tx,
+                &format!("net: outbox delivery of {key} failed: {e}"),
+            )
+            .await;
+            outbox.reschedule(key, now_secs())
+        }
+    };
+    if let Err(e) = updated {
+        let _ = print_tx
+            .send(Printout {
+                verbosity: 0,
+                content: format!("net: failed to update outbox entry {key}: {e}"),
+            })
+            .await;
+    }
+}
+
+fn make_net_request(our_name: &str, target: &str, action: &NetAction) -> Result<KernelMessage> {
+    Ok(KernelMessage {
+        id: rand::random(),
+        source: Address {
+            node: our_name.to_string(),
+            process: ProcessId::new(Some("net"), "distro", "sys"),
+        },
+        target: Address {
+            node: target.to_string(),
+            process: ProcessId::new(Some("net"), "distro", "sys"),
+        },
+        rsvp: None,
+        message: Message::Request(Request {
+            inherit: false,
+            expects_response: None,
+            body: rmp_serde::to_vec(action)?,
+            metadata: None,
+            capabilities: vec![],
+            priority: MessagePriority::System,
+            deadline: None,
+        }),
+        lazy_load_blob: None,
+    })
+}
+
 async fn init_connection_via_router(
     our: &Identity,
     our_ip: &str,
@@ -578,6 +1088,9 @@ async fn init_connection_via_router(
                 save_new_peer(
                     peer_id,
                     false,
+                    ConnectionType::Routed {
+                        router: router_id.name.clone(),
+                    },
                     peers,
                     direct_conn,
                     Some(km),
@@ -598,7 +1111,7 @@ async fn recv_connection(
     our_ip: &str,
     pki: &OnchainPKI,
     peers: &Peers,
-    pending_passthroughs: &mut PendingPassthroughs,
+    pending_passthroughs: Option<&mut PendingPassthroughs>,
     keypair: &Ed25519KeyPair,
     websocket: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
 ) -> Result<(Identity, bool, Connection)> {
@@ -614,6 +1127,10 @@ async fn recv_connection(
     // and create a Passthrough connection if so.
     // a Noise 'e' message with have len 32
     if first_message.len() != 32 {
+        // indirect nodes don't route for others
+        let Some(pending_passthroughs) = pending_passthroughs else {
+            return Err(anyhow!("got routing request as non-router"));
+        };
         let (their_id, target_name) = validate_routing_request(&our.name, first_message, pki)?;
         let (id, conn) = create_passthrough(
             our,
@@ -759,9 +1276,6 @@ async fn init_connection(
     use_router: Option<&Identity>,
     proxy_request: bool,
 ) -> Result<PeerConnection> {
-    let mut buf = vec![0u8; 65535];
-    let (mut noise, our_static_key) = build_initiator();
-
     let (ref ip, ref port) = match use_router {
         None => peer_id
             .ws_routing
@@ -776,6 +1290,52 @@ async fn init_connection(
     let Ok(Ok((websocket, _response))) = time::timeout(TIMEOUT, connect_async(ws_url)).await else {
         return Err(anyhow!("failed to connect to target"));
     };
+    init_handshake(our, peer_id, keypair, use_router, proxy_request, websocket).await
+}
+
+/// Like [`init_connection`] without a router, but connecting from
+/// `local_port`, and trying again and again for up to `window` so as to
+/// punch through the target's NAT.
+async fn init_connection_from(
+    our: &Identity,
+    our_ip: &str,
+    peer_id: &Identity,
+    keypair: &Ed25519KeyPair,
+    local_port: u16,
+    window: std::time::Duration,
+    proxy_request: bool,
+) -> Result<PeerConnection> {
+    let (ref ip, ref port) = peer_id
+        .ws_routing
+        .as_ref()
+        .ok_or(anyhow!("target has no routing information"))?;
+    let ws_url = make_ws_url(our_ip, ip, port)?;
+    let addr = *ws_url
+        .socket_addrs(|| None)?
+        .first()
+        .ok_or(anyhow!("target has no address"))?;
+    let stream = punch(local_port, addr, window)
+        .await
+        .ok_or(anyhow!("failed to connect to target"))?;
+    let Ok(Ok((websocket, _response))) =
+        time::timeout(TIMEOUT, client_async(ws_url, MaybeTlsStream::Plain(stream))).await
+    else {
+        return Err(anyhow!("failed to connect to target"));
+    };
+    init_handshake(our, peer_id, keypair, None, proxy_request, websocket).await
+}
+
+/// The initiator's side of the handshake over a new websocket.
+async fn init_handshake(
+    our: &Identity,
+    peer_id: &Identity,
+    keypair: &Ed25519KeyPair,
+    use_router: Option<&Identity>,
+    proxy_request: bool,
+    websocket: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+) -> Result<PeerConnection> {
+    let mut buf = vec![0u8; 65535];
+    let (mut noise, our_static_key) = build_initiator();
     let (mut write_stream, mut read_stream) = websocket.split();
 
     // if this is a routed request, before starting XX handshake pattern, send a
@@ -840,13 +1400,16 @@ async fn init_connection(
 async fn handle_local_message(
     our: &Identity,
     our_ip: &str,
-    keypair: &Ed25519KeyPair,
+    keypair: &Arc<Ed25519KeyPair>,
     km: KernelMessage,
     peers: Peers,
     pki: OnchainPKI,
     pending_passthroughs: Option<&mut PendingPassthroughs>,
-    forwarding_connections: Option<&JoinSet<()>>,
+    passthroughs: Option<&Passthroughs>,
     names: PKINames,
+    outbox: &mut Outbox,
+    direct_upgrades: &mut DirectUpgrades,
+    caps_oracle: &CapMessageSender,
     kernel_message_tx: &MessageSender,
     print_tx: &PrintSender,
 ) -> Result<()> {
@@ -879,7 +1442,9 @@ async fn handle_local_message(
     if km.source.node != our.name {
         if let Ok(act) = rmp_serde::from_slice::<NetAction>(body) {
             match act {
-                NetAction::KnsBatchUpdate(_) | NetAction::KnsUpdate(_) => {
+                NetAction::KnsBatchUpdate(_)
+                | NetAction::KnsUpdate(_)
+                | NetAction::KnsRemove(_) => {
                     // for now, we don't get these from remote.
                 }
                 NetAction::ConnectionRequest(from) => {
@@ -903,6 +1468,9 @@ async fn handle_local_message(
                         save_new_peer(
                             &peer_id,
                             false,
+                            ConnectionType::Routed {
+                                router: router_id.name.clone(),
+                            },
                             peers,
                             peer_conn,
                             None,
@@ -938,6 +1506,124 @@ async fn handle_local_message(
                         })
                         .await?;
                 }
+                NetAction::DirectOffer { ip, port } => {
+                    // a peer we are connected to through a router can be reached
+                    // directly: try to replace our routed connection with a direct
+                    // one. the attempt runs in the background, and each peer gets
+                    // at most one attempt per offer interval.
+                    let Some(peer) = peers
+                        .get(&km.source.node)
+                        .filter(|p| matches!(p.connection_type, ConnectionType::Routed { .. }))
+                        .map(|p| p.clone())
+                    else {
+                        return Ok(());
+                    };
+                    // only connect out to the internet, and to the port the peer
+                    // published if it has one, so that an offer can't point us at
+                    // other services on our network or theirs
+                    let Some(offered) = ip
+                        .parse::<IpAddr>()
+                        .ok()
+                        .filter(|ip| is_public(*ip))
+                        .map(|ip| SocketAddr::new(ip, port))
+                        .filter(|addr| match peer.identity.ws_routing {
+                            Some((_, published)) => addr.port() == published,
+                            None => addr.port() >= 1024,
+                        })
+                    else {
+                        print_debug(
+                            print_tx,
+                            &format!(
+                                "net: ignoring direct offer from {} for {ip}:{port}",
+                                peer.identity.name
+                            ),
+                        )
+                        .await;
+                        return Ok(());
+                    };
+                    direct_upgrades
+                        .tried
+                        .retain(|_, tried| tried.elapsed() < DIRECT_OFFER_INTERVAL);
+                    if direct_upgrades.tried.contains_key(&peer.identity.name) {
+                        return Ok(());
+                    }
+                    direct_upgrades
+                        .tried
+                        .insert(peer.identity.name.clone(), std::time::Instant::now());
+                    // if they can't reach us without it, offer them our address
+                    // too so that we punch through to each other at once
+                    offer_direct(&our.name, &peer, direct_upgrades)?;
+                    let initiator = direct_upgrades.ours.is_none() || our.name < peer.identity.name;
+                    tokio::spawn(upgrade_to_direct(
+                        our.clone(),
+                        our_ip.to_string(),
+                        keypair.clone(),
+                        peer.identity,
+                        offered,
+                        direct_upgrades.local_port,
+                        initiator,
+                        peers,
+                        kernel_message_tx.clone(),
+                        print_tx.clone(),
+                    ));
+                }
+                NetAction::ObservedAddress { ip, port } => {
+                    // one of our routers saw our connection to it come from here:
+                    // unless our gateway has mapped a port for us, offer it to
+                    // routed peers so that we can punch through to each other
+                    let Some(local_port) = direct_upgrades.local_port else {
+                        return Ok(());
+                    };
+                    if !our.allowed_routers.contains(&km.source.node)
+                        || !ip.parse::<IpAddr>().is_ok_and(is_public)
+                    {
+                        return Ok(());
+                    }
+                    if direct_upgrades.ours.as_ref().is_some_and(|ours| {
+                        ours.protocol != MappingProtocol::Router
+                            || (ours.external_ip == ip && ours.external_port == port)
+                    }) {
+                        return Ok(());
+                    }
+                    direct_upgrades.ours = Some(PortMapping {
+                        protocol: MappingProtocol::Router,
+                        external_ip: ip,
+                        external_port: port,
+                        internal_port: local_port,
+                    });
+                    direct_upgrades.offered_to.clear();
+                }
+                NetAction::Hold(held) => {
+                    // someone wants us to hold a message for an indirect node
+                    // until it connects to us. only accept if we are one of
+                    // the target's routers and the message is really theirs.
+                    if let Err(e) = accept_held_message(our, &km, held, &pki, &names, outbox) {
+                        print_debug(print_tx, &format!("net: rejected held message: {e}")).await;
+                    }
+                }
+                NetAction::Deliver(held) => {
+                    // one of our routers is delivering a message held for us.
+                    // each is accepted only once, and only before its deadline.
+                    let source = &held.message.source.node;
+                    if our.allowed_routers.contains(&km.source.node)
+                        && held.message.target.node == our.name
+                        && held.deadline <= now_secs() + MAX_HOLD_SECS + MAX_CLOCK_SKEW_SECS
+                        && validate_signature(
+                            source,
+                            &held.signature,
+                            &held_message_bytes(&held.message, held.deadline)?,
+                            &pki,
+                        )
+                        && outbox.record_delivery(
+                            source,
+                            held.message.id,
+                            held.deadline,
+                            now_secs(),
+                        )?
+                    {
+                        kernel_message_tx.send(held.message).await?;
+                    }
+                }
                 _ => {
                     // we don't accept any other actions from remote
                 }
@@ -991,6 +1677,11 @@ async fn handle_local_message(
                     }
                     None
                 }
+                NetAction::KnsRemove(name) => {
+                    pki.remove(&name);
+                    names.retain(|_, n| *n != name);
+                    None
+                }
                 NetAction::GetPeers => Some((
                     NetResponse::Peers(
                         peers
@@ -1008,34 +1699,90 @@ async fn handle_local_message(
                     NetResponse::Name(names.get(&namehash).map(|n| n.clone())),
                     None,
                 )),
-                NetAction::GetDiagnostics => {
-                    let mut printout = String::new();
-                    printout.push_str(&format!(
-                        "indexing from contract address {}\r\n",
-                        KNS_ADDRESS
-                    ));
-                    printout.push_str(&format!("our Identity: {:#?}\r\n", our));
-                    printout.push_str("we have connections with peers:\r\n");
-                    for peer in peers.iter() {
-                        printout.push_str(&format!(
-                            "    {}, routing_for={}\r\n",
-                            peer.identity.name, peer.routing_for,
-                        ));
-                    }
-                    printout.push_str(&format!("we have {} entries in the PKI\r\n", pki.len()));
-                    if pending_passthroughs.is_some() {
-                        printout.push_str(&format!(
-                            "we have {} pending passthrough connections\r\n",
-                            pending_passthroughs.unwrap().len()
-                        ));
-                    }
-                    if forwarding_connections.is_some() {
-                        printout.push_str(&format!(
-                            "we have {} open passthrough connections\r\n",
-                            forwarding_connections.unwrap().len()
+                NetAction::GetDiagnostics => Some((
+                    NetResponse::Diagnostics(
+                        get_diagnostics(
+                            our,
+                            &peers,
+                            &pki,
+                            pending_passthroughs.as_deref(),
+                            passthroughs,
+                        )
+                        .to_string(),
+                    ),
+                    None,
+                )),
+                NetAction::GetDiagnosticsReport => Some((
+                    NetResponse::DiagnosticsReport(get_diagnostics(
+                        our,
+                        &peers,
+                        &pki,
+                        pending_passthroughs.as_deref(),
+                        passthroughs,
+                    )),
+                    None,
+                )),
+                NetAction::Enqueue {
+                    target,
+                    request,
+                    deadline,
+                    router_hold,
+                } => {
+                    // queued requests are sent by us directly, so we have to
+                    // check that the source is allowed to use the network
+                    let (send_cap_bool, recv_cap_bool) = tokio::sync::oneshot::channel();
+                    caps_oracle
+                        .send(CapMessage::Has {
+                            on: km.source.process.clone(),
+                            cap: Capability {
+                                issuer: Address {
+                                    node: our.name.clone(),
+                                    process: KERNEL_PROCESS_ID.clone(),
+                                },
+                                params: "\"network\"".into(),
+                            },
+                            responder: send_cap_bool,
+                        })
+                        .await?;
+                    if !recv_cap_bool.await? {
+                        return Err(anyhow!(
+                            "{} doesn't have capability to send networked messages",
+                            km.source.process
                         ));
                     }
-                    Some((NetResponse::Diagnostics(printout), None))
+                    let id: u64 = rand::random();
+                    outbox.insert(OutboxEntry {
+                        message: KernelMessage {
+                            id,
+                            source: km.source.clone(),
+                            target,
+                            rsvp: None,
+                            message: Message::Request(Request {
+                                inherit: false,
+                                expects_response: None,
+                                body: request.body,
+                                metadata: request.metadata,
+                                capabilities: vec![],
+                                priority: request.priority,
+                                deadline: request.deadline,
+                            }),
+                            lazy_load_blob: km.lazy_load_blob.clone(),
+                        },
+                        deadline,
+                        router_hold,
+                        attempts: 0,
+                        next_attempt: 0,
+                        held_signature: None,
+                    })?;
+                    Some((NetResponse::Queued(id), None))
+                }
+                NetAction::GetOutbox => Some((NetResponse::Outbox(outbox.entries()), None)),
+                NetAction::Hold(_)
+                | NetAction::Deliver(_)
+                | NetAction::DirectOffer { .. }
+                | NetAction::ObservedAddress { .. } => {
+                    // only received from other nodes
+                    None
                 }
                 NetAction::Sign => Some((
                     NetResponse::Signed,
@@ -1103,3 +1850,110 @@ async fn handle_local_message(
         Ok(())
     }
 }
+
+fn get_diagnostics(
+    our: &Identity,
+    peers: &Peers,
+    pki: &OnchainPKI,
+    pending_passthroughs: Option<&PendingPassthroughs>,
+    passthroughs: Option<&Passthroughs>,
+) -> NetDiagnostics {
+    let mut peer_diagnostics: Vec<PeerDiagnostics> = peers
+        .iter()
+        .map(|peer| {
+            peer.stats.to_diagnostics(
+                &peer.identity.name,
+                peer.connection_type.clone(),
+                peer.routing_for,
+            )
+        })
+        .collect();
+    if let Some(passthroughs) = passthroughs {
+        for entry in passthroughs.iter() {
+            let (source, target, stats) = entry.value();
+            peer_diagnostics.push(stats.to_diagnostics(
+                source,
+                ConnectionType::Passthrough {
+                    target: target.clone(),
+                },
+                false,
+            ));
+        }
+    }
+    NetDiagnostics {
+        our: our.clone(),
+        kns_address: KNS_ADDRESS.to_string(),
+        pki_entries: pki.len() as u64,
+        peers: peer_diagnostics,
+        pending_passthroughs: pending_passthroughs
+            .map(|pending| pending.keys().cloned().collect())
+            .unwrap_or_default(),
+        // direct nodes don't use routers
+        routers: if our.ws_routing.is_some() {
+            vec![]
+        } else {
+            our.allowed_routers
+                .iter()
+                .map(|router| RouterStatus {
+                    name: router.clone(),
+                    connected: peers.contains_key(router),
+                })
+                .collect()
+        },
+    }
+}
+
+/// as a router, validate a [`HeldMessage`] and store it in our outbox
+fn accept_held_message(
+    our: &Identity,
+    km: &KernelMessage,
+    held: HeldMessage,
+    pki: &OnchainPKI,
+    names: &PKINames,
+    outbox: &mut Outbox,
+) -> Result<()> {
+    let source = &km.source.node;
+    if &held.message.source.node != source {
+        return Err(anyhow!("held message source doesn't match sender"));
+    }
+    if !validate_signature(
+        source,
+        &held.signature,
+        &held_message_bytes(&held.message, held.deadline)?,
+        pki,
+    ) {
+        return Err(anyhow!("bad signature"));
+    }
+    let now = now_secs();
+    if held.deadline > now + MAX_HOLD_SECS + MAX_CLOCK_SKEW_SECS {
+        return Err(anyhow!("asked to hold message for too long"));
+    }
+    let target_id = pki
+        .get(&held.message.target.node)
+        .ok_or(anyhow!("unknown target"))?;
+    let our_namehash = names
+        .iter()
+        .find(|entry| entry.value() == &our.name)
+        .map(|entry| entry.key().clone())
+        .ok_or(anyhow!("our namehash is not in PKI"))?;
+    if target_id.ws_routing.is_some() || !target_id.allowed_routers.contains(&our_namehash) {
+        return Err(anyhow!("we are not a router for {}", target_id.name));
+    }
+    if outbox.held_from(source) >= MAX_HELD_PER_SOURCE {
+        return Err(anyhow!("holding too many messages from {source}"));
+    }
+    let entry = OutboxEntry {
+        message: held.message,
+        deadline: held.deadline,
+        router_hold: false,
+        attempts: 0,
+        next_attempt: now,
+        held_signature: Some(held.signature),
+    };
+    let (held, held_bytes) = outbox.held();
+    if held >= MAX_HELD || held_bytes + held_size(&entry) > MAX_HELD_BYTES {
+        return Err(anyhow!("holding too many messages"));
+    }
+    outbox.insert(entry)?;
+    Ok(())
+}
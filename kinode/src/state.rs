@@ -2,6 +2,7 @@ use anyhow::Result;
 use ring::signature;
 use rocksdb::checkpoint::Checkpoint;
 use rocksdb::{Options, DB};
+use serde::Deserialize;
 use std::collections::{HashMap, VecDeque};
 use std::io::Read;
 use std::path::Path;
@@ -14,12 +15,44 @@ use lib::types::core::*;
 
 include!("bootstrapped_processes.rs");
 
+/// `PersistedProcess` as saved by nodes from before capabilities had limits
+#[derive(Deserialize)]
+struct LegacyPersistedProcess {
+    wasm_bytes_handle: String,
+    wit_version: Option<u32>,
+    on_exit: OnExit,
+    capabilities: HashMap<Capability, Vec<u8>>,
+    public: bool,
+}
+
+fn deserialize_process_map(bytes: &[u8]) -> bincode::Result<ProcessMap> {
+    bincode::deserialize::<ProcessMap>(bytes).or_else(|_| {
+        let legacy = bincode::deserialize::<HashMap<ProcessId, LegacyPersistedProcess>>(bytes)?;
+        Ok(legacy
+            .into_iter()
+            .map(|(id, p)| {
+                (
+                    id,
+                    PersistedProcess {
+                        wasm_bytes_handle: p.wasm_bytes_handle,
+                        wit_version: p.wit_version,
+                        on_exit: p.on_exit,
+                        capabilities: p.capabilities,
+                        public: p.public,
+                        cap_limits: HashMap::new(),
+                    },
+                )
+            })
+            .collect())
+    })
+}
+
 pub async fn load_state(
     our_name: String,
     keypair: Arc<signature::Ed25519KeyPair>,
     home_directory_path: String,
     runtime_extensions: Vec<(ProcessId, MessageSender, Option<NetworkErrorSender>, bool)>,
-) -> Result<(ProcessMap, DB, ReverseCapIndex), StateError> {
+) -> Result<(ProcessMap, Arc<DB>, ReverseCapIndex), StateError> {
     let state_path = format!("{}/kernel", &home_directory_path);
 
     if let Err(e) = fs::create_dir_all(&state_path).await {
@@ -33,24 +66,38 @@ pub async fn load_state(
     //let db = DB::open_default(&state_directory_path_str).unwrap();
     let mut opts = Options::default();
     opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
     // let cf_name = "kernel_state";
     // let cf_descriptor = ColumnFamilyDescriptor::new(cf_name, Options::default());
-    let db = DB::open_default(state_path).unwrap();
+    let db = Arc::new(
+        DB::open_cf(
+            &opts,
+            state_path,
+            [
+                crate::kernel::audit::CAP_AUDIT_CF,
+                crate::kernel::suspended::SUSPENDED_CF,
+                crate::kernel::queues::QUEUE_CONFIG_CF,
+                crate::kernel::scheduler::PRIORITY_CF,
+            ],
+        )
+        .unwrap(),
+    );
     let mut process_map: ProcessMap = HashMap::new();
     let mut reverse_cap_index: ReverseCapIndex = HashMap::new();
 
     let kernel_id = process_to_vec(KERNEL_PROCESS_ID.clone());
     match db.get(&kernel_id) {
         Ok(Some(value)) => {
-            process_map = bincode::deserialize::<ProcessMap>(&value).unwrap();
-            // if our networking key changed, we need to re-sign all local caps
+            process_map = deserialize_process_map(&value).unwrap();
+            let now = crate::kernel::now();
             process_map.iter_mut().for_each(|(_id, process)| {
+                // drop any caps that expired while we were offline
+                process.prune_caps(now);
+                // if our networking key changed, we need to re-sign all local caps
+                let cap_limits = &process.cap_limits;
                 process.capabilities.iter_mut().for_each(|(cap, sig)| {
                     if cap.issuer.node == our_name {
-                        *sig = keypair
-                            .sign(&rmp_serde::to_vec(&cap).unwrap())
-                            .as_ref()
-                            .to_vec();
+                        *sig = crate::kernel::sign_capability(&keypair, cap, cap_limits.get(cap));
                     }
                 })
             });
@@ -87,11 +134,9 @@ pub async fn state_sender(
     send_to_loop: MessageSender,
     send_to_terminal: PrintSender,
     mut recv_state: MessageReceiver,
-    db: DB,
+    db: Arc<DB>,
     home_directory_path: String,
 ) -> Result<(), anyhow::Error> {
-    let db = Arc::new(db);
-
     let mut process_queues: HashMap<ProcessId, Arc<Mutex<VecDeque<KernelMessage>>>> =
         HashMap::new();
 
@@ -362,6 +407,7 @@ async fn bootstrap(
             on_exit: OnExit::Restart,
             capabilities: runtime_caps.clone(),
             public: false,
+            cap_limits: HashMap::new(),
         });
     current_kernel.capabilities.extend(runtime_caps.clone());
     let current_net = process_map
@@ -372,6 +418,7 @@ async fn bootstrap(
             on_exit: OnExit::Restart,
             capabilities: runtime_caps.clone(),
             public: false,
+            cap_limits: HashMap::new(),
         });
     current_net.capabilities.extend(runtime_caps.clone());
     for runtime_module in runtime_extensions {
@@ -383,6 +430,7 @@ async fn bootstrap(
                 on_exit: OnExit::Restart,
                 capabilities: runtime_caps.clone(),
                 public: runtime_module.3,
+                cap_limits: HashMap::new(),
             });
         current.capabilities.extend(runtime_caps.clone());
     }
@@ -590,6 +638,9 @@ async fn bootstrap(
                     let p = p.into_mut();
                     p.wasm_bytes_handle = wasm_bytes_handle.clone();
                     p.on_exit = entry.on_exit;
+                    for cap in requested_caps.keys() {
+                        p.cap_limits.remove(cap);
+                    }
                     p.capabilities.extend(requested_caps);
                     p.public = public_process;
                 }
@@ -600,6 +651,7 @@ async fn bootstrap(
                         on_exit: entry.on_exit,
                         capabilities: requested_caps,
                         public: public_process,
+                        cap_limits: HashMap::new(),
                     });
                 }
             }
@@ -654,6 +706,7 @@ async fn bootstrap(
                                     },
                                     params: "\"messaging\"".into(),
                                 };
+                                process.cap_limits.remove(&cap);
                                 process
                                     .capabilities
                                     .insert(cap.clone(), sign_cap(cap.clone(), keypair.clone()));
@@ -680,6 +733,7 @@ async fn bootstrap(
                                             },
                                             params: params.to_string(),
                                         };
+                                        process.cap_limits.remove(&cap);
                                         process.capabilities.insert(
                                             cap.clone(),
                                             sign_cap(cap.clone(), keypair.clone()),
@@ -647,6 +647,8 @@ pub async fn terminal(
                                             body: command.into_bytes(),
                                             metadata: None,
                                             capabilities: vec![],
+                                            priority: MessagePriority::System,
+                                            deadline: None,
                                         }),
                                         lazy_load_blob: None,
                                     }
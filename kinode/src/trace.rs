@@ -0,0 +1,499 @@
+use lib::types::core::{Address, KernelMessage, Message, SendErrorKind};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The most spans kept. Once there are this many, the oldest is dropped
+/// for each new one.
+const MAX_SPANS: usize = 10_000;
+
+/// Bytes of trace context sent after a request over the network: its
+/// trace id, then the id of the span it was sent in.
+pub const CONTEXT_LEN: usize = 24;
+
+/// How many traces are listed when no message is asked for.
+const RECENT_TRACES: usize = 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer::default());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// no response yet, nor any error
+    Pending,
+    /// routed, and doesn't expect a response
+    Sent,
+    Responded,
+    Failed(SendErrorKind),
+}
+
+/// One hop of a request through the kernel, from when it was routed until
+/// it was answered or failed.
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub trace_id: u128,
+    pub message_id: u64,
+    pub source: Address,
+    pub target: Address,
+    pub rsvp: Option<Address>,
+    /// bytes of body and blob
+    pub size: usize,
+    /// unix time in nanoseconds
+    pub start: u64,
+    pub end: Option<u64>,
+    pub outcome: Outcome,
+    /// things that happened to the request along the way, with when
+    pub events: Vec<(u64, String)>,
+}
+
+/// Where the span of a request that hasn't been routed yet will attach.
+#[derive(Clone, Copy)]
+enum Parent {
+    /// the message a process was handling when it sent the request
+    Message(u64),
+    /// the span the request was sent in on another node
+    Remote { trace_id: u128, span_id: u64 },
+}
+
+#[derive(Default)]
+struct Tracer {
+    spans: HashMap<u64, Span>,
+    /// span ids, oldest first
+    order: VecDeque<u64>,
+    /// the most recent span of each message id. a request that inherits
+    /// its id is another hop of the same message.
+    latest: HashMap<u64, u64>,
+    /// the spans of each message id still awaiting a response
+    open: HashMap<u64, Vec<u64>>,
+    /// parents of requests not routed yet. an entry is taken when its
+    /// request is routed.
+    parents: HashMap<u64, Parent>,
+    /// message ids in `parents`, oldest first
+    parent_order: VecDeque<u64>,
+}
+
+impl Tracer {
+    fn insert(&mut self, span: Span) {
+        if self.order.len() >= MAX_SPANS {
+            if let Some(oldest) = self.order.pop_front() {
+                if let Some(old) = self.spans.remove(&oldest) {
+                    if self.latest.get(&old.message_id) == Some(&oldest) {
+                        self.latest.remove(&old.message_id);
+                    }
+                    if let Some(open) = self.open.get_mut(&old.message_id) {
+                        open.retain(|span_id| *span_id != oldest);
+                        if open.is_empty() {
+                            self.open.remove(&old.message_id);
+                        }
+                    }
+                }
+            }
+        }
+        if span.outcome == Outcome::Pending {
+            self.open
+                .entry(span.message_id)
+                .or_default()
+                .push(span.span_id);
+        }
+        self.latest.insert(span.message_id, span.span_id);
+        self.order.push_back(span.span_id);
+        self.spans.insert(span.span_id, span);
+    }
+
+    /// Record the parent of a request before it is routed. Parents of
+    /// requests that are never routed are dropped oldest first.
+    fn set_parent(&mut self, message_id: u64, parent: Parent) {
+        if self.parents.insert(message_id, parent).is_none() {
+            self.parent_order.push_back(message_id);
+        }
+        while self.parent_order.len() > MAX_SPANS {
+            if let Some(oldest) = self.parent_order.pop_front() {
+                self.parents.remove(&oldest);
+            }
+        }
+    }
+
+    fn take_parent(&mut self, message_id: u64) -> Option<Parent> {
+        let parent = self.parents.remove(&message_id)?;
+        if let Some(index) = self.parent_order.iter().position(|id| *id == message_id) {
+            self.parent_order.remove(index);
+        }
+        Some(parent)
+    }
+
+    fn latest(&self, message_id: u64) -> Option<&Span> {
+        self.latest
+            .get(&message_id)
+            .and_then(|span_id| self.spans.get(span_id))
+    }
+
+    /// end every open span of a message
+    fn end(&mut self, message_id: u64, outcome: Outcome) {
+        let Some(open) = self.open.remove(&message_id) else {
+            return;
+        };
+        let now = now_nanos();
+        for span_id in open {
+            if let Some(span) = self.spans.get_mut(&span_id) {
+                span.end = Some(now);
+                span.outcome = outcome;
+            }
+        }
+    }
+}
+
+fn now_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// Ids that every node derives the same way for a message. A request from a
+/// node that doesn't send trace context continues the trace these name.
+fn derived_id(node: &str, message_id: u64) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(node.as_bytes())
+        .chain_update(message_id.to_be_bytes())
+        .finalize()
+        .into()
+}
+
+fn trace_id_for(node: &str, message_id: u64) -> u128 {
+    u128::from_be_bytes(derived_id(node, message_id)[..16].try_into().unwrap())
+}
+
+fn span_id_for(node: &str, message_id: u64) -> u64 {
+    u64::from_be_bytes(derived_id(node, message_id)[16..24].try_into().unwrap())
+}
+
+fn size_of(km: &KernelMessage) -> usize {
+    let body = match &km.message {
+        Message::Request(request) => request.body.len(),
+        Message::Response((response, _)) => response.body.len(),
+    };
+    body + km
+        .lazy_load_blob
+        .as_ref()
+        .map_or(0, |blob| blob.bytes.len())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turn tracing on or off. Spans recorded so far are kept until turned on again.
+pub fn set_enabled(enabled: bool) {
+    if enabled && !ENABLED.load(Ordering::Relaxed) {
+        *TRACER.lock().unwrap() = Tracer::default();
+    }
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// A process that was handling one message sent a new request.
+pub fn link(message_id: u64, parent_message_id: u64) {
+    if !enabled() || message_id == parent_message_id {
+        return;
+    }
+    TRACER
+        .lock()
+        .unwrap()
+        .set_parent(message_id, Parent::Message(parent_message_id));
+}
+
+/// The trace context to send after a request going to another node: the
+/// trace it is part of and the span it is sent in, so that the span it is
+/// received in joins the same trace however many hops it is from the root.
+pub fn context(km: &KernelMessage) -> Option<[u8; CONTEXT_LEN]> {
+    if !enabled() || !matches!(km.message, Message::Request(_)) {
+        return None;
+    }
+    let tracer = TRACER.lock().unwrap();
+    let span = tracer.latest(km.id)?;
+    let mut context = [0; CONTEXT_LEN];
+    context[..16].copy_from_slice(&span.trace_id.to_be_bytes());
+    context[16..].copy_from_slice(&span.span_id.to_be_bytes());
+    Some(context)
+}
+
+/// A request came in from another node with trace context. Anything that
+/// isn't context as sent by [`context`] is ignored.
+pub fn received(km: &KernelMessage, context: &[u8]) {
+    if !enabled() || !matches!(km.message, Message::Request(_)) {
+        return;
+    }
+    let Ok(context) = <[u8; CONTEXT_LEN]>::try_from(context) else {
+        return;
+    };
+    let trace_id = u128::from_be_bytes(context[..16].try_into().unwrap());
+    let span_id = u64::from_be_bytes(context[16..].try_into().unwrap());
+    TRACER
+        .lock()
+        .unwrap()
+        .set_parent(km.id, Parent::Remote { trace_id, span_id });
+}
+
+/// The kernel routed a message. A request starts a span, a response ends
+/// the spans of the request it answers.
+pub fn routed(our: &str, km: &KernelMessage) {
+    if !enabled() {
+        return;
+    }
+    let mut tracer = TRACER.lock().unwrap();
+    let Message::Request(request) = &km.message else {
+        tracer.end(km.id, Outcome::Responded);
+        return;
+    };
+    let parent = tracer.take_parent(km.id);
+    let parent = match tracer.latest(km.id) {
+        Some(span) => Some((span.span_id, span.trace_id)),
+        None => match parent {
+            Some(Parent::Message(parent_message_id)) => tracer
+                .latest(parent_message_id)
+                .map(|span| (span.span_id, span.trace_id)),
+            Some(Parent::Remote { trace_id, span_id }) => Some((span_id, trace_id)),
+            None => None,
+        },
+    };
+    let (parent_span_id, trace_id) = match parent {
+        Some((span_id, trace_id)) => (Some(span_id), trace_id),
+        // a request from a node that sent no trace context continues the
+        // span it was sent in there, as that node derives it
+        None if km.source.node != our => (
+            Some(span_id_for(&km.source.node, km.id)),
+            trace_id_for(&km.source.node, km.id),
+        ),
+        None => (None, trace_id_for(our, km.id)),
+    };
+    let span_id = if km.target.node == our {
+        rand::random()
+    } else {
+        span_id_for(our, km.id)
+    };
+    let now = now_nanos();
+    let mut events = vec![];
+    if km.source.node != our {
+        events.push((now, format!("received from {}", km.source.node)));
+    }
+    let span = Span {
+        span_id,
+        parent_span_id,
+        trace_id,
+        message_id: km.id,
+        source: km.source.clone(),
+        target: km.target.clone(),
+        rsvp: km.rsvp.clone(),
+        size: size_of(km),
+        start: now,
+        end: match request.expects_response {
+            Some(_) => None,
+            None => Some(now),
+        },
+        outcome: match request.expects_response {
+            Some(_) => Outcome::Pending,
+            None => Outcome::Sent,
+        },
+        events,
+    };
+    tracer.insert(span);
+}
+
+/// A request could not be delivered, or its response didn't come in time.
+pub fn failed(message_id: u64, kind: SendErrorKind) {
+    if !enabled() {
+        return;
+    }
+    TRACER
+        .lock()
+        .unwrap()
+        .end(message_id, Outcome::Failed(kind));
+}
+
+/// Something happened to a request on its way, such as being sent over the network.
+pub fn event(message_id: u64, name: String) {
+    if !enabled() {
+        return;
+    }
+    let mut tracer = TRACER.lock().unwrap();
+    let Some(span_id) = tracer.latest.get(&message_id).copied() else {
+        return;
+    };
+    if let Some(span) = tracer.spans.get_mut(&span_id) {
+        span.events.push((now_nanos(), name));
+    }
+}
+
+/// every span in the trace of a message, or in the most recent traces
+fn collect(message_id: Option<u64>) -> Vec<Span> {
+    let tracer = TRACER.lock().unwrap();
+    let trace_ids: Vec<u128> = match message_id {
+        Some(message_id) => tracer
+            .spans
+            .values()
+            .filter(|span| span.message_id == message_id)
+            .map(|span| span.trace_id)
+            .collect(),
+        None => {
+            let mut trace_ids = vec![];
+            for span_id in tracer.order.iter().rev() {
+                let Some(span) = tracer.spans.get(span_id) else {
+                    continue;
+                };
+                if !trace_ids.contains(&span.trace_id) {
+                    trace_ids.push(span.trace_id);
+                }
+                if trace_ids.len() >= RECENT_TRACES {
+                    break;
+                }
+            }
+            trace_ids
+        }
+    };
+    let mut spans: Vec<Span> = tracer
+        .spans
+        .values()
+        .filter(|span| trace_ids.contains(&span.trace_id))
+        .cloned()
+        .collect();
+    spans.sort_by_key(|span| span.start);
+    spans
+}
+
+fn describe(span: &Span) -> String {
+    let duration = match span.end {
+        Some(end) => format!(
+            "{:.3}ms",
+            end.saturating_sub(span.start) as f64 / 1_000_000.0
+        ),
+        None => "...".into(),
+    };
+    let outcome = match span.outcome {
+        Outcome::Pending => "pending".into(),
+        Outcome::Sent => "sent".into(),
+        Outcome::Responded => "responded".into(),
+        Outcome::Failed(kind) => format!("failed: {kind:?}"),
+    };
+    let rsvp = match &span.rsvp {
+        Some(rsvp) => format!(" (rsvp {rsvp})"),
+        None => "".into(),
+    };
+    format!(
+        "{} {} -> {}{rsvp}, {}B, {duration}, {outcome}",
+        span.message_id, span.source, span.target, span.size
+    )
+}
+
+/// The trace of a message as an indented tree, one line per span, or the
+/// most recent traces if no message is given.
+pub fn render_tree(message_id: Option<u64>) -> String {
+    if !enabled() && TRACER.lock().unwrap().spans.is_empty() {
+        return "tracing is off".into();
+    }
+    let spans = collect(message_id);
+    if spans.is_empty() {
+        return "no spans found".into();
+    }
+    let mut children: HashMap<Option<u64>, Vec<&Span>> = HashMap::new();
+    for span in &spans {
+        // a span whose parent was dropped is shown as a root
+        let parent = span
+            .parent_span_id
+            .filter(|parent| spans.iter().any(|s| s.span_id == *parent));
+        children.entry(parent).or_default().push(span);
+    }
+    let mut out = String::new();
+    let mut stack: Vec<(&Span, usize)> = children
+        .get(&None)
+        .map(|roots| roots.iter().rev().map(|span| (*span, 0)).collect())
+        .unwrap_or_default();
+    while let Some((span, depth)) = stack.pop() {
+        out.push_str(&format!("{}{}\r\n", "  ".repeat(depth), describe(span)));
+        for (at, name) in &span.events {
+            out.push_str(&format!(
+                "{}  - {name} at +{:.3}ms\r\n",
+                "  ".repeat(depth),
+                at.saturating_sub(span.start) as f64 / 1_000_000.0
+            ));
+        }
+        if let Some(kids) = children.get(&Some(span.span_id)) {
+            stack.extend(kids.iter().rev().map(|kid| (*kid, depth + 1)));
+        }
+    }
+    out
+}
+
+fn attribute(key: &str, value: serde_json::Value) -> serde_json::Value {
+    let value = match value {
+        serde_json::Value::Number(n) => serde_json::json!({ "intValue": n.to_string() }),
+        other => serde_json::json!({ "stringValue": other.as_str().unwrap_or_default() }),
+    };
+    serde_json::json!({ "key": key, "value": value })
+}
+
+/// The trace of a message, or the most recent traces, in OpenTelemetry's
+/// OTLP JSON format, ready to be sent to a collector.
+pub fn export_otlp(our: &str, message_id: Option<u64>) -> serde_json::Value {
+    let spans: Vec<serde_json::Value> = collect(message_id)
+        .iter()
+        .map(|span| {
+            let mut attributes = vec![
+                attribute("kinode.message.id", span.message_id.into()),
+                attribute("kinode.source", span.source.to_string().into()),
+                attribute("kinode.target", span.target.to_string().into()),
+                attribute("kinode.size", span.size.into()),
+            ];
+            if let Some(rsvp) = &span.rsvp {
+                attributes.push(attribute("kinode.rsvp", rsvp.to_string().into()));
+            }
+            let status = match span.outcome {
+                Outcome::Failed(kind) => {
+                    serde_json::json!({ "code": 2, "message": format!("{kind:?}") })
+                }
+                Outcome::Pending => serde_json::json!({ "code": 0 }),
+                Outcome::Sent | Outcome::Responded => serde_json::json!({ "code": 1 }),
+            };
+            serde_json::json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "parentSpanId": span
+                    .parent_span_id
+                    .map(|id| format!("{id:016x}"))
+                    .unwrap_or_default(),
+                "name": format!("{} -> {}", span.source.process, span.target.process),
+                "kind": 4, // SPAN_KIND_PRODUCER
+                "startTimeUnixNano": span.start.to_string(),
+                "endTimeUnixNano": span.end.unwrap_or(span.start).to_string(),
+                "attributes": attributes,
+                "events": span
+                    .events
+                    .iter()
+                    .map(|(at, name)| serde_json::json!({
+                        "timeUnixNano": at.to_string(),
+                        "name": name,
+                    }))
+                    .collect::<Vec<_>>(),
+                "status": status,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", "kinode".into()),
+                    attribute("kinode.node", our.into()),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "kinode.kernel" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
@@ -419,6 +419,31 @@ pub struct Request {
     pub body: Vec<u8>,
     pub metadata: Option<String>, // JSON-string
     pub capabilities: Vec<(Capability, Vec<u8>)>,
+    /// how urgently the kernel should deliver this request. only meaningful
+    /// on the node that sent it, so it is never serialized. set only by
+    /// runtime modules: requests from processes are always `User`, and take
+    /// the priority set for their process with [`KernelCommand::SetPriority`].
+    #[serde(skip)]
+    pub priority: MessagePriority,
+    /// milliseconds since the epoch after which the kernel will no longer
+    /// deliver this request, and instead fail it with `SendErrorKind::Timeout`.
+    /// only meaningful on the node that sent it, so it is never serialized.
+    #[serde(skip)]
+    pub deadline: Option<u64>,
+}
+
+/// The order in which the kernel delivers messages that are waiting on it:
+/// `System` messages go before `User` messages, and `User` messages before
+/// `Bulk` messages, but a message that has waited long enough goes ahead of
+/// more urgent ones that arrived after it, so no priority is starved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MessagePriority {
+    /// large transfers that can wait, such as app downloads
+    Bulk,
+    #[default]
+    User,
+    /// the kernel and runtime modules
+    System,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -453,6 +478,49 @@ impl std::fmt::Display for Capability {
     }
 }
 
+/// Restrictions a capability was granted under, signed along with it and
+/// carried after its signature to other nodes. A capability granted without limits never expires, may be used any number
+/// of times, and may be passed on to other processes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapLimits {
+    /// unix time in seconds after which the capability is no longer valid
+    pub expires: Option<u64>,
+    /// how many more times the capability may be checked before it is dropped
+    pub uses_left: Option<u64>,
+    /// whether the holder may attach the capability to messages for others
+    pub delegable: bool,
+    /// every holder the capability passed through on its way here, oldest first
+    pub delegated_by: Vec<Address>,
+}
+
+impl CapLimits {
+    pub fn is_valid(&self, now: u64) -> bool {
+        self.expires.map_or(true, |expires| now < expires) && self.uses_left != Some(0)
+    }
+
+    /// Split off the limits that `delegator` passes this capability on under.
+    /// If its uses are limited, the new holder gets half of those left,
+    /// rounded down, and they are taken from this holder, so delegating never
+    /// adds uses. Returns None if the capability may not be passed on, or if
+    /// it has too few uses left to split.
+    pub fn delegate(&mut self, delegator: &Address, now: u64) -> Option<CapLimits> {
+        if !self.delegable || !self.is_valid(now) {
+            return None;
+        }
+        let mut delegated = self.clone();
+        if let Some(uses_left) = self.uses_left.as_mut() {
+            let given = *uses_left / 2;
+            if given == 0 {
+                return None;
+            }
+            *uses_left -= given;
+            delegated.uses_left = Some(given);
+        }
+        delegated.delegated_by.push(delegator.clone());
+        Some(delegated)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SendError {
     pub kind: SendErrorKind,
@@ -461,10 +529,12 @@ pub struct SendError {
     pub lazy_load_blob: Option<LazyLoadBlob>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SendErrorKind {
     Offline,
     Timeout,
+    /// the target process had too many messages queued to accept another
+    QueueFull,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -622,6 +692,8 @@ pub fn de_wit_request(wit: wit::Request) -> Request {
             .iter()
             .map(|cap| de_wit_capability(cap.clone()))
             .collect(),
+        priority: MessagePriority::User,
+        deadline: None,
     }
 }
 
@@ -730,6 +802,8 @@ pub fn en_wit_send_error_kind(kind: SendErrorKind) -> wit::SendErrorKind {
     match kind {
         SendErrorKind::Offline => wit::SendErrorKind::Offline,
         SendErrorKind::Timeout => wit::SendErrorKind::Timeout,
+        // processes see a full queue as a timeout until the WIT has a kind for it
+        SendErrorKind::QueueFull => wit::SendErrorKind::Timeout,
     }
 }
 
@@ -943,6 +1017,111 @@ pub enum KernelCommand {
     Shutdown,
     /// Ask kernel to produce debugging information
     Debug(KernelPrint),
+    /// Like `GrantCapabilities`, but each capability is granted under the
+    /// given limits: it may expire, run out of uses, or be barred from
+    /// being passed on by the process that holds it.
+    GrantLimitedCapabilities {
+        target: ProcessId,
+        capabilities: Vec<(Capability, CapLimits)>,
+    },
+    /// Read entries from the capability audit log, newest first.
+    /// Responds with [`KernelResponse::CapAudit`].
+    GetCapAudit(CapAuditQuery),
+    /// Stop delivering messages to a process without killing it. Messages
+    /// sent to it are held by the kernel, up to a limit, and delivered in
+    /// order on `ResumeProcess`. If `persist` is true, the held messages
+    /// (and the suspension) survive a reboot.
+    SuspendProcess { target: ProcessId, persist: bool },
+    /// Deliver every message held for a suspended process, and go back to
+    /// delivering messages to it as they arrive. Held messages that don't fit
+    /// in the process's queue are delivered, in order, as it makes room.
+    ResumeProcess(ProcessId),
+    /// Swap the code of a running process for the wasm at `wasm_bytes_handle`
+    /// without dropping its messages. The process handles every message sent
+    /// before the upgrade with its old code, then the new code is started with
+    /// the same state, queued messages and outstanding requests. If the new
+    /// code exports `migrate: func(our: string, previous: string) -> result<_, string>`,
+    /// it is called before `init` with the old `wasm_bytes_handle`.
+    ///
+    /// If the new code fails to instantiate or migrate, or crashes before it
+    /// first calls `receive`, the process is rolled back to its old code.
+    /// Responds with [`KernelResponse::UpgradedProcess`] once the new code is
+    /// running, [`KernelResponse::UpgradeProcessNotRunning`] if the process
+    /// isn't running, or [`KernelResponse::UpgradeProcessError`].
+    UpgradeProcess {
+        id: ProcessId,
+        wasm_bytes_handle: String,
+    },
+    /// RUNTIME ONLY: sent by a process once its new code is running after an
+    /// `UpgradeProcess`, so the kernel persists it for the next boot.
+    RecordUpgrade {
+        id: ProcessId,
+        wasm_bytes_handle: String,
+    },
+    /// Set how many messages may be queued for a process, and what happens
+    /// to messages sent to it once that many are.
+    SetQueueConfig {
+        target: ProcessId,
+        config: QueueConfig,
+    },
+    /// Get the depth of, and traffic through, every process's message queue.
+    /// Responds with [`KernelResponse::QueueMetrics`].
+    GetQueueMetrics,
+    /// Set the priority the kernel delivers a process's requests and
+    /// responses at, for those requests that weren't sent with one. Processes
+    /// can't set a priority on individual requests, so this is how their
+    /// traffic is prioritized.
+    SetPriority {
+        target: ProcessId,
+        priority: MessagePriority,
+    },
+    /// Turn message tracing on or off. While on, the kernel records a span
+    /// for each request it routes. `Debug(KernelPrint::Trace(Some(id)))`
+    /// prints the trace of a message, and `Trace(None)` the most recent
+    /// traces. Turning it on clears old spans.
+    SetTracing(bool),
+}
+
+/// What the kernel does with a request for a process whose queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueuePolicy {
+    /// refuse the message, and give its sender a `SendErrorKind::QueueFull`
+    #[default]
+    Reject,
+    /// drop the message without telling its sender
+    Drop,
+}
+
+/// The bounds on a process's message queue.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueConfig {
+    /// how many requests may be waiting for the process at once. responses
+    /// and send errors are queued past this.
+    pub capacity: usize,
+    pub policy: QueuePolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            policy: QueuePolicy::Reject,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueueMetrics {
+    pub process: ProcessId,
+    /// messages waiting for the process right now
+    pub depth: usize,
+    pub capacity: usize,
+    /// the deepest the queue has been since the process started
+    pub high_water: usize,
+    /// messages refused since the process started
+    pub rejected: u64,
+    /// messages dropped since the process started
+    pub dropped: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -950,6 +1129,9 @@ pub enum KernelPrint {
     ProcessMap,
     Process(ProcessId),
     HasCap { on: ProcessId, cap: Capability },
+    CapAudit(CapAuditQuery),
+    QueueMetrics,
+    Trace(Option<u64>),
 }
 
 /// IPC format for all KernelCommand responses
@@ -960,6 +1142,66 @@ pub enum KernelResponse {
     StartedProcess,
     RunProcessError,
     KilledProcess(ProcessId),
+    CapAudit(Vec<CapAuditEntry>),
+    SuspendedProcess(ProcessId),
+    SuspendProcessError,
+    /// the process, and how many held messages were delivered to it right
+    /// away. any others follow as its queue makes room.
+    ResumedProcess(ProcessId, usize),
+    ResumeProcessError,
+    UpgradedProcess(ProcessId),
+    UpgradeProcessError,
+    QueueMetrics(Vec<QueueMetrics>),
+    /// the process isn't running, so there is no code to swap out
+    UpgradeProcessNotRunning(ProcessId),
+}
+
+/// Something that happened to, or was done with, a process's capabilities.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CapAuditEvent {
+    /// the caps were granted to the process
+    Added,
+    /// the caps were dropped from the process
+    Dropped,
+    /// the caps were taken from the process because their issuer exited
+    Revoked,
+    /// the caps expired or ran out of uses, and were pruned
+    Expired,
+    /// a runtime module checked whether the process holds the cap
+    Checked { allowed: bool },
+    /// the process sent a message that requires the cap
+    Sent { target: Address, allowed: bool },
+    /// the process was sent a message over the network, which requires the cap
+    Received { source: Address, allowed: bool },
+}
+
+/// One entry in the kernel's append-only capability audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapAuditEntry {
+    /// unix time in milliseconds
+    pub timestamp: u64,
+    pub process: ProcessId,
+    pub caps: Vec<Capability>,
+    pub event: CapAuditEvent,
+}
+
+/// Which capability audit log entries to return. Each field that is set
+/// narrows the search; an empty query returns the most recent entries.
+/// A query looks at a bounded number of entries, newest first: to search
+/// further back with `process` or `issuer` set, narrow `since` and `until`.
+/// Entries are kept for 30 days.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CapAuditQuery {
+    /// only entries about this process
+    pub process: Option<ProcessId>,
+    /// only entries involving a cap issued by this process
+    pub issuer: Option<ProcessId>,
+    /// only entries at or after this unix time in milliseconds
+    pub since: Option<u64>,
+    /// only entries at or before this unix time in milliseconds
+    pub until: Option<u64>,
+    /// at most this many entries
+    pub limit: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -1013,6 +1255,54 @@ pub struct PersistedProcess {
     pub on_exit: OnExit,
     pub capabilities: HashMap<Capability, Vec<u8>>,
     pub public: bool, // marks if a process allows messages from any process
+    /// limits on any of `capabilities` that were granted with them
+    #[serde(default)]
+    pub cap_limits: HashMap<Capability, CapLimits>,
+}
+
+impl PersistedProcess {
+    /// Does the process hold `cap`, and is it still valid?
+    pub fn has_cap(&self, cap: &Capability, now: u64) -> bool {
+        self.capabilities.contains_key(cap)
+            && self
+                .cap_limits
+                .get(cap)
+                .map_or(true, |limits| limits.is_valid(now))
+    }
+
+    /// Check that the process holds a valid `cap`, counting this as one use
+    /// of it if its uses are limited. A cap on its last use is dropped.
+    pub fn use_cap(&mut self, cap: &Capability, now: u64) -> bool {
+        if !self.has_cap(cap, now) {
+            return false;
+        }
+        if let Some(limits) = self.cap_limits.get_mut(cap) {
+            if let Some(uses_left) = limits.uses_left.as_mut() {
+                *uses_left -= 1;
+            }
+            if !limits.is_valid(now) {
+                self.capabilities.remove(cap);
+                self.cap_limits.remove(cap);
+            }
+        }
+        true
+    }
+
+    /// Drop every capability that has expired or run out of uses.
+    /// Returns the capabilities that were dropped.
+    pub fn prune_caps(&mut self, now: u64) -> Vec<Capability> {
+        let pruned: Vec<Capability> = self
+            .cap_limits
+            .iter()
+            .filter(|(_, limits)| !limits.is_valid(now))
+            .map(|(cap, _)| cap.clone())
+            .collect();
+        for cap in &pruned {
+            self.capabilities.remove(cap);
+            self.cap_limits.remove(cap);
+        }
+        pruned
+    }
 }
 
 impl std::fmt::Display for PersistedProcess {
@@ -1034,6 +1324,9 @@ impl std::fmt::Display for PersistedProcess {
                 let mut caps_string = "[".to_string();
                 for cap in self.capabilities.keys() {
                     caps_string += &format!("\n        {}", cap.to_string());
+                    if let Some(limits) = self.cap_limits.get(cap) {
+                        caps_string += &format!(" {limits:?}");
+                    }
                 }
                 caps_string + "\n    ]"
             },
@@ -1558,6 +1851,52 @@ pub enum NetAction {
         from: Address,
         signature: Vec<u8>,
     },
+    /// get a [`NetDiagnostics`] struct containing the same networking information
+    /// as [`NetAction::GetDiagnostics`], plus per-connection statistics
+    GetDiagnosticsReport,
+    /// deliver `request` to `target` with store-and-forward semantics: if the
+    /// target is offline, the request is persisted in our outbox and retried
+    /// with backoff until `deadline` (unix seconds). the blob attached to this
+    /// message is delivered along with the request. queued requests never
+    /// expect a response. if delivery fails before the deadline, the source
+    /// receives an offline [`SendError`] containing the request.
+    /// if `router_hold` is set and the target is an indirect node, the request
+    /// may be handed to one of its routers to deliver when the target reconnects.
+    /// **only accepted from our own node**
+    Enqueue {
+        target: Address,
+        request: Request,
+        deadline: u64,
+        router_hold: bool,
+    },
+    /// get the [`OutboxEntry`]s of all requests awaiting delivery
+    GetOutbox,
+    /// sent to a router to hold a message for an indirect node it routes for
+    Hold(HeldMessage),
+    /// sent by a router to an indirect node, containing a message held for it
+    Deliver(HeldMessage),
+    /// sent over a routed connection by an indirect node that has mapped a
+    /// port on its gateway, or learned from its router where it can be
+    /// reached: the receiver may connect to it directly at this address,
+    /// replacing the routed connection. a receiver that can be reached too
+    /// offers its own address back, and both connect to each other at once,
+    /// punching holes in their NATs; the one whose name sorts first keeps
+    /// the connection.
+    DirectOffer {
+        ip: String,
+        port: u16,
+    },
+    /// remove a node from our PKI, when the chain event that registered it
+    /// has been reorged out. like [`NetAction::KnsUpdate`], only accepted
+    /// from ourselves locally.
+    KnsRemove(String),
+    /// sent by a router to an indirect node it routes for when it connects:
+    /// the address the router saw the connection come from, which the node
+    /// can offer routed peers in a [`NetAction::DirectOffer`]
+    ObservedAddress {
+        ip: String,
+        port: u16,
+    },
 }
 
 /// For now, only sent in response to a ConnectionRequest.
@@ -1581,6 +1920,168 @@ pub enum NetResponse {
     /// cannot be found in our representation of PKI, this will return false,
     /// because we cannot find the networking public key to verify with.
     Verified(bool),
+    /// response to [`NetAction::GetDiagnosticsReport`]
+    DiagnosticsReport(NetDiagnostics),
+    /// response to [`NetAction::Enqueue`]. contains the ID that the queued
+    /// request will be sent with, and that an eventual [`SendError`] will carry.
+    Queued(u64),
+    /// response to [`NetAction::GetOutbox`]
+    Outbox(Vec<OutboxEntry>),
+}
+
+/// A message persisted in the networking module's outbox, awaiting delivery.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub message: KernelMessage,
+    /// unix seconds after which we give up on delivery
+    pub deadline: u64,
+    pub router_hold: bool,
+    pub attempts: u32,
+    /// unix seconds at which the next delivery attempt will be made
+    pub next_attempt: u64,
+    /// set if we are holding this message as a router on behalf of the
+    /// target: the source node's signature of the message
+    pub held_signature: Option<Vec<u8>>,
+}
+
+/// A message held by a router for an offline indirect node. The signature is
+/// made by the source node's networking key over the message-packed
+/// [`KernelMessage`] and deadline, so the target can verify the router didn't
+/// forge the message or extend how long it may be delivered for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeldMessage {
+    pub message: KernelMessage,
+    pub deadline: u64,
+    pub signature: Vec<u8>,
+}
+
+/// A snapshot of the networking module's state, for monitoring node health.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NetDiagnostics {
+    pub our: Identity,
+    /// address of the KNS contract the PKI is indexed from
+    pub kns_address: String,
+    pub pki_entries: u64,
+    pub peers: Vec<PeerDiagnostics>,
+    /// (source, target) pairs waiting for the target to build the other side
+    /// of a passthrough. only populated on direct nodes.
+    pub pending_passthroughs: Vec<(NodeId, NodeId)>,
+    /// status of each of our allowed routers. only populated on indirect nodes.
+    pub routers: Vec<RouterStatus>,
+}
+
+/// Statistics for a single connection. Passthroughs we forward as a router
+/// are also reported here, with `name` set to the node that requested it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerDiagnostics {
+    pub name: NodeId,
+    pub connection_type: ConnectionType,
+    /// true if we are acting as a router for this peer
+    pub routing_for: bool,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// round-trip time of the most recent keepalive ping, if one has completed
+    pub rtt_ms: Option<u64>,
+    /// seconds since the connection was established
+    pub uptime_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ConnectionType {
+    /// a websocket connection straight to the peer
+    Direct,
+    /// a connection to the peer proxied through one of their (or our) routers
+    Routed { router: NodeId },
+    /// a connection we are forwarding, as a router, to `target`
+    Passthrough { target: NodeId },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouterStatus {
+    pub name: NodeId,
+    pub connected: bool,
+}
+
+impl std::fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectionType::Direct => write!(f, "direct"),
+            ConnectionType::Routed { router } => write!(f, "routed via {router}"),
+            ConnectionType::Passthrough { target } => write!(f, "passthrough to {target}"),
+        }
+    }
+}
+
+impl std::fmt::Display for NetDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "indexing from contract address {}\r\n", self.kns_address)?;
+        write!(f, "our Identity: {:#?}\r\n", self.our)?;
+        if !self.routers.is_empty() {
+            write!(f, "our routers:\r\n")?;
+            for router in &self.routers {
+                write!(f, "    {}, connected={}\r\n", router.name, router.connected)?;
+            }
+        }
+        write!(f, "we have connections with peers:\r\n")?;
+        for peer in self
+            .peers
+            .iter()
+            .filter(|p| !matches!(p.connection_type, ConnectionType::Passthrough { .. }))
+        {
+            write!(
+                f,
+                "    {}, {}, routing_for={}, sent={}msg/{}B, received={}msg/{}B, rtt={}, up {}s\r\n",
+                peer.name,
+                peer.connection_type,
+                peer.routing_for,
+                peer.messages_sent,
+                peer.bytes_sent,
+                peer.messages_received,
+                peer.bytes_received,
+                peer.rtt_ms
+                    .map(|ms| format!("{ms}ms"))
+                    .unwrap_or("unknown".into()),
+                peer.uptime_secs,
+            )?;
+        }
+        write!(f, "we have {} entries in the PKI\r\n", self.pki_entries)?;
+        if self.our.ws_routing.is_some() {
+            write!(
+                f,
+                "we have {} pending passthrough connections\r\n",
+                self.pending_passthroughs.len()
+            )?;
+            for (source, target) in &self.pending_passthroughs {
+                write!(f, "    {source} -> {target}\r\n")?;
+            }
+            let passthroughs: Vec<&PeerDiagnostics> = self
+                .peers
+                .iter()
+                .filter(|p| matches!(p.connection_type, ConnectionType::Passthrough { .. }))
+                .collect();
+            write!(
+                f,
+                "we have {} open passthrough connections\r\n",
+                passthroughs.len()
+            )?;
+            for peer in passthroughs {
+                write!(
+                    f,
+                    "    {} {}, sent={}msg/{}B, received={}msg/{}B, up {}s\r\n",
+                    peer.name,
+                    peer.connection_type,
+                    peer.messages_sent,
+                    peer.bytes_sent,
+                    peer.messages_received,
+                    peer.bytes_received,
+                    peer.uptime_secs,
+                )?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Hash, Eq, PartialEq)]
@@ -1593,3 +2094,174 @@ pub struct KnsUpdate {
     pub port: u16,
     pub routers: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(process: &str) -> Address {
+        Address {
+            node: "fake.os".into(),
+            process: process.parse().unwrap(),
+        }
+    }
+
+    fn cap(params: &str) -> Capability {
+        Capability {
+            issuer: address("vfs:distro:sys"),
+            params: params.into(),
+        }
+    }
+
+    fn process_with(caps: Vec<(Capability, Option<CapLimits>)>) -> PersistedProcess {
+        let mut process = PersistedProcess {
+            wasm_bytes_handle: String::new(),
+            wit_version: None,
+            on_exit: OnExit::None,
+            capabilities: HashMap::new(),
+            public: false,
+            cap_limits: HashMap::new(),
+        };
+        for (cap, limits) in caps {
+            process.capabilities.insert(cap.clone(), vec![]);
+            if let Some(limits) = limits {
+                process.cap_limits.insert(cap, limits);
+            }
+        }
+        process
+    }
+
+    #[test]
+    fn cap_limits_validity() {
+        assert!(CapLimits::default().is_valid(u64::MAX));
+        let expiring = CapLimits {
+            expires: Some(100),
+            ..Default::default()
+        };
+        assert!(expiring.is_valid(99));
+        assert!(!expiring.is_valid(100));
+        let used_up = CapLimits {
+            uses_left: Some(0),
+            ..Default::default()
+        };
+        assert!(!used_up.is_valid(0));
+    }
+
+    #[test]
+    fn delegating_splits_uses() {
+        let mut limits = CapLimits {
+            uses_left: Some(5),
+            delegable: true,
+            ..Default::default()
+        };
+        let delegated = limits.delegate(&address("a:b:c"), 0).unwrap();
+        assert_eq!(delegated.uses_left, Some(2));
+        assert_eq!(delegated.delegated_by, vec![address("a:b:c")]);
+        assert_eq!(limits.uses_left, Some(3));
+        assert!(limits.delegated_by.is_empty());
+
+        let mut last_use = CapLimits {
+            uses_left: Some(1),
+            delegable: true,
+            ..Default::default()
+        };
+        assert!(last_use.delegate(&address("a:b:c"), 0).is_none());
+        assert_eq!(last_use.uses_left, Some(1));
+    }
+
+    #[test]
+    fn delegating_requires_delegable_and_valid() {
+        let mut not_delegable = CapLimits::default();
+        assert!(not_delegable.delegate(&address("a:b:c"), 0).is_none());
+        let mut expired = CapLimits {
+            expires: Some(10),
+            delegable: true,
+            ..Default::default()
+        };
+        assert!(expired.delegate(&address("a:b:c"), 10).is_none());
+        let unlimited_uses = expired.delegate(&address("a:b:c"), 9).unwrap();
+        assert_eq!(unlimited_uses.uses_left, None);
+        assert_eq!(unlimited_uses.expires, Some(10));
+    }
+
+    #[test]
+    fn using_a_cap_counts_down_and_drops_it() {
+        let limited = cap("\"read\"");
+        let unlimited = cap("\"write\"");
+        let mut process = process_with(vec![
+            (
+                limited.clone(),
+                Some(CapLimits {
+                    uses_left: Some(2),
+                    ..Default::default()
+                }),
+            ),
+            (unlimited.clone(), None),
+        ]);
+        assert!(process.use_cap(&limited, 0));
+        assert_eq!(process.cap_limits[&limited].uses_left, Some(1));
+        assert!(process.use_cap(&limited, 0));
+        assert!(!process.capabilities.contains_key(&limited));
+        assert!(!process.cap_limits.contains_key(&limited));
+        assert!(!process.use_cap(&limited, 0));
+
+        for _ in 0..3 {
+            assert!(process.use_cap(&unlimited, 0));
+        }
+        assert!(!process.use_cap(&cap("\"other\""), 0));
+    }
+
+    #[test]
+    fn using_an_expired_cap_fails() {
+        let expiring = cap("\"read\"");
+        let mut process = process_with(vec![(
+            expiring.clone(),
+            Some(CapLimits {
+                expires: Some(10),
+                ..Default::default()
+            }),
+        )]);
+        assert!(process.has_cap(&expiring, 9));
+        assert!(!process.use_cap(&expiring, 10));
+    }
+
+    #[test]
+    fn pruning_drops_only_invalid_caps() {
+        let expired = cap("\"a\"");
+        let used_up = cap("\"b\"");
+        let valid = cap("\"c\"");
+        let unlimited = cap("\"d\"");
+        let mut process = process_with(vec![
+            (
+                expired.clone(),
+                Some(CapLimits {
+                    expires: Some(5),
+                    ..Default::default()
+                }),
+            ),
+            (
+                used_up.clone(),
+                Some(CapLimits {
+                    uses_left: Some(0),
+                    ..Default::default()
+                }),
+            ),
+            (
+                valid.clone(),
+                Some(CapLimits {
+                    expires: Some(50),
+                    uses_left: Some(1),
+                    ..Default::default()
+                }),
+            ),
+            (unlimited.clone(), None),
+        ]);
+        let mut pruned = process.prune_caps(10);
+        pruned.sort_by_key(|cap| cap.params.clone());
+        assert_eq!(pruned, vec![expired, used_up]);
+        assert!(process.capabilities.contains_key(&valid));
+        assert!(process.capabilities.contains_key(&unlimited));
+        assert_eq!(process.capabilities.len(), 2);
+        assert!(process.prune_caps(10).is_empty());
+    }
+}
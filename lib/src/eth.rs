@@ -24,6 +24,24 @@ pub enum EthAction {
         method: String,
         params: serde_json::Value,
     },
+    /// Sign a transaction with one of the node's signers and send it. The node
+    /// fills in the nonce, and the gas price and limit if not given. Requires
+    /// the `{"send_transaction": "<from>"}` capability from eth:distro:sys, with
+    /// `from` checksummed as returned by [`EthConfigAction::GetSigners`], and can
+    /// only come from processes on this node. Responds with the transaction hash.
+    SendTransaction {
+        chain_id: u64,
+        /// address of a signer, as returned by [`EthConfigAction::GetSigners`]
+        from: String,
+        /// None to deploy a contract
+        to: Option<String>,
+        /// in wei
+        value: u128,
+        data: Vec<u8>,
+        gas_limit: Option<u64>,
+        /// in wei
+        gas_price: Option<u128>,
+    },
 }
 
 /// Incoming `Request` containing subscription updates or errors that processes will receive.
@@ -52,14 +70,14 @@ pub struct EthSubError {
 ///
 /// In the case of an [`EthAction::SubscribeLogs`] request, the response will indicate if
 /// the subscription was successfully created or not.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum EthResponse {
     Ok,
     Response { value: serde_json::Value },
     Err(EthError),
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum EthError {
     /// provider module cannot parse message
     MalformedRequest,
@@ -77,13 +95,16 @@ pub enum EthError {
     RpcTimeout,
     /// RPC gave garbage back
     RpcMalformedResponse,
+    /// Untrusted providers disagreed on the result of a read, and too few
+    /// gave the same answer to reach the chain's quorum
+    NoQuorum,
 }
 
 /// The action type used for configuring eth:distro:sys. Only processes which have the "root"
 /// capability from eth:distro:sys can successfully send this action.
 ///
-/// NOTE: changes to config will not be persisted between boots, they must be saved in .env
-/// to be reflected between boots. TODO: can change this
+/// Changes to providers, access settings, and quorums are saved to the node's home
+/// directory and reloaded at boot.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum EthConfigAction {
     /// Add a new provider to the list of providers.
@@ -112,6 +133,29 @@ pub enum EthConfigAction {
     GetAccessSettings,
     /// Get the state of calls and subscriptions. Used for debugging.
     GetState,
+    /// Set how many providers must return the same result before a read
+    /// request on this chain is answered. Only reads of a block given by
+    /// number or hash are checked, since reads of "latest" can differ between
+    /// honest providers. A response from a trusted provider is always accepted
+    /// on its own. 1 (the default) disables quorum reads. Fails with
+    /// [`EthError::NoRpcForChain`] if the chain has no providers.
+    SetQuorum { chain_id: u64, quorum: usize },
+    /// Set the limits on how much each remote node may use our provider.
+    SetQuota(RemoteQuota),
+    /// Get how much each remote node has used our provider.
+    GetUsage,
+    /// Create a new signing key for [`EthAction::SendTransaction`].
+    CreateSigner,
+    /// Import a hex-encoded private key for [`EthAction::SendTransaction`].
+    ImportSigner(String),
+    /// Delete the signing key with this address.
+    RemoveSigner(String),
+    /// Get the addresses of all our signing keys.
+    GetSigners,
+    /// Set which RPC methods local processes and remote nodes may call.
+    SetMethodPolicy(MethodPolicy),
+    /// Get the current [`MethodPolicy`].
+    GetMethodPolicy,
 }
 
 /// Response type from an [`EthConfigAction`] request.
@@ -126,19 +170,97 @@ pub enum EthConfigResponse {
     AccessSettings(AccessSettings),
     /// Permission denied due to missing capability
     PermissionDenied,
+    /// Response from a GetUsage request, keyed by node.
+    Usage(HashMap<String, NodeUsage>),
+    /// Response from a CreateSigner or ImportSigner request: the signer's address.
+    Signer(String),
+    /// Response from a GetSigners request.
+    Signers(Vec<String>),
+    /// Response from a GetMethodPolicy request.
+    MethodPolicy(MethodPolicy),
+    /// Error from a CreateSigner or ImportSigner request.
+    Err(EthError),
     /// Response from a GetState request
     State {
         active_subscriptions: HashMap<crate::core::Address, HashMap<u64, Option<String>>>, // None if local, Some(node_provider_name) if remote
         outstanding_requests: HashSet<u64>,
+        #[serde(default)]
+        providers: Vec<ProviderStatus>,
+        #[serde(default)]
+        quorums: HashMap<u64, usize>,
+        #[serde(default)]
+        cache: CacheStats,
     },
 }
 
+/// Counters for the RPC response cache, reported in response to
+/// [`EthConfigAction::GetState`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CacheStats {
+    /// requests answered from the cache
+    pub hits: u64,
+    /// requests sent to a provider
+    pub misses: u64,
+    /// requests that shared the response of an identical in-flight request
+    pub coalesced: u64,
+    /// number of responses currently cached
+    pub entries: u64,
+}
+
+/// Health of a single provider, as tracked by eth:distro:sys.
+/// Reported in response to [`EthConfigAction::GetState`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProviderStatus {
+    pub chain_id: u64,
+    /// node name or RPC URL
+    pub provider: String,
+    pub trusted: bool,
+    /// false if the provider has recently failed and is being passed over
+    pub healthy: bool,
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// latency of the last successful request, in milliseconds
+    pub latency_ms: Option<u64>,
+}
+
 /// Settings for our ETH provider
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AccessSettings {
     pub public: bool,           // whether or not other nodes can access through us
     pub allow: HashSet<String>, // whitelist for access (only used if public == false)
     pub deny: HashSet<String>,  // blacklist for access (always used)
+    #[serde(default)]
+    pub quota: RemoteQuota, // limits for remote nodes not in the whitelist
+}
+
+/// Limits on what a single remote node may use of our provider. Nodes in
+/// [`AccessSettings::allow`] are exempt. A limit of 0 means no limit.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RemoteQuota {
+    /// requests and subscription attempts, over a rolling one-minute window
+    pub requests_per_minute: u32,
+    /// subscriptions open at once
+    pub max_subscriptions: u32,
+}
+
+impl Default for RemoteQuota {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: 120,
+            max_subscriptions: 10,
+        }
+    }
+}
+
+/// How much a remote node has used our provider since we booted.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodeUsage {
+    pub requests_last_minute: u32,
+    pub requests_total: u64,
+    /// requests refused for exceeding the quota
+    pub rejected_total: u64,
+    pub subscriptions: u32,
 }
 
 pub type SavedConfigs = HashSet<ProviderConfig>;
@@ -169,12 +291,106 @@ impl std::cmp::PartialEq<str> for NodeOrRpcUrl {
     }
 }
 
+/// Which JSON-RPC methods may be called through eth:distro:sys with
+/// [`EthAction::Request`]. Each rule is a method name, or a prefix ending in
+/// `*` such as `debug_*`. Deny rules take precedence over allow rules, and a
+/// method matching no rule is denied.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MethodPolicy {
+    /// rules for processes on this node
+    pub local: MethodRules,
+    /// rules for remote nodes using our provider
+    pub remote: MethodRules,
+    /// rules for specific remote nodes, replacing `remote` for them
+    pub nodes: HashMap<String, MethodRules>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MethodRules {
+    pub allow: HashSet<String>,
+    pub deny: HashSet<String>,
+}
+
+impl MethodRules {
+    pub fn allows(&self, method: &str) -> bool {
+        let matches = |rule: &String| match rule.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => rule == method,
+        };
+        !self.deny.iter().any(matches) && self.allow.iter().any(matches)
+    }
+}
+
+impl MethodPolicy {
+    /// Whether `method` may be called by a process on `node`, where `None`
+    /// is this node.
+    pub fn allows(&self, method: &str, node: Option<&str>) -> bool {
+        match node {
+            None => self.local.allows(method),
+            Some(node) => self.nodes.get(node).unwrap_or(&self.remote).allows(method),
+        }
+    }
+}
+
+/// standard methods that are cheap for a provider to serve
+const DEFAULT_METHODS: &[&str] = &[
+    "eth_accounts",
+    "eth_blockNumber",
+    "eth_call",
+    "eth_chainId",
+    "eth_estimateGas",
+    "eth_feeHistory",
+    "eth_gasPrice",
+    "eth_getBalance",
+    "eth_getBlockByHash",
+    "eth_getBlockByNumber",
+    "eth_getBlockTransactionCountByHash",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getCode",
+    "eth_getLogs",
+    "eth_getProof",
+    "eth_getStorageAt",
+    "eth_getTransactionByHash",
+    "eth_getTransactionCount",
+    "eth_getTransactionReceipt",
+    "eth_hashrate",
+    "eth_maxPriorityFeePerGas",
+    "eth_sendRawTransaction",
+    "eth_syncing",
+    "net_version",
+    "web3_clientVersion",
+    "web3_sha3",
+];
+
+impl Default for MethodPolicy {
+    /// Local processes may also use the `debug_*` and `trace_*` namespaces,
+    /// where our providers support them; remote nodes may not.
+    fn default() -> Self {
+        let standard: HashSet<String> = DEFAULT_METHODS.iter().map(|m| m.to_string()).collect();
+        let mut local = standard.clone();
+        local.insert("debug_*".to_string());
+        local.insert("trace_*".to_string());
+        Self {
+            local: MethodRules {
+                allow: local,
+                deny: HashSet::new(),
+            },
+            remote: MethodRules {
+                allow: standard,
+                deny: HashSet::new(),
+            },
+            nodes: HashMap::new(),
+        }
+    }
+}
+
 //
 // Internal types
 //
 
 /// For static lifetimes of method strings.
 /// Replaced soon by alloy-rs network abstraction.
+#[deprecated(note = "methods are now allowed by a configurable `MethodPolicy`")]
 pub fn to_static_str(method: &str) -> Option<&'static str> {
     match method {
         "eth_getBalance" => Some("eth_getBalance"),